@@ -33,6 +33,18 @@ pub fn generate_random_number() -> Option<u32> {
 	None
 }
 
+pub fn generate_random_seed64() -> Option<u64> {
+	None
+}
+
+pub fn generate_random_number32() -> Option<u32> {
+	None
+}
+
+pub fn generate_random_number64() -> Option<u64> {
+	None
+}
+
 /// Search the most significant bit
 #[inline(always)]
 pub fn msb(value: u64) -> Option<u64> {
@@ -70,6 +82,21 @@ pub fn get_timer_ticks() -> u64 {
 	0
 }
 
+/// Returns the time in nanoseconds since boot.
+pub fn get_timer_nanos() -> u64 {
+	get_timer_ticks() * 1000
+}
+
+/// Returns the resolution of get_timer_nanos in nanoseconds.
+pub fn get_timer_resolution() -> u64 {
+	1000
+}
+
+/// Enables interrupts and waits for the next one.
+pub fn idle(_wakeup_time: Option<u64>) {
+	crate::arch::kernel::irq::enable_and_wait();
+}
+
 pub fn get_frequency() -> u16 {
 	0
 }
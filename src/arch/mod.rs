@@ -45,7 +45,7 @@ pub use crate::arch::aarch64::kernel::processor;
 pub use crate::arch::aarch64::kernel::irq;
 
 #[cfg(target_arch = "aarch64")]
-pub use crate::arch::aarch64::kernel::systemtime::get_boot_time;
+pub use crate::arch::aarch64::kernel::systemtime::{get_boot_time, set_system_time};
 
 #[cfg(target_arch = "riscv64")]
 pub use crate::arch::riscv64::*;
@@ -75,7 +75,7 @@ pub use crate::arch::riscv64::kernel::processor;
 pub use crate::arch::riscv64::kernel::irq;
 
 #[cfg(target_arch = "riscv64")]
-pub use crate::arch::riscv64::kernel::systemtime::get_boot_time;
+pub use crate::arch::riscv64::kernel::systemtime::{get_boot_time, set_system_time};
 
 #[cfg(target_arch = "x86_64")]
 pub use crate::arch::x86_64::*;
@@ -93,7 +93,7 @@ pub use crate::arch::x86_64::kernel::processor;
 #[cfg(target_arch = "x86_64")]
 pub use crate::arch::x86_64::kernel::scheduler;
 #[cfg(target_arch = "x86_64")]
-pub use crate::arch::x86_64::kernel::systemtime::get_boot_time;
+pub use crate::arch::x86_64::kernel::systemtime::{get_boot_time, set_system_time};
 #[cfg(target_os = "hermit")]
 #[cfg(target_arch = "x86_64")]
 pub use crate::arch::x86_64::kernel::{
@@ -6,6 +6,7 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::environment;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 extern "C" {
 	static mut boot_gtod: u64;
@@ -14,3 +15,12 @@ extern "C" {
 pub fn get_boot_time() -> u64 {
 	unsafe { boot_gtod }
 }
+
+/// The real-time clock isn't supported => the time is only set for the running system.
+pub fn set_system_time(_microseconds_since_epoch: u64) {
+	static WARNED: AtomicBool = AtomicBool::new(false);
+
+	if !WARNED.swap(true, Ordering::Relaxed) {
+		warn!("Writing the time back to the real-time clock is not supported");
+	}
+}
@@ -9,7 +9,8 @@ use crate::arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlag
 use crate::arch::x86_64::mm::{paging, virtualmem};
 use crate::arch::x86_64::mm::{PhysAddr, VirtAddr};
 use crate::x86::io::*;
-use core::{mem, slice, str};
+use alloc::vec::Vec;
+use core::{mem, ptr, slice, str};
 
 /// Memory at this physical address is supposed to contain a pointer to the Extended BIOS Data Area (EBDA).
 const EBDA_PTR_LOCATION: PhysAddr = PhysAddr(0x0000_040E);
@@ -46,6 +47,10 @@ static mut MADT: Option<AcpiTable<'_>> = None;
 static mut PM1A_CNT_BLK: Option<u16> = None;
 /// The Sleeping State Type code for powering off the computer through ACPI.
 static mut SLP_TYPA: Option<u8> = None;
+/// The index of the CMOS RTC register holding the century, if the firmware provides one.
+static mut CENTURY_REGISTER: Option<u8> = None;
+/// The memory-mapped PCI configuration spaces described in the MCFG table.
+static mut PCI_CONFIG_REGIONS: Vec<PciConfigRegion> = Vec::new();
 
 /// The "Root System Description Pointer" structure providing pointers to all other ACPI tables.
 #[repr(C, packed)]
@@ -159,6 +164,26 @@ impl<'a> Drop for AcpiTable<'a> {
 	}
 }
 
+/// An entry of the "PCI Express Memory-mapped Configuration Space Base Address Description Table" (MCFG).
+/// Described in PCI Firmware Specification 3.0, 4.1.2 MCFG Table Description.
+#[repr(C, packed)]
+struct AcpiMcfgEntry {
+	base_address: u64,
+	segment_group: u16,
+	start_bus: u8,
+	end_bus: u8,
+	reserved: u32,
+}
+
+/// The memory-mapped configuration space of a range of PCI buses (ECAM).
+#[derive(Clone, Copy, Debug)]
+pub struct PciConfigRegion {
+	pub base_address: u64,
+	pub segment_group: u16,
+	pub start_bus: u8,
+	pub end_bus: u8,
+}
+
 /// The ACPI Generic Address Structure (GAS).
 /// Described in ACPI Specification 6.2 A, 5.2.3.2 Generic Address Structure.
 #[repr(C, packed)]
@@ -410,6 +435,14 @@ fn parse_fadt(fadt: AcpiTable<'_>) {
 		PM1A_CNT_BLK = Some(pm1a_cnt_blk);
 	}
 
+	// The century field is optional as well and zero if the RTC does not provide a century register.
+	let century_field_address = &fadt_table.century as *const _ as usize;
+	if century_field_address < fadt.table_end_address() && fadt_table.century > 0 {
+		unsafe {
+			CENTURY_REGISTER = Some(fadt_table.century);
+		}
+	}
+
 	// Map the "Differentiated System Description Table" (DSDT).
 	// TODO: This must not require "unsafe", see https://github.com/rust-lang/rust/issues/46043#issuecomment-393072398
 	let x_dsdt_field_address = unsafe { &fadt_table.x_dsdt as *const _ as usize };
@@ -449,10 +482,39 @@ fn parse_ssdt(ssdt: AcpiTable<'_>) {
 	search_s5_in_table(ssdt);
 }
 
+fn parse_mcfg(mcfg: AcpiTable<'_>) {
+	// The configuration space entries follow 8 reserved bytes after the header.
+	let mut current_address = mcfg.table_start_address() + 8;
+
+	while current_address + mem::size_of::<AcpiMcfgEntry>() <= mcfg.table_end_address() {
+		let entry = unsafe { ptr::read_unaligned(current_address as *const AcpiMcfgEntry) };
+		current_address += mem::size_of::<AcpiMcfgEntry>();
+
+		let region = PciConfigRegion {
+			base_address: entry.base_address,
+			segment_group: entry.segment_group,
+			start_bus: entry.start_bus,
+			end_bus: entry.end_bus,
+		};
+		debug!("Found PCI configuration space: {:?}", region);
+		unsafe {
+			PCI_CONFIG_REGIONS.push(region);
+		}
+	}
+}
+
 pub fn get_madt() -> Option<&'static AcpiTable<'static>> {
 	unsafe { MADT.as_ref() }
 }
 
+pub fn get_century_register() -> Option<u8> {
+	unsafe { CENTURY_REGISTER }
+}
+
+pub fn get_pci_config_regions() -> &'static [PciConfigRegion] {
+	unsafe { &PCI_CONFIG_REGIONS }
+}
+
 pub fn poweroff() {
 	unsafe {
 		if let (Some(pm1a_cnt_blk), Some(slp_typa)) = (PM1A_CNT_BLK, SLP_TYPA) {
@@ -527,6 +589,15 @@ pub fn init() {
 				table_physical_address
 			);
 			parse_ssdt(table);
+		} else if table.header.signature() == "MCFG" {
+			// The "PCI Express Memory-mapped Configuration Space Base Address Description Table" (MCFG)
+			// Check and save the configuration spaces for the get_pci_config_regions() call.
+			assert!(
+				verify_checksum(table.header_start_address(), table.header.length as usize).is_ok(),
+				"MCFG at {:#X} has invalid checksum",
+				table_physical_address
+			);
+			parse_mcfg(table);
 		}
 	}
 }
@@ -178,6 +178,21 @@ pub fn add_local_apic_id(id: u8) {
 	}
 }
 
+/// Returns the number of detected cores, which is already valid
+/// before the application processors have been booted.
+pub fn get_detected_core_count() -> usize {
+	unsafe {
+		CPU_LOCAL_APIC_IDS
+			.as_ref()
+			.map_or(1, |ids| ids.len().max(1))
+	}
+}
+
+/// Returns the Local APIC ID of the core with the given Core ID.
+pub fn get_local_apic_id(core_id: CoreId) -> Option<u8> {
+	unsafe { CPU_LOCAL_APIC_IDS.as_ref()?.get(core_id as usize).copied() }
+}
+
 #[cfg(not(feature = "acpi"))]
 fn detect_from_acpi() -> Result<usize, ()> {
 	// dummy implementation if acpi support is disabled
@@ -441,9 +456,9 @@ pub fn set_oneshot_timer(wakeup_time: Option<u64>) {
 	if let Some(wt) = wakeup_time {
 		if processor::supports_tsc_deadline() {
 			// wt is the absolute wakeup time in microseconds based on processor::get_timer_ticks.
-			// We can simply multiply it by the processor frequency to get the absolute Time-Stamp Counter deadline
-			// (see processor::get_timer_ticks).
-			let tsc_deadline = wt * (u64::from(processor::get_frequency()));
+			// Convert it back into the absolute Time-Stamp Counter deadline using the same calibration
+			// as the nanosecond clock (see processor::get_timer_nanos).
+			let tsc_deadline = processor::nanos_to_timestamp(wt * 1000);
 
 			// Enable the APIC Timer in TSC-Deadline Mode and let it start by writing to the respective MSR.
 			local_apic_write(
@@ -617,6 +632,9 @@ pub fn boot_application_processors() {
 			while current_processor_count == arch::get_processor_count() {
 				processor::udelay(1000);
 			}
+
+			// Verify that the Time-Stamp Counter of the new processor runs in sync with ours.
+			processor::synchronize_tsc_source(core_id_to_boot.try_into().unwrap());
 		}
 	}
 }
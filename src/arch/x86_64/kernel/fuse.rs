@@ -6,10 +6,24 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::arch::kernel::pci::get_filesystem_driver;
-use crate::syscalls::fs::{FileError, FilePerms, PosixFile, PosixFileSystem, SeekWhence};
+use crate::arch::kernel::virtio_fs::VirtioFsDriver;
+use crate::arch::mm::paging::{self, BasePageSize, PageSize, PageTableEntryFlags};
+use crate::arch::mm::{virtualmem, PhysAddr, VirtAddr};
+use crate::fs::{components, split_parent};
+use crate::synch::semaphore::Semaphore;
+use crate::synch::spinlock::Spinlock;
+use crate::syscalls::fs::{
+	DirEntry, FileAttr, FileError, FileMapping, FilePerms, FsStat, PosixFile, PosixFileSystem,
+	SeekWhence,
+};
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::{fmt, u32, u8};
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::{fmt, mem, slice, u32, u8};
 
 // response out layout eg @ https://github.com/zargony/fuse-rs/blob/bf6d1cf03f3277e35b580f3c7b9999255d72ecf3/src/ll/request.rs#L44
 // op in/out sizes/layout: https://github.com/hanwen/go-fuse/blob/204b45dba899dfa147235c255908236d5fde2d32/fuse/opcode.go#L439
@@ -18,139 +32,849 @@ use core::{fmt, u32, u8};
 const FUSE_ROOT_ID: u64 = 1;
 const MAX_READ_LEN: usize = 1024 * 64;
 const MAX_WRITE_LEN: usize = 1024 * 64;
+/// Maximum length of the target of a symbolic link
+const MAX_LINK_LEN: usize = 4096;
+/// Size of the buffer, which receives the entries of a directory
+const READDIR_BUFFER_LEN: usize = 4096;
+
+// Attributes, which are changed by FUSE_SETATTR
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_FH: u32 = 1 << 6;
+
+/// The fh field of FUSE_GETATTR is valid.
+const FUSE_GETATTR_FH: u32 = 1 << 0;
+/// Only the data of the file has to be synchronized.
+const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
+
+const SEEK_DATA: u32 = 3;
+const SEEK_HOLE: u32 = 4;
+
+/// The server supports FUSE_SETUPMAPPING and reports the alignment of the mappings (FUSE_INIT flag).
+pub const FUSE_MAP_ALIGNMENT: u32 = 1 << 26;
+const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0;
+const FUSE_SETUPMAPPING_FLAG_READ: u64 = 1 << 1;
+/// Maximum number of ranges, which are removed by one FUSE_REMOVEMAPPING
+const REMOVEMAPPING_MAX_ENTRIES: usize = 256;
+/// Size of the ranges of the DAX window, which are mapped to files
+pub const DAX_RANGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Unique id of the next request
+static NEXT_UNIQUE: AtomicU64 = AtomicU64::new(1);
 
 pub trait FuseInterface {
-	fn send_command<S, T>(&mut self, cmd: Cmd<S>, rsp: Option<Rsp<T>>) -> Option<Rsp<T>>
+	fn send_command<S, T>(&self, cmd: Cmd<S>, rsp: Option<Rsp<T>>) -> Option<Rsp<T>>
 	where
 		S: FuseIn + core::fmt::Debug,
 		T: FuseOut + core::fmt::Debug;
 }
 
-pub struct Fuse;
+/// References to the nodes, which have been looked up. The FUSE server keeps a node until
+/// the client forgets all of its lookups, which happens when the last reference is dropped.
+#[derive(Debug, Default)]
+struct NodeTable {
+	/// Number of references and number of lookups per node id
+	nodes: BTreeMap<u64, (u64, u64)>,
+}
 
-impl PosixFileSystem for Fuse {
-	fn open(&self, path: &str, perms: FilePerms) -> Result<Box<dyn PosixFile + Send>, FileError> {
-		let mut file = FuseFile {
-			fuse_nid: None,
-			fuse_fh: None,
-			offset: 0,
-		};
-		// 1.FUSE_INIT to create session
-		// Already done
+impl NodeTable {
+	/// Records a lookup of `nodeid`, which is referenced until `put` is called.
+	fn get(&mut self, nodeid: u64) {
+		if nodeid != FUSE_ROOT_ID {
+			let (refs, nlookup) = self.nodes.entry(nodeid).or_insert((0, 0));
+			*refs += 1;
+			*nlookup += 1;
+		}
+	}
 
-		// Differentiate between opening and creating new file, since fuse does not support O_CREAT on open.
-		if !perms.creat {
-			// 2.FUSE_LOOKUP(FUSE_ROOT_ID, “foo”) -> nodeid
-			file.fuse_nid = self.lookup(path);
+	/// Drops a reference to `nodeid`. Returns the number of lookups, which have
+	/// to be forgotten, if the node isn't referenced anymore.
+	fn put(&mut self, nodeid: u64) -> Option<u64> {
+		let (refs, nlookup) = self.nodes.get_mut(&nodeid)?;
+		*refs -= 1;
+		if *refs > 0 {
+			return None;
+		}
+
+		let nlookup = *nlookup;
+		self.nodes.remove(&nodeid);
+		Some(nlookup)
+	}
+}
+
+/// Range of the DAX window, which is mapped to a part of a file
+#[derive(Debug)]
+struct DaxRange {
+	nodeid: u64,
+	foffset: u64,
+	writable: bool,
+	/// Number of reads and memory mappings, which access the range
+	pins: usize,
+	/// Time of the last access, which selects the range to be reused
+	last_use: u64,
+	/// The node has been forgotten, so that the server may reuse its id.
+	orphaned: bool,
+}
+
+/// Range of the DAX window, which can't be reused until it is unpinned
+#[derive(Clone, Copy, Debug)]
+struct PinnedRange {
+	index: usize,
+	addr: VirtAddr,
+	phys_addr: PhysAddr,
+}
+
+/// Shared memory of a virtio-fs device, whose ranges are mapped to files with FUSE_SETUPMAPPING.
+/// The host maps the pages of the files directly, which avoids copying through the virtqueues.
+struct DaxWindow {
+	addr: VirtAddr,
+	phys_addr: PhysAddr,
+	ranges: Vec<Option<DaxRange>>,
+	clock: u64,
+}
+
+impl fmt::Debug for DaxWindow {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"DaxWindow {{ addr: {:#X}, size: {:#X}, mapped: {} }}",
+			self.addr,
+			self.size(),
+			self.ranges.iter().filter(|range| range.is_some()).count()
+		)
+	}
+}
+
+impl DaxWindow {
+	/// Creates the window of `size` bytes, which is mapped at `addr`.
+	pub fn new(addr: VirtAddr, phys_addr: PhysAddr, size: usize) -> Self {
+		let mut ranges = Vec::new();
+		ranges.resize_with(size / DAX_RANGE_SIZE, || None);
+
+		Self {
+			addr,
+			phys_addr,
+			ranges,
+			clock: 0,
+		}
+	}
+
+	pub fn addr(&self) -> VirtAddr {
+		self.addr
+	}
+
+	pub fn size(&self) -> usize {
+		self.ranges.len() * DAX_RANGE_SIZE
+	}
+
+	/// Returns the range, which maps the file at `foffset`.
+	fn find(&self, nodeid: u64, foffset: u64) -> Option<usize> {
+		self.ranges.iter().position(|range| {
+			range.as_ref().map_or(false, |range| {
+				range.nodeid == nodeid && range.foffset == foffset && !range.orphaned
+			})
+		})
+	}
+
+	fn is_writable(&self, index: usize) -> bool {
+		self.ranges[index]
+			.as_ref()
+			.map_or(false, |range| range.writable)
+	}
+
+	/// Selects the range for a new mapping, which is a free range or the least recently used
+	/// range, which isn't pinned. Returns the index and the node, whose mapping has to be removed.
+	fn allocate(&mut self) -> Option<(usize, Option<u64>)> {
+		if let Some(index) = self.ranges.iter().position(Option::is_none) {
+			return Some((index, None));
+		}
+
+		let (index, _) = self
+			.ranges
+			.iter()
+			.enumerate()
+			.filter_map(|(index, range)| {
+				range
+					.as_ref()
+					.filter(|range| range.pins == 0)
+					.map(|range| (index, range.last_use))
+			})
+			.min_by_key(|(_, last_use)| *last_use)?;
+		let range = self.ranges[index].take().unwrap();
+
+		Some((index, Some(range.nodeid)))
+	}
+
+	/// Records that the range `index` maps the file at `foffset` and pins it.
+	fn insert(&mut self, index: usize, nodeid: u64, foffset: u64, writable: bool) -> PinnedRange {
+		let pins = self.ranges[index].as_ref().map_or(0, |range| range.pins);
+		self.ranges[index] = Some(DaxRange {
+			nodeid,
+			foffset,
+			writable,
+			pins,
+			last_use: 0,
+			orphaned: false,
+		});
+
+		self.pin(index)
+	}
+
+	fn pin(&mut self, index: usize) -> PinnedRange {
+		self.clock += 1;
+		let range = self.ranges[index].as_mut().unwrap();
+		range.pins += 1;
+		range.last_use = self.clock;
+
+		PinnedRange {
+			index,
+			addr: self.addr + index * DAX_RANGE_SIZE,
+			phys_addr: self.phys_addr + index * DAX_RANGE_SIZE,
+		}
+	}
+
+	fn unpin(&mut self, index: usize) {
+		if let Some(range) = self.ranges[index].as_mut() {
+			range.pins -= 1;
+		}
+	}
+
+	/// Releases the ranges of a forgotten node and returns the ranges, whose mappings have to
+	/// be removed. Pinned ranges keep their mappings until they are reused.
+	fn forget(&mut self, nodeid: u64) -> Vec<usize> {
+		let mut removed = Vec::new();
+		for (index, slot) in self.ranges.iter_mut().enumerate() {
+			if let Some(range) = slot {
+				if range.nodeid != nodeid || range.orphaned {
+					continue;
+				}
+
+				if range.pins == 0 {
+					*slot = None;
+					removed.push(index);
+				} else {
+					range.orphaned = true;
+				}
+			}
+		}
+
+		removed
+	}
+}
+
+/// DAX window, which is shared by the tasks accessing the files of a device
+pub struct Dax {
+	window: Spinlock<DaxWindow>,
+	/// Serializes the tasks, which set up or remove mappings. Their requests block,
+	/// so that the window itself is only locked briefly.
+	mapping: Semaphore,
+}
+
+impl fmt::Debug for Dax {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:?}", *self.window.lock())
+	}
+}
+
+impl Dax {
+	/// Creates the window of `size` bytes, which is mapped at `addr`.
+	pub fn new(addr: VirtAddr, phys_addr: PhysAddr, size: usize) -> Self {
+		Self {
+			window: Spinlock::new(DaxWindow::new(addr, phys_addr, size)),
+			mapping: Semaphore::new(1),
+		}
+	}
+
+	pub fn addr(&self) -> VirtAddr {
+		self.window.lock().addr()
+	}
+
+	pub fn size(&self) -> usize {
+		self.window.lock().size()
+	}
+
+	/// Pins the range, which already maps the file at `foffset`.
+	fn pin_mapped(&self, nodeid: u64, foffset: u64, writable: bool) -> Option<PinnedRange> {
+		let mut window = self.window.lock();
+		let index = window.find(nodeid, foffset)?;
+		if writable && !window.is_writable(index) {
+			return None;
+		}
+
+		Some(window.pin(index))
+	}
+}
+
+/// Returns the reply, if the server didn't report an error.
+fn check_reply<T>(rsp: Option<Rsp<T>>) -> Result<Rsp<T>, FileError>
+where
+	T: FuseOut + fmt::Debug,
+{
+	let rsp = rsp.ok_or(FileError::EIO())?;
+	trace!("Fuse answer {:?}", rsp);
+
+	match rsp.error() {
+		0 => Ok(rsp),
+		error => Err(FileError::from_errno(-error)),
+	}
+}
+
+/// Removes the mappings of the ranges `indices` of the DAX window, which map the node.
+fn remove_mappings(driver: &VirtioFsDriver<'static>, nodeid: u64, indices: &[usize]) {
+	for indices in indices.chunks(REMOVEMAPPING_MAX_ENTRIES) {
+		let (cmd, rsp) = create_removemapping(nodeid, indices);
+		if let Err(error) = check_reply(driver.send_command(cmd, Some(rsp))) {
+			warn!(
+				"Unable to remove DAX mappings of node {}: {:?}",
+				nodeid, error
+			);
+		}
+	}
+}
+
+/// Connection to the FUSE server of a share, which is used by the filesystem and its open files
+#[derive(Clone)]
+struct Session {
+	driver: &'static VirtioFsDriver<'static>,
+	nodes: Arc<Spinlock<NodeTable>>,
+	/// The device has a DAX window.
+	dax: bool,
+}
+
+impl Session {
+	/// Sends a request and returns the reply, if the server didn't report an error.
+	fn send<S, T>(&self, cmd: Cmd<S>, rsp: Rsp<T>) -> Result<Rsp<T>, FileError>
+	where
+		S: FuseIn + fmt::Debug,
+		T: FuseOut + fmt::Debug,
+	{
+		check_reply(self.driver.send_command(cmd, Some(rsp)))
+	}
+
+	/// Tells the server, that the lookups `(nodeid, nlookup)` are not used anymore.
+	fn forget(&self, nodes: &[(u64, u64)]) {
+		match nodes {
+			[] => {}
+			[(nodeid, nlookup)] => {
+				let cmd = create_forget(*nodeid, *nlookup);
+				self.driver.send_command::<_, fuse_forget_out>(cmd, None);
+			}
+			_ => {
+				let cmd = create_batch_forget(nodes);
+				self.driver.send_command::<_, fuse_forget_out>(cmd, None);
+			}
+		}
+	}
+
+	/// Drops a reference to `nodeid`, which has been returned by a lookup.
+	fn release_node(&self, nodeid: u64) {
+		let nlookup = self.nodes.lock().put(nodeid);
+		if let Some(nlookup) = nlookup {
+			if let Some(dax) = self.driver.dax() {
+				// a concurrent FUSE_SETUPMAPPING must not reuse a range before its removal
+				dax.mapping.acquire(None);
+				let removed = dax.window.lock().forget(nodeid);
+				remove_mappings(self.driver, nodeid, &removed);
+				dax.mapping.release();
+			}
+			self.forget(&[(nodeid, nlookup)]);
+		}
+	}
+
+	/// Pins the range of the DAX window, which maps the file at `foffset`. An unmapped part
+	/// of the file is mapped with FUSE_SETUPMAPPING. Returns None, if all ranges are pinned.
+	fn dax_pin(
+		&self,
+		nodeid: u64,
+		fh: u64,
+		foffset: u64,
+		writable: bool,
+	) -> Result<Option<PinnedRange>, FileError> {
+		let dax = self.driver.dax().ok_or(FileError::ENODEV())?;
+		if let Some(range) = dax.pin_mapped(nodeid, foffset, writable) {
+			return Ok(Some(range));
+		}
+
+		dax.mapping.acquire(None);
+		let result = self.dax_map(dax, nodeid, fh, foffset, writable);
+		dax.mapping.release();
+
+		result
+	}
 
-			if file.fuse_nid == None {
-				warn!("Fuse lookup seems to have failed!");
-				return Err(FileError::ENOENT());
+	/// Sets up the mapping of the file at `foffset`, while the mappings of the window
+	/// can't be changed by other tasks.
+	fn dax_map(
+		&self,
+		dax: &Dax,
+		nodeid: u64,
+		fh: u64,
+		foffset: u64,
+		writable: bool,
+	) -> Result<Option<PinnedRange>, FileError> {
+		let (index, evicted) = {
+			let mut window = dax.window.lock();
+			match window.find(nodeid, foffset) {
+				// another task has mapped the range in the meantime
+				Some(index) if !writable || window.is_writable(index) => {
+					return Ok(Some(window.pin(index)));
+				}
+				// the read-only mapping is replaced by a writable one
+				Some(index) => (index, None),
+				None => match window.allocate() {
+					Some(allocation) => allocation,
+					None => return Ok(None),
+				},
 			}
+		};
+		if let Some(evicted) = evicted {
+			remove_mappings(self.driver, evicted, &[index]);
+		}
 
-			// 3.FUSE_OPEN(nodeid, O_RDONLY) -> fh
-			let (cmd, rsp) = create_open(file.fuse_nid.unwrap(), perms.raw);
-			let rsp = get_filesystem_driver()
-				.ok_or(FileError::ENOSYS())?
-				.lock()
-				.send_command(cmd, Some(rsp))
-				.unwrap();
-			trace!("Open answer {:?}", rsp);
-			file.fuse_fh = Some(rsp.rsp.fh);
+		let flags = if writable {
+			FUSE_SETUPMAPPING_FLAG_READ | FUSE_SETUPMAPPING_FLAG_WRITE
 		} else {
-			// Create file (opens implicitly, returns results from both lookup and open calls)
-			let (cmd, rsp) = create_create(path, perms.raw, perms.mode);
-			let rsp = get_filesystem_driver()
-				.ok_or(FileError::ENOSYS())?
-				.lock()
-				.send_command(cmd, Some(rsp))
-				.unwrap();
-			trace!("Create answer {:?}", rsp);
+			FUSE_SETUPMAPPING_FLAG_READ
+		};
+		let moffset = (index * DAX_RANGE_SIZE) as u64;
+		let (cmd, rsp) = create_setupmapping(nodeid, fh, foffset, moffset, flags);
+		check_reply(self.driver.send_command(cmd, Some(rsp)))?;
+
+		let range = dax.window.lock().insert(index, nodeid, foffset, writable);
+		Ok(Some(range))
+	}
+
+	fn dax_unpin(&self, index: usize) {
+		if let Some(dax) = self.driver.dax() {
+			dax.window.lock().unpin(index);
+		}
+	}
+
+	/// Looks up `name` in the directory `parent` and returns the node with a reference.
+	fn lookup(&self, parent: u64, name: &str) -> Result<u64, FileError> {
+		let (cmd, rsp) = create_lookup(parent, name);
+		let rsp = self.send(cmd, rsp)?;
+		self.nodes.lock().get(rsp.rsp.nodeid);
+		Ok(rsp.rsp.nodeid)
+	}
+
+	/// Looks up `path` and returns its node with a reference.
+	fn lookup_path(&self, path: &str) -> Result<u64, FileError> {
+		let mut nodeid = FUSE_ROOT_ID;
+		for name in components(path) {
+			let result = self.lookup(nodeid, name);
+			self.release_node(nodeid);
+			nodeid = result?;
+		}
+
+		Ok(nodeid)
+	}
+
+	/// Looks up the parent directory of `path`. Returns its node with a reference and the file name.
+	fn lookup_parent<'a>(&self, path: &'a str) -> Result<(u64, &'a str), FileError> {
+		let (parent, name) = split_parent(path)?;
+		Ok((self.lookup_path(parent)?, name))
+	}
+
+	/// Forgets the node, which is returned by a request creating a file.
+	fn forget_entry(&self, entry: &fuse_entry_out) {
+		self.forget(&[(entry.nodeid, 1)]);
+	}
+
+	fn getattr(&self, nodeid: u64, fh: Option<u64>) -> Result<FileAttr, FileError> {
+		let (cmd, rsp) = create_getattr(nodeid, fh);
+		let rsp = self.send(cmd, rsp)?;
+		Ok(FileAttr::from(&rsp.rsp.attr))
+	}
+
+	fn readdir(&self, nodeid: u64) -> Result<Vec<DirEntry>, FileError> {
+		let (cmd, rsp) = create_open(nodeid, 0, Opcode::FUSE_OPENDIR);
+		let fh = self.send(cmd, rsp)?.rsp.fh;
+
+		let mut entries = Vec::new();
+		let mut offset = 0;
+		let result = loop {
+			let (cmd, rsp) = create_readdirplus(nodeid, fh, offset);
+			let rsp = match self.send(cmd, rsp) {
+				Ok(rsp) => rsp,
+				Err(error) => break Err(error),
+			};
+			let len = rsp.header.len as usize - mem::size_of::<fuse_out_header>();
+			let buf = rsp.extra_buffer.as_ref().unwrap();
+			let len = len.min(buf.len());
+
+			let mut lookups = Vec::new();
+			let start = entries.len();
+			offset = parse_direntplus(&buf[..len], &mut entries, &mut lookups);
+			self.forget(&lookups);
+			if entries.len() == start {
+				break Ok(entries);
+			}
+		};
+
+		let (cmd, rsp) = create_release(nodeid, fh, Opcode::FUSE_RELEASEDIR);
+		self.send(cmd, rsp)?;
+
+		result
+	}
+}
 
-			file.fuse_nid = Some(rsp.rsp.entry.nodeid);
-			file.fuse_fh = Some(rsp.rsp.open.fh);
+/// FUSE session of a virtio-fs device, which is identified by its tag
+pub struct Fuse {
+	tag: String,
+	read_only: bool,
+	nodes: Arc<Spinlock<NodeTable>>,
+}
+
+impl PosixFileSystem for Fuse {
+	fn open(&self, path: &str, perms: FilePerms) -> Result<Box<dyn PosixFile + Send>, FileError> {
+		if self.read_only && (perms.write || perms.creat || perms.trunc) {
+			return Err(FileError::EROFS());
 		}
 
-		Ok(Box::new(file))
+		let session = self.session()?;
+
+		// Differentiate between opening and creating new file, since fuse does not support O_CREAT on open.
+		let (nodeid, fh) = if !perms.creat {
+			let nodeid = session.lookup_path(path)?;
+			let (cmd, rsp) = create_open(nodeid, perms.raw, Opcode::FUSE_OPEN);
+			match session.send(cmd, rsp) {
+				Ok(rsp) => (nodeid, rsp.rsp.fh),
+				Err(error) => {
+					session.release_node(nodeid);
+					return Err(error);
+				}
+			}
+		} else {
+			// Create file (opens implicitly, returns results from both lookup and open calls)
+			let (parent, name) = session.lookup_parent(path)?;
+			let (cmd, rsp) = create_create(parent, name, perms.raw, perms.mode);
+			let result = session.send(cmd, rsp);
+			session.release_node(parent);
+
+			let rsp = result?;
+			session.nodes.lock().get(rsp.rsp.entry.nodeid);
+			(rsp.rsp.entry.nodeid, rsp.rsp.open.fh)
+		};
+
+		Ok(Box::new(FuseFile {
+			session,
+			nodeid,
+			fh,
+			offset: 0,
+			writable: perms.write,
+			size: 0,
+		}))
 	}
 
 	fn unlink(&self, path: &str) -> core::result::Result<(), FileError> {
-		let (cmd, rsp) = create_unlink(path);
-		let rsp = get_filesystem_driver()
-			.ok_or(FileError::ENOSYS())?
-			.lock()
-			.send_command(cmd, Some(rsp));
-		trace!("unlink answer {:?}", rsp);
+		self.check_writable()?;
+		let session = self.session()?;
+		let (parent, name) = session.lookup_parent(path)?;
+		let (cmd, rsp) = create_unlink(parent, name);
+		let result = session.send(cmd, rsp);
+		session.release_node(parent);
+
+		result.map(|_| ())
+	}
+
+	fn stat(&self, path: &str) -> Result<FileAttr, FileError> {
+		let session = self.session()?;
+		let nodeid = session.lookup_path(path)?;
+		let result = session.getattr(nodeid, None);
+		session.release_node(nodeid);
+
+		result
+	}
+
+	fn mkdir(&self, path: &str, mode: u32) -> Result<(), FileError> {
+		self.check_writable()?;
+		let session = self.session()?;
+		let (parent, name) = session.lookup_parent(path)?;
+		let (cmd, rsp) = create_mkdir(parent, name, mode);
+		let result = session.send(cmd, rsp);
+		session.release_node(parent);
+
+		session.forget_entry(&result?.rsp);
+		Ok(())
+	}
+
+	fn rmdir(&self, path: &str) -> Result<(), FileError> {
+		self.check_writable()?;
+		let session = self.session()?;
+		let (parent, name) = session.lookup_parent(path)?;
+		let (cmd, rsp) = create_rmdir(parent, name);
+		let result = session.send(cmd, rsp);
+		session.release_node(parent);
+
+		result.map(|_| ())
+	}
+
+	fn rename(&self, from: &str, to: &str) -> Result<(), FileError> {
+		self.check_writable()?;
+		let session = self.session()?;
+		let (from_parent, from_name) = session.lookup_parent(from)?;
+		let result = session.lookup_parent(to).and_then(|(to_parent, to_name)| {
+			let (cmd, rsp) = create_rename2(from_parent, from_name, to_parent, to_name, 0);
+			let result = session.send(cmd, rsp);
+			session.release_node(to_parent);
+			result
+		});
+		session.release_node(from_parent);
+
+		result.map(|_| ())
+	}
+
+	fn symlink(&self, target: &str, path: &str) -> Result<(), FileError> {
+		self.check_writable()?;
+		let session = self.session()?;
+		let (parent, name) = session.lookup_parent(path)?;
+		let (cmd, rsp) = create_symlink(parent, name, target);
+		let result = session.send(cmd, rsp);
+		session.release_node(parent);
+
+		session.forget_entry(&result?.rsp);
+		Ok(())
+	}
+
+	fn readlink(&self, path: &str) -> Result<String, FileError> {
+		let session = self.session()?;
+		let nodeid = session.lookup_path(path)?;
+		let (cmd, rsp) = create_readlink(nodeid);
+		let result = session.send(cmd, rsp);
+		session.release_node(nodeid);
+
+		let rsp = result?;
+		let len = rsp.header.len as usize - mem::size_of::<fuse_out_header>();
+		let mut target = rsp.extra_buffer.unwrap();
+		target.truncate(len);
+		String::from_utf8(target).map_err(|_| FileError::EINVAL())
+	}
 
+	fn link(&self, from: &str, to: &str) -> Result<(), FileError> {
+		self.check_writable()?;
+		let session = self.session()?;
+		let nodeid = session.lookup_path(from)?;
+		let result = session.lookup_parent(to).and_then(|(parent, name)| {
+			let (cmd, rsp) = create_link(nodeid, parent, name);
+			let result = session.send(cmd, rsp);
+			session.release_node(parent);
+			result
+		});
+		session.release_node(nodeid);
+
+		session.forget_entry(&result?.rsp);
 		Ok(())
 	}
+
+	fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, FileError> {
+		let session = self.session()?;
+		let nodeid = session.lookup_path(path)?;
+		let result = session.readdir(nodeid);
+		session.release_node(nodeid);
+
+		result
+	}
+
+	fn statfs(&self) -> Result<FsStat, FileError> {
+		let session = self.session()?;
+		let (cmd, rsp) = create_statfs(FUSE_ROOT_ID);
+		let st = session.send(cmd, rsp)?.rsp.st;
+
+		Ok(FsStat {
+			block_size: st.bsize.into(),
+			fragment_size: st.frsize.into(),
+			blocks: st.blocks,
+			blocks_free: st.bfree,
+			blocks_available: st.bavail,
+			files: st.files,
+			files_free: st.ffree,
+			name_max: st.namelen.into(),
+		})
+	}
 }
 
 impl Fuse {
-	pub fn new() -> Self {
-		Self {}
+	/// Creates the filesystem of the virtio-fs device `tag`. A read-only filesystem
+	/// rejects all requests, which modify the share.
+	pub fn new(tag: &str, read_only: bool) -> Self {
+		Self {
+			tag: String::from(tag),
+			read_only,
+			nodes: Arc::new(Spinlock::new(NodeTable::default())),
+		}
 	}
 
-	pub fn send_init(&self) {
-		let (cmd, rsp) = create_init();
-		let rsp = get_filesystem_driver()
-			.unwrap()
-			.lock()
-			.send_command(cmd, Some(rsp));
-		trace!("fuse init answer: {:?}", rsp);
+	/// Returns the session with the driver of the device. The device is looked up by its tag,
+	/// because the filesystem is mounted before the driver is registered.
+	fn session(&self) -> Result<Session, FileError> {
+		let driver = get_filesystem_driver(&self.tag).ok_or(FileError::ENOSYS())?;
+		let dax = driver.dax().is_some();
+		Ok(Session {
+			driver,
+			nodes: self.nodes.clone(),
+			dax,
+		})
 	}
 
-	pub fn lookup(&self, name: &str) -> Option<u64> {
-		let (cmd, rsp) = create_lookup(name);
-		let rsp = get_filesystem_driver()
-			.unwrap()
-			.lock()
-			.send_command(cmd, Some(rsp));
-		Some(rsp.unwrap().rsp.nodeid)
+	fn check_writable(&self) -> Result<(), FileError> {
+		if self.read_only {
+			Err(FileError::EROFS())
+		} else {
+			Ok(())
+		}
 	}
 }
 
 struct FuseFile {
-	fuse_nid: Option<u64>,
-	fuse_fh: Option<u64>,
+	session: Session,
+	nodeid: u64,
+	fh: u64,
 	offset: usize,
+	writable: bool,
+	/// Size of the file, which limits the accesses to the DAX window. Accesses behind
+	/// the end of the file can't be served by the host.
+	size: u64,
+}
+
+impl FuseFile {
+	/// Reads the file through the DAX window. Returns None, if no range of the window is available.
+	fn read_dax(&mut self, len: usize) -> Result<Option<Vec<u8>>, FileError> {
+		let start = self.offset as u64;
+		let mut end = start + len as u64;
+		if end > self.size {
+			self.size = self.session.getattr(self.nodeid, Some(self.fh))?.st_size;
+			end = end.min(self.size);
+		}
+
+		let mut buf = Vec::with_capacity(end.saturating_sub(start) as usize);
+		let mut pos = start;
+		while pos < end {
+			let foffset = align_down!(pos, DAX_RANGE_SIZE as u64);
+			let range = match self.session.dax_pin(self.nodeid, self.fh, foffset, false)? {
+				Some(range) => range,
+				None => break,
+			};
+
+			let chunk_end = end.min(foffset + DAX_RANGE_SIZE as u64);
+			let src = unsafe {
+				slice::from_raw_parts(
+					(range.addr + (pos - foffset) as usize).as_mut_ptr::<u8>(),
+					(chunk_end - pos) as usize,
+				)
+			};
+			buf.extend_from_slice(src);
+			self.session.dax_unpin(range.index);
+			pos = chunk_end;
+		}
+
+		if pos < end && buf.is_empty() {
+			return Ok(None);
+		}
+
+		self.offset += buf.len();
+		Ok(Some(buf))
+	}
+}
+
+/// Mapping of a file into memory, whose pages are located in the DAX window
+struct FuseMapping {
+	session: Session,
+	addr: VirtAddr,
+	size: usize,
+	/// Number of bytes, which are mapped to pinned ranges of the window
+	mapped: usize,
+	ranges: Vec<usize>,
+}
+
+impl FuseMapping {
+	fn new(session: Session, size: usize) -> Result<Self, FileError> {
+		let size = align_up!(size, BasePageSize::SIZE);
+		let addr = virtualmem::allocate(size).map_err(|_| FileError::ENOMEM())?;
+
+		Ok(Self {
+			session,
+			addr,
+			size,
+			mapped: 0,
+			ranges: Vec::new(),
+		})
+	}
+
+	/// Appends `len` bytes at `offset` of the pinned range to the mapping.
+	fn map(&mut self, range: PinnedRange, offset: usize, len: usize, writable: bool) {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().execute_disable();
+		if writable {
+			flags.writable();
+		}
+
+		let count = align_up!(len, BasePageSize::SIZE) / BasePageSize::SIZE;
+		paging::map::<BasePageSize>(
+			self.addr + self.mapped,
+			range.phys_addr + offset,
+			count,
+			flags,
+		);
+		self.mapped += count * BasePageSize::SIZE;
+		self.ranges.push(range.index);
+	}
+}
+
+impl FileMapping for FuseMapping {
+	fn addr(&self) -> usize {
+		self.addr.as_usize()
+	}
+}
+
+impl Drop for FuseMapping {
+	fn drop(&mut self) {
+		if self.mapped > 0 {
+			paging::unmap::<BasePageSize>(self.addr, self.mapped / BasePageSize::SIZE);
+		}
+		virtualmem::deallocate(self.addr, self.size);
+
+		for index in self.ranges.iter() {
+			self.session.dax_unpin(*index);
+		}
+	}
 }
 
 impl PosixFile for FuseFile {
 	fn close(&mut self) -> Result<(), FileError> {
-		let (cmd, rsp) = create_release(self.fuse_nid.unwrap(), self.fuse_fh.unwrap());
-		get_filesystem_driver()
-			.ok_or(FileError::ENOSYS())?
-			.lock()
-			.send_command(cmd, Some(rsp));
+		// FLUSH reports errors of delayed writes, which are not reported by RELEASE
+		let (cmd, rsp) = create_flush(self.nodeid, self.fh);
+		let result = self.session.send(cmd, rsp);
 
-		Ok(())
+		// the node is released, even if the host fails to release the file handle
+		let (cmd, rsp) = create_release(self.nodeid, self.fh, Opcode::FUSE_RELEASE);
+		let release = self.session.send(cmd, rsp);
+		self.session.release_node(self.nodeid);
+
+		result.and(release).map(|_| ())
 	}
 
 	fn read(&mut self, len: u32) -> Result<Vec<u8>, FileError> {
+		if self.session.dax {
+			if let Some(buf) = self.read_dax(len as usize)? {
+				return Ok(buf);
+			}
+		}
+
 		let mut len = len;
 		if len as usize > MAX_READ_LEN {
 			debug!("Reading longer than max_read_len: {}", len);
 			len = MAX_READ_LEN as u32;
 		}
-		if let Some(fh) = self.fuse_fh {
-			let (cmd, rsp) = create_read(fh, len, self.offset as u64);
-			let rsp = get_filesystem_driver()
-				.ok_or(FileError::ENOSYS())?
-				.lock()
-				.send_command(cmd, Some(rsp));
-			let rsp = rsp.unwrap();
-			let len = rsp.header.len as usize - ::core::mem::size_of::<fuse_out_header>();
-			self.offset += len;
-			// TODO: do this zerocopy
-			let mut vec = rsp.extra_buffer.unwrap();
-			vec.truncate(len);
-			trace!("LEN: {}, VEC: {:?}", len, vec);
-			Ok(vec)
-		} else {
-			warn!("File not open, cannot read!");
-			Err(FileError::ENOENT())
-		}
+
+		let (cmd, rsp) = create_read(self.nodeid, self.fh, len, self.offset as u64);
+		let rsp = self.session.send(cmd, rsp)?;
+		let len = rsp.header.len as usize - ::core::mem::size_of::<fuse_out_header>();
+		self.offset += len;
+		// TODO: do this zerocopy
+		let mut vec = rsp.extra_buffer.unwrap();
+		vec.truncate(len);
+		trace!("LEN: {}, VEC: {:?}", len, vec);
+		Ok(vec)
 	}
 
 	fn write(&mut self, buf: &[u8]) -> Result<u64, FileError> {
@@ -164,36 +888,181 @@ impl PosixFile for FuseFile {
 			);
 			len = MAX_WRITE_LEN;
 		}
-		if let Some(fh) = self.fuse_fh {
-			let (cmd, rsp) = create_write(fh, &buf[..len], self.offset as u64);
-			let rsp = get_filesystem_driver()
-				.ok_or(FileError::ENOSYS())?
-				.lock()
-				.send_command(cmd, Some(rsp));
-			trace!("write response: {:?}", rsp);
-			let rsp = rsp.unwrap();
-
-			let len = rsp.rsp.size as usize;
-			self.offset += len;
-			debug!("Written {} bytes", len);
-			Ok(len as u64)
-		} else {
-			warn!("File not open, cannot read!");
-			Err(FileError::ENOENT())
-		}
+
+		let (cmd, rsp) = create_write(self.nodeid, self.fh, &buf[..len], self.offset as u64);
+		let rsp = self.session.send(cmd, rsp)?;
+		let len = rsp.rsp.size as usize;
+		self.offset += len;
+		self.size = self.size.max(self.offset as u64);
+		debug!("Written {} bytes", len);
+		Ok(len as u64)
 	}
 
 	fn lseek(&mut self, offset: isize, whence: SeekWhence) -> Result<usize, FileError> {
 		debug!("fuse lseek");
 
-		match whence {
-			SeekWhence::Set => self.offset = offset as usize,
-			SeekWhence::Cur => self.offset = (self.offset as isize + offset) as usize,
-			SeekWhence::End => unimplemented!("Cant seek from end yet!"),
+		let base = match whence {
+			SeekWhence::Set => 0,
+			SeekWhence::Cur => self.offset as isize,
+			SeekWhence::End => self.fstat()?.st_size as isize,
+			SeekWhence::Data | SeekWhence::Hole => {
+				if offset < 0 {
+					return Err(FileError::ENXIO());
+				}
+				let whence = match whence {
+					SeekWhence::Data => SEEK_DATA,
+					_ => SEEK_HOLE,
+				};
+				let (cmd, rsp) = create_lseek(self.nodeid, self.fh, offset as u64, whence);
+				let rsp = self.session.send(cmd, rsp)?;
+				rsp.rsp.offset as isize - offset
+			}
+		};
+		if base + offset < 0 {
+			return Err(FileError::EINVAL());
 		}
 
+		self.offset = (base + offset) as usize;
 		Ok(self.offset)
 	}
+
+	fn fstat(&mut self) -> Result<FileAttr, FileError> {
+		self.session.getattr(self.nodeid, Some(self.fh))
+	}
+
+	fn fsync(&mut self, datasync: bool) -> Result<(), FileError> {
+		let (cmd, rsp) = create_fsync(self.nodeid, self.fh, datasync);
+		self.session.send(cmd, rsp).map(|_| ())
+	}
+
+	fn ftruncate(&mut self, size: u64) -> Result<(), FileError> {
+		let (cmd, rsp) = create_setattr(
+			self.nodeid,
+			fuse_setattr_in {
+				valid: FATTR_SIZE | FATTR_FH,
+				fh: self.fh,
+				size,
+				..Default::default()
+			},
+		);
+		self.session.send(cmd, rsp)?;
+		self.size = size;
+		Ok(())
+	}
+
+	fn fallocate(&mut self, mode: u32, offset: u64, len: u64) -> Result<(), FileError> {
+		let (cmd, rsp) = create_fallocate(self.nodeid, self.fh, mode, offset, len);
+		self.session.send(cmd, rsp).map(|_| ())
+	}
+
+	fn mmap(
+		&mut self,
+		offset: u64,
+		len: usize,
+		writable: bool,
+	) -> Result<Box<dyn FileMapping + Send>, FileError> {
+		if !self.session.dax {
+			return Err(FileError::ENODEV());
+		}
+		if writable && !self.writable {
+			return Err(FileError::EACCES());
+		}
+		if len == 0 || offset % BasePageSize::SIZE as u64 != 0 {
+			return Err(FileError::EINVAL());
+		}
+
+		// in contrast to Linux, the mapping can't be extended behind the end of the file
+		self.size = self.session.getattr(self.nodeid, Some(self.fh))?.st_size;
+		let end = offset + len as u64;
+		if end > align_up!(self.size, BasePageSize::SIZE as u64) {
+			return Err(FileError::ENXIO());
+		}
+
+		let mut mapping = FuseMapping::new(self.session.clone(), len)?;
+		let mut pos = offset;
+		while pos < end {
+			let foffset = align_down!(pos, DAX_RANGE_SIZE as u64);
+			let range = self
+				.session
+				.dax_pin(self.nodeid, self.fh, foffset, writable)?
+				.ok_or(FileError::ENOMEM())?;
+
+			let chunk_end = end.min(foffset + DAX_RANGE_SIZE as u64);
+			mapping.map(
+				range,
+				(pos - foffset) as usize,
+				(chunk_end - pos) as usize,
+				writable,
+			);
+			pos = chunk_end;
+		}
+
+		Ok(Box::new(mapping))
+	}
+}
+
+/// Parses the reply of FUSE_READDIRPLUS and appends the entries to `entries`. The lookups,
+/// which are implied by the entries, are appended to `lookups`. Returns the offset of the next entry.
+fn parse_direntplus(buf: &[u8], entries: &mut Vec<DirEntry>, lookups: &mut Vec<(u64, u64)>) -> u64 {
+	let entry_len = mem::size_of::<fuse_entry_out>();
+	let dirent_len = mem::size_of::<fuse_dirent>();
+	let read_u64 = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+	let read_u32 = |pos: usize| u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+
+	let mut next_offset = 0;
+	let mut pos = 0;
+	while pos + entry_len + dirent_len <= buf.len() {
+		let nodeid = read_u64(pos);
+		let dirent = pos + entry_len;
+		let ino = read_u64(dirent);
+		let off = read_u64(dirent + 8);
+		let namelen = read_u32(dirent + 16) as usize;
+		let file_type = read_u32(dirent + 20) as u8;
+		let name_start = dirent + dirent_len;
+		if name_start + namelen > buf.len() {
+			warn!("Truncated directory entry");
+			break;
+		}
+
+		let name = String::from_utf8_lossy(&buf[name_start..name_start + namelen]).into_owned();
+		// the server doesn't look up "." and ".."
+		if nodeid != 0 && name != "." && name != ".." {
+			lookups.push((nodeid, 1));
+		}
+		entries.push(DirEntry {
+			ino,
+			file_type,
+			name,
+		});
+
+		next_offset = off;
+		pos = align_up!(name_start + namelen, 8);
+	}
+
+	next_offset
+}
+
+impl From<&fuse_attr> for FileAttr {
+	fn from(attr: &fuse_attr) -> Self {
+		Self {
+			st_ino: attr.ino,
+			st_nlink: attr.nlink.into(),
+			st_mode: attr.mode,
+			st_uid: attr.uid,
+			st_gid: attr.gid,
+			st_rdev: attr.rdev.into(),
+			st_size: attr.size,
+			st_blksize: attr.blksize.into(),
+			st_blocks: attr.blocks as i64,
+			st_atime: attr.atime as i64,
+			st_atime_nsec: attr.atimensec.into(),
+			st_mtime: attr.mtime as i64,
+			st_mtime_nsec: attr.mtimensec.into(),
+			st_ctime: attr.ctime as i64,
+			st_ctime_nsec: attr.ctimensec.into(),
+			..Default::default()
+		}
+	}
 }
 
 #[repr(C)]
@@ -239,8 +1108,14 @@ pub enum Opcode {
 	FUSE_IOCTL = 39,
 	FUSE_POLL = 40,
 	FUSE_NOTIFY_REPLY = 41,
-	FUSE_BATCH_FORGET = 42,
+	FUSE_BATCH_FORGET = 42, // no reply
 	FUSE_FALLOCATE = 43,
+	FUSE_READDIRPLUS = 44,
+	FUSE_RENAME2 = 45,
+	FUSE_LSEEK = 46,
+	FUSE_COPY_FILE_RANGE = 47,
+	FUSE_SETUPMAPPING = 48,
+	FUSE_REMOVEMAPPING = 49,
 
 	FUSE_SETVOLNAME = 61,
 	FUSE_GETXTIMES = 62,
@@ -322,6 +1197,11 @@ where
 			vec![rawrsp]
 		}
 	}
+
+	/// Returns the error of the reply, which is a negated errno or zero on success.
+	pub fn error(&self) -> i32 {
+		self.header.error
+	}
 }
 
 pub fn create_in_header<T>(opcode: Opcode) -> fuse_in_header
@@ -329,9 +1209,9 @@ where
 	T: FuseIn,
 {
 	fuse_in_header {
-		len: (core::mem::size_of::<T>() + core::mem::size_of::<T>()) as u32,
+		len: (core::mem::size_of::<fuse_in_header>() + core::mem::size_of::<T>()) as u32,
 		opcode: opcode as u32,
-		unique: 1,
+		unique: NEXT_UNIQUE.fetch_add(1, Ordering::Relaxed),
 		nodeid: 0,
 		uid: 0,
 		pid: 0,
@@ -340,48 +1220,64 @@ where
 	}
 }
 
-pub fn create_init() -> (Cmd<fuse_init_in>, Rsp<fuse_init_out>) {
-	let cmd = fuse_init_in {
-		major: 7,
-		minor: 31,
-		max_readahead: 0,
-		flags: 0,
-	};
-	let cmdhdr = create_in_header::<fuse_init_in>(Opcode::FUSE_INIT);
-	let rsp: fuse_init_out = Default::default();
-	let rsphdr: fuse_out_header = Default::default();
-	(
-		Cmd {
-			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
-	)
+/// Creates a request for the node `nodeid`, whose arguments `cmd` are followed by `extra_buffer`.
+fn create_cmd<T>(opcode: Opcode, nodeid: u64, cmd: T, extra_buffer: Option<Vec<u8>>) -> Cmd<T>
+where
+	T: FuseIn + fmt::Debug,
+{
+	let mut header = create_in_header::<T>(opcode);
+	header.nodeid = nodeid;
+	if let Some(extra) = &extra_buffer {
+		header.len += extra.len() as u32;
+	}
+
+	Cmd {
+		header,
+		cmd,
+		extra_buffer,
+	}
 }
 
-pub fn create_lookup(name: &str) -> (Cmd<fuse_lookup_in>, Rsp<fuse_entry_out>) {
-	let cmd = name.into();
-	let mut cmdhdr = create_in_header::<fuse_lookup_in>(Opcode::FUSE_LOOKUP);
-	cmdhdr.nodeid = FUSE_ROOT_ID;
-	let rsp: fuse_entry_out = Default::default();
-	let rsphdr: fuse_out_header = Default::default();
-	(
-		Cmd {
-			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
-	)
+/// Creates an empty reply, whose variable-sized data is received in `extra_buffer`.
+fn create_rsp<T>(extra_buffer: Option<Vec<u8>>) -> Rsp<T>
+where
+	T: FuseOut + fmt::Debug + Default,
+{
+	Rsp {
+		header: Default::default(),
+		rsp: Default::default(),
+		extra_buffer,
+	}
+}
+
+/// Converts names into the null-terminated strings, which follow the arguments of a request.
+fn names_to_u8buf(names: &[&str]) -> Vec<u8> {
+	let mut buf = Vec::new();
+	for name in names {
+		buf.extend_from_slice(name.as_bytes());
+		buf.push(0);
+	}
+	buf
+}
+
+#[repr(C, align(4096))]
+struct AlignToPage([u8; 4096]);
+
+/// Allocates a page-aligned buffer, because direct-io requires aligned memory.
+fn aligned_buffer(len: usize) -> Vec<u8> {
+	// ugly hack from https://stackoverflow.com/questions/60180121/how-do-i-allocate-a-vecu8-that-is-aligned-to-the-size-of-the-cache-line
+	let mut aligned: Vec<AlignToPage> =
+		Vec::with_capacity(len / ::core::mem::size_of::<AlignToPage>() + 1);
+	let ptr = aligned.as_mut_ptr();
+	let cap_units = aligned.capacity();
+	::core::mem::forget(aligned);
+	unsafe {
+		Vec::from_raw_parts(
+			ptr as *mut u8,
+			len,
+			cap_units * ::core::mem::size_of::<AlignToPage>(),
+		)
+	}
 }
 
 #[repr(C)]
@@ -426,10 +1322,197 @@ pub struct fuse_init_out {
 	pub congestion_threshold: u16,
 	pub max_write: u32,
 	pub time_gran: u32,
-	pub unused: [u32; 9],
+	pub max_pages: u16,
+	/// Alignment of the file offsets and lengths of FUSE_SETUPMAPPING as log2
+	pub map_alignment: u16,
+	pub unused: [u32; 8],
 }
 unsafe impl FuseOut for fuse_init_out {}
 
+impl Rsp<fuse_init_out> {
+	/// Returns true, if the server maps files into the DAX window with the alignment of its ranges.
+	pub fn supports_dax(&self) -> bool {
+		self.rsp.flags & FUSE_MAP_ALIGNMENT != 0
+			&& u32::from(self.rsp.map_alignment) <= DAX_RANGE_SIZE.trailing_zeros()
+	}
+}
+
+/// Creates the request, which starts the session. `flags` are the FUSE_INIT flags of the client.
+pub fn create_init(flags: u32) -> (Cmd<fuse_init_in>, Rsp<fuse_init_out>) {
+	let cmd = fuse_init_in {
+		major: 7,
+		minor: 31,
+		max_readahead: 0,
+		flags,
+	};
+	(
+		create_cmd(Opcode::FUSE_INIT, 0, cmd, None),
+		create_rsp(None),
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_attr {
+	pub ino: u64,
+	pub size: u64,
+	pub blocks: u64,
+	pub atime: u64,
+	pub mtime: u64,
+	pub ctime: u64,
+	pub atimensec: u32,
+	pub mtimensec: u32,
+	pub ctimensec: u32,
+	pub mode: u32,
+	pub nlink: u32,
+	pub uid: u32,
+	pub gid: u32,
+	pub rdev: u32,
+	pub blksize: u32,
+	pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_entry_out {
+	pub nodeid: u64,
+	pub generation: u64,
+	pub entry_valid: u64,
+	pub attr_valid: u64,
+	pub entry_valid_nsec: u32,
+	pub attr_valid_nsec: u32,
+	pub attr: fuse_attr,
+}
+unsafe impl FuseOut for fuse_entry_out {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_lookup_in {}
+unsafe impl FuseIn for fuse_lookup_in {}
+
+pub fn create_lookup(parent: u64, name: &str) -> (Cmd<fuse_lookup_in>, Rsp<fuse_entry_out>) {
+	let cmd = create_cmd(
+		Opcode::FUSE_LOOKUP,
+		parent,
+		fuse_lookup_in {},
+		Some(names_to_u8buf(&[name])),
+	);
+	(cmd, create_rsp(None))
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_forget_in {
+	pub nlookup: u64,
+}
+unsafe impl FuseIn for fuse_forget_in {}
+
+/// FUSE_FORGET and FUSE_BATCH_FORGET don't have a reply.
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_forget_out {}
+unsafe impl FuseOut for fuse_forget_out {}
+
+pub fn create_forget(nodeid: u64, nlookup: u64) -> Cmd<fuse_forget_in> {
+	create_cmd(
+		Opcode::FUSE_FORGET,
+		nodeid,
+		fuse_forget_in { nlookup },
+		None,
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_batch_forget_in {
+	pub count: u32,
+	pub dummy: u32,
+}
+unsafe impl FuseIn for fuse_batch_forget_in {}
+
+/// Creates a request, which forgets the lookups `(nodeid, nlookup)` of several nodes.
+pub fn create_batch_forget(nodes: &[(u64, u64)]) -> Cmd<fuse_batch_forget_in> {
+	// the arguments are followed by an array of fuse_forget_one
+	let mut forget_one = Vec::with_capacity(nodes.len() * 16);
+	for (nodeid, nlookup) in nodes {
+		forget_one.extend_from_slice(&nodeid.to_le_bytes());
+		forget_one.extend_from_slice(&nlookup.to_le_bytes());
+	}
+
+	let cmd = fuse_batch_forget_in {
+		count: nodes.len() as u32,
+		dummy: 0,
+	};
+	create_cmd(Opcode::FUSE_BATCH_FORGET, 0, cmd, Some(forget_one))
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_getattr_in {
+	pub getattr_flags: u32,
+	pub dummy: u32,
+	pub fh: u64,
+}
+unsafe impl FuseIn for fuse_getattr_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_attr_out {
+	pub attr_valid: u64,
+	pub attr_valid_nsec: u32,
+	pub dummy: u32,
+	pub attr: fuse_attr,
+}
+unsafe impl FuseOut for fuse_attr_out {}
+
+pub fn create_getattr(nid: u64, fh: Option<u64>) -> (Cmd<fuse_getattr_in>, Rsp<fuse_attr_out>) {
+	let cmd = match fh {
+		Some(fh) => fuse_getattr_in {
+			getattr_flags: FUSE_GETATTR_FH,
+			fh,
+			..Default::default()
+		},
+		None => Default::default(),
+	};
+	(
+		create_cmd(Opcode::FUSE_GETATTR, nid, cmd, None),
+		create_rsp(None),
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_setattr_in {
+	pub valid: u32,
+	pub padding: u32,
+	pub fh: u64,
+	pub size: u64,
+	pub lock_owner: u64,
+	pub atime: u64,
+	pub mtime: u64,
+	pub ctime: u64,
+	pub atimensec: u32,
+	pub mtimensec: u32,
+	pub ctimensec: u32,
+	pub mode: u32,
+	pub unused4: u32,
+	pub uid: u32,
+	pub gid: u32,
+	pub unused5: u32,
+}
+unsafe impl FuseIn for fuse_setattr_in {}
+
+/// Creates a request, which changes the attributes, which are selected by `cmd.valid`.
+pub fn create_setattr(
+	nid: u64,
+	cmd: fuse_setattr_in,
+) -> (Cmd<fuse_setattr_in>, Rsp<fuse_attr_out>) {
+	(
+		create_cmd(Opcode::FUSE_SETATTR, nid, cmd, None),
+		create_rsp(None),
+	)
+}
+
 #[repr(C)]
 #[derive(Default, Debug)]
 pub struct fuse_read_in {
@@ -448,45 +1531,54 @@ unsafe impl FuseIn for fuse_read_in {}
 pub struct fuse_read_out {}
 unsafe impl FuseOut for fuse_read_out {}
 
-pub fn create_read(nid: u64, size: u32, offset: u64) -> (Cmd<fuse_read_in>, Rsp<fuse_read_out>) {
+pub fn create_read(
+	nid: u64,
+	fh: u64,
+	size: u32,
+	offset: u64,
+) -> (Cmd<fuse_read_in>, Rsp<fuse_read_out>) {
 	let cmd = fuse_read_in {
+		fh,
 		offset,
 		size,
 		..Default::default()
 	};
-	let mut cmdhdr = create_in_header::<fuse_read_in>(Opcode::FUSE_READ);
-	cmdhdr.nodeid = nid;
-	let rsp = Default::default();
-	let rsphdr = Default::default();
-	// direct-io requires aligned memory.
-	// ugly hack from https://stackoverflow.com/questions/60180121/how-do-i-allocate-a-vecu8-that-is-aligned-to-the-size-of-the-cache-line
-	let mut aligned: Vec<AlignToPage> =
-		Vec::with_capacity(size as usize / ::core::mem::size_of::<AlignToPage>() + 1);
-	let ptr = aligned.as_mut_ptr();
-	let cap_units = aligned.capacity();
-	::core::mem::forget(aligned);
-	let readbuf = unsafe {
-		Vec::from_raw_parts(
-			ptr as *mut u8,
-			size as usize,
-			cap_units * ::core::mem::size_of::<AlignToPage>(),
-		)
+	let readbuf = aligned_buffer(size as usize);
+	(
+		create_cmd(Opcode::FUSE_READ, nid, cmd, None),
+		create_rsp(Some(readbuf)),
+	)
+}
+
+/// Creates a request, which reads the entries of the directory `fh` starting at `offset`.
+/// The reply consists of fuse_direntplus structures.
+pub fn create_readdirplus(
+	nid: u64,
+	fh: u64,
+	offset: u64,
+) -> (Cmd<fuse_read_in>, Rsp<fuse_read_out>) {
+	let cmd = fuse_read_in {
+		fh,
+		offset,
+		size: READDIR_BUFFER_LEN as u32,
+		..Default::default()
 	};
-	// let readbuf = vec![0; size as usize];
 	(
-		Cmd {
-			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: Some(readbuf),
-		},
+		create_cmd(Opcode::FUSE_READDIRPLUS, nid, cmd, None),
+		create_rsp(Some(vec![0; READDIR_BUFFER_LEN])),
 	)
 }
 
+/// Header of a directory entry, which is followed by the name
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_dirent {
+	pub ino: u64,
+	pub off: u64,
+	pub namelen: u32,
+	pub typ: u32,
+}
+
 #[repr(C)]
 #[derive(Default, Debug)]
 pub struct fuse_write_in {
@@ -508,53 +1600,26 @@ pub struct fuse_write_out {
 }
 unsafe impl FuseOut for fuse_write_out {}
 
-#[repr(C, align(4096))]
-struct AlignToPage([u8; 4096]);
 // TODO: do write zerocopy? currently does buf.to_vec()
 // problem: i cannot create owned type, since this would deallocate memory on drop. But memory belongs to userspace!
 //          Using references, i have to be careful of lifetimes!
 pub fn create_write(
 	nid: u64,
+	fh: u64,
 	buf: &[u8],
 	offset: u64,
 ) -> (Cmd<fuse_write_in>, Rsp<fuse_write_out>) {
 	let cmd = fuse_write_in {
+		fh,
 		offset,
 		size: buf.len() as u32,
 		..Default::default()
 	};
-	let mut cmdhdr = create_in_header::<fuse_write_in>(Opcode::FUSE_WRITE);
-	cmdhdr.nodeid = nid;
-	let rsp = Default::default();
-	let rsphdr = Default::default();
-
-	//direct-io requires aligned memory.
-	// ugly hack from https://stackoverflow.com/questions/60180121/how-do-i-allocate-a-vecu8-that-is-aligned-to-the-size-of-the-cache-line
-	let mut aligned: Vec<AlignToPage> =
-		Vec::with_capacity(buf.len() / ::core::mem::size_of::<AlignToPage>() + 1);
-	let ptr = aligned.as_mut_ptr();
-	let cap_units = aligned.capacity();
-	::core::mem::forget(aligned);
-	let mut writebuf = unsafe {
-		Vec::from_raw_parts(
-			ptr as *mut u8,
-			buf.len(),
-			cap_units * ::core::mem::size_of::<AlignToPage>(),
-		)
-	};
+	let mut writebuf = aligned_buffer(buf.len());
 	writebuf.clone_from_slice(buf);
-	// let writebuf = buf.to_vec();
 	(
-		Cmd {
-			cmd,
-			header: cmdhdr,
-			extra_buffer: Some(writebuf),
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
+		create_cmd(Opcode::FUSE_WRITE, nid, cmd, Some(writebuf)),
+		create_rsp(None),
 	)
 }
 
@@ -575,27 +1640,17 @@ pub struct fuse_open_out {
 }
 unsafe impl FuseOut for fuse_open_out {}
 
-pub fn create_open(nid: u64, flags: u32) -> (Cmd<fuse_open_in>, Rsp<fuse_open_out>) {
+/// Creates a FUSE_OPEN or FUSE_OPENDIR request.
+pub fn create_open(
+	nid: u64,
+	flags: u32,
+	opcode: Opcode,
+) -> (Cmd<fuse_open_in>, Rsp<fuse_open_out>) {
 	let cmd = fuse_open_in {
 		flags,
 		..Default::default()
 	};
-	let mut cmdhdr = create_in_header::<fuse_open_in>(Opcode::FUSE_OPEN);
-	cmdhdr.nodeid = nid;
-	let rsp = Default::default();
-	let rsphdr = Default::default();
-	(
-		Cmd {
-			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
-	)
+	(create_cmd(opcode, nid, cmd, None), create_rsp(None))
 }
 
 #[repr(C)]
@@ -613,152 +1668,350 @@ unsafe impl FuseIn for fuse_release_in {}
 pub struct fuse_release_out {}
 unsafe impl FuseOut for fuse_release_out {}
 
-pub fn create_release(nid: u64, fh: u64) -> (Cmd<fuse_release_in>, Rsp<fuse_release_out>) {
-	let mut cmd: fuse_release_in = Default::default();
-	let mut cmdhdr = create_in_header::<fuse_release_in>(Opcode::FUSE_RELEASE);
-	cmdhdr.nodeid = nid;
-	cmd.fh = fh;
-	let rsp = Default::default();
-	let rsphdr = Default::default();
+/// Creates a FUSE_RELEASE or FUSE_RELEASEDIR request.
+pub fn create_release(
+	nid: u64,
+	fh: u64,
+	opcode: Opcode,
+) -> (Cmd<fuse_release_in>, Rsp<fuse_release_out>) {
+	let cmd = fuse_release_in {
+		fh,
+		..Default::default()
+	};
+	(create_cmd(opcode, nid, cmd, None), create_rsp(None))
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_flush_in {
+	pub fh: u64,
+	pub unused: u32,
+	pub padding: u32,
+	pub lock_owner: u64,
+}
+unsafe impl FuseIn for fuse_flush_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_flush_out {}
+unsafe impl FuseOut for fuse_flush_out {}
+
+pub fn create_flush(nid: u64, fh: u64) -> (Cmd<fuse_flush_in>, Rsp<fuse_flush_out>) {
+	let cmd = fuse_flush_in {
+		fh,
+		..Default::default()
+	};
+	(
+		create_cmd(Opcode::FUSE_FLUSH, nid, cmd, None),
+		create_rsp(None),
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_fsync_in {
+	pub fh: u64,
+	pub fsync_flags: u32,
+	pub padding: u32,
+}
+unsafe impl FuseIn for fuse_fsync_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_fsync_out {}
+unsafe impl FuseOut for fuse_fsync_out {}
+
+pub fn create_fsync(
+	nid: u64,
+	fh: u64,
+	datasync: bool,
+) -> (Cmd<fuse_fsync_in>, Rsp<fuse_fsync_out>) {
+	let cmd = fuse_fsync_in {
+		fh,
+		fsync_flags: if datasync { FUSE_FSYNC_FDATASYNC } else { 0 },
+		padding: 0,
+	};
+	(
+		create_cmd(Opcode::FUSE_FSYNC, nid, cmd, None),
+		create_rsp(None),
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_unlink_in {}
+unsafe impl FuseIn for fuse_unlink_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_unlink_out {}
+unsafe impl FuseOut for fuse_unlink_out {}
+
+pub fn create_unlink(parent: u64, name: &str) -> (Cmd<fuse_unlink_in>, Rsp<fuse_unlink_out>) {
+	let cmd = create_cmd(
+		Opcode::FUSE_UNLINK,
+		parent,
+		fuse_unlink_in {},
+		Some(names_to_u8buf(&[name])),
+	);
+	(cmd, create_rsp(None))
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_mkdir_in {
+	pub mode: u32,
+	pub umask: u32,
+}
+unsafe impl FuseIn for fuse_mkdir_in {}
+
+pub fn create_mkdir(
+	parent: u64,
+	name: &str,
+	mode: u32,
+) -> (Cmd<fuse_mkdir_in>, Rsp<fuse_entry_out>) {
+	let cmd = fuse_mkdir_in { mode, umask: 0 };
 	(
-		Cmd {
+		create_cmd(
+			Opcode::FUSE_MKDIR,
+			parent,
 			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
+			Some(names_to_u8buf(&[name])),
+		),
+		create_rsp(None),
 	)
 }
 
-fn str_into_u8buf(s: &str, u8buf: &mut [u8]) {
-	// TODO: fix this hacky conversion..
-	for (i, c) in s.chars().enumerate() {
-		u8buf[i] = c as u8;
-		if i > u8buf.len() {
-			warn!("FUSE: Name too long!");
-			break;
-		}
-	}
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_rmdir_in {}
+unsafe impl FuseIn for fuse_rmdir_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_rmdir_out {}
+unsafe impl FuseOut for fuse_rmdir_out {}
+
+pub fn create_rmdir(parent: u64, name: &str) -> (Cmd<fuse_rmdir_in>, Rsp<fuse_rmdir_out>) {
+	let cmd = create_cmd(
+		Opcode::FUSE_RMDIR,
+		parent,
+		fuse_rmdir_in {},
+		Some(names_to_u8buf(&[name])),
+	);
+	(cmd, create_rsp(None))
 }
 
-// TODO: max path length?
-const MAX_PATH_LEN: usize = 256;
-fn str_to_path(s: &str) -> [u8; MAX_PATH_LEN] {
-	let mut buf = [0 as u8; MAX_PATH_LEN];
-	str_into_u8buf(s, &mut buf);
-	buf
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_rename2_in {
+	pub newdir: u64,
+	pub flags: u32,
+	pub padding: u32,
 }
+unsafe impl FuseIn for fuse_rename2_in {}
 
 #[repr(C)]
-pub struct fuse_lookup_in {
-	pub name: [u8; MAX_PATH_LEN],
+#[derive(Default, Debug)]
+pub struct fuse_rename2_out {}
+unsafe impl FuseOut for fuse_rename2_out {}
+
+/// Creates a request, which moves `name` of the directory `parent` to `newname` of `newdir`.
+/// `flags` are the RENAME_* flags of renameat2.
+pub fn create_rename2(
+	parent: u64,
+	name: &str,
+	newdir: u64,
+	newname: &str,
+	flags: u32,
+) -> (Cmd<fuse_rename2_in>, Rsp<fuse_rename2_out>) {
+	let cmd = fuse_rename2_in {
+		newdir,
+		flags,
+		padding: 0,
+	};
+	(
+		create_cmd(
+			Opcode::FUSE_RENAME2,
+			parent,
+			cmd,
+			Some(names_to_u8buf(&[name, newname])),
+		),
+		create_rsp(None),
+	)
 }
-unsafe impl FuseIn for fuse_lookup_in {}
 
-impl From<&str> for fuse_lookup_in {
-	fn from(name: &str) -> Self {
-		Self {
-			name: str_to_path(name),
-		}
-	}
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_symlink_in {}
+unsafe impl FuseIn for fuse_symlink_in {}
+
+pub fn create_symlink(
+	parent: u64,
+	name: &str,
+	target: &str,
+) -> (Cmd<fuse_symlink_in>, Rsp<fuse_entry_out>) {
+	let cmd = create_cmd(
+		Opcode::FUSE_SYMLINK,
+		parent,
+		fuse_symlink_in {},
+		Some(names_to_u8buf(&[name, target])),
+	);
+	(cmd, create_rsp(None))
 }
 
-impl fmt::Debug for fuse_lookup_in {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "fuse_lookup_in {{ {:?} }}", &self.name[..])
-	}
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_readlink_in {}
+unsafe impl FuseIn for fuse_readlink_in {}
+
+/// The target of the link is returned in the extra buffer of the reply.
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_readlink_out {}
+unsafe impl FuseOut for fuse_readlink_out {}
+
+pub fn create_readlink(nid: u64) -> (Cmd<fuse_readlink_in>, Rsp<fuse_readlink_out>) {
+	(
+		create_cmd(Opcode::FUSE_READLINK, nid, fuse_readlink_in {}, None),
+		create_rsp(Some(vec![0; MAX_LINK_LEN])),
+	)
 }
 
 #[repr(C)]
 #[derive(Default, Debug)]
-pub struct fuse_entry_out {
-	pub nodeid: u64,
-	pub generation: u64,
-	pub entry_valid: u64,
-	pub attr_valid: u64,
-	pub entry_valid_nsec: u32,
-	pub attr_valid_nsec: u32,
-	pub attr: fuse_attr,
+pub struct fuse_link_in {
+	pub oldnodeid: u64,
+}
+unsafe impl FuseIn for fuse_link_in {}
+
+/// Creates a request, which creates the link `name` in the directory `parent` to the node `nid`.
+pub fn create_link(nid: u64, parent: u64, name: &str) -> (Cmd<fuse_link_in>, Rsp<fuse_entry_out>) {
+	let cmd = fuse_link_in { oldnodeid: nid };
+	(
+		create_cmd(
+			Opcode::FUSE_LINK,
+			parent,
+			cmd,
+			Some(names_to_u8buf(&[name])),
+		),
+		create_rsp(None),
+	)
 }
-unsafe impl FuseOut for fuse_entry_out {}
 
 #[repr(C)]
 #[derive(Default, Debug)]
-pub struct fuse_attr {
-	pub ino: u64,
-	pub size: u64,
+pub struct fuse_statfs_in {}
+unsafe impl FuseIn for fuse_statfs_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_kstatfs {
 	pub blocks: u64,
-	pub atime: u64,
-	pub mtime: u64,
-	pub ctime: u64,
-	pub atimensec: u32,
-	pub mtimensec: u32,
-	pub ctimensec: u32,
-	pub mode: u32,
-	pub nlink: u32,
-	pub uid: u32,
-	pub gid: u32,
-	pub rdev: u32,
-	pub blksize: u32,
+	pub bfree: u64,
+	pub bavail: u64,
+	pub files: u64,
+	pub ffree: u64,
+	pub bsize: u32,
+	pub namelen: u32,
+	pub frsize: u32,
 	pub padding: u32,
+	pub spare: [u32; 6],
 }
 
 #[repr(C)]
-pub struct fuse_unlink_in {
-	pub name: [u8; MAX_PATH_LEN],
+#[derive(Default, Debug)]
+pub struct fuse_statfs_out {
+	pub st: fuse_kstatfs,
 }
-unsafe impl FuseIn for fuse_unlink_in {}
+unsafe impl FuseOut for fuse_statfs_out {}
 
-impl From<&str> for fuse_unlink_in {
-	fn from(name: &str) -> Self {
-		Self {
-			name: str_to_path(name),
-		}
-	}
+pub fn create_statfs(nid: u64) -> (Cmd<fuse_statfs_in>, Rsp<fuse_statfs_out>) {
+	(
+		create_cmd(Opcode::FUSE_STATFS, nid, fuse_statfs_in {}, None),
+		create_rsp(None),
+	)
 }
 
-impl fmt::Debug for fuse_unlink_in {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "fuse_unlink_in {{ {:?} }}", &self.name[..])
-	}
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_fallocate_in {
+	pub fh: u64,
+	pub offset: u64,
+	pub length: u64,
+	pub mode: u32,
+	pub padding: u32,
 }
+unsafe impl FuseIn for fuse_fallocate_in {}
 
 #[repr(C)]
 #[derive(Default, Debug)]
-pub struct fuse_unlink_out {}
-unsafe impl FuseOut for fuse_unlink_out {}
+pub struct fuse_fallocate_out {}
+unsafe impl FuseOut for fuse_fallocate_out {}
 
-pub fn create_unlink(name: &str) -> (Cmd<fuse_unlink_in>, Rsp<fuse_unlink_out>) {
-	let cmd = name.into();
-	let mut cmdhdr = create_in_header::<fuse_unlink_in>(Opcode::FUSE_UNLINK);
-	cmdhdr.nodeid = FUSE_ROOT_ID;
-	let rsp: fuse_unlink_out = Default::default();
-	let rsphdr: fuse_out_header = Default::default();
+pub fn create_fallocate(
+	nid: u64,
+	fh: u64,
+	mode: u32,
+	offset: u64,
+	length: u64,
+) -> (Cmd<fuse_fallocate_in>, Rsp<fuse_fallocate_out>) {
+	let cmd = fuse_fallocate_in {
+		fh,
+		offset,
+		length,
+		mode,
+		padding: 0,
+	};
 	(
-		Cmd {
-			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
+		create_cmd(Opcode::FUSE_FALLOCATE, nid, cmd, None),
+		create_rsp(None),
 	)
 }
 
 #[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_lseek_in {
+	pub fh: u64,
+	pub offset: u64,
+	pub whence: u32,
+	pub padding: u32,
+}
+unsafe impl FuseIn for fuse_lseek_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_lseek_out {
+	pub offset: u64,
+}
+unsafe impl FuseOut for fuse_lseek_out {}
+
+/// Creates a request, which finds the next data (SEEK_DATA) or hole (SEEK_HOLE) behind `offset`.
+pub fn create_lseek(
+	nid: u64,
+	fh: u64,
+	offset: u64,
+	whence: u32,
+) -> (Cmd<fuse_lseek_in>, Rsp<fuse_lseek_out>) {
+	let cmd = fuse_lseek_in {
+		fh,
+		offset,
+		whence,
+		padding: 0,
+	};
+	(
+		create_cmd(Opcode::FUSE_LSEEK, nid, cmd, None),
+		create_rsp(None),
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
 pub struct fuse_create_in {
 	pub flags: u32,
 	pub mode: u32,
 	pub umask: u32,
 	pub padding: u32,
-	pub name: [u8; MAX_PATH_LEN],
 }
 unsafe impl FuseIn for fuse_create_in {}
 
@@ -770,51 +2023,173 @@ pub struct fuse_create_out {
 }
 unsafe impl FuseOut for fuse_create_out {}
 
-impl fuse_create_in {
-	fn new(name: &str, flags: u32, mode: u32) -> Self {
-		Self {
-			flags,
-			mode,
-			umask: 0,
-			padding: 0,
-			name: str_to_path(name),
-		}
-	}
-}
-
-impl fmt::Debug for fuse_create_in {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(
-			f,
-			"fuse_create_in {{ flags: {}, mode: {}, umask: {}, name: {:?} ...}}",
-			self.flags,
-			self.mode,
-			self.umask,
-			&self.name[..10]
-		)
-	}
-}
-
 pub fn create_create(
-	path: &str,
+	parent: u64,
+	name: &str,
 	flags: u32,
 	mode: u32,
 ) -> (Cmd<fuse_create_in>, Rsp<fuse_create_out>) {
-	let cmd = fuse_create_in::new(path, flags, mode);
-	let mut cmdhdr = create_in_header::<fuse_create_in>(Opcode::FUSE_CREATE);
-	cmdhdr.nodeid = FUSE_ROOT_ID;
-	let rsp = Default::default();
-	let rsphdr = Default::default();
+	let cmd = fuse_create_in {
+		flags,
+		mode,
+		..Default::default()
+	};
 	(
-		Cmd {
+		create_cmd(
+			Opcode::FUSE_CREATE,
+			parent,
 			cmd,
-			header: cmdhdr,
-			extra_buffer: None,
-		},
-		Rsp {
-			rsp,
-			header: rsphdr,
-			extra_buffer: None,
-		},
+			Some(names_to_u8buf(&[name])),
+		),
+		create_rsp(None),
 	)
 }
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_setupmapping_in {
+	pub fh: u64,
+	pub foffset: u64,
+	pub len: u64,
+	pub flags: u64,
+	pub moffset: u64,
+}
+unsafe impl FuseIn for fuse_setupmapping_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_setupmapping_out {}
+unsafe impl FuseOut for fuse_setupmapping_out {}
+
+/// Creates a request, which maps a range of the file at `foffset` into the DAX window at `moffset`.
+pub fn create_setupmapping(
+	nid: u64,
+	fh: u64,
+	foffset: u64,
+	moffset: u64,
+	flags: u64,
+) -> (Cmd<fuse_setupmapping_in>, Rsp<fuse_setupmapping_out>) {
+	let cmd = fuse_setupmapping_in {
+		fh,
+		foffset,
+		len: DAX_RANGE_SIZE as u64,
+		flags,
+		moffset,
+	};
+	(
+		create_cmd(Opcode::FUSE_SETUPMAPPING, nid, cmd, None),
+		create_rsp(None),
+	)
+}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_removemapping_in {
+	pub count: u32,
+}
+unsafe impl FuseIn for fuse_removemapping_in {}
+
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct fuse_removemapping_out {}
+unsafe impl FuseOut for fuse_removemapping_out {}
+
+/// Creates a request, which removes the mappings of the ranges `indices` of the DAX window.
+pub fn create_removemapping(
+	nid: u64,
+	indices: &[usize],
+) -> (Cmd<fuse_removemapping_in>, Rsp<fuse_removemapping_out>) {
+	// the arguments are followed by an array of fuse_removemapping_one
+	let mut remove_one = Vec::with_capacity(indices.len() * 16);
+	for index in indices {
+		let moffset = (index * DAX_RANGE_SIZE) as u64;
+		remove_one.extend_from_slice(&moffset.to_le_bytes());
+		remove_one.extend_from_slice(&(DAX_RANGE_SIZE as u64).to_le_bytes());
+	}
+
+	let cmd = fuse_removemapping_in {
+		count: indices.len() as u32,
+	};
+	(
+		create_cmd(Opcode::FUSE_REMOVEMAPPING, nid, cmd, Some(remove_one)),
+		create_rsp(None),
+	)
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn node_references() {
+	let mut nodes = NodeTable::default();
+	nodes.get(5);
+	nodes.get(5);
+	nodes.get(FUSE_ROOT_ID);
+
+	// the lookups are forgotten, when the last reference is dropped
+	assert_eq!(nodes.put(5), None);
+	assert_eq!(nodes.put(5), Some(2));
+	assert_eq!(nodes.put(5), None);
+	assert_eq!(nodes.put(FUSE_ROOT_ID), None);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn readdirplus_parsing() {
+	fn push_entry(buf: &mut Vec<u8>, nodeid: u64, off: u64, name: &str) {
+		let mut entry = vec![0u8; mem::size_of::<fuse_entry_out>()];
+		entry[..8].copy_from_slice(&nodeid.to_le_bytes());
+		buf.extend_from_slice(&entry);
+		buf.extend_from_slice(&(nodeid + 100).to_le_bytes());
+		buf.extend_from_slice(&off.to_le_bytes());
+		buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+		buf.extend_from_slice(&8u32.to_le_bytes());
+		buf.extend_from_slice(name.as_bytes());
+		buf.resize(align_up!(buf.len(), 8), 0);
+	}
+
+	let mut buf = Vec::new();
+	push_entry(&mut buf, 0, 1, ".");
+	push_entry(&mut buf, 7, 2, "file.txt");
+
+	let mut entries = Vec::new();
+	let mut lookups = Vec::new();
+	assert_eq!(parse_direntplus(&buf, &mut entries, &mut lookups), 2);
+	assert_eq!(entries.len(), 2);
+	assert_eq!(entries[1].name, "file.txt");
+	assert_eq!(entries[1].ino, 107);
+	assert_eq!(entries[1].file_type, 8);
+	assert_eq!(lookups, [(7, 1)]);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn dax_ranges() {
+	let mut window = DaxWindow::new(
+		VirtAddr::from(0x1000_0000u64),
+		PhysAddr::from(0x8000_0000u64),
+		2 * DAX_RANGE_SIZE,
+	);
+
+	// free ranges are used first
+	assert_eq!(window.allocate(), Some((0, None)));
+	let range = window.insert(0, 5, 0, false);
+	assert_eq!(range.phys_addr, PhysAddr::from(0x8000_0000u64));
+	assert_eq!(window.allocate(), Some((1, None)));
+	window.insert(1, 6, 0, false);
+	assert_eq!(window.find(6, 0), Some(1));
+
+	// pinned ranges are not reused
+	window.unpin(1);
+	assert_eq!(window.allocate(), Some((1, Some(6))));
+	window.insert(1, 6, 0, false);
+	assert_eq!(window.allocate(), None);
+
+	// a forgotten node releases its unpinned ranges
+	window.unpin(1);
+	window.pin(window.find(6, 0).unwrap());
+	window.unpin(1);
+	assert_eq!(window.forget(6), [1]);
+	assert!(window.forget(5).is_empty());
+	assert_eq!(window.find(5, 0), None);
+	window.unpin(0);
+	assert_eq!(window.allocate(), Some((1, None)));
+}
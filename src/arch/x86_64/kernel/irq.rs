@@ -18,9 +18,25 @@ use crate::x86::bits64::rflags;
 use crate::alloc::string::ToString;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
 
+/// Number of interrupts (starting at vector 32), which are dispatched to device handlers
+const DEVICE_IRQS: u32 = 80;
+/// First interrupt number, which is allocated for MSI and MSI-X.
+/// The interrupts below are the legacy interrupt lines.
+const MSI_IRQ_BASE: u32 = 32;
+
+/// Interrupt handler of a device, which receives the context of its registration.
+/// Returns true, if the handler has woken up tasks and the scheduler should run.
+pub type DeviceInterruptHandler = fn(usize) -> bool;
+
 static IRQ_NAMES: SpinlockIrqSave<BTreeMap<u32, String>> = SpinlockIrqSave::new(BTreeMap::new());
+/// Registered device handlers and their contexts per interrupt number
+static DEVICE_HANDLERS: SpinlockIrqSave<BTreeMap<u32, Vec<(DeviceInterruptHandler, usize)>>> =
+	SpinlockIrqSave::new(BTreeMap::new());
+/// Bitmap of the allocated MSI/MSI-X interrupts, bit 0 represents `MSI_IRQ_BASE`
+static ALLOCATED_MSI_IRQS: SpinlockIrqSave<u64> = SpinlockIrqSave::new(0);
 
 // Derived from Philipp Oppermann's blog
 // => https://github.com/phil-opp/blog_os/blob/master/src/interrupts/mod.rs
@@ -158,40 +174,17 @@ pub fn install() {
 	idt::set_gate(30, reserved_exception as usize, 0);
 	idt::set_gate(31, reserved_exception as usize, 0);
 
-	idt::set_gate(32, unhandled_interrupt0 as usize, 0);
-	idt::set_gate(33, unhandled_interrupt1 as usize, 0);
-	idt::set_gate(34, unhandled_interrupt2 as usize, 0);
-	idt::set_gate(35, unhandled_interrupt3 as usize, 0);
-	idt::set_gate(36, unhandled_interrupt4 as usize, 0);
-	idt::set_gate(37, unhandled_interrupt5 as usize, 0);
-	idt::set_gate(38, unhandled_interrupt6 as usize, 0);
-	idt::set_gate(39, unhandled_interrupt7 as usize, 0);
-	idt::set_gate(40, unhandled_interrupt8 as usize, 0);
-	idt::set_gate(41, unhandled_interrupt9 as usize, 0);
-	idt::set_gate(42, unhandled_interrupt10 as usize, 0);
-	idt::set_gate(43, unhandled_interrupt11 as usize, 0);
-	idt::set_gate(44, unhandled_interrupt12 as usize, 0);
-	idt::set_gate(45, unhandled_interrupt13 as usize, 0);
-	idt::set_gate(46, unhandled_interrupt14 as usize, 0);
-	idt::set_gate(47, unhandled_interrupt15 as usize, 0);
-	idt::set_gate(48, unhandled_interrupt16 as usize, 0);
-	idt::set_gate(49, unhandled_interrupt17 as usize, 0);
-	idt::set_gate(50, unhandled_interrupt18 as usize, 0);
-	idt::set_gate(51, unhandled_interrupt19 as usize, 0);
-	idt::set_gate(52, unhandled_interrupt20 as usize, 0);
-	idt::set_gate(53, unhandled_interrupt21 as usize, 0);
-	idt::set_gate(54, unhandled_interrupt22 as usize, 0);
-	idt::set_gate(55, unhandled_interrupt23 as usize, 0);
-	idt::set_gate(56, unhandled_interrupt24 as usize, 0);
-	idt::set_gate(57, unhandled_interrupt25 as usize, 0);
-	idt::set_gate(58, unhandled_interrupt26 as usize, 0);
-	idt::set_gate(59, unhandled_interrupt27 as usize, 0);
-	idt::set_gate(60, unhandled_interrupt28 as usize, 0);
-	idt::set_gate(61, unhandled_interrupt29 as usize, 0);
-	idt::set_gate(62, unhandled_interrupt30 as usize, 0);
-	idt::set_gate(63, unhandled_interrupt31 as usize, 0);
-
-	for i in 64..idt::IDT_ENTRIES {
+	// The legacy interrupt lines and the interrupts of MSI/MSI-X are dispatched
+	// to the handlers, which are registered by the device drivers.
+	for irq_number in 0..DEVICE_IRQS {
+		idt::set_gate(
+			(32 + irq_number) as u8,
+			get_device_interrupt_handler(irq_number),
+			0,
+		);
+	}
+
+	for i in (32 + DEVICE_IRQS as usize)..idt::IDT_ENTRIES {
 		idt::set_gate(i as u8, unknown_interrupt as usize, 0);
 	}
 }
@@ -212,139 +205,168 @@ pub fn get_irq_name(irq_number: u32) -> Option<String> {
 	Some(name)
 }
 
-fn unhandled_interrupt(irq_number: u8) {
-	warn!("Receive unhandled interrupt {}", irq_number);
-	apic::eoi();
-	increment_irq_counter((32 + irq_number).into());
-}
-
-extern "x86-interrupt" fn unhandled_interrupt0(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(0);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt1(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(1);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt2(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(2);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt3(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(3);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt4(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(4);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt5(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(5);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt6(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(6);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt7(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(7);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt8(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(8);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt9(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(9);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt10(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(10);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt11(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(11);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt12(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(12);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt13(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(13);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt14(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(14);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt15(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(15);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt16(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(16);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt17(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(17);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt18(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(18);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt19(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(19);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt20(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(20);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt21(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(21);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt22(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(22);
-}
-
-extern "x86-interrupt" fn unhandled_interrupt23(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(23);
-}
+/// Allocates an interrupt number for MSI or MSI-X, which is not shared with other devices.
+/// Returns None, if all interrupt numbers are in use.
+pub fn allocate_irq() -> Option<u32> {
+	let mut allocated = ALLOCATED_MSI_IRQS.lock();
+	let index = (!*allocated).trailing_zeros();
+	if index >= DEVICE_IRQS - MSI_IRQ_BASE {
+		return None;
+	}
 
-extern "x86-interrupt" fn unhandled_interrupt24(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(24);
+	*allocated |= 1 << index;
+	Some(MSI_IRQ_BASE + index)
 }
 
-extern "x86-interrupt" fn unhandled_interrupt25(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(25);
-}
+/// Registers `handler` for the interrupt `irq_number`, which is called with `context`.
+/// A legacy interrupt line may be shared by several devices. In this case, every
+/// handler has to check, whether its device has raised the interrupt.
+pub fn add_device_handler(
+	irq_number: u32,
+	name: &'static str,
+	handler: DeviceInterruptHandler,
+	context: usize,
+) {
+	assert!(
+		irq_number < DEVICE_IRQS,
+		"Interrupt {} is not available for devices",
+		irq_number
+	);
 
-extern "x86-interrupt" fn unhandled_interrupt26(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(26);
+	DEVICE_HANDLERS
+		.lock()
+		.entry(irq_number)
+		.or_insert_with(Vec::new)
+		.push((handler, context));
+	add_irq_name(irq_number, name);
 }
 
-extern "x86-interrupt" fn unhandled_interrupt27(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(27);
-}
+fn dispatch_interrupt(irq_number: u32) {
+	apic::eoi();
+	increment_irq_counter((32 + irq_number) as usize);
+
+	// The lock is not held, while a handler is running. Hence, the handlers
+	// are able to use the interrupt-safe locks of their drivers.
+	let mut check_scheduler = false;
+	let mut index = 0;
+	while let Some((handler, context)) = DEVICE_HANDLERS
+		.lock()
+		.get(&irq_number)
+		.and_then(|handlers| handlers.get(index).copied())
+	{
+		check_scheduler |= handler(context);
+		index += 1;
+	}
 
-extern "x86-interrupt" fn unhandled_interrupt28(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(28);
-}
+	if index == 0 {
+		warn!("Receive unhandled interrupt {}", irq_number);
+	}
 
-extern "x86-interrupt" fn unhandled_interrupt29(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(29);
+	if check_scheduler {
+		core_scheduler().scheduler();
+	}
 }
 
-extern "x86-interrupt" fn unhandled_interrupt30(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(30);
-}
+/// Every interrupt number needs its own entry point, which knows the number.
+macro_rules! device_interrupt_handlers {
+	($($irq:literal => $name:ident),*) => {
+		$(
+			extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+				dispatch_interrupt($irq);
+			}
+		)*
 
-extern "x86-interrupt" fn unhandled_interrupt31(_stack_frame: &mut ExceptionStackFrame) {
-	unhandled_interrupt(31);
-}
+		fn get_device_interrupt_handler(irq_number: u32) -> usize {
+			match irq_number {
+				$($irq => $name as usize,)*
+				_ => panic!("Invalid device interrupt {}", irq_number),
+			}
+		}
+	};
+}
+
+device_interrupt_handlers!(
+	0 => device_interrupt0,
+	1 => device_interrupt1,
+	2 => device_interrupt2,
+	3 => device_interrupt3,
+	4 => device_interrupt4,
+	5 => device_interrupt5,
+	6 => device_interrupt6,
+	7 => device_interrupt7,
+	8 => device_interrupt8,
+	9 => device_interrupt9,
+	10 => device_interrupt10,
+	11 => device_interrupt11,
+	12 => device_interrupt12,
+	13 => device_interrupt13,
+	14 => device_interrupt14,
+	15 => device_interrupt15,
+	16 => device_interrupt16,
+	17 => device_interrupt17,
+	18 => device_interrupt18,
+	19 => device_interrupt19,
+	20 => device_interrupt20,
+	21 => device_interrupt21,
+	22 => device_interrupt22,
+	23 => device_interrupt23,
+	24 => device_interrupt24,
+	25 => device_interrupt25,
+	26 => device_interrupt26,
+	27 => device_interrupt27,
+	28 => device_interrupt28,
+	29 => device_interrupt29,
+	30 => device_interrupt30,
+	31 => device_interrupt31,
+	32 => device_interrupt32,
+	33 => device_interrupt33,
+	34 => device_interrupt34,
+	35 => device_interrupt35,
+	36 => device_interrupt36,
+	37 => device_interrupt37,
+	38 => device_interrupt38,
+	39 => device_interrupt39,
+	40 => device_interrupt40,
+	41 => device_interrupt41,
+	42 => device_interrupt42,
+	43 => device_interrupt43,
+	44 => device_interrupt44,
+	45 => device_interrupt45,
+	46 => device_interrupt46,
+	47 => device_interrupt47,
+	48 => device_interrupt48,
+	49 => device_interrupt49,
+	50 => device_interrupt50,
+	51 => device_interrupt51,
+	52 => device_interrupt52,
+	53 => device_interrupt53,
+	54 => device_interrupt54,
+	55 => device_interrupt55,
+	56 => device_interrupt56,
+	57 => device_interrupt57,
+	58 => device_interrupt58,
+	59 => device_interrupt59,
+	60 => device_interrupt60,
+	61 => device_interrupt61,
+	62 => device_interrupt62,
+	63 => device_interrupt63,
+	64 => device_interrupt64,
+	65 => device_interrupt65,
+	66 => device_interrupt66,
+	67 => device_interrupt67,
+	68 => device_interrupt68,
+	69 => device_interrupt69,
+	70 => device_interrupt70,
+	71 => device_interrupt71,
+	72 => device_interrupt72,
+	73 => device_interrupt73,
+	74 => device_interrupt74,
+	75 => device_interrupt75,
+	76 => device_interrupt76,
+	77 => device_interrupt77,
+	78 => device_interrupt78,
+	79 => device_interrupt79
+);
 
 extern "x86-interrupt" fn unknown_interrupt(_stack_frame: &mut ExceptionStackFrame) {
 	info!("Receive unknown interrupt");
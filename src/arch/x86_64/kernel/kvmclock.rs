@@ -0,0 +1,280 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Paravirtual clock source of KVM (kvmclock).
+//!
+//! KVM publishes a `pvclock_vcpu_time_info` structure for every virtual CPU, which the guest
+//! registers through an MSR. The hypervisor keeps it up to date, e.g. after a live migration
+//! or a change of the host TSC frequency, so that the guest does not have to rely on a
+//! calibrated TSC frequency.
+//! See https://www.kernel.org/doc/html/latest/virt/kvm/msr.html for details.
+
+use crate::arch::x86_64::kernel::percore::*;
+use crate::arch::x86_64::kernel::processor;
+use crate::arch::x86_64::mm::paging;
+use crate::arch::x86_64::mm::VirtAddr;
+use crate::environment;
+use crate::x86::cpuid::*;
+use crate::x86::msr::wrmsr;
+use alloc::boxed::Box;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use core::{fmt, ptr};
+
+/// Feature leaf of KVM
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+/// kvmclock is available at the MSRs MSR_KVM_WALL_CLOCK_NEW and MSR_KVM_SYSTEM_TIME_NEW
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+/// The host guarantees that the pvclocks of all vCPUs are synchronized,
+/// if the PVCLOCK_TSC_STABLE_BIT is set in the time info
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+/// Enable bit in MSR_KVM_SYSTEM_TIME_NEW
+const KVM_SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+static KVMCLOCK_AVAILABLE: AtomicBool = AtomicBool::new(false);
+static KVMCLOCK_STABLE: AtomicBool = AtomicBool::new(false);
+
+/// Time information of a vCPU, written by the hypervisor.
+/// The structure must not cross a page boundary, which is guaranteed by its alignment.
+#[repr(C, align(32))]
+pub struct PvclockVcpuTimeInfo {
+	version: u32,
+	pad0: u32,
+	tsc_timestamp: u64,
+	system_time: u64,
+	tsc_to_system_mul: u32,
+	tsc_shift: i8,
+	flags: u8,
+	pad: [u8; 2],
+}
+
+impl PvclockVcpuTimeInfo {
+	const fn new() -> Self {
+		Self {
+			version: 0,
+			pad0: 0,
+			tsc_timestamp: 0,
+			system_time: 0,
+			tsc_to_system_mul: 0,
+			tsc_shift: 0,
+			flags: 0,
+			pad: [0; 2],
+		}
+	}
+
+	/// Take a consistent snapshot of the time info.
+	/// The hypervisor sets the lowest bit of the version while it updates the structure.
+	fn read(&self) -> (u64, u64, u32, i8, u8) {
+		loop {
+			let version = unsafe { ptr::read_volatile(&self.version) };
+			if version & 1 != 0 {
+				continue;
+			}
+			fence(Ordering::Acquire);
+
+			let snapshot = unsafe {
+				(
+					ptr::read_volatile(&self.tsc_timestamp),
+					ptr::read_volatile(&self.system_time),
+					ptr::read_volatile(&self.tsc_to_system_mul),
+					ptr::read_volatile(&self.tsc_shift),
+					ptr::read_volatile(&self.flags),
+				)
+			};
+
+			fence(Ordering::Acquire);
+			if version == unsafe { ptr::read_volatile(&self.version) } {
+				return snapshot;
+			}
+		}
+	}
+}
+
+/// Wall clock time at the moment, where the system time of the pvclock was zero.
+#[repr(C, align(16))]
+struct PvclockWallClock {
+	version: u32,
+	sec: u32,
+	nsec: u32,
+}
+
+impl fmt::Display for PvclockWallClock {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{:09}s", { self.sec }, { self.nsec })
+	}
+}
+
+#[inline]
+fn scale_delta(delta: u64, mul: u32, shift: i8) -> u64 {
+	let delta = if shift < 0 {
+		delta >> -shift
+	} else {
+		delta << shift
+	};
+
+	((u128::from(delta) * u128::from(mul)) >> 32) as u64
+}
+
+#[inline]
+fn unscale_delta(delta: u64, mul: u32, shift: i8) -> u64 {
+	let delta = ((u128::from(delta) << 32) / u128::from(mul)) as u64;
+
+	if shift < 0 {
+		delta << -shift
+	} else {
+		delta >> shift
+	}
+}
+
+#[inline]
+fn get_time_info() -> Option<&'static PvclockVcpuTimeInfo> {
+	unsafe { PERCORE.pvclock.get().as_ref() }
+}
+
+/// Returns the system time of the current vCPU in nanoseconds,
+/// if kvmclock has been registered for this core.
+#[inline]
+pub fn get_nanos() -> Option<u64> {
+	let time_info = get_time_info()?;
+	let (tsc_timestamp, system_time, mul, shift, _) = time_info.read();
+	let delta = processor::get_timestamp().saturating_sub(tsc_timestamp);
+
+	Some(system_time + scale_delta(delta, mul, shift))
+}
+
+/// Converts a system time in nanoseconds (see get_nanos) into the corresponding TSC value of the current vCPU.
+pub fn nanos_to_timestamp(nanos: u64) -> Option<u64> {
+	let time_info = get_time_info()?;
+	let (tsc_timestamp, system_time, mul, shift, _) = time_info.read();
+
+	Some(tsc_timestamp + unscale_delta(nanos.saturating_sub(system_time), mul, shift))
+}
+
+#[inline]
+pub fn is_available() -> bool {
+	KVMCLOCK_AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Returns true if the pvclocks of all vCPUs are guaranteed to be synchronized.
+#[inline]
+pub fn is_stable() -> bool {
+	KVMCLOCK_STABLE.load(Ordering::Relaxed)
+}
+
+fn detect() -> bool {
+	// uhyve provides its own boot time based on the TSC
+	if environment::is_uhyve() || !processor::run_on_hypervisor() {
+		return false;
+	}
+
+	let cpuid = CpuId::new();
+	match cpuid.get_hypervisor_info() {
+		Some(info) if matches!(info.identify(), Hypervisor::KVM) => {}
+		_ => return false,
+	}
+
+	let features = unsafe { __cpuid(KVM_CPUID_FEATURES) }.eax;
+	if features & KVM_FEATURE_CLOCKSOURCE2 == 0 {
+		return false;
+	}
+
+	KVMCLOCK_STABLE.store(
+		features & KVM_FEATURE_CLOCKSOURCE_STABLE_BIT != 0,
+		Ordering::Relaxed,
+	);
+	true
+}
+
+/// Register a time info structure for the current vCPU.
+pub fn register_current_core() {
+	if !is_available() {
+		return;
+	}
+
+	let time_info = Box::leak(Box::new(PvclockVcpuTimeInfo::new()));
+	let physical_address =
+		paging::virtual_to_physical(VirtAddr(time_info as *const _ as u64)).as_u64();
+
+	unsafe {
+		wrmsr(
+			MSR_KVM_SYSTEM_TIME_NEW,
+			physical_address | KVM_SYSTEM_TIME_ENABLE,
+		);
+	}
+
+	let (_, _, _, _, flags) = time_info.read();
+	if flags & PVCLOCK_TSC_STABLE_BIT == 0 {
+		KVMCLOCK_STABLE.store(false, Ordering::Relaxed);
+	}
+
+	unsafe {
+		PERCORE.pvclock.set(time_info);
+	}
+}
+
+/// Returns the wall clock time in microseconds since the epoch, which corresponds to a system time of zero.
+pub fn get_wall_clock() -> Option<u64> {
+	if !is_available() {
+		return None;
+	}
+
+	let wall_clock = Box::new(PvclockWallClock {
+		version: 0,
+		sec: 0,
+		nsec: 0,
+	});
+	let physical_address =
+		paging::virtual_to_physical(VirtAddr(&*wall_clock as *const _ as u64)).as_u64();
+
+	// Writing the MSR triggers the hypervisor to fill in the structure.
+	unsafe {
+		wrmsr(MSR_KVM_WALL_CLOCK_NEW, physical_address);
+	}
+
+	loop {
+		let version = unsafe { ptr::read_volatile(&wall_clock.version) };
+		if version & 1 != 0 {
+			continue;
+		}
+		fence(Ordering::Acquire);
+
+		let sec = unsafe { ptr::read_volatile(&wall_clock.sec) };
+		let nsec = unsafe { ptr::read_volatile(&wall_clock.nsec) };
+
+		fence(Ordering::Acquire);
+		if version == unsafe { ptr::read_volatile(&wall_clock.version) } {
+			debug!("kvmclock: wall clock at system time zero is {}", wall_clock);
+			return Some(u64::from(sec) * 1_000_000 + u64::from(nsec) / 1000);
+		}
+	}
+}
+
+/// Detect kvmclock and register it for the boot processor.
+pub fn init() {
+	if detect() {
+		KVMCLOCK_AVAILABLE.store(true, Ordering::SeqCst);
+		register_current_core();
+		info!(
+			"Use kvmclock as clock source ({})",
+			if is_stable() { "stable" } else { "unstable" }
+		);
+	}
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn scale_kvmclock_delta() {
+	// For a 2.5 GHz TSC, KVM reports a multiplier of 0.8 * 2^32 and a shift of -1.
+	let mul = 3_435_973_836;
+	assert_eq!(scale_delta(2_500_000_000, mul, -1), 999_999_999);
+	assert_eq!(unscale_delta(1_000_000_000, mul, -1), 2_500_000_000);
+}
@@ -29,6 +29,7 @@ pub mod fuse;
 pub mod gdt;
 pub mod idt;
 pub mod irq;
+pub mod kvmclock;
 #[cfg(feature = "pci")]
 pub mod pci;
 #[cfg(feature = "pci")]
@@ -45,8 +46,15 @@ pub mod systemtime;
 #[cfg(feature = "vga")]
 mod vga;
 pub mod virtio;
+pub mod virtio_balloon;
+pub mod virtio_blk;
+pub mod virtio_console;
 pub mod virtio_fs;
 pub mod virtio_net;
+pub mod virtio_packed;
+pub mod virtio_rng;
+#[cfg(not(feature = "newlib"))]
+pub mod virtio_vsock;
 
 #[cfg(not(test))]
 global_asm!(include_str!("start.s"));
@@ -293,6 +301,14 @@ pub fn output_message_byte(byte: u8) {
 
 //#[cfg(target_os = "hermit")]
 pub fn output_message_buf(buf: &[u8]) {
+	// A virtio console replaces the serial port, as soon as it is initialized.
+	// The data, which the console hasn't accepted, is printed on the serial port.
+	#[cfg(feature = "pci")]
+	let buf = match pci::get_console_driver() {
+		Some(driver) => &buf[driver.write_console(buf)..],
+		None => buf,
+	};
+
 	for byte in buf {
 		output_message_byte(*byte);
 	}
@@ -319,25 +335,28 @@ pub fn boot_processor_init() {
 
 	irq::install();
 	processor::detect_frequency();
+	kvmclock::init();
 	processor::print_information();
 	unsafe {
 		trace!("Cr0: 0x{:x}, Cr4: 0x{:x}", cr0(), cr4());
 	}
-	systemtime::init();
 
 	if environment::is_single_kernel() {
+		// The PCI scan uses the memory-mapped configuration space described in the MCFG.
+		if !environment::is_uhyve() {
+			#[cfg(feature = "acpi")]
+			acpi::init();
+		}
 		if is_uhyve_with_pci() || !is_uhyve() {
 			#[cfg(feature = "pci")]
 			pci::init();
 			#[cfg(feature = "pci")]
 			pci::print_information();
 		}
-		if !environment::is_uhyve() {
-			#[cfg(feature = "acpi")]
-			acpi::init();
-		}
 	}
 
+	// The RTC century register is described in the FADT.
+	systemtime::init();
 	apic::init();
 	scheduler::install_timer_handler();
 	finish_processor_init();
@@ -361,11 +380,17 @@ pub fn application_processor_init() {
 	idt::install();
 	apic::init_x2apic();
 	apic::init_local_apic();
+	kvmclock::register_current_core();
 	unsafe {
 		trace!("Cr0: 0x{:x}, Cr4: 0x{:x}", cr0(), cr4());
 	}
 	irq::enable();
 	finish_processor_init();
+
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		// The boot processor waits for us to check the synchronization of the Time-Stamp Counters.
+		processor::synchronize_tsc_target();
+	}
 }
 
 fn finish_processor_init() {
@@ -406,6 +431,15 @@ pub fn print_statistics() {
 			}
 		}
 	}
+
+	info!("Idle time");
+	unsafe {
+		for core_id in IRQ_COUNTERS.keys() {
+			if let Some(idle_time) = crate::scheduler::get_idle_time(*core_id) {
+				info!("[{}]: {} ms", core_id, idle_time / 1_000_000);
+			}
+		}
+	}
 }
 
 #[cfg(target_os = "hermit")]
@@ -6,20 +6,38 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+#[cfg(feature = "acpi")]
+use crate::arch::x86_64::kernel::acpi;
+use crate::arch::x86_64::kernel::apic;
+use crate::arch::x86_64::kernel::irq::{add_device_handler, allocate_irq, DeviceInterruptHandler};
 use crate::arch::x86_64::kernel::pci_ids::{CLASSES, VENDORS};
 use crate::arch::x86_64::kernel::virtio;
+use crate::arch::x86_64::kernel::virtio_balloon::VirtioBalloonDriver;
+use crate::arch::x86_64::kernel::virtio_blk::VirtioBlkDriver;
+use crate::arch::x86_64::kernel::virtio_console::VirtioConsoleDriver;
 use crate::arch::x86_64::kernel::virtio_fs::VirtioFsDriver;
 use crate::arch::x86_64::kernel::virtio_net::VirtioNetDriver;
+use crate::arch::x86_64::kernel::virtio_rng::VirtioRngDriver;
+#[cfg(not(feature = "newlib"))]
+use crate::arch::x86_64::kernel::virtio_vsock::VirtioVsockDriver;
 use crate::arch::x86_64::mm::{PhysAddr, VirtAddr};
-use crate::synch::spinlock::SpinlockIrqSave;
+use crate::environment;
+use crate::scheduler::CoreId;
 use crate::x86::io::*;
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::any::Any;
 use core::convert::TryInto;
-use core::{fmt, u32, u8};
+use core::{fmt, mem, ptr, u32, u8};
 
-// TODO: should these be pub? currently needed since used in virtio.rs maybe use getter methods to be more flexible.
-pub const PCI_MAX_BUS_NUMBER: u8 = 32;
 pub const PCI_MAX_DEVICE_NUMBER: u8 = 32;
+pub const PCI_MAX_FUNCTION_NUMBER: u8 = 8;
+
+/// Size of the configuration space, which is accessible through the I/O ports
+const PCI_CONFIG_SPACE_SIZE: u32 = 256;
+/// Size of the memory-mapped configuration space of a bus (32 devices with 8 functions and 4 KiB each)
+const PCI_ECAM_BUS_SIZE: usize = 1 << 20;
 
 pub const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
 pub const PCI_CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
@@ -32,6 +50,7 @@ pub const PCI_COMMAND_REGISTER: u32 = 0x04;
 pub const PCI_CLASS_REGISTER: u32 = 0x08;
 pub const PCI_HEADER_REGISTER: u32 = 0x0C;
 pub const PCI_BAR0_REGISTER: u32 = 0x10;
+pub const PCI_BRIDGE_BUS_REGISTER: u32 = 0x18;
 pub const PCI_CAPABILITY_LIST_REGISTER: u32 = 0x34;
 pub const PCI_INTERRUPT_REGISTER: u32 = 0x3C;
 
@@ -44,12 +63,52 @@ pub const PCI_MEM_BASE_ADDRESS_MASK: u32 = 0xFFFF_FFF0;
 pub const PCI_IO_BASE_ADDRESS_MASK: u32 = 0xFFFF_FFFC;
 
 pub const PCI_HEADER_TYPE_MASK: u32 = 0x007F_0000;
+pub const PCI_HEADER_TYPE_NORMAL: u32 = 0x0000_0000;
+pub const PCI_HEADER_TYPE_BRIDGE: u32 = 0x0001_0000;
 pub const PCI_MULTIFUNCTION_MASK: u32 = 0x0080_0000;
 
+pub const PCI_CAP_ID_MSI: u32 = 0x05;
 pub const PCI_CAP_ID_VNDR: u32 = 0x09;
+pub const PCI_CAP_ID_MSIX: u32 = 0x11;
+
+/// The capabilities are located behind the standard header
+const PCI_CAPABILITIES_START: u32 = 0x40;
+/// Upper bound of the capabilities in the config space, which stops a cyclic list
+const PCI_MAX_CAPABILITIES: usize = (256 - PCI_CAPABILITIES_START as usize) / 4;
+
+/// MSI Enable bit in the Message Control register (upper half of the first capability dword)
+const PCI_MSI_ENABLE: u32 = 1 << 16;
+/// Multiple Message Enable field, the driver requests only one message
+const PCI_MSI_MULTIPLE_MESSAGE_ENABLE_MASK: u32 = 0x7 << 20;
+/// The device supports 64 bit message addresses, which moves the data register
+const PCI_MSI_64BIT_CAPABLE: u32 = 1 << 23;
+
+/// MSI-X Enable bit in the Message Control register (upper half of the first capability dword)
+const PCI_MSIX_ENABLE: u32 = 1 << 31;
+/// Function Mask bit in the Message Control register
+const PCI_MSIX_FUNCTION_MASK: u32 = 1 << 30;
+const PCI_MSIX_TABLE_SIZE_MASK: u32 = 0x07FF;
+const PCI_MSIX_BIR_MASK: u32 = 0x7;
+/// Physical base address of the Local APIC, which receives the MSI and MSI-X messages
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_ADDRESS_DESTINATION_SHIFT: u32 = 12;
+const MSIX_ENTRY_VECTOR_MASKED: u32 = 1 << 0;
 
 static mut PCI_ADAPTERS: Vec<PciAdapter> = Vec::new();
-static mut PCI_DRIVERS: Vec<PciDriver> = Vec::new();
+static mut PCI_DRIVERS: Vec<&'static dyn PciDriver> = Vec::new();
+static mut PCI_DEVICES: Vec<PciInstance> = Vec::new();
+/// Memory-mapped configuration space of the PCI segment group 0, if the firmware provides one.
+/// It is only written once by `init`, before the configuration space is accessed concurrently.
+static mut ECAM: Option<Ecam> = None;
+
+/// Enhanced Configuration Access Mechanism of PCI Express, which maps the
+/// extended (4 KiB) configuration space of every function into memory
+struct Ecam {
+	start_bus: u8,
+	end_bus: u8,
+	/// Virtual address of the configuration space of `start_bus`
+	address: VirtAddr,
+}
 
 /// Classes of PCI nodes.
 #[allow(dead_code)]
@@ -94,7 +153,8 @@ pub enum PciNetworkControllerSubclass {
 #[derive(Clone, Debug)]
 pub struct PciAdapter {
 	pub bus: u8,
-	pub device: u8,
+	/// Device number (bits 3-7) and function number (bits 0-2)
+	pub devfn: u8,
 	pub vendor_id: u16,
 	pub device_id: u16,
 	pub class_id: u8,
@@ -123,51 +183,176 @@ pub struct MemoryBar {
 	pub prefetchable: bool,
 }
 
-pub enum PciDriver<'a> {
-	VirtioFs(SpinlockIrqSave<VirtioFsDriver<'a>>),
-	VirtioNet(SpinlockIrqSave<VirtioNetDriver<'a>>),
+/// Identifies a device, which is supported by a driver
+#[derive(Clone, Copy, Debug)]
+pub struct PciDeviceId {
+	pub vendor_id: u16,
+	pub device_id: u16,
 }
 
-impl<'a> PciDriver<'a> {
-	fn get_network_driver(&self) -> Option<&SpinlockIrqSave<VirtioNetDriver<'a>>> {
-		match self {
-			Self::VirtioNet(drv) => Some(drv),
-			_ => None,
+impl PciDeviceId {
+	pub const fn new(vendor_id: u16, device_id: u16) -> Self {
+		Self {
+			vendor_id,
+			device_id,
 		}
 	}
 
-	fn get_filesystem_driver(&self) -> Option<&SpinlockIrqSave<VirtioFsDriver<'a>>> {
-		match self {
-			Self::VirtioFs(drv) => Some(drv),
-			_ => None,
+	fn matches(&self, adapter: &PciAdapter) -> bool {
+		self.vendor_id == adapter.vendor_id && self.device_id == adapter.device_id
+	}
+}
+
+/// Driver of PCI devices, which is registered by `register_driver`.
+/// Every device, which matches the id table, gets its own instance of the driver.
+pub trait PciDriver: Sync {
+	/// Name of the driver, which is also the prefix of the instance names (e.g. "virtio-net0")
+	fn name(&self) -> &'static str;
+
+	/// Devices, which are supported by the driver
+	fn id_table(&self) -> &'static [PciDeviceId];
+
+	/// Initializes the device and returns the driver instance.
+	/// Returns None, if the device is not usable.
+	fn probe(&self, adapter: &PciAdapter) -> Option<Box<dyn PciDevice>>;
+}
+
+/// Instance of a `PciDriver`, which drives one device
+pub trait PciDevice: Send + Sync {
+	/// Returns the instance as `Any`, which allows the lookup of the concrete driver.
+	fn as_any(&self) -> &dyn Any;
+
+	/// Returns the MSI-X table, if the device uses MSI-X.
+	fn msix_table(&self) -> Option<&MsixTable> {
+		None
+	}
+
+	/// Routes the interrupts of the device to `handle_interrupt`. By default, the device
+	/// uses the first entry of its MSI-X table, MSI or its legacy interrupt line.
+	/// `instance` identifies the device in the interrupt handler.
+	fn install_interrupt_handler(&self, adapter: &PciAdapter, name: &'static str, instance: usize) {
+		match self.msix_table() {
+			Some(msix_table) => {
+				msix_table.install_handler(0, 0, name, device_interrupt_handler, instance);
+			}
+			None => adapter.install_interrupt_handler(name, device_interrupt_handler, instance),
 		}
 	}
+
+	/// Handles an interrupt of the device. Returns true, if tasks have been woken up.
+	fn handle_interrupt(&self) -> bool {
+		false
+	}
+
+	/// Stops the device, before the system is shut down.
+	fn shutdown(&self) {}
+}
+
+/// Device, which is driven by an instance of a driver
+struct PciInstance {
+	name: String,
+	driver: &'static dyn PciDriver,
+	device: Box<dyn PciDevice>,
 }
-pub fn register_driver(drv: PciDriver<'static>) {
+
+/// Interrupt handler of a device, whose context is the number of the instance.
+pub fn device_interrupt_handler(instance: usize) -> bool {
+	unsafe { PCI_DEVICES.get(instance) }
+		.map_or(false, |instance| instance.device.handle_interrupt())
+}
+
+pub fn register_driver(driver: &'static dyn PciDriver) {
 	unsafe {
-		PCI_DRIVERS.push(drv);
+		PCI_DRIVERS.push(driver);
 	}
 }
 
-pub fn get_network_driver() -> Option<&'static SpinlockIrqSave<VirtioNetDriver<'static>>> {
-	unsafe { PCI_DRIVERS.iter().find_map(|drv| drv.get_network_driver()) }
+/// Returns the device `instance`, if it is driven by a driver of type `T`.
+/// The number of an instance is passed to its interrupt handlers.
+pub fn get_device_instance<T: Any>(instance: usize) -> Option<&'static T> {
+	unsafe { PCI_DEVICES.get(instance) }?
+		.device
+		.as_any()
+		.downcast_ref::<T>()
 }
 
-pub fn get_filesystem_driver() -> Option<&'static SpinlockIrqSave<VirtioFsDriver<'static>>> {
-	unsafe {
-		PCI_DRIVERS
-			.iter()
-			.find_map(|drv| drv.get_filesystem_driver())
+/// Returns the `index`-th device, which is driven by a driver of type `T`.
+pub fn get_device<T: Any>(index: usize) -> Option<&'static T> {
+	unsafe { PCI_DEVICES.iter() }
+		.filter_map(|instance| instance.device.as_any().downcast_ref::<T>())
+		.nth(index)
+}
+
+/// Returns the device with the instance name `name` (e.g. "virtio-net1").
+pub fn get_device_by_name<T: Any>(name: &str) -> Option<&'static T> {
+	unsafe { PCI_DEVICES.iter() }
+		.find(|instance| instance.name == name)?
+		.device
+		.as_any()
+		.downcast_ref::<T>()
+}
+
+/// Returns the instance names of all devices, which have a driver.
+pub fn get_device_names() -> impl Iterator<Item = &'static str> {
+	unsafe { PCI_DEVICES.iter() }.map(|instance| instance.name.as_str())
+}
+
+/// Returns the network driver, which is selected by the -nic command-line parameter, or the
+/// first one. In contrast to the other drivers, it is not protected by a global lock,
+/// because every queue pair of the device is locked separately.
+pub fn get_network_driver() -> Option<&'static VirtioNetDriver<'static>> {
+	match environment::get_command_line_nic() {
+		Some(name) => get_device_by_name(name),
+		None => get_device(0),
 	}
 }
 
+/// Returns the block driver `index`. Like the network driver, it is not protected
+/// by a lock, because it synchronizes its requests itself.
+pub fn get_block_driver(index: usize) -> Option<&'static VirtioBlkDriver<'static>> {
+	get_device(index)
+}
+
+/// Returns the first console driver, which synchronizes its ports itself.
+pub fn get_console_driver() -> Option<&'static VirtioConsoleDriver<'static>> {
+	get_device(0)
+}
+
+/// Returns the first entropy device, which feeds the entropy pool of the kernel.
+pub fn get_rng_driver() -> Option<&'static VirtioRngDriver<'static>> {
+	get_device(0)
+}
+
+/// Returns the first memory balloon, which is resized by its own thread.
+pub fn get_balloon_driver() -> Option<&'static VirtioBalloonDriver<'static>> {
+	get_device(0)
+}
+
+/// Returns the virtio-fs driver of the share `tag`.
+pub fn get_filesystem_driver(tag: &str) -> Option<&'static VirtioFsDriver<'static>> {
+	unsafe { PCI_DEVICES.iter() }
+		.filter_map(|instance| {
+			instance
+				.device
+				.as_any()
+				.downcast_ref::<VirtioFsDriver<'static>>()
+		})
+		.find(|driver| driver.tag() == tag)
+}
+
+/// Returns the first socket driver, which synchronizes its connections itself.
+#[cfg(not(feature = "newlib"))]
+pub fn get_vsock_driver() -> Option<&'static VirtioVsockDriver<'static>> {
+	get_device(0)
+}
+
 /// Reads all bar registers of specified device and returns vector of PciBar's containing addresses and sizes.
-fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar> {
+fn parse_bars(bus: u8, devfn: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar> {
 	let mut bar_idxs = 0..6;
 	let mut bars = Vec::new();
 	while let Some(i) = bar_idxs.next() {
 		let register = PCI_BAR0_REGISTER + ((i as u32) << 2);
-		let barword = read_config(bus, device, register);
+		let barword = read_config(bus, devfn, register);
 		debug!(
 			"Found bar{} @{:x}:{:x} as 0x{:x}",
 			i, vendor_id, device_id, barword
@@ -188,10 +373,10 @@ fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar
 			let base_addr = barword & PCI_IO_BASE_ADDRESS_MASK;
 
 			// determine size by writing 0xFFFFFFFF
-			write_config(bus, device, register, u32::MAX);
-			let sizebits = read_config(bus, device, register);
+			write_config(bus, devfn, register, u32::MAX);
+			let sizebits = read_config(bus, devfn, register);
 			// Restore original value of register
-			write_config(bus, device, register, barword);
+			write_config(bus, devfn, register, barword);
 			let size = (!(sizebits & PCI_IO_BASE_ADDRESS_MASK) + 1) as usize;
 
 			bars.push(PciBar::IO(IOBar {
@@ -206,7 +391,7 @@ fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar
 			if barword & PCI_MEM_BASE_ADDRESS_64BIT != 0 {
 				// 64-bit, load additional bar-word
 				let register_high = PCI_BAR0_REGISTER + (bar_idxs.next().unwrap() << 2);
-				let barword_high = read_config(bus, device, register_high);
+				let barword_high = read_config(bus, devfn, register_high);
 
 				let base_addr = ((barword_high as usize) << 32) + (barword & 0xFFFF_FFF0) as usize;
 				debug!(
@@ -215,15 +400,15 @@ fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar
 				);
 
 				// determine size by writing 0xFFFFFFFF
-				write_config(bus, device, register, u32::MAX);
-				let sizebits = read_config(bus, device, register);
+				write_config(bus, devfn, register, u32::MAX);
+				let sizebits = read_config(bus, devfn, register);
 
 				// Also read/write to register_high if needed
 				let size = if sizebits == 0 {
-					write_config(bus, device, register_high, u32::MAX);
-					let sizebits = read_config(bus, device, register_high);
+					write_config(bus, devfn, register_high, u32::MAX);
+					let sizebits = read_config(bus, devfn, register_high);
 					// Restore original value of register_high
-					write_config(bus, device, register_high, barword);
+					write_config(bus, devfn, register_high, barword);
 
 					((!sizebits + 1) as usize) << 32
 				} else {
@@ -231,7 +416,7 @@ fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar
 				};
 
 				// Restore original value
-				write_config(bus, device, register, barword);
+				write_config(bus, devfn, register, barword);
 
 				bars.push(PciBar::Memory(MemoryBar {
 					index: i as u8,
@@ -245,11 +430,11 @@ fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar
 				let base_addr = (barword & 0xFFFF_FFF0) as usize;
 
 				// determine size by writing 0xFFFFFFFF
-				write_config(bus, device, register, u32::MAX);
-				let size = !(read_config(bus, device, register) & PCI_MEM_BASE_ADDRESS_MASK) + 1;
+				write_config(bus, devfn, register, u32::MAX);
+				let size = !(read_config(bus, devfn, register) & PCI_MEM_BASE_ADDRESS_MASK) + 1;
 
 				// Restore original value
-				write_config(bus, device, register, barword);
+				write_config(bus, devfn, register, barword);
 
 				bars.push(PciBar::Memory(MemoryBar {
 					index: i as u8,
@@ -266,29 +451,23 @@ fn parse_bars(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Vec<PciBar
 }
 
 impl PciAdapter {
-	fn new(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Option<Self> {
-		let header = read_config(bus, device, PCI_HEADER_REGISTER);
-		if header & PCI_HEADER_TYPE_MASK != 0 {
+	fn new(bus: u8, devfn: u8, vendor_id: u16, device_id: u16) -> Option<Self> {
+		let header = read_config(bus, devfn, PCI_HEADER_REGISTER);
+		if header & PCI_HEADER_TYPE_MASK != PCI_HEADER_TYPE_NORMAL {
 			error!(
 				"PCI Device @{:x}:{:x} does not have header type 0!",
 				vendor_id, device_id
 			);
 			return None;
 		}
-		if header & PCI_MULTIFUNCTION_MASK != 0 {
-			warn!(
-				"PCI Device @{:x}:{:x} has multiple functions! Currently only one is handled.",
-				vendor_id, device_id
-			);
-		}
 
-		let class_ids = read_config(bus, device, PCI_CLASS_REGISTER);
-		let bars = parse_bars(bus, device, vendor_id, device_id);
-		let interrupt_info = read_config(bus, device, PCI_INTERRUPT_REGISTER);
+		let class_ids = read_config(bus, devfn, PCI_CLASS_REGISTER);
+		let bars = parse_bars(bus, devfn, vendor_id, device_id);
+		let interrupt_info = read_config(bus, devfn, PCI_INTERRUPT_REGISTER);
 
 		Some(Self {
 			bus,
-			device,
+			devfn,
 			vendor_id,
 			device_id,
 			class_id: (class_ids >> 24) as u8,
@@ -300,9 +479,9 @@ impl PciAdapter {
 	}
 
 	pub fn make_bus_master(&self) {
-		let mut command = read_config(self.bus, self.device, PCI_COMMAND_REGISTER);
+		let mut command = read_config(self.bus, self.devfn, PCI_COMMAND_REGISTER);
 		command |= PCI_COMMAND_BUSMASTER;
-		write_config(self.bus, self.device, PCI_COMMAND_REGISTER, command);
+		write_config(self.bus, self.devfn, PCI_COMMAND_REGISTER, command);
 	}
 
 	/// Returns the bar at bar-register baridx.
@@ -367,6 +546,265 @@ impl PciAdapter {
 
 		Some((virtual_address, pci_bar.size))
 	}
+
+	/// Returns an iterator over the capability list of the device.
+	pub fn capabilities(&self) -> PciCapabilities {
+		let status = read_config(self.bus, self.devfn, PCI_COMMAND_REGISTER) >> 16;
+		let offset = if status & PCI_STATUS_CAPABILITIES_LIST != 0 {
+			read_config(self.bus, self.devfn, PCI_CAPABILITY_LIST_REGISTER) & 0xFC
+		} else {
+			0
+		};
+
+		PciCapabilities {
+			bus: self.bus,
+			devfn: self.devfn,
+			offset,
+			remaining: PCI_MAX_CAPABILITIES,
+		}
+	}
+
+	/// Returns the config space offset of the first capability with the given id.
+	pub fn find_capability(&self, cap_id: u32) -> Option<u32> {
+		self.capabilities()
+			.find(|(id, _)| *id == cap_id)
+			.map(|(_, offset)| offset)
+	}
+
+	/// Enables MSI and sends the single message of the device to the interrupt `irq_number`
+	/// of the core with the given Local APIC ID. Returns false, if the device does not support MSI.
+	pub fn enable_msi(&self, apic_id: u8, irq_number: u32) -> bool {
+		let offset = match self.find_capability(PCI_CAP_ID_MSI) {
+			Some(offset) => offset,
+			None => return false,
+		};
+		let control = read_config(self.bus, self.devfn, offset);
+
+		write_config(
+			self.bus,
+			self.devfn,
+			offset + 4,
+			MSI_ADDRESS_BASE | (u32::from(apic_id) << MSI_ADDRESS_DESTINATION_SHIFT),
+		);
+		let data_offset = if control & PCI_MSI_64BIT_CAPABLE != 0 {
+			write_config(self.bus, self.devfn, offset + 8, 0);
+			offset + 12
+		} else {
+			offset + 8
+		};
+		// the upper half of the data register is reserved or belongs to the mask bits
+		let data = read_config(self.bus, self.devfn, data_offset);
+		write_config(
+			self.bus,
+			self.devfn,
+			data_offset,
+			(data & 0xFFFF_0000) | (32 + irq_number),
+		);
+
+		// enabling MSI implicitly disables the legacy interrupt pin
+		write_config(
+			self.bus,
+			self.devfn,
+			offset,
+			(control & !PCI_MSI_MULTIPLE_MESSAGE_ENABLE_MASK) | PCI_MSI_ENABLE,
+		);
+
+		info!(
+			"Enabled MSI for device {:x}:{:x} with interrupt {}",
+			self.vendor_id, self.device_id, irq_number
+		);
+
+		true
+	}
+
+	/// Routes the interrupt of the device to `handler`, which is called with `context`.
+	/// MSI is preferred, because the legacy interrupt line may be shared with other devices.
+	pub fn install_interrupt_handler(
+		&self,
+		name: &'static str,
+		handler: DeviceInterruptHandler,
+		context: usize,
+	) {
+		let boot_apic_id = apic::get_local_apic_id(0);
+		if let Some(apic_id) = self.find_capability(PCI_CAP_ID_MSI).and(boot_apic_id) {
+			if let Some(irq_number) = allocate_irq() {
+				// the handler has to exist, before the device sends its first message
+				add_device_handler(irq_number, name, handler, context);
+				self.enable_msi(apic_id, irq_number);
+				return;
+			}
+		}
+
+		add_device_handler(u32::from(self.irq), name, handler, context);
+	}
+
+	/// Maps the MSI-X table of the device and enables MSI-X.
+	/// All entries are masked until they are programmed by `MsixTable::set_entry`.
+	/// Returns None if the device does not support MSI-X.
+	pub fn enable_msix(&self) -> Option<MsixTable> {
+		let offset = self.find_capability(PCI_CAP_ID_MSIX)?;
+		let control = read_config(self.bus, self.devfn, offset);
+		let size = (((control >> 16) & PCI_MSIX_TABLE_SIZE_MASK) + 1) as usize;
+		let table = read_config(self.bus, self.devfn, offset + 4);
+		let pci_bar = match self.get_bar((table & PCI_MSIX_BIR_MASK) as u8) {
+			Some(PciBar::Memory(mem_bar)) => mem_bar,
+			_ => {
+				error!("MSI-X table is not located in a memory bar!");
+				return None;
+			}
+		};
+		let table_offset = (table & !PCI_MSIX_BIR_MASK) as usize;
+
+		if table_offset + size * mem::size_of::<MsixTableEntry>() > pci_bar.size {
+			error!("MSI-X table does not fit in bar!");
+			return None;
+		}
+
+		// The MSI-X table is usually located in a 32 bit, non-prefetchable bar, which is not
+		// supported by `memory_map_bar`. Therefore, the bar is mapped uncached in here.
+		let bar_addr = crate::mm::map(
+			PhysAddr::from(pci_bar.addr),
+			pci_bar.size,
+			true,
+			false,
+			true,
+		);
+		let msix = MsixTable {
+			entries: bar_addr + table_offset,
+			size,
+		};
+		for entry in 0..size {
+			msix.mask_entry(entry);
+		}
+
+		// enabling MSI-X implicitly disables the legacy interrupt pin
+		write_config(
+			self.bus,
+			self.devfn,
+			offset,
+			(control | PCI_MSIX_ENABLE) & !PCI_MSIX_FUNCTION_MASK,
+		);
+
+		info!(
+			"Enabled MSI-X for device {:x}:{:x} with {} vectors",
+			self.vendor_id, self.device_id, size
+		);
+
+		Some(msix)
+	}
+}
+
+/// Iterator over the capability list of a device, which returns the
+/// id and the config space offset of every capability
+pub struct PciCapabilities {
+	bus: u8,
+	devfn: u8,
+	offset: u32,
+	remaining: usize,
+}
+
+impl Iterator for PciCapabilities {
+	type Item = (u32, u32);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.offset < PCI_CAPABILITIES_START || self.remaining == 0 {
+			return None;
+		}
+
+		let offset = self.offset;
+		let capword = read_config(self.bus, self.devfn, offset);
+		self.offset = (capword >> 8) & 0xFC;
+		self.remaining -= 1;
+
+		Some((capword & 0xFF, offset))
+	}
+}
+
+/// Entry of the MSI-X table (see PCI Local Bus Specification 3.0, 6.8.2)
+#[repr(C)]
+struct MsixTableEntry {
+	address_low: u32,
+	address_high: u32,
+	data: u32,
+	vector_control: u32,
+}
+
+/// Memory mapped MSI-X table of a device
+#[derive(Debug)]
+pub struct MsixTable {
+	entries: VirtAddr,
+	size: usize,
+}
+
+impl MsixTable {
+	/// Returns the number of table entries
+	pub fn len(&self) -> usize {
+		self.size
+	}
+
+	fn entry(&self, entry: usize) -> *mut MsixTableEntry {
+		assert!(entry < self.size, "Invalid MSI-X table entry {}", entry);
+		unsafe { self.entries.as_mut_ptr::<MsixTableEntry>().add(entry) }
+	}
+
+	/// Masks the table entry, so that the device does not send its message.
+	pub fn mask_entry(&self, entry: usize) {
+		let entry = self.entry(entry);
+		unsafe {
+			let control = ptr::read_volatile(&(*entry).vector_control);
+			ptr::write_volatile(
+				&mut (*entry).vector_control,
+				control | MSIX_ENTRY_VECTOR_MASKED,
+			);
+		}
+	}
+
+	/// Allocates an interrupt for the table entry, which is handled by `handler` on the core `core_id`.
+	/// Returns false, if no interrupt is available.
+	pub fn install_handler(
+		&self,
+		entry: usize,
+		core_id: CoreId,
+		name: &'static str,
+		handler: DeviceInterruptHandler,
+		context: usize,
+	) -> bool {
+		let apic_id = match apic::get_local_apic_id(core_id) {
+			Some(apic_id) => apic_id,
+			None => return false,
+		};
+		let irq_number = match allocate_irq() {
+			Some(irq_number) => irq_number,
+			None => {
+				error!("No interrupt available for MSI-X table entry {}", entry);
+				return false;
+			}
+		};
+
+		add_device_handler(irq_number, name, handler, context);
+		self.set_entry(entry, apic_id, (32 + irq_number) as u8);
+
+		true
+	}
+
+	/// Routes the table entry to the interrupt vector of the core with the given Local APIC ID
+	/// (fixed delivery mode, edge triggered) and unmasks it.
+	pub fn set_entry(&self, entry: usize, apic_id: u8, vector: u8) {
+		let entry = self.entry(entry);
+		unsafe {
+			ptr::write_volatile(
+				&mut (*entry).address_low,
+				MSI_ADDRESS_BASE | (u32::from(apic_id) << MSI_ADDRESS_DESTINATION_SHIFT),
+			);
+			ptr::write_volatile(&mut (*entry).address_high, 0);
+			ptr::write_volatile(&mut (*entry).data, u32::from(vector));
+			let control = ptr::read_volatile(&(*entry).vector_control);
+			ptr::write_volatile(
+				&mut (*entry).vector_control,
+				control & !MSIX_ENTRY_VECTOR_MASKED,
+			);
+		}
+	}
 }
 
 impl fmt::Display for PciBar {
@@ -419,9 +857,10 @@ impl fmt::Display for PciAdapter {
 		// Output detailed readable information about this device.
 		write!(
 			f,
-			"{:02X}:{:02X} {} [{:02X}{:02X}]: {} {} [{:04X}:{:04X}]",
+			"{:02X}:{:02X}.{} {} [{:02X}{:02X}]: {} {} [{:04X}:{:04X}]",
 			self.bus,
-			self.device,
+			self.devfn >> 3,
+			self.devfn & 0x7,
 			class_name,
 			self.class_id,
 			self.subclass_id,
@@ -444,18 +883,51 @@ impl fmt::Display for PciAdapter {
 	}
 }
 
-pub fn read_config(bus: u8, device: u8, register: u32) -> u32 {
+/// Returns the address of a register in the memory-mapped configuration space,
+/// if the bus is covered by ECAM.
+fn ecam_address(bus: u8, devfn: u8, register: u32) -> Option<VirtAddr> {
+	let ecam = unsafe { ECAM.as_ref()? };
+	if bus < ecam.start_bus || bus > ecam.end_bus {
+		return None;
+	}
+
+	let index = usize::from(bus - ecam.start_bus);
+	let offset = index * PCI_ECAM_BUS_SIZE + (usize::from(devfn) << 12) + register as usize;
+	Some(ecam.address + offset)
+}
+
+/// Reads a register of the configuration space. The extended configuration space
+/// above 256 bytes is only accessible by ECAM, otherwise all bits are set.
+pub fn read_config(bus: u8, devfn: u8, register: u32) -> u32 {
+	if let Some(address) = ecam_address(bus, devfn, register) {
+		return unsafe { ptr::read_volatile(address.as_ptr::<u32>()) };
+	}
+	if register >= PCI_CONFIG_SPACE_SIZE {
+		return u32::MAX;
+	}
+
 	let address =
-		PCI_CONFIG_ADDRESS_ENABLE | u32::from(bus) << 16 | u32::from(device) << 11 | register;
+		PCI_CONFIG_ADDRESS_ENABLE | u32::from(bus) << 16 | u32::from(devfn) << 8 | register;
 	unsafe {
 		outl(PCI_CONFIG_ADDRESS_PORT, address);
 		inl(PCI_CONFIG_DATA_PORT)
 	}
 }
 
-pub fn write_config(bus: u8, device: u8, register: u32, data: u32) {
+pub fn write_config(bus: u8, devfn: u8, register: u32, data: u32) {
+	if let Some(address) = ecam_address(bus, devfn, register) {
+		unsafe {
+			ptr::write_volatile(address.as_mut_ptr::<u32>(), data);
+		}
+		return;
+	}
+	if register >= PCI_CONFIG_SPACE_SIZE {
+		warn!("Extended configuration space is not accessible without ECAM");
+		return;
+	}
+
 	let address =
-		PCI_CONFIG_ADDRESS_ENABLE | u32::from(bus) << 16 | u32::from(device) << 11 | register;
+		PCI_CONFIG_ADDRESS_ENABLE | u32::from(bus) << 16 | u32::from(devfn) << 8 | register;
 	unsafe {
 		outl(PCI_CONFIG_ADDRESS_PORT, address);
 		outl(PCI_CONFIG_DATA_PORT, data);
@@ -472,43 +944,194 @@ pub fn get_adapter(vendor_id: u16, device_id: u16) -> Option<PciAdapter> {
 	None
 }
 
-pub fn init() {
-	debug!("Scanning PCI Busses 0 to {}", PCI_MAX_BUS_NUMBER - 1);
-
-	// HermitCore only uses PCI for network devices.
-	// Therefore, multifunction devices as well as additional bridges are not scanned.
-	// We also limit scanning to the first 32 buses.
-	for bus in 0..PCI_MAX_BUS_NUMBER {
-		for device in 0..PCI_MAX_DEVICE_NUMBER {
-			let device_vendor_id = read_config(bus, device, PCI_ID_REGISTER);
-			if device_vendor_id != u32::MAX {
-				let device_id = (device_vendor_id >> 16) as u16;
-				let vendor_id = device_vendor_id as u16;
-				let adapter = PciAdapter::new(bus, device, vendor_id, device_id);
-				if let Some(adapter) = adapter {
-					unsafe {
-						PCI_ADAPTERS.push(adapter);
-					}
+/// Uses the memory-mapped configuration space of the segment group 0, which
+/// is described by the MCFG table of ACPI.
+#[cfg(feature = "acpi")]
+fn init_ecam() {
+	for region in acpi::get_pci_config_regions() {
+		if region.segment_group != 0 {
+			warn!(
+				"PCI segment group {} is not supported, skipping!",
+				region.segment_group
+			);
+			continue;
+		}
+
+		info!(
+			"Using ECAM at 0x{:x} for PCI buses {} to {}",
+			region.base_address, region.start_bus, region.end_bus
+		);
+		// Map the configuration spaces of all buses at once. Hence, the accesses to the
+		// configuration space don't have to map them lazily while holding a lock.
+		let bus_count = usize::from(region.end_bus - region.start_bus) + 1;
+		let address = crate::mm::map(
+			PhysAddr(region.base_address),
+			bus_count * PCI_ECAM_BUS_SIZE,
+			true,
+			true,
+			true,
+		);
+		unsafe {
+			ECAM = Some(Ecam {
+				start_bus: region.start_bus,
+				end_bus: region.end_bus,
+				address,
+			});
+		}
+		break;
+	}
+}
+
+fn is_present(bus: u8, devfn: u8) -> bool {
+	read_config(bus, devfn, PCI_ID_REGISTER) & 0xFFFF != 0xFFFF
+}
+
+fn scan_bus(bus: u8) {
+	debug!("Scanning PCI bus {}", bus);
+
+	for device in 0..PCI_MAX_DEVICE_NUMBER {
+		let devfn = device << 3;
+		if !is_present(bus, devfn) {
+			continue;
+		}
+
+		scan_function(bus, devfn);
+		if read_config(bus, devfn, PCI_HEADER_REGISTER) & PCI_MULTIFUNCTION_MASK != 0 {
+			for function in 1..PCI_MAX_FUNCTION_NUMBER {
+				if is_present(bus, devfn | function) {
+					scan_function(bus, devfn | function);
 				}
 			}
 		}
 	}
 }
 
+fn scan_function(bus: u8, devfn: u8) {
+	let device_vendor_id = read_config(bus, devfn, PCI_ID_REGISTER);
+	let device_id = (device_vendor_id >> 16) as u16;
+	let vendor_id = device_vendor_id as u16;
+
+	match read_config(bus, devfn, PCI_HEADER_REGISTER) & PCI_HEADER_TYPE_MASK {
+		PCI_HEADER_TYPE_NORMAL => {
+			if let Some(adapter) = PciAdapter::new(bus, devfn, vendor_id, device_id) {
+				unsafe {
+					PCI_ADAPTERS.push(adapter);
+				}
+			}
+		}
+		PCI_HEADER_TYPE_BRIDGE => {
+			let secondary_bus = (read_config(bus, devfn, PCI_BRIDGE_BUS_REGISTER) >> 8) as u8;
+			debug!(
+				"Found PCI bridge @{:x}:{:x} to bus {}",
+				vendor_id, device_id, secondary_bus
+			);
+
+			// The firmware numbers the buses in depth-first order. Hence, a lower
+			// number indicates an unconfigured bridge, which would lead to a loop.
+			if secondary_bus > bus {
+				scan_bus(secondary_bus);
+			} else {
+				warn!(
+					"PCI bridge @{:x}:{:x} is not configured, skipping!",
+					vendor_id, device_id
+				);
+			}
+		}
+		header_type => {
+			debug!(
+				"Skipping PCI device @{:x}:{:x} with header type {}",
+				vendor_id,
+				device_id,
+				header_type >> 16
+			);
+		}
+	}
+}
+
+pub fn init() {
+	#[cfg(feature = "acpi")]
+	init_ecam();
+
+	// Every function of a multifunction host bridge is a separate host
+	// controller, which is responsible for the bus with the function number.
+	if read_config(0, 0, PCI_HEADER_REGISTER) & PCI_MULTIFUNCTION_MASK == 0 {
+		scan_bus(0);
+	} else {
+		for function in 0..PCI_MAX_FUNCTION_NUMBER {
+			if is_present(0, function) {
+				scan_bus(function);
+			}
+		}
+	}
+}
+
+fn probe_device(driver: &'static dyn PciDriver, adapter: &PciAdapter) {
+	let number = unsafe { PCI_DEVICES.iter() }
+		.filter(|instance| instance.driver.name() == driver.name())
+		.count();
+	let name = format!("{}{}", driver.name(), number);
+	info!("Probing {} as {}", adapter, name);
+
+	let device = match driver.probe(adapter) {
+		Some(device) => device,
+		None => {
+			error!("Unable to initialize {}", name);
+			return;
+		}
+	};
+
+	let instance = unsafe { PCI_DEVICES.len() };
+	unsafe {
+		PCI_DEVICES.push(PciInstance {
+			name,
+			driver,
+			device,
+		});
+	}
+
+	// the handlers find the device in the list of instances
+	unsafe { &PCI_DEVICES[instance] }
+		.device
+		.install_interrupt_handler(adapter, driver.name(), instance);
+}
+
 pub fn init_drivers() {
-	// virtio: 4.1.2 PCI Device Discovery
+	virtio::register_drivers();
+
+	// interrupt handlers access the list of instances, which must therefore not be reallocated
+	unsafe {
+		PCI_DEVICES.reserve(PCI_ADAPTERS.len());
+	}
+
 	for adapter in unsafe { PCI_ADAPTERS.iter() } {
-		if adapter.vendor_id == 0x1AF4 && adapter.device_id >= 0x1000 && adapter.device_id <= 0x107F
-		{
-			info!(
-				"Found virtio device with device id 0x{:x}",
-				adapter.device_id
+		let driver = unsafe { PCI_DRIVERS.iter() }
+			.find(|driver| driver.id_table().iter().any(|id| id.matches(adapter)));
+		match driver {
+			Some(driver) => probe_device(*driver, adapter),
+			None => debug!("No driver for {}", adapter),
+		}
+	}
+
+	if let Some(name) = environment::get_command_line_nic() {
+		if get_network_driver().is_none() {
+			let names: Vec<&str> = get_device_names().collect();
+			error!(
+				"Network device {} not found, available devices: {}",
+				name,
+				names.join(", ")
 			);
-			virtio::init_virtio_device(adapter);
 		}
 	}
 }
 
+/// Stops all devices in the reverse order of their initialization.
+pub fn shutdown_devices() {
+	for instance in unsafe { PCI_DEVICES.iter().rev() } {
+		debug!("Shutting down {}", instance.name);
+		instance.device.shutdown();
+	}
+}
+
 pub fn print_information() {
 	infoheader!(" PCI BUS INFORMATION ");
 
@@ -6,6 +6,7 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::arch::x86_64::kernel::irq::IrqStatistics;
+use crate::arch::x86_64::kernel::kvmclock::PvclockVcpuTimeInfo;
 use crate::arch::x86_64::kernel::BOOT_INFO;
 use crate::collections::CachePadded;
 use crate::scheduler::{CoreId, PerCoreScheduler};
@@ -28,6 +29,8 @@ pub struct PerCoreInnerVariables {
 	pub kernel_stack: PerCoreVariable<u64>,
 	/// Interface to the interrupt counters
 	pub irq_statistics: PerCoreVariable<*mut IrqStatistics>,
+	/// Time information of kvmclock for this CPU Core
+	pub pvclock: PerCoreVariable<*const PvclockVcpuTimeInfo>,
 }
 
 impl PerCoreInnerVariables {
@@ -38,6 +41,7 @@ impl PerCoreInnerVariables {
 			tss: PerCoreVariable::new(ptr::null_mut() as *mut TaskStateSegment),
 			kernel_stack: PerCoreVariable::new(0),
 			irq_statistics: PerCoreVariable::new(ptr::null_mut() as *mut IrqStatistics),
+			pvclock: PerCoreVariable::new(ptr::null() as *const PvclockVcpuTimeInfo),
 		}
 	}
 }
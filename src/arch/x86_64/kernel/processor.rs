@@ -10,16 +10,19 @@
 
 #[cfg(feature = "acpi")]
 use crate::arch::x86_64::kernel::acpi;
-use crate::arch::x86_64::kernel::{idt, irq, pic, pit, BOOT_INFO};
+use crate::arch::x86_64::kernel::{idt, irq, kvmclock, pic, pit, BOOT_INFO};
 use crate::environment;
+use crate::scheduler::CoreId;
+use crate::synch::spinlock::Spinlock;
 use crate::x86::controlregs::*;
 use crate::x86::cpuid::*;
 use crate::x86::msr::*;
 use core::arch::x86_64::__rdtscp as rdtscp;
 use core::arch::x86_64::_rdtsc as rdtsc;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
 use core::convert::TryInto;
-use core::sync::atomic::spin_loop_hint;
-use core::{fmt, u32};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::{cmp, fmt, u32};
 
 const IA32_MISC_ENABLE_ENHANCED_SPEEDSTEP: u64 = 1 << 16;
 const IA32_MISC_ENABLE_SPEEDSTEP_LOCK: u64 = 1 << 20;
@@ -35,6 +38,32 @@ const EFER_LMSLE: u64 = 1 << 13;
 const EFER_FFXSR: u64 = 1 << 14;
 const EFER_TCE: u64 = 1 << 15;
 
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Time (in microseconds) spent on checking the TSC synchronization of each application processor.
+const TSC_SYNC_CHECK_DURATION: u64 = 2_000;
+
+/// CPUID leaf describing the structured extended features (e.g. RDSEED in EBX)
+const CPUID_EXTENDED_FEATURES_LEAF: u32 = 0x7;
+const CPUID_EXTENDED_FEATURES_RDSEED: u32 = 1 << 18;
+/// Number of attempts to get a random seed, before RDSEED is considered exhausted
+const RDSEED_RETRIES: usize = 128;
+/// CPUID leaf describing the MONITOR/MWAIT features
+const CPUID_MWAIT_LEAF: u32 = 0x5;
+/// MWAIT can treat interrupts as break events, even if they are masked
+const CPUID_MWAIT_INTERRUPT_BREAK: u32 = 1 << 1;
+/// MWAIT extension to wake up on masked interrupts
+const MWAIT_ECX_INTERRUPT_BREAK: u32 = 1 << 0;
+/// CPUID leaf describing the thermal and power management features
+const CPUID_THERMAL_POWER_LEAF: u32 = 0x6;
+/// The local APIC timer keeps running in deep C-states (Always Running APIC Timer)
+const CPUID_THERMAL_POWER_ARAT: u32 = 1 << 2;
+/// Number of C-states (C1 to C7), which can be requested through MWAIT
+const MWAIT_MAX_CSTATES: usize = 7;
+/// Minimum expected idle time (in microseconds) to make entering C1 to C7 worthwhile.
+/// Deeper C-states save more power, but have a higher exit latency and flush more caches.
+const MWAIT_TARGET_RESIDENCY: [u64; MWAIT_MAX_CSTATES] = [1, 20, 100, 200, 400, 600, 800];
+
 static mut CPU_FREQUENCY: CpuFrequency = CpuFrequency::new();
 static mut CPU_SPEEDSTEP: CpuSpeedStep = CpuSpeedStep::new();
 static mut PHYSICAL_ADDRESS_BITS: u8 = 0;
@@ -43,12 +72,36 @@ static mut MEASUREMENT_TIMER_TICKS: u64 = 0;
 static mut SUPPORTS_1GIB_PAGES: bool = false;
 static mut SUPPORTS_AVX: bool = false;
 static mut SUPPORTS_RDRAND: bool = false;
+static mut SUPPORTS_RDSEED: bool = false;
 static mut SUPPORTS_TSC_DEADLINE: bool = false;
 static mut SUPPORTS_X2APIC: bool = false;
 static mut SUPPORTS_XSAVE: bool = false;
 static mut SUPPORTS_FSGS: bool = false;
+static mut SUPPORTS_INVARIANT_TSC: bool = false;
+static mut SUPPORTS_MWAIT: bool = false;
+/// MWAIT hints for the C-states C1 to C7, if the processor supports them
+static mut MWAIT_HINTS: [Option<u32>; MWAIT_MAX_CSTATES] = [None; MWAIT_MAX_CSTATES];
+/// Cache line monitored by MWAIT.
+/// We only wake up through interrupts, so all cores may share it.
+static MWAIT_MONITOR_LINE: AtomicU64 = AtomicU64::new(0);
 static mut RUN_ON_HYPERVISOR: bool = false;
 static mut TIMESTAMP_FUNCTION: unsafe fn() -> u64 = get_timestamp_rdtsc;
+/// Conversion from Time-Stamp Counter cycles to nanoseconds
+static mut TSC_TO_NANOSECONDS: ClockConversion = ClockConversion::new();
+/// Conversion from nanoseconds to Time-Stamp Counter cycles (required for the TSC-Deadline mode)
+static mut NANOSECONDS_TO_TSC: ClockConversion = ClockConversion::new();
+
+/// Set to false if the TSC sync check has detected that the TSCs of different cores are not synchronized.
+static TSC_SYNCHRONIZED: AtomicBool = AtomicBool::new(true);
+/// Last value returned by get_timer_nanos, only maintained if the TSCs are not synchronized.
+static LAST_TIMER_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Rendezvous counters for the TSC sync check between the boot processor and an application processor.
+static TSC_SYNC_START: AtomicU32 = AtomicU32::new(0);
+static TSC_SYNC_STOP: AtomicU32 = AtomicU32::new(0);
+/// Last Time-Stamp Counter value read by any core participating in the TSC sync check.
+static TSC_SYNC_LAST: Spinlock<u64> = Spinlock::new(0);
+/// Largest backward step of the Time-Stamp Counter observed during the TSC sync check.
+static TSC_SYNC_MAX_WARP: AtomicU64 = AtomicU64::new(0);
 
 #[repr(C, align(16))]
 pub struct XSaveLegacyRegion {
@@ -230,6 +283,7 @@ impl fmt::Display for CpuFrequencySources {
 
 struct CpuFrequency {
 	mhz: u16,
+	hz: u64,
 	source: CpuFrequencySources,
 }
 
@@ -237,6 +291,7 @@ impl CpuFrequency {
 	const fn new() -> Self {
 		CpuFrequency {
 			mhz: 0,
+			hz: 0,
 			source: CpuFrequencySources::Invalid,
 		}
 	}
@@ -249,6 +304,7 @@ impl CpuFrequency {
 		//occur during runtime
 		if mhz > 0 {
 			self.mhz = mhz;
+			self.hz = u64::from(mhz) * 1_000_000;
 			self.source = source;
 			Ok(())
 		} else {
@@ -256,6 +312,18 @@ impl CpuFrequency {
 		}
 	}
 
+	/// Sets the frequency from a source, which provides it with a higher precision than MHz.
+	fn set_detected_tsc_frequency(
+		&mut self,
+		hz: u64,
+		source: CpuFrequencySources,
+	) -> Result<(), ()> {
+		let mhz: u16 = (hz / 1_000_000).try_into().map_err(|_| ())?;
+		self.set_detected_cpu_frequency(mhz, source)?;
+		self.hz = hz;
+		Ok(())
+	}
+
 	unsafe fn detect_from_cmdline(&mut self) -> Result<(), ()> {
 		let mhz = environment::get_command_line_cpu_frequency();
 		self.set_detected_cpu_frequency(mhz, CpuFrequencySources::CommandLine)
@@ -276,17 +344,14 @@ impl CpuFrequency {
 	unsafe fn detect_from_cpuid_tsc_info(&mut self, cpuid: &CpuId) -> Result<(), ()> {
 		let tsc_info = cpuid.get_tsc_info().ok_or(())?;
 		let freq = tsc_info.tsc_frequency().ok_or(())?;
-		let mhz = (freq / 1000000u64) as u16;
-		self.set_detected_cpu_frequency(mhz, CpuFrequencySources::CpuIdTscInfo)
+		self.set_detected_tsc_frequency(freq, CpuFrequencySources::CpuIdTscInfo)
 	}
 
 	unsafe fn detect_from_cpuid_hypervisor_info(&mut self, cpuid: &CpuId) -> Result<(), ()> {
 		const KHZ_TO_HZ: u64 = 1000;
-		const MHZ_TO_HZ: u64 = 1000000;
 		let hypervisor_info = cpuid.get_hypervisor_info().ok_or(())?;
 		let freq = hypervisor_info.tsc_frequency().ok_or(())? as u64 * KHZ_TO_HZ;
-		let mhz: u16 = (freq / MHZ_TO_HZ).try_into().unwrap();
-		self.set_detected_cpu_frequency(mhz, CpuFrequencySources::HypervisorTscInfo)
+		self.set_detected_tsc_frequency(freq, CpuFrequencySources::HypervisorTscInfo)
 	}
 
 	unsafe fn detect_from_cpuid_brand_string(&mut self, cpuid: &CpuId) -> Result<(), ()> {
@@ -416,8 +481,8 @@ impl CpuFrequency {
 
 		// Calculate the CPU frequency out of this measurement.
 		let cycle_count = end - start;
-		let mhz = (measurement_frequency * cycle_count / (1_000_000 * tick_count)) as u16;
-		self.set_detected_cpu_frequency(mhz, CpuFrequencySources::Measurement)
+		let hz = measurement_frequency * cycle_count / tick_count;
+		self.set_detected_tsc_frequency(hz, CpuFrequencySources::Measurement)
 	}
 
 	unsafe fn detect(&mut self) {
@@ -435,6 +500,55 @@ impl CpuFrequency {
 	fn get(&self) -> u16 {
 		self.mhz
 	}
+
+	fn get_hz(&self) -> u64 {
+		self.hz
+	}
+}
+
+/// Fixed-point factors to convert between two clocks without a division,
+/// computed as `(value * mult) >> shift`.
+#[derive(Clone, Copy)]
+struct ClockConversion {
+	mult: u64,
+	shift: u32,
+}
+
+impl ClockConversion {
+	const fn new() -> Self {
+		Self { mult: 0, shift: 0 }
+	}
+
+	/// Determine the factors to convert a clock running at `from` Hz into a clock running at `to` Hz.
+	/// Returns `None`, if one of the frequencies is zero.
+	///
+	/// Similar to Linux' clocks_calc_mult_shift(), the largest shift is chosen, for which the
+	/// multiplier still fits into 32 bits. This gives the best precision while keeping the
+	/// 128-bit intermediate product in `convert` far from overflowing.
+	fn calculate(from: u64, to: u64) -> Option<Self> {
+		if from == 0 || to == 0 {
+			return None;
+		}
+
+		let mut shift = 32;
+
+		loop {
+			let mult = ((u128::from(to) << shift) + u128::from(from / 2)) / u128::from(from);
+			if mult <= u128::from(u32::MAX) || shift == 0 {
+				return Some(Self {
+					mult: mult as u64,
+					shift,
+				});
+			}
+
+			shift -= 1;
+		}
+	}
+
+	#[inline]
+	fn convert(&self, value: u64) -> u64 {
+		((u128::from(value) * u128::from(self.mult)) >> self.shift) as u64
+	}
 }
 
 impl fmt::Display for CpuFrequency {
@@ -500,6 +614,9 @@ impl fmt::Display for CpuFeaturePrinter {
 		if self.feature_info.has_rdrand() {
 			write!(f, "RDRAND ")?;
 		}
+		if supports_rdseed() {
+			write!(f, "RDSEED ")?;
+		}
 		if self.feature_info.has_fma() {
 			write!(f, "FMA ")?;
 		}
@@ -731,20 +848,58 @@ pub fn detect_features() {
 		SUPPORTS_1GIB_PAGES = extended_function_info.has_1gib_pages();
 		SUPPORTS_AVX = feature_info.has_avx();
 		SUPPORTS_RDRAND = feature_info.has_rdrand();
+		SUPPORTS_RDSEED = __cpuid_count(CPUID_EXTENDED_FEATURES_LEAF, 0).ebx
+			& CPUID_EXTENDED_FEATURES_RDSEED
+			!= 0;
 		SUPPORTS_TSC_DEADLINE = feature_info.has_tsc_deadline();
 		SUPPORTS_X2APIC = feature_info.has_x2apic();
 		SUPPORTS_XSAVE = feature_info.has_xsave();
 		RUN_ON_HYPERVISOR = feature_info.has_hypervisor();
 		SUPPORTS_FSGS = extended_feature_info.has_fsgsbase();
+		SUPPORTS_INVARIANT_TSC = extended_function_info.has_invariant_tsc();
 
 		if extended_function_info.has_rdtscp() {
 			TIMESTAMP_FUNCTION = get_timestamp_rdtscp;
 		}
 
+		if feature_info.has_monitor_mwait() {
+			detect_mwait_cstates();
+		}
+
 		CPU_SPEEDSTEP.detect_features(&cpuid);
 	}
 }
 
+/// Determine the C-states, which can be entered through MWAIT.
+unsafe fn detect_mwait_cstates() {
+	let mwait_info = __cpuid(CPUID_MWAIT_LEAF);
+
+	// We enter the idle state with disabled interrupts to not miss a wakeup.
+	// Without the interrupt break extension, MWAIT would not return in this case.
+	if mwait_info.ecx & CPUID_MWAIT_INTERRUPT_BREAK == 0 {
+		return;
+	}
+
+	// EDX holds the number of sub C-states for each C-state in 4 bits, starting with C0.
+	// The MWAIT hint encodes the target C-state minus one in bits 4 to 7.
+	for (i, hint) in MWAIT_HINTS.iter_mut().enumerate() {
+		let substates = (mwait_info.edx >> (4 * (i + 1))) & 0xF;
+		if substates > 0 {
+			*hint = Some((i as u32) << 4);
+			SUPPORTS_MWAIT = true;
+		}
+	}
+
+	// Without ARAT, the local APIC timer stops in C-states deeper than C1 and
+	// the wakeup of a timed sleep would be lost. Therefore, only use C1 in this case.
+	if __cpuid(CPUID_THERMAL_POWER_LEAF).eax & CPUID_THERMAL_POWER_ARAT == 0 {
+		for hint in MWAIT_HINTS.iter_mut().skip(1) {
+			*hint = None;
+		}
+		SUPPORTS_MWAIT = MWAIT_HINTS[0].is_some();
+	}
+}
+
 pub fn configure() {
 	// setup MSR EFER
 	unsafe {
@@ -832,6 +987,99 @@ pub fn configure() {
 pub fn detect_frequency() {
 	unsafe {
 		CPU_FREQUENCY.detect();
+
+		// Our monotonic clock is based on the Time-Stamp Counter.
+		// Precompute the factors to convert TSC cycles to nanoseconds and back.
+		// A frequency of 0 Hz would lead to a division by zero, so the previous calibration is kept.
+		let hz = CPU_FREQUENCY.get_hz();
+		match (
+			ClockConversion::calculate(hz, NANOSECONDS_PER_SECOND),
+			ClockConversion::calculate(NANOSECONDS_PER_SECOND, hz),
+		) {
+			(Some(tsc_to_nanoseconds), Some(nanoseconds_to_tsc)) => {
+				TSC_TO_NANOSECONDS = tsc_to_nanoseconds;
+				NANOSECONDS_TO_TSC = nanoseconds_to_tsc;
+			}
+			_ => warn!("Ignoring a TSC frequency of 0 Hz, keeping the previous calibration"),
+		}
+	}
+
+	if !supports_invariant_tsc() {
+		warn!("The Time-Stamp Counter is not invariant, the system time may drift with changes of the CPU frequency");
+	}
+}
+
+/// Check the synchronization of the Time-Stamp Counters by letting two cores read the TSC alternately
+/// and detecting whether a core observes a value smaller than the last one read by the other core.
+fn check_tsc_warp() {
+	let end = get_timestamp() + u64::from(get_frequency()) * TSC_SYNC_CHECK_DURATION;
+
+	loop {
+		let (prev, now) = {
+			let mut last = TSC_SYNC_LAST.lock();
+			let prev = *last;
+			let now = get_timestamp();
+			*last = now;
+			(prev, now)
+		};
+
+		if prev > now {
+			TSC_SYNC_MAX_WARP.fetch_max(prev - now, Ordering::SeqCst);
+		}
+
+		if now > end {
+			break;
+		}
+	}
+}
+
+/// Called by the boot processor after an application processor has been booted to check the
+/// TSC synchronization between both cores. The application processor has to call synchronize_tsc_target
+/// at the same time.
+pub fn synchronize_tsc_source(target_core_id: CoreId) {
+	TSC_SYNC_START.fetch_add(1, Ordering::SeqCst);
+	while TSC_SYNC_START.load(Ordering::SeqCst) != 2 {
+		spin_loop_hint();
+	}
+
+	check_tsc_warp();
+
+	TSC_SYNC_STOP.fetch_add(1, Ordering::SeqCst);
+	while TSC_SYNC_STOP.load(Ordering::SeqCst) != 2 {
+		spin_loop_hint();
+	}
+
+	let warp = TSC_SYNC_MAX_WARP.swap(0, Ordering::SeqCst);
+	if warp > 0 {
+		warn!(
+			"Time-Stamp Counter of core {} is not synchronized (warp of {} cycles), enforcing monotonicity of the system clock",
+			target_core_id, warp
+		);
+		TSC_SYNCHRONIZED.store(false, Ordering::SeqCst);
+	} else {
+		debug!(
+			"Time-Stamp Counter of core {} is synchronized with the boot processor",
+			target_core_id
+		);
+	}
+
+	// Release the application processor.
+	TSC_SYNC_START.store(0, Ordering::SeqCst);
+	TSC_SYNC_STOP.store(0, Ordering::SeqCst);
+}
+
+/// Counterpart of synchronize_tsc_source, which is called by the booted application processor.
+pub fn synchronize_tsc_target() {
+	TSC_SYNC_START.fetch_add(1, Ordering::SeqCst);
+	while TSC_SYNC_START.load(Ordering::SeqCst) != 2 {
+		spin_loop_hint();
+	}
+
+	check_tsc_warp();
+
+	TSC_SYNC_STOP.fetch_add(1, Ordering::SeqCst);
+	while TSC_SYNC_STOP.load(Ordering::SeqCst) != 0 {
+		spin_loop_hint();
 	}
 }
 
@@ -865,6 +1113,20 @@ pub fn print_information() {
 		"Supports 1GiB Pages",
 		if supports_1gib_pages() { "Yes" } else { "No" }
 	);
+	infoentry!(
+		"Invariant TSC",
+		if supports_invariant_tsc() {
+			"Yes"
+		} else {
+			"No"
+		}
+	);
+	infoentry!("Timer Resolution", "{} ns", get_timer_resolution());
+	if let Some(i) = unsafe { MWAIT_HINTS.iter().rposition(Option::is_some) } {
+		infoentry!("Deepest MWAIT C-state", "C{}", i + 1);
+	} else {
+		infoentry!("Deepest MWAIT C-state", "None");
+	}
 	infofooter!();
 }
 
@@ -882,7 +1144,7 @@ pub fn generate_random_number32() -> Option<u32> {
 		if SUPPORTS_RDRAND {
 			let mut value: u32 = 0;
 
-			while core::arch::x86_64::_rdrand32_step(&mut value) == 1 {
+			while core::arch::x86_64::_rdrand32_step(&mut value) != 1 {
 				spin_loop_hint();
 			}
 
@@ -898,7 +1160,7 @@ pub fn generate_random_number64() -> Option<u64> {
 		if SUPPORTS_RDRAND {
 			let mut value: u64 = 0;
 
-			while core::arch::x86_64::_rdrand64_step(&mut value) == 1 {
+			while core::arch::x86_64::_rdrand64_step(&mut value) != 1 {
 				spin_loop_hint();
 			}
 
@@ -909,6 +1171,24 @@ pub fn generate_random_number64() -> Option<u64> {
 	}
 }
 
+/// Returns a seed of the processor's entropy source (RDSEED). In contrast to RDRAND,
+/// RDSEED may be exhausted, so `None` is also returned after some failed attempts.
+pub fn generate_random_seed64() -> Option<u64> {
+	if !supports_rdseed() {
+		return None;
+	}
+
+	let mut value: u64 = 0;
+	for _ in 0..RDSEED_RETRIES {
+		if unsafe { core::arch::x86_64::_rdseed64_step(&mut value) } == 1 {
+			return Some(value);
+		}
+		spin_loop_hint();
+	}
+
+	None
+}
+
 #[inline]
 pub fn get_linear_address_bits() -> u8 {
 	unsafe { LINEAR_ADDRESS_BITS }
@@ -924,6 +1204,11 @@ pub fn supports_1gib_pages() -> bool {
 	unsafe { SUPPORTS_1GIB_PAGES }
 }
 
+#[inline]
+pub fn supports_rdseed() -> bool {
+	unsafe { SUPPORTS_RDSEED }
+}
+
 #[inline]
 pub fn supports_avx() -> bool {
 	unsafe { SUPPORTS_AVX }
@@ -934,6 +1219,16 @@ pub fn supports_tsc_deadline() -> bool {
 	unsafe { SUPPORTS_TSC_DEADLINE }
 }
 
+#[inline]
+pub fn supports_invariant_tsc() -> bool {
+	unsafe { SUPPORTS_INVARIANT_TSC }
+}
+
+#[inline]
+pub fn supports_mwait() -> bool {
+	unsafe { SUPPORTS_MWAIT }
+}
+
 #[inline]
 pub fn supports_x2apic() -> bool {
 	unsafe { SUPPORTS_X2APIC }
@@ -970,9 +1265,50 @@ pub fn halt() {
 	}
 }
 
+/// Returns the MWAIT hint for the deepest C-state, which is worthwhile for the given idle time (in microseconds).
+fn get_mwait_hint(idle_time: u64) -> Option<u32> {
+	unsafe {
+		MWAIT_HINTS
+			.iter()
+			.zip(MWAIT_TARGET_RESIDENCY.iter())
+			.filter(|(_, residency)| idle_time >= **residency)
+			.filter_map(|(hint, _)| *hint)
+			.last()
+	}
+}
+
+/// Puts the processor into an idle state until the next interrupt arrives.
+/// The expected wakeup time (in microseconds) selects the deepest C-state that is worthwhile.
+///
+/// Interrupts must be disabled when calling this function and are enabled when it returns.
+/// This guarantees that we cannot miss a wakeup interrupt in between.
+pub fn idle(wakeup_time: Option<u64>) {
+	if supports_mwait() {
+		let idle_time = match wakeup_time {
+			Some(wt) => wt.saturating_sub(get_timer_ticks()),
+			None => u64::MAX,
+		};
+
+		if let Some(hint) = get_mwait_hint(idle_time) {
+			// With the interrupt break extension, a pending interrupt terminates MWAIT
+			// even though interrupts are still disabled. It is handled after reenabling them.
+			unsafe {
+				llvm_asm!("monitor" :: "{rax}"(&MWAIT_MONITOR_LINE as *const _ as u64), "{ecx}"(0), "{edx}"(0) :: "volatile");
+				llvm_asm!("mwait" :: "{eax}"(hint), "{ecx}"(MWAIT_ECX_INTERRUPT_BREAK) :: "volatile");
+			}
+			irq::enable();
+			return;
+		}
+	}
+
+	irq::enable_and_wait();
+}
+
 /// Shutdown the system
 pub fn shutdown() -> ! {
 	info!("Shutting down system");
+	#[cfg(feature = "pci")]
+	crate::arch::x86_64::kernel::pci::shutdown_devices();
 	#[cfg(feature = "acpi")]
 	acpi::poweroff();
 
@@ -982,9 +1318,51 @@ pub fn shutdown() -> ! {
 }
 
 pub fn get_timer_ticks() -> u64 {
-	// We simulate a timer with a 1 microsecond resolution by taking the CPU timestamp
-	// and dividing it by the CPU frequency in MHz.
-	get_timestamp() / u64::from(get_frequency())
+	// We simulate a timer with a 1 microsecond resolution by deriving it from the
+	// nanosecond clock.
+	get_timer_nanos() / 1000
+}
+
+/// Returns the time in nanoseconds since the Time-Stamp Counter has been reset.
+/// If kvmclock is available, the time is provided by the hypervisor instead.
+pub fn get_timer_nanos() -> u64 {
+	let (nanos, synchronized) = match kvmclock::get_nanos() {
+		Some(nanos) => (nanos, kvmclock::is_stable()),
+		None => (
+			unsafe { TSC_TO_NANOSECONDS.convert(get_timestamp()) },
+			TSC_SYNCHRONIZED.load(Ordering::Relaxed),
+		),
+	};
+
+	if synchronized {
+		nanos
+	} else {
+		// The clocks of the cores are not synchronized.
+		// Never return a smaller value than any core has returned before.
+		let last = LAST_TIMER_NANOS.fetch_max(nanos, Ordering::SeqCst);
+		cmp::max(last, nanos)
+	}
+}
+
+/// Returns the resolution of get_timer_nanos in nanoseconds.
+pub fn get_timer_resolution() -> u64 {
+	if kvmclock::is_available() {
+		return 1;
+	}
+
+	let hz = unsafe { CPU_FREQUENCY.get_hz() };
+	if hz == 0 {
+		// not calibrated yet
+		return 1;
+	}
+
+	cmp::max(1, (NANOSECONDS_PER_SECOND + hz - 1) / hz)
+}
+
+/// Converts a time in nanoseconds (based on get_timer_nanos) into a Time-Stamp Counter value.
+pub fn nanos_to_timestamp(nanos: u64) -> u64 {
+	kvmclock::nanos_to_timestamp(nanos)
+		.unwrap_or_else(|| unsafe { NANOSECONDS_TO_TSC.convert(nanos) })
 }
 
 pub fn get_frequency() -> u16 {
@@ -1050,3 +1428,25 @@ pub fn udelay(usecs: u64) {
 		spin_loop_hint();
 	}
 }
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn convert_tsc_to_nanoseconds() {
+	let tsc_to_nanoseconds =
+		ClockConversion::calculate(2_400_000_000, NANOSECONDS_PER_SECOND).unwrap();
+	assert_eq!(
+		tsc_to_nanoseconds.convert(2_400_000_000),
+		NANOSECONDS_PER_SECOND
+	);
+
+	let nanoseconds_to_tsc =
+		ClockConversion::calculate(NANOSECONDS_PER_SECOND, 2_400_000_000).unwrap();
+	assert_eq!(
+		nanoseconds_to_tsc.convert(NANOSECONDS_PER_SECOND),
+		2_400_000_000
+	);
+
+	// a frequency of 0 Hz is rejected
+	assert!(ClockConversion::calculate(0, NANOSECONDS_PER_SECOND).is_none());
+	assert!(ClockConversion::calculate(NANOSECONDS_PER_SECOND, 0).is_none());
+}
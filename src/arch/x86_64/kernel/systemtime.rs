@@ -5,10 +5,15 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+#[cfg(feature = "acpi")]
+use crate::arch::x86_64::kernel::acpi;
 use crate::arch::x86_64::kernel::irq;
+use crate::arch::x86_64::kernel::kvmclock;
+use crate::arch::x86_64::kernel::percore::*;
 use crate::arch::x86_64::kernel::processor;
 use crate::arch::x86_64::kernel::BOOT_INFO;
 use crate::environment;
+use crate::synch::spinlock::{SpinlockIrqSave, SpinlockIrqSaveGuard};
 use core::sync::atomic::spin_loop_hint;
 use x86::io::*;
 
@@ -18,29 +23,53 @@ const CMOS_DATA_PORT: u16 = 0x71;
 const CMOS_DISABLE_NMI: u8 = 1 << 7;
 
 const CMOS_SECOND_REGISTER: u8 = 0x00;
+const CMOS_SECOND_ALARM_REGISTER: u8 = 0x01;
 const CMOS_MINUTE_REGISTER: u8 = 0x02;
+const CMOS_MINUTE_ALARM_REGISTER: u8 = 0x03;
 const CMOS_HOUR_REGISTER: u8 = 0x04;
+const CMOS_HOUR_ALARM_REGISTER: u8 = 0x05;
 const CMOS_DAY_REGISTER: u8 = 0x07;
 const CMOS_MONTH_REGISTER: u8 = 0x08;
 const CMOS_YEAR_REGISTER: u8 = 0x09;
 const CMOS_STATUS_REGISTER_A: u8 = 0x0A;
 const CMOS_STATUS_REGISTER_B: u8 = 0x0B;
+const CMOS_STATUS_REGISTER_C: u8 = 0x0C;
 
 const CMOS_UPDATE_IN_PROGRESS_FLAG: u8 = 1 << 7;
+const CMOS_RATE_SELECT_MASK: u8 = 0x0F;
 const CMOS_24_HOUR_FORMAT_FLAG: u8 = 1 << 1;
 const CMOS_BINARY_FORMAT_FLAG: u8 = 1 << 2;
+const CMOS_UPDATE_ENDED_INTERRUPT_FLAG: u8 = 1 << 4;
+const CMOS_ALARM_INTERRUPT_FLAG: u8 = 1 << 5;
+const CMOS_PERIODIC_INTERRUPT_FLAG: u8 = 1 << 6;
+const CMOS_SET_FLAG: u8 = 1 << 7;
 const CMOS_12_HOUR_PM_FLAG: u8 = 0x80;
 
+/// The RTC is connected to ISA interrupt 8, which is routed 1:1 by the IOAPIC.
+const RTC_IRQ: u32 = 8;
+
+/// The slowest and fastest rate of the periodic interrupt, which runs at 32768 >> (rate - 1) Hz.
+const RTC_MIN_PERIODIC_RATE: u8 = 3;
+const RTC_MAX_PERIODIC_RATE: u8 = 15;
+
+/// Serializes all accesses to the CMOS index and data ports.
+/// Selecting a register and reading/writing it must not be interrupted.
+static CMOS_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(());
+
 struct Rtc {
 	cmos_format: u8,
+	century_register: Option<u8>,
+	_guard: SpinlockIrqSaveGuard<'static, ()>,
 }
 
 impl Rtc {
 	fn new() -> Self {
-		irq::disable();
+		let guard = CMOS_LOCK.lock();
 
 		Self {
 			cmos_format: Self::read_cmos_register(CMOS_STATUS_REGISTER_B),
+			century_register: get_century_register(),
+			_guard: guard,
 		}
 	}
 
@@ -63,6 +92,13 @@ impl Rtc {
 		((value / 16) * 10) + (value & 0xF)
 	}
 
+	/**
+	 * Returns the BCD (Binary-Coded Decimal) value for a given binary value.
+	 */
+	const fn convert_to_bcd_value(value: u8) -> u8 {
+		((value / 10) << 4) | (value % 10)
+	}
+
 	/**
 		* Returns the number of microseconds since the epoch from a given date.
 		* Inspired by Linux Kernel's mktime64(), see kernel/time/time.c.
@@ -97,6 +133,17 @@ impl Rtc {
 		}
 	}
 
+	fn write_cmos_register(register: u8, value: u8) {
+		unsafe {
+			outb(CMOS_COMMAND_PORT, CMOS_DISABLE_NMI | register);
+			outb(CMOS_DATA_PORT, value);
+		}
+	}
+
+	fn is_update_in_progress() -> bool {
+		Self::read_cmos_register(CMOS_STATUS_REGISTER_A) & CMOS_UPDATE_IN_PROGRESS_FLAG > 0
+	}
+
 	fn read_datetime_register(&self, register: u8) -> u8 {
 		let value = Self::read_cmos_register(register);
 
@@ -109,9 +156,45 @@ impl Rtc {
 		}
 	}
 
+	fn write_datetime_register(&self, register: u8, value: u8) {
+		// Store the value in the same format as the RTC uses for all other date/time registers.
+		if self.is_binary_format() {
+			Self::write_cmos_register(register, value);
+		} else {
+			Self::write_cmos_register(register, Self::convert_to_bcd_value(value));
+		}
+	}
+
+	/// Converts an hour in 24-hour format into the representation of the hour registers.
+	fn encode_hour(&self, hour: u8) -> u8 {
+		let (hour, pm_flag) = if self.is_24_hour_format() {
+			(hour, 0)
+		} else {
+			// 00:00 is 12:00 AM, 12:00 is 12:00 PM, and {13:00, 14:00, ...} is {01:00 PM, 02:00 PM, ...}.
+			let pm_flag = if hour >= 12 { CMOS_12_HOUR_PM_FLAG } else { 0 };
+			let hour = match hour % 12 {
+				0 => 12,
+				hour => hour,
+			};
+			(hour, pm_flag)
+		};
+
+		if self.is_binary_format() {
+			hour | pm_flag
+		} else {
+			Self::convert_to_bcd_value(hour) | pm_flag
+		}
+	}
+
 	fn read_all_values(&self) -> u64 {
-		// Reading year, month, and day is straightforward.
-		let year = u16::from(self.read_datetime_register(CMOS_YEAR_REGISTER)) + 2000;
+		// The year register only holds the last two digits.
+		// Take the century from the century register if the firmware told us about one,
+		// otherwise assume that we live in the 21st century.
+		let century = match self.century_register {
+			Some(register) => u16::from(self.read_datetime_register(register)),
+			None => 20,
+		};
+		let year = century * 100 + u16::from(self.read_datetime_register(CMOS_YEAR_REGISTER));
 		let month = self.read_datetime_register(CMOS_MONTH_REGISTER);
 		let day = self.read_datetime_register(CMOS_DAY_REGISTER);
 
@@ -156,9 +239,7 @@ impl Rtc {
 	pub fn get_microseconds_since_epoch(&self) -> u64 {
 		loop {
 			// If a clock update is currently in progress, wait until it is finished.
-			while Self::read_cmos_register(CMOS_STATUS_REGISTER_A) & CMOS_UPDATE_IN_PROGRESS_FLAG
-				> 0
-			{
+			while Self::is_update_in_progress() {
 				spin_loop_hint();
 			}
 
@@ -167,7 +248,7 @@ impl Rtc {
 
 			// If the clock is already updating the time again, the read values may be inconsistent
 			// and we have to repeat this process.
-			if Self::read_cmos_register(CMOS_STATUS_REGISTER_A) & CMOS_UPDATE_IN_PROGRESS_FLAG > 0 {
+			if Self::is_update_in_progress() {
 				continue;
 			}
 
@@ -179,11 +260,61 @@ impl Rtc {
 			}
 		}
 	}
-}
 
-impl Drop for Rtc {
-	fn drop(&mut self) {
-		irq::enable();
+	pub fn set_microseconds_since_epoch(&self, microseconds_since_epoch: u64) {
+		let (year, month, day, hour, minute, second) =
+			date_from_microseconds(microseconds_since_epoch);
+
+		// Halt the clock updates while we write the date/time registers.
+		// Otherwise, an update cycle may carry over into a partially written date.
+		Self::write_cmos_register(CMOS_STATUS_REGISTER_B, self.cmos_format | CMOS_SET_FLAG);
+
+		self.write_datetime_register(CMOS_SECOND_REGISTER, second);
+		self.write_datetime_register(CMOS_MINUTE_REGISTER, minute);
+		Self::write_cmos_register(CMOS_HOUR_REGISTER, self.encode_hour(hour));
+		self.write_datetime_register(CMOS_DAY_REGISTER, day);
+		self.write_datetime_register(CMOS_MONTH_REGISTER, month);
+		self.write_datetime_register(CMOS_YEAR_REGISTER, (year % 100) as u8);
+		if let Some(register) = self.century_register {
+			self.write_datetime_register(register, (year / 100) as u8);
+		}
+
+		// Restart the clock.
+		Self::write_cmos_register(CMOS_STATUS_REGISTER_B, self.cmos_format & !CMOS_SET_FLAG);
+	}
+
+	/// Fires the alarm interrupt once a day at the given time.
+	pub fn set_alarm(&mut self, hour: u8, minute: u8, second: u8) {
+		self.write_datetime_register(CMOS_SECOND_ALARM_REGISTER, second);
+		self.write_datetime_register(CMOS_MINUTE_ALARM_REGISTER, minute);
+		Self::write_cmos_register(CMOS_HOUR_ALARM_REGISTER, self.encode_hour(hour));
+		self.enable_interrupts(CMOS_ALARM_INTERRUPT_FLAG);
+	}
+
+	/// Fires the periodic interrupt at a frequency of 32768 >> (rate - 1) Hz.
+	pub fn set_periodic_rate(&mut self, rate: u8) {
+		let status_register_a = Self::read_cmos_register(CMOS_STATUS_REGISTER_A);
+		Self::write_cmos_register(
+			CMOS_STATUS_REGISTER_A,
+			(status_register_a & !CMOS_RATE_SELECT_MASK) | (rate & CMOS_RATE_SELECT_MASK),
+		);
+		self.enable_interrupts(CMOS_PERIODIC_INTERRUPT_FLAG);
+	}
+
+	pub fn enable_interrupts(&mut self, flags: u8) {
+		self.cmos_format |= flags;
+		Self::write_cmos_register(CMOS_STATUS_REGISTER_B, self.cmos_format);
+	}
+
+	pub fn disable_interrupts(&mut self, flags: u8) {
+		self.cmos_format &= !flags;
+		Self::write_cmos_register(CMOS_STATUS_REGISTER_B, self.cmos_format);
+	}
+
+	/// Returns the interrupt flags, which caused the last interrupt.
+	/// Reading status register C acknowledges the interrupt, otherwise the RTC will not raise another one.
+	pub fn acknowledge_interrupt(&self) -> u8 {
+		Self::read_cmos_register(CMOS_STATUS_REGISTER_C)
 	}
 }
 
@@ -224,6 +355,83 @@ fn date_from_microseconds(microseconds_since_epoch: u64) -> (u16, u8, u8, u8, u8
 	(year, month, day, hour, minute, second)
 }
 
+#[cfg(feature = "acpi")]
+fn get_century_register() -> Option<u8> {
+	acpi::get_century_register()
+}
+
+#[cfg(not(feature = "acpi"))]
+fn get_century_register() -> Option<u8> {
+	None
+}
+
+/// The CMOS RTC is only accessible if we run on bare-metal or in a full machine emulation like QEMU.
+fn has_rtc() -> bool {
+	environment::is_single_kernel() && !environment::is_uhyve()
+}
+
+/// Acknowledges the interrupt of the RTC and wakes up the tasks, whose timeout has expired.
+fn rtc_handler(_context: usize) -> bool {
+	let mut rtc = Rtc::new();
+	let flags = rtc.acknowledge_interrupt();
+	debug!("Received RTC interrupt (status {:#X})", flags);
+	if flags == 0 {
+		return false;
+	}
+
+	if flags & CMOS_ALARM_INTERRUPT_FLAG > 0 {
+		// The alarm is a one-shot wakeup source.
+		rtc.disable_interrupts(CMOS_ALARM_INTERRUPT_FLAG);
+	}
+	drop(rtc);
+
+	core_scheduler().handle_waiting_tasks();
+	true
+}
+
+/// Writes the given wall clock time in microseconds since the epoch back to the CMOS RTC,
+/// so that it survives a reboot.
+pub fn set_system_time(microseconds_since_epoch: u64) {
+	if has_rtc() {
+		Rtc::new().set_microseconds_since_epoch(microseconds_since_epoch);
+	}
+}
+
+/// Programs the RTC alarm to wake up the system at the given wall clock time in microseconds since the epoch.
+/// The alarm only has a resolution of one second and must be within the next 24 hours.
+pub fn set_alarm(microseconds_since_epoch: u64) -> Result<(), ()> {
+	if !has_rtc() {
+		return Err(());
+	}
+
+	let (_, _, _, hour, minute, second) = date_from_microseconds(microseconds_since_epoch);
+	Rtc::new().set_alarm(hour, minute, second);
+	Ok(())
+}
+
+pub fn disable_alarm() {
+	if has_rtc() {
+		Rtc::new().disable_interrupts(CMOS_ALARM_INTERRUPT_FLAG);
+	}
+}
+
+/// Enables the periodic RTC interrupt at a frequency of 32768 >> (rate - 1) Hz,
+/// i.e. between 8192 Hz (rate 3) and 2 Hz (rate 15).
+pub fn set_periodic_interrupt(rate: u8) -> Result<(), ()> {
+	if !has_rtc() || rate < RTC_MIN_PERIODIC_RATE || rate > RTC_MAX_PERIODIC_RATE {
+		return Err(());
+	}
+
+	Rtc::new().set_periodic_rate(rate);
+	Ok(())
+}
+
+pub fn disable_periodic_interrupt() {
+	if has_rtc() {
+		Rtc::new().disable_interrupts(CMOS_PERIODIC_INTERRUPT_FLAG);
+	}
+}
+
 pub fn get_boot_time() -> u64 {
 	unsafe { core::ptr::read_volatile(&(*BOOT_INFO).boot_gtod) }
 }
@@ -231,6 +439,15 @@ pub fn get_boot_time() -> u64 {
 pub fn init() {
 	let mut microseconds_offset = get_boot_time();
 
+	if microseconds_offset == 0 {
+		// kvmclock provides the wall clock time at its system time zero,
+		// which is exactly the base of processor::get_timer_ticks.
+		if let Some(wall_clock) = kvmclock::get_wall_clock() {
+			microseconds_offset = wall_clock;
+			unsafe { core::ptr::write_volatile(&mut (*BOOT_INFO).boot_gtod, microseconds_offset) }
+		}
+	}
+
 	if microseconds_offset == 0 && !environment::is_uhyve() {
 		// Get the current time in microseconds since the epoch (1970-01-01) from the x86 RTC.
 		// Subtract the timer ticks to get the actual time when HermitCore-rs was booted.
@@ -239,9 +456,35 @@ pub fn init() {
 		unsafe { core::ptr::write_volatile(&mut (*BOOT_INFO).boot_gtod, microseconds_offset) }
 	}
 
+	if has_rtc() {
+		// Start with all RTC interrupts disabled and acknowledge a possibly pending one.
+		let mut rtc = Rtc::new();
+		rtc.disable_interrupts(
+			CMOS_ALARM_INTERRUPT_FLAG
+				| CMOS_PERIODIC_INTERRUPT_FLAG
+				| CMOS_UPDATE_ENDED_INTERRUPT_FLAG,
+		);
+		rtc.acknowledge_interrupt();
+		drop(rtc);
+
+		irq::add_device_handler(RTC_IRQ, "RTC", rtc_handler, 0);
+	}
+
 	let (year, month, day, hour, minute, second) = date_from_microseconds(microseconds_offset);
 	info!(
 		"HermitCore-rs booted on {:04}-{:02}-{:02} at {:02}:{:02}:{:02}",
 		year, month, day, hour, minute, second
 	);
 }
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn convert_date_from_microseconds() {
+	let microseconds = Rtc::microseconds_from_date(2020, 2, 29, 13, 37, 42);
+	assert_eq!(microseconds, 1_582_983_462_000_000);
+	assert_eq!(
+		date_from_microseconds(microseconds),
+		(2020, 2, 29, 13, 37, 42)
+	);
+	assert_eq!(Rtc::convert_bcd_value(Rtc::convert_to_bcd_value(59)), 59);
+}
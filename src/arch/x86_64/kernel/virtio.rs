@@ -5,19 +5,20 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use crate::arch::x86_64::kernel::apic;
-use crate::arch::x86_64::kernel::irq::*;
-use crate::arch::x86_64::kernel::pci::{
-	self, get_network_driver, PciAdapter, PciClassCode, PciDriver, PciNetworkControllerSubclass,
-};
-use crate::arch::x86_64::kernel::percore::{core_scheduler, increment_irq_counter};
+use crate::arch::x86_64::kernel::pci::{self, PciAdapter, PciBar};
+use crate::arch::x86_64::kernel::virtio_balloon;
+use crate::arch::x86_64::kernel::virtio_blk;
+use crate::arch::x86_64::kernel::virtio_console;
 use crate::arch::x86_64::kernel::virtio_fs;
 use crate::arch::x86_64::kernel::virtio_net;
+use crate::arch::x86_64::kernel::virtio_packed::PackedVirtq;
+use crate::arch::x86_64::kernel::virtio_rng;
+#[cfg(not(feature = "newlib"))]
+use crate::arch::x86_64::kernel::virtio_vsock;
 
-use crate::arch::x86_64::mm::paging;
-use crate::arch::x86_64::mm::VirtAddr;
+use crate::arch::x86_64::mm::paging::{self, BasePageSize, PageSize};
+use crate::arch::x86_64::mm::{PhysAddr, VirtAddr};
 use crate::config::VIRTIO_MAX_QUEUE_SIZE;
-use crate::synch::spinlock::SpinlockIrqSave;
 
 use alloc::boxed::Box;
 use alloc::rc::Rc;
@@ -30,6 +31,9 @@ use core::sync::atomic::{fence, Ordering};
 use self::consts::*;
 
 pub mod consts {
+	/// Vendor id of all virtio devices
+	pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
 	/* Common configuration */
 	pub const VIRTIO_PCI_CAP_COMMON_CFG: u32 = 1;
 	/* Notifications */
@@ -40,6 +44,8 @@ pub mod consts {
 	pub const VIRTIO_PCI_CAP_DEVICE_CFG: u32 = 4;
 	/* PCI configuration access */
 	pub const VIRTIO_PCI_CAP_PCI_CFG: u32 = 5;
+	/* Shared memory region */
+	pub const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u32 = 8;
 
 	pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
 	pub const VIRTIO_F_RING_EVENT_IDX: u64 = 1 << 29;
@@ -62,9 +68,149 @@ pub mod consts {
 	pub const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
 	/// Default behaviour, where the guest expects interrupts from the host
 	pub const VRING_AVAIL_F_DEFAULT: u16 = 0;
+	// The host uses this in flag to advise the guest: don't kick me when
+	// you add a buffer.
+	pub const VRING_USED_F_NO_NOTIFY: u16 = 1;
+
+	/// MSI-X vector, which disables the interrupts of a virtqueue or of configuration changes
+	pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+}
+
+/// Virtqueue, which uses the split (2.6) or the packed (2.7) ring layout.
+/// The layout is selected by the negotiated feature VIRTIO_F_RING_PACKED.
+pub enum Virtq<'a> {
+	Split(SplitVirtq<'a>),
+	Packed(PackedVirtq<'a>),
+}
+
+impl<'a> Virtq<'a> {
+	/// Creates the virtqueue `index`. `features` are the feature bits, which have been negotiated with the device.
+	pub fn new_from_common(
+		index: u16,
+		common_cfg: &mut virtio_pci_common_cfg,
+		notify_cfg: &mut VirtioNotification,
+		features: u64,
+	) -> Option<Self> {
+		if features & VIRTIO_F_RING_PACKED != 0 {
+			PackedVirtq::new_from_common(index, common_cfg, notify_cfg, features).map(Virtq::Packed)
+		} else {
+			SplitVirtq::new_from_common(index, common_cfg, notify_cfg, features).map(Virtq::Split)
+		}
+	}
+
+	/// Makes the buffer `index`, which has been registered by `add_buffer`, available to the device.
+	pub fn send_non_blocking(&mut self, index: usize, len: usize) -> Result<(), ()> {
+		match self {
+			Virtq::Split(vq) => vq.send_non_blocking(index, len),
+			Virtq::Packed(vq) => vq.send_non_blocking(index, len),
+		}
+	}
+
+	/// Places dat in virtq, waits until buffer is used and response is in rsp_buf.
+	pub fn send_blocking(&mut self, dat: &[&[u8]], rsp_buf: Option<&[&mut [u8]]>) {
+		match self {
+			Virtq::Split(vq) => vq.send_blocking(dat, rsp_buf),
+			Virtq::Packed(vq) => vq.send_blocking(dat, rsp_buf),
+		}
+	}
+
+	/// Places dat and rsp_buf in virtq without waiting for the device. Returns the id of the descriptor chain,
+	/// which is returned by `pop_used_buffer` after completion and has to be released by `release_chain`.
+	pub fn send_chain(&mut self, dat: &[&[u8]], rsp_buf: &[&mut [u8]]) -> Result<u32, ()> {
+		match self {
+			Virtq::Split(vq) => vq.send_chain(dat, rsp_buf),
+			Virtq::Packed(vq) => vq.send_chain(dat, rsp_buf),
+		}
+	}
+
+	/// Places device-writable buffers, which are specified by their physical address and length,
+	/// in virtq. In contrast to `send_chain`, the memory doesn't have to be mapped.
+	pub fn send_physical_chain(&mut self, rsp_buf: &[(PhysAddr, usize)]) -> Result<u32, ()> {
+		match self {
+			Virtq::Split(vq) => vq.send_physical_chain(rsp_buf),
+			Virtq::Packed(vq) => vq.send_physical_chain(rsp_buf),
+		}
+	}
+
+	/// Releases the descriptor chain `id` of a completed request.
+	pub fn release_chain(&mut self, id: u32) {
+		match self {
+			Virtq::Split(vq) => vq.release_chain(id),
+			Virtq::Packed(vq) => vq.release_chain(id),
+		}
+	}
+
+	pub fn check_used_elements(&mut self) -> Option<u32> {
+		match self {
+			Virtq::Split(vq) => vq.check_used_elements(),
+			Virtq::Packed(vq) => vq.check_used_elements(),
+		}
+	}
+
+	/// Registers the buffer `index`. Device-writable buffers are made available to the device immediately.
+	pub fn add_buffer(&mut self, index: usize, addr: VirtAddr, len: usize, flags: u16) {
+		match self {
+			Virtq::Split(vq) => vq.add_buffer(index, addr, len, flags),
+			Virtq::Packed(vq) => vq.add_buffer(index, addr, len, flags),
+		}
+	}
+
+	pub fn set_polling_mode(&mut self, value: bool) {
+		match self {
+			Virtq::Split(vq) => vq.set_polling_mode(value),
+			Virtq::Packed(vq) => vq.set_polling_mode(value),
+		}
+	}
+
+	pub fn has_packet(&self) -> bool {
+		match self {
+			Virtq::Split(vq) => vq.has_packet(),
+			Virtq::Packed(vq) => vq.has_packet(),
+		}
+	}
+
+	pub fn get_available_buffer(&self) -> Result<u32, ()> {
+		match self {
+			Virtq::Split(vq) => vq.get_available_buffer(),
+			Virtq::Packed(vq) => vq.get_available_buffer(),
+		}
+	}
+
+	pub fn get_used_buffer(&self) -> Result<(u32, u32), ()> {
+		match self {
+			Virtq::Split(vq) => vq.get_used_buffer(),
+			Virtq::Packed(vq) => vq.get_used_buffer(),
+		}
+	}
+
+	pub fn buffer_consumed(&mut self) {
+		match self {
+			Virtq::Split(vq) => vq.buffer_consumed(),
+			Virtq::Packed(vq) => vq.buffer_consumed(),
+		}
+	}
+
+	/// Removes the next used buffer from the queue without returning it to the device.
+	/// Returns the id of the buffer and the number of bytes, which are written by the device.
+	pub fn pop_used_buffer(&mut self) -> Option<(u32, u32)> {
+		match self {
+			Virtq::Split(vq) => vq.pop_used_buffer(),
+			Virtq::Packed(vq) => vq.pop_used_buffer(),
+		}
+	}
+
+	/// Makes the buffer `index` available to the device again.
+	/// If `buffer` is specified, the buffer is replaced by a new one (address, length).
+	pub fn recycle_buffer(&mut self, index: u32, buffer: Option<(VirtAddr, usize)>) {
+		match self {
+			Virtq::Split(vq) => vq.recycle_buffer(index, buffer),
+			Virtq::Packed(vq) => vq.recycle_buffer(index, buffer),
+		}
+	}
 }
 
-pub struct Virtq<'a> {
+/// Virtqueue with separate descriptor table, available and used rings
+pub struct SplitVirtq<'a> {
 	index: u16,  // Index of vq in common config
 	vqsize: u16, // Elements in ring/descrs
 	// The actial descriptors (16 bytes each)
@@ -75,9 +221,11 @@ pub struct Virtq<'a> {
 	used: Rc<RefCell<VirtqUsed<'a>>>,
 	// Address where queue index is written to on notify
 	queue_notify_address: &'a mut u16,
+	// Notifications are suppressed by used_event/avail_event (VIRTIO_F_RING_EVENT_IDX)
+	event_idx: bool,
 }
 
-impl<'a> Virtq<'a> {
+impl<'a> SplitVirtq<'a> {
 	// TODO: are the lifetimes correct?
 	fn new(
 		index: u16,
@@ -86,21 +234,24 @@ impl<'a> Virtq<'a> {
 		avail: VirtqAvail<'a>,
 		used: VirtqUsed<'a>,
 		queue_notify_address: &'a mut u16,
+		event_idx: bool,
 	) -> Self {
-		Virtq {
+		SplitVirtq {
 			index,
 			vqsize,
 			virtq_desc: VirtqDescriptors::new(virtq_desc),
 			avail: Rc::new(RefCell::new(avail)),
 			used: Rc::new(RefCell::new(used)),
 			queue_notify_address,
+			event_idx,
 		}
 	}
 
-	pub fn new_from_common(
+	fn new_from_common(
 		index: u16,
 		common_cfg: &mut virtio_pci_common_cfg,
 		notify_cfg: &mut VirtioNotification,
+		features: u64,
 	) -> Option<Self> {
 		// 1.Write the virtqueue index to queue_select.
 		common_cfg.queue_select = index;
@@ -163,8 +314,10 @@ impl<'a> Virtq<'a> {
 		// Split buffers into usable structs:
 		let (avail_flags, avail_mem) = avail_mem.split_first_mut().unwrap();
 		let (avail_idx, avail_mem) = avail_mem.split_first_mut().unwrap();
+		let (used_event, avail_mem) = avail_mem.split_last_mut().unwrap();
 		let (used_flags, used_mem) = used_mem.split_first_mut().unwrap();
 		let (used_idx, used_mem) = used_mem.split_first_mut().unwrap();
+		let (avail_event, used_mem) = used_mem.split_last_mut().unwrap();
 
 		// Tell device about the guest-physical addresses of our queue structs:
 		// TODO: cleanup pointer conversions (use &mut vq....?)
@@ -186,6 +339,7 @@ impl<'a> Virtq<'a> {
 			idx: avail_idx,
 			ring: avail_mem,
 			//rawmem: avail_mem_box,
+			used_event,
 		};
 		let used = VirtqUsed {
 			flags: used_flags,
@@ -193,19 +347,45 @@ impl<'a> Virtq<'a> {
 			ring: unsafe { core::slice::from_raw_parts(used_mem.as_ptr() as *const _, vqsize) },
 			//rawmem: used_mem_box,
 			last_idx: 0,
+			avail_event,
 		};
-		let vq = Virtq::new(
+		let vq = SplitVirtq::new(
 			index,
 			vqsize as u16,
 			desc_raw_wrappers,
 			avail,
 			used,
 			notify_cfg.get_notify_addr(common_cfg.queue_notify_off as u32),
+			features & VIRTIO_F_RING_EVENT_IDX != 0,
 		);
 
 		Some(vq)
 	}
 
+	/// Sends an available buffer notification to the device, if such notifications are not suppressed.
+	/// `old_idx` is the available index before the new buffers have been added.
+	fn kick(&mut self, old_idx: u16) {
+		// 2.6.10.1 Driver Requirements: Available Buffer Notification Suppression
+		let new_idx = *self.avail.borrow().idx;
+		let vqused = self.used.borrow();
+		let should_notify = if self.event_idx {
+			// If the VIRTIO_F_EVENT_IDX feature bit is negotiated, the driver MUST read avail_event and
+			// send a notification, if the available index has passed avail_event.
+			let avail_event = unsafe { core::ptr::read_volatile(vqused.avail_event) };
+			vring_need_event(avail_event, new_idx, old_idx)
+		} else {
+			// If flags is 1, the driver SHOULD NOT send a notification.
+			// If flags is 0, the driver MUST send a notification.
+			let flags = unsafe { core::ptr::read_volatile(vqused.flags) };
+			flags & VRING_USED_F_NO_NOTIFY == 0
+		};
+		drop(vqused);
+
+		if should_notify {
+			self.notify_device();
+		}
+	}
+
 	fn notify_device(&mut self) {
 		// 4.1.4.4.1 Device Requirements: Notification capability
 		// virtio-fs does NOT offer VIRTIO_F_NOTIFICATION_DATA
@@ -244,7 +424,7 @@ impl<'a> Virtq<'a> {
 		// The available idx is increased by the number of descriptor chain heads added to the available ring.
 		// idx always increments, and wraps naturally at 65536:
 
-		*vqavail.flags = VRING_AVAIL_F_NO_INTERRUPT;
+		let old_idx = *vqavail.idx;
 		*vqavail.idx = vqavail.idx.wrapping_add(1);
 
 		if *vqavail.idx == 0 {
@@ -255,20 +435,8 @@ impl<'a> Virtq<'a> {
 		fence(Ordering::SeqCst);
 
 		// The driver sends an available buffer notification to the device if such notifications are not suppressed.
-		// 2.6.10.1 Driver Requirements: Available Buffer Notification Suppression
-		// If the VIRTIO_F_EVENT_IDX feature bit is not negotiated:
-		// - The driver MUST ignore the avail_event value.
-		// - After the driver writes a descriptor index into the available ring:
-		//     If flags is 1, the driver SHOULD NOT send a notification.
-		//     If flags is 0, the driver MUST send a notification.
-		let vqused = self.used.borrow();
-		let should_notify = *vqused.flags == 0;
 		drop(vqavail);
-		drop(vqused);
-
-		if should_notify {
-			self.notify_device();
-		}
+		self.kick(old_idx);
 
 		Ok(())
 	}
@@ -335,6 +503,7 @@ impl<'a> Virtq<'a> {
 		// 5. The available idx is increased by the number of descriptor chain heads added to the available ring.
 		// idx always increments, and wraps naturally at 65536:
 
+		let old_idx = *vqavail.idx;
 		*vqavail.idx = vqavail.idx.wrapping_add(1);
 
 		if *vqavail.idx == 0 {
@@ -345,20 +514,8 @@ impl<'a> Virtq<'a> {
 		fence(Ordering::SeqCst);
 
 		// 7. The driver sends an available buffer notification to the device if such notifications are not suppressed.
-		// 2.6.10.1 Driver Requirements: Available Buffer Notification Suppression
-		// If the VIRTIO_F_EVENT_IDX feature bit is not negotiated:
-		// - The driver MUST ignore the avail_event value.
-		// - After the driver writes a descriptor index into the available ring:
-		//     If flags is 1, the driver SHOULD NOT send a notification.
-		//     If flags is 0, the driver MUST send a notification.
-		let vqused = self.used.borrow();
-		let should_notify = *vqused.flags == 0;
 		drop(vqavail);
-		drop(vqused);
-
-		if should_notify {
-			self.notify_device();
-		}
+		self.kick(old_idx);
 
 		// wait until done (placed in used buffer)
 		let mut vqused = self.used.borrow_mut();
@@ -369,6 +526,71 @@ impl<'a> Virtq<'a> {
 		self.virtq_desc.recycle_chain(chainrc)
 	}
 
+	pub fn send_chain(&mut self, dat: &[&[u8]], rsp_buf: &[&mut [u8]]) -> Result<u32, ()> {
+		let mut elements = Vec::with_capacity(dat.len() + rsp_buf.len());
+		for dat in dat {
+			let addr = paging::virt_to_phys(VirtAddr(dat.as_ptr() as u64));
+			elements.push((addr, dat.len(), 0));
+		}
+		for dat in rsp_buf {
+			let addr = paging::virt_to_phys(VirtAddr(dat.as_ptr() as u64));
+			elements.push((addr, dat.len(), VIRTQ_DESC_F_WRITE));
+		}
+
+		self.send_elements(&elements)
+	}
+
+	pub fn send_physical_chain(&mut self, rsp_buf: &[(PhysAddr, usize)]) -> Result<u32, ()> {
+		let elements: Vec<_> = rsp_buf
+			.iter()
+			.map(|&(addr, len)| (addr, len, VIRTQ_DESC_F_WRITE))
+			.collect();
+
+		self.send_elements(&elements)
+	}
+
+	/// Places a descriptor chain of the elements (physical address, length, flags) in the available ring.
+	fn send_elements(&mut self, elements: &[(PhysAddr, usize, u16)]) -> Result<u32, ()> {
+		if self.virtq_desc.free.borrow().0.len() < elements.len() {
+			return Err(());
+		}
+
+		let chainrc = self.virtq_desc.get_empty_chain();
+		let mut chain = chainrc.borrow_mut();
+		for &(addr, len, flags) in elements {
+			self.virtq_desc.extend(&mut chain);
+			let desc = &mut chain.0.last_mut().unwrap().raw;
+			desc.addr = addr.as_u64();
+			desc.len = len.try_into().unwrap();
+			desc.flags = flags;
+		}
+		let head = chain.0.first().unwrap().index;
+		drop(chain);
+
+		let mut vqavail = self.avail.borrow_mut();
+		let aind = (*vqavail.idx % self.vqsize) as usize;
+		vqavail.ring[aind] = head;
+
+		fence(Ordering::SeqCst);
+
+		let old_idx = *vqavail.idx;
+		*vqavail.idx = vqavail.idx.wrapping_add(1);
+
+		fence(Ordering::SeqCst);
+
+		drop(vqavail);
+		self.kick(old_idx);
+
+		Ok(u32::from(head))
+	}
+
+	pub fn release_chain(&mut self, id: u32) {
+		match self.virtq_desc.get_chain_by_head(id.try_into().unwrap()) {
+			Some(chainrc) => self.virtq_desc.recycle_chain(chainrc),
+			None => warn!("Descriptor chain {} does not exist!", id),
+		}
+	}
+
 	pub fn check_used_elements(&mut self) -> Option<u32> {
 		let mut vqused = self.used.borrow_mut();
 		vqused.check_elements()
@@ -403,13 +625,30 @@ impl<'a> Virtq<'a> {
 		}
 	}
 
+	/// Disables (`value == true`) or enables the used buffer notifications of the queue.
+	/// After enabling the notifications, the caller has to check for used buffers, which
+	/// have arrived in the meantime.
 	pub fn set_polling_mode(&mut self, value: bool) {
 		let mut vqavail = self.avail.borrow_mut();
-		if value {
+		if self.event_idx {
+			// 2.6.7.1 Driver Requirements: Used Buffer Notification Suppression
+			// If the VIRTIO_F_EVENT_IDX feature bit is negotiated, the driver MUST set flags to 0.
+			// The device interrupts, when its used index passes used_event.
+			let last_idx = self.used.borrow().last_idx;
+			*vqavail.used_event = if value {
+				// the device passes this index only after a wrap around
+				last_idx.wrapping_sub(1)
+			} else {
+				last_idx
+			};
+		} else if value {
 			*vqavail.flags = VRING_AVAIL_F_NO_INTERRUPT;
 		} else {
 			*vqavail.flags = VRING_AVAIL_F_DEFAULT;
 		}
+
+		// publish the setting before the caller checks the used ring again
+		fence(Ordering::SeqCst);
 	}
 
 	pub fn has_packet(&self) -> bool {
@@ -439,31 +678,51 @@ impl<'a> Virtq<'a> {
 	}
 
 	pub fn buffer_consumed(&mut self) {
+		if let Some((index, _)) = self.pop_used_buffer() {
+			self.recycle_buffer(index, None);
+		}
+	}
+
+	/// Removes the next used buffer from the queue without returning it to the device.
+	/// Returns the descriptor index of the buffer and the number of bytes, which are written by the device.
+	pub fn pop_used_buffer(&mut self) -> Option<(u32, u32)> {
 		let mut vqused = self.used.borrow_mut();
 
-		if vqused.last_idx != *vqused.idx {
+		if unsafe { core::ptr::read_volatile(vqused.idx) } == vqused.last_idx {
+			None
+		} else {
 			let usedelem = vqused.ring[vqused.last_idx as usize % vqused.ring.len()];
-
 			vqused.last_idx = vqused.last_idx.wrapping_add(1);
 
-			let mut vqavail = self.avail.borrow_mut();
-			let aind = (*vqavail.idx % self.vqsize) as usize;
-			vqavail.ring[aind] = usedelem.id.try_into().unwrap();
+			Some((usedelem.id, usedelem.len))
+		}
+	}
 
-			fence(Ordering::SeqCst);
+	/// Makes the buffer of descriptor `index` available to the device again.
+	/// Buffers may be recycled in a different order than they have been used.
+	/// If `buffer` is specified, the descriptor is backed by a new buffer (address, length).
+	pub fn recycle_buffer(&mut self, index: u32, buffer: Option<(VirtAddr, usize)>) {
+		if let Some((addr, len)) = buffer {
+			let chainrc = self.virtq_desc.get_chain_by_index(index as usize);
+			let mut chain = chainrc.borrow_mut();
+			let desc = &mut chain.0.first_mut().unwrap().raw;
+			desc.addr = paging::virt_to_phys(addr).as_u64();
+			desc.len = len.try_into().unwrap();
+		}
 
-			*vqavail.idx = vqavail.idx.wrapping_add(1);
+		let mut vqavail = self.avail.borrow_mut();
+		let aind = (*vqavail.idx % self.vqsize) as usize;
+		vqavail.ring[aind] = index.try_into().unwrap();
 
-			fence(Ordering::SeqCst);
+		fence(Ordering::SeqCst);
 
-			let should_notify = *vqused.flags == 0;
-			drop(vqavail);
-			drop(vqused);
+		let old_idx = *vqavail.idx;
+		*vqavail.idx = vqavail.idx.wrapping_add(1);
 
-			if should_notify {
-				self.notify_device();
-			}
-		}
+		fence(Ordering::SeqCst);
+
+		drop(vqavail);
+		self.kick(old_idx);
 	}
 }
 
@@ -555,6 +814,14 @@ impl VirtqDescriptors {
 		self.used_chains.borrow()[idx].clone()
 	}
 
+	fn get_chain_by_head(&self, head: u16) -> Option<Rc<RefCell<VirtqDescriptorChain>>> {
+		self.used_chains
+			.borrow()
+			.iter()
+			.find(|c| c.borrow().0.first().map(|desc| desc.index) == Some(head))
+			.cloned()
+	}
+
 	// Can't guarantee that the caller will pass back the chain to us, so never hand out complete ownership!
 	fn get_empty_chain(&self) -> Rc<RefCell<VirtqDescriptorChain>> {
 		// TODO: handle no-free case!
@@ -615,7 +882,8 @@ struct VirtqAvail<'a> {
 	idx: &'a mut u16,
 	ring: &'a mut [u16],
 	//rawmem: Box<[u16]>,
-	// Only if VIRTIO_F_EVENT_IDX used_event: u16,
+	// Only if VIRTIO_F_EVENT_IDX
+	used_event: &'a mut u16,
 }
 
 #[allow(dead_code)]
@@ -625,6 +893,8 @@ struct VirtqUsed<'a> {
 	ring: &'a [virtq_used_elem],
 	//rawmem: Box<[u16]>,
 	last_idx: u16,
+	// Only if VIRTIO_F_EVENT_IDX
+	avail_event: &'a u16,
 }
 
 impl<'a> VirtqUsed<'a> {
@@ -660,6 +930,12 @@ impl<'a> VirtqUsed<'a> {
 	}
 }
 
+/// Returns true, if the index `event_idx` lies between `old_idx` (inclusive) and `new_idx` (exclusive),
+/// i.e. the other side asked for a notification for one of the new entries (see vring_need_event in Linux).
+pub(crate) fn vring_need_event(event_idx: u16, new_idx: u16, old_idx: u16) -> bool {
+	new_idx.wrapping_sub(event_idx).wrapping_sub(1) < new_idx.wrapping_sub(old_idx)
+}
+
 // u32 is used here for ids for padding reasons.
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -819,72 +1095,107 @@ pub fn map_virtiocap(
 	}
 }
 
-pub fn init_virtio_device(adapter: &pci::PciAdapter) {
-	// TODO: 2.3.1: Loop until get_config_generation static, since it might change mid-read
+/// Scans pci-capabilities for the shared memory region `id` (see 4.1.4.7 Shared memory capability).
+/// When found, returns the physical address and the length of the region, else None
+pub fn find_shared_memory(adapter: &PciAdapter, id: u8) -> Option<(PhysAddr, usize)> {
+	let read_config = |offset| pci::read_config(adapter.bus, adapter.devfn, offset);
+
+	adapter
+		.capabilities()
+		.filter(|(cap_id, _)| *cap_id == pci::PCI_CAP_ID_VNDR)
+		.find_map(|(_, offset)| {
+			let cfg_type = (read_config(offset) >> 24) & 0xFF;
+			let barword = read_config(offset + 4);
+			if cfg_type != VIRTIO_PCI_CAP_SHARED_MEMORY_CFG || (barword >> 8) & 0xFF != id.into() {
+				return None;
+			}
 
-	match adapter.device_id {
-		0x1000..=0x103F => {
-			// Legacy device, skip
-			warn!("Legacy Virtio devices are not supported, skipping!");
-			return;
-		}
-		0x1041 => {
-			match num::FromPrimitive::from_u8(adapter.class_id).unwrap() {
-				PciClassCode::NetworkController => {
-					match num::FromPrimitive::from_u8(adapter.subclass_id).unwrap() {
-						PciNetworkControllerSubclass::EthernetController => {
-							// TODO: proper error handling on driver creation fail
-							let drv = virtio_net::create_virtionet_driver(adapter).unwrap();
-							pci::register_driver(PciDriver::VirtioNet(SpinlockIrqSave::new(drv)));
-						}
-						_ => {
-							warn!("Virtio device is NOT supported, skipping!");
-							return;
-						}
-					}
-				}
+			// virtio_pci_cap64 extends offset and length by offset_hi and length_hi
+			let region_offset =
+				u64::from(read_config(offset + 8)) | u64::from(read_config(offset + 16)) << 32;
+			let length =
+				u64::from(read_config(offset + 12)) | u64::from(read_config(offset + 20)) << 32;
+			debug!(
+				"Found shared memory region {} in bar {}, offset 0x{:x}, length 0x{:x}",
+				id,
+				barword & 0xFF,
+				region_offset,
+				length
+			);
+
+			match adapter.get_bar((barword & 0xFF) as u8) {
+				Some(PciBar::Memory(bar)) if region_offset + length <= bar.size as u64 => Some((
+					PhysAddr::from(bar.addr as u64 + region_offset),
+					length as usize,
+				)),
 				_ => {
-					warn!("Virtio device is NOT supported, skipping!");
-					return;
+					warn!("Shared memory region {} does not fit in its bar!", id);
+					None
 				}
 			}
-		}
-		0x105a => {
-			info!("Found Virtio-FS device!");
-			// TODO: check subclass
-			// TODO: proper error handling on driver creation fail
-			let drv = virtio_fs::create_virtiofs_driver(adapter).unwrap();
-			pci::register_driver(PciDriver::VirtioFs(SpinlockIrqSave::new(drv)));
-		}
-		_ => {
-			warn!("Virtio device is NOT supported, skipping!");
-			return;
-		}
-	};
+		})
+}
 
-	// Install interrupt handler
-	unsafe {
-		VIRTIO_IRQ_NO = adapter.irq;
+/// Assigns the MSI-X table entry `vector` to the virtqueue `index`.
+/// The vector has to be assigned before the queue is enabled.
+pub fn set_queue_vector(common_cfg: &mut virtio_pci_common_cfg, index: u16, vector: u16) {
+	common_cfg.queue_select = index;
+	common_cfg.queue_msix_vector = vector;
+	if unsafe { core::ptr::read_volatile(&common_cfg.queue_msix_vector) } != vector {
+		warn!(
+			"Unable to assign MSI-X vector {} to virtqueue {}",
+			vector, index
+		);
 	}
-	irq_install_handler(adapter.irq as u32, virtio_irqhandler as usize);
-	add_irq_name(adapter.irq as u32, "virtio");
 }
 
-/// Specifies the interrupt number of the virtio device
-static mut VIRTIO_IRQ_NO: u8 = 0;
+/// Splits a buffer into parts, which do not cross a page boundary. Only these
+/// parts are physically contiguous and can be passed to the device as one descriptor.
+pub fn page_segments(addr: usize, len: usize) -> Vec<(usize, usize)> {
+	let mut segments = Vec::new();
+	let mut offset = 0;
+
+	while offset < len {
+		let page_end = align_down!(addr + offset, BasePageSize::SIZE) + BasePageSize::SIZE;
+		let next = (page_end - addr).min(len);
+		segments.push((offset, next - offset));
+		offset = next;
+	}
 
-#[cfg(target_arch = "x86_64")]
-extern "x86-interrupt" fn virtio_irqhandler(_stack_frame: &mut ExceptionStackFrame) {
-	debug!("Receive virtio interrupt");
-	apic::eoi();
-	increment_irq_counter((32 + unsafe { VIRTIO_IRQ_NO }).into());
+	segments
+}
 
-	let check_scheduler = match get_network_driver() {
-		Some(driver) => driver.lock().handle_interrupt(),
-		_ => false,
-	};
+/// Registers the drivers of the virtio devices (see 4.1.2 PCI Device Discovery).
+/// Only modern devices are supported, the transitional device ids are not matched.
+pub fn register_drivers() {
+	pci::register_driver(&virtio_net::VirtioNetPciDriver);
+	pci::register_driver(&virtio_blk::VirtioBlkPciDriver);
+	pci::register_driver(&virtio_console::VirtioConsolePciDriver);
+	pci::register_driver(&virtio_fs::VirtioFsPciDriver);
+	pci::register_driver(&virtio_rng::VirtioRngPciDriver);
+	pci::register_driver(&virtio_balloon::VirtioBalloonPciDriver);
+	// the sockets of the device are file descriptors of event objects
+	#[cfg(not(feature = "newlib"))]
+	pci::register_driver(&virtio_vsock::VirtioVsockPciDriver);
+}
 
-	if check_scheduler {
-		core_scheduler().scheduler();
-	}
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn need_event() {
+	// the device asks for a notification, when the available index passes 5
+	assert!(vring_need_event(5, 6, 5));
+	assert!(vring_need_event(5, 8, 3));
+	assert!(!vring_need_event(5, 5, 3));
+	assert!(!vring_need_event(5, 9, 6));
+	// the indices wrap at 65536
+	assert!(vring_need_event(0xffff, 2, 0xfffe));
+	assert!(!vring_need_event(0xfffe, 2, 0xffff));
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn buffer_segments() {
+	// the buffer crosses a page boundary
+	assert_eq!(page_segments(0x1f00, 0x200), [(0, 0x100), (0x100, 0x100)]);
+	assert_eq!(page_segments(0x1000, 0x1000), [(0, 0x1000)]);
 }
@@ -0,0 +1,641 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Driver for virtio memory balloon devices (see 5.5 Traditional Memory Balloon Device
+//! of the virtio specification)
+//!
+//! The host sets the number of pages, which the guest has to give back. The driver
+//! inflates the balloon by allocating these frames from the physical memory and by
+//! passing their page frame numbers to the host. Deflating returns the frames to the
+//! physical memory. In addition, the driver periodically reports large free regions
+//! of the physical memory, whose content is discarded by the host (free page reporting).
+
+use crate::arch::percore::*;
+use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::processor::get_timer_ticks;
+use crate::arch::x86_64::kernel::virtio::{
+	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
+};
+use crate::arch::x86_64::mm::paging::{BasePageSize, LargePageSize, PageSize};
+use crate::arch::x86_64::mm::{physicalmem, PhysAddr};
+use crate::collections::irqsave;
+use crate::config::USER_STACK_SIZE;
+use crate::scheduler::task::LOW_PRIO;
+use crate::scheduler::PerCoreScheduler;
+use crate::synch::spinlock::SpinlockIrqSave;
+use crate::synch::waitqueue::WaitQueue;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::convert::TryInto;
+use core::sync::atomic::spin_loop_hint;
+use core::{fmt, mem, ptr};
+
+/// The host has to be told before the guest uses deflated pages
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1 << 0;
+/// The device has a queue for memory statistics
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+/// The device has a queue for free page hinting
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u64 = 1 << 3;
+/// The device has a queue for free page reporting
+const VIRTIO_BALLOON_F_PAGE_REPORTING: u64 = 1 << 5;
+
+/// Size of the pages, whose frame numbers are passed to the device
+const BALLOON_PAGE_SHIFT: usize = 12;
+/// Maximal number of frames, which are inflated or deflated at once (like Linux)
+const MAX_TRANSFER_FRAMES: usize = 256;
+/// Size of the free regions, which are reported to the host
+const REPORTING_SIZE: usize = LargePageSize::SIZE;
+/// Maximal number of regions, which are reported at once (like Linux)
+const MAX_REPORTED_REGIONS: usize = 32;
+/// Delay between two reports of free memory in microseconds
+const REPORTING_DELAY: u64 = 2_000_000;
+
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_balloon_config {
+	/// Number of pages, which the host wants to reclaim
+	num_pages: u32,
+	/// Number of pages, which the driver has given to the host
+	actual: u32,
+}
+
+/// Frames, which are processed by the inflate or the deflate queue
+struct Transfer {
+	inflate: bool,
+	/// Page frame numbers of the frames in the format of the device
+	pfns: Box<[u8]>,
+}
+
+impl Transfer {
+	fn frames(&self) -> impl Iterator<Item = PhysAddr> + '_ {
+		self.pfns.chunks_exact(mem::size_of::<u32>()).map(|pfn| {
+			let pfn = u32::from_le_bytes(pfn.try_into().unwrap());
+			PhysAddr(u64::from(pfn) << BALLOON_PAGE_SHIFT)
+		})
+	}
+}
+
+struct BalloonState<'a> {
+	device_cfg: &'a mut virtio_balloon_config,
+	inflateq: Option<Virtq<'a>>,
+	deflateq: Option<Virtq<'a>>,
+	reportq: Option<Virtq<'a>>,
+	/// Frames, which are owned by the host
+	frames: Vec<PhysAddr>,
+	transfer: Option<Transfer>,
+	/// Free regions, which are reported to the host
+	reports: Vec<PhysAddr>,
+}
+
+pub struct VirtioBalloonDriver<'a> {
+	common_cfg: &'a mut virtio_pci_common_cfg,
+	isr_cfg: &'a mut u32,
+	notify_cfg: VirtioNotification,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// Index of the reporting queue
+	reporting_queue: u16,
+	/// MSI-X table of the device, whose first entry is used by the queues and configuration changes
+	msix_table: Option<pci::MsixTable>,
+	state: SpinlockIrqSave<BalloonState<'a>>,
+	/// The balloon thread waits for completions and configuration changes
+	waiters: WaitQueue,
+}
+
+// The state is protected by a lock and the configuration is only changed during the initialization.
+unsafe impl Send for VirtioBalloonDriver<'_> {}
+unsafe impl Sync for VirtioBalloonDriver<'_> {}
+
+impl<'a> fmt::Debug for VirtioBalloonDriver<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "VirtioBalloonDriver {{ ")?;
+		write!(f, "common_cfg: {:?}, ", self.common_cfg)?;
+		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
+		write!(f, "notify_cfg: {:?}, ", self.notify_cfg)?;
+		write!(f, "features: 0x{:x}", self.features)?;
+		write!(f, " }}")
+	}
+}
+
+/// Returns the feature bits, which the driver accepts from `device_features`.
+/// The statistics and the free page hinting are not supported.
+fn select_features(device_features: u64) -> u64 {
+	let supported: u64 = VIRTIO_F_VERSION_1
+		| VIRTIO_F_RING_EVENT_IDX
+		| VIRTIO_F_RING_PACKED
+		| VIRTIO_BALLOON_F_MUST_TELL_HOST
+		| VIRTIO_BALLOON_F_PAGE_REPORTING;
+
+	device_features & supported
+}
+
+/// Returns the index of the reporting queue. The device (e.g. QEMU) creates the queues of
+/// the statistics and the free page hinting, if it offers them, even though the driver
+/// doesn't accept them. Therefore, the index is derived from `device_features`.
+fn reporting_queue_index(device_features: u64) -> u16 {
+	let mut index = 2;
+	if device_features & VIRTIO_BALLOON_F_STATS_VQ != 0 {
+		index += 1;
+	}
+	if device_features & VIRTIO_BALLOON_F_FREE_PAGE_HINT != 0 {
+		index += 1;
+	}
+
+	index
+}
+
+/// Returns the page frame numbers of `frames` as array of 32-bit little-endian integers.
+fn pfn_array(frames: &[PhysAddr]) -> Box<[u8]> {
+	let mut pfns = Vec::with_capacity(frames.len() * mem::size_of::<u32>());
+	for frame in frames {
+		let pfn: u32 = (frame.as_usize() >> BALLOON_PAGE_SHIFT).try_into().unwrap();
+		pfns.extend_from_slice(&pfn.to_le_bytes());
+	}
+
+	pfns.into_boxed_slice()
+}
+
+impl<'a> VirtioBalloonDriver<'a> {
+	pub fn init_vqs(&mut self) {
+		debug!("Setting up virtqueues...");
+
+		// configuration changes use the same interrupt as the queues
+		let vector = if self.msix_table.is_some() {
+			self.common_cfg.msix_config = 0;
+			0
+		} else {
+			VIRTIO_MSI_NO_VECTOR
+		};
+
+		// inflateq, deflateq and the reporting queue, if it is negotiated
+		let mut indices = vec![0, 1];
+		if self.features & VIRTIO_BALLOON_F_PAGE_REPORTING != 0 {
+			indices.push(self.reporting_queue);
+		}
+		let mut vqs = Vec::new();
+		for index in indices {
+			virtio::set_queue_vector(self.common_cfg, index, vector);
+			let vq =
+				Virtq::new_from_common(index, self.common_cfg, &mut self.notify_cfg, self.features);
+			if vq.is_none() {
+				error!("Unable to set up queue {} of the balloon device", index);
+			}
+			vqs.push(vq);
+		}
+
+		let mut vqs = vqs.into_iter();
+		let mut state = self.state.lock();
+		state.inflateq = vqs.next().flatten();
+		state.deflateq = vqs.next().flatten();
+		state.reportq = vqs.next().flatten();
+	}
+
+	pub fn negotiate_features(&mut self) {
+		let common_cfg = &mut self.common_cfg;
+		// Linux kernel reads 2x32 featurebits: https://elixir.bootlin.com/linux/latest/ident/vp_get_features
+		common_cfg.device_feature_select = 0;
+		let mut device_features: u64 = common_cfg.device_feature as u64;
+		common_cfg.device_feature_select = 1;
+		device_features |= (common_cfg.device_feature as u64) << 32;
+
+		let features = select_features(device_features);
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
+		self.reporting_queue = reporting_queue_index(device_features);
+
+		info!(
+			"Virtio features: device 0x{:x}, accepted 0x{:x}",
+			device_features, features
+		);
+	}
+
+	/// 3.1 VirtIO Device Initialization
+	pub fn init(&mut self) {
+		// 1. Reset the device.
+		self.common_cfg.device_status = 0;
+
+		// 2. Set the ACKNOWLEDGE status bit: the guest OS has notice the device.
+		self.common_cfg.device_status |= 1;
+
+		// 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+		self.common_cfg.device_status |= 2;
+
+		// 4. Read device feature bits, and write the subset of feature bits understood by the OS and driver to the device.
+		self.negotiate_features();
+
+		// 5. Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
+		self.common_cfg.device_status |= 8;
+
+		// 6. Re-read device status to ensure the FEATURES_OK bit is still set:
+		//   otherwise, the device does not support our subset of features and the device is unusable.
+		if self.common_cfg.device_status & 8 == 0 {
+			error!("Device unset FEATURES_OK, aborting!");
+			return;
+		}
+
+		// 7. Perform device-specific setup, including discovery of virtqueues for the device.
+		self.init_vqs();
+
+		// 8. Set the DRIVER_OK status bit. At this point the device is “live”.
+		self.common_cfg.device_status |= 4;
+	}
+
+	/// Completes the requests of the device, moves the balloon towards the size,
+	/// which is requested by the host, and reports free memory.
+	fn process(&self) {
+		let mut guard = self.state.lock();
+		let state = &mut *guard;
+
+		self.complete_transfer(state);
+		if state.transfer.is_none() {
+			let num_pages = unsafe { ptr::read_volatile(&state.device_cfg.num_pages) };
+			let target = u32::from_le(num_pages) as usize;
+			let actual = state.frames.len();
+			if target > actual {
+				self.inflate(state, target - actual);
+			} else if target < actual {
+				self.deflate(state, actual - target);
+			}
+		}
+
+		self.report_free_memory(state);
+	}
+
+	fn complete_transfer(&self, state: &mut BalloonState<'a>) {
+		let vq = match &state.transfer {
+			Some(transfer) if transfer.inflate => state.inflateq.as_mut(),
+			Some(_) => state.deflateq.as_mut(),
+			None => None,
+		};
+		let vq = match vq {
+			Some(vq) => vq,
+			None => return,
+		};
+		match vq.pop_used_buffer() {
+			Some((id, _)) => vq.release_chain(id),
+			None => return,
+		}
+
+		let transfer = state.transfer.take().unwrap();
+		if transfer.inflate {
+			state.frames.extend(transfer.frames());
+		} else {
+			// the host has been told about the deflation (VIRTIO_BALLOON_F_MUST_TELL_HOST)
+			for frame in transfer.frames() {
+				physicalmem::deallocate(frame, BasePageSize::SIZE);
+			}
+		}
+
+		let actual: u32 = state.frames.len().try_into().unwrap();
+		unsafe {
+			ptr::write_volatile(&mut state.device_cfg.actual, actual.to_le());
+		}
+	}
+
+	/// Gives up to `count` frames of the physical memory to the host.
+	fn inflate(&self, state: &mut BalloonState<'a>, count: usize) {
+		let mut frames = Vec::new();
+		for _ in 0..count.min(MAX_TRANSFER_FRAMES) {
+			match physicalmem::allocate(BasePageSize::SIZE) {
+				Ok(frame) => frames.push(frame),
+				Err(()) => {
+					debug!("Unable to inflate the balloon, the physical memory is exhausted");
+					break;
+				}
+			}
+		}
+
+		if let Err(frames) = self.start_transfer(state, true, frames) {
+			for frame in frames {
+				physicalmem::deallocate(frame, BasePageSize::SIZE);
+			}
+		}
+	}
+
+	/// Takes up to `count` frames back from the host.
+	fn deflate(&self, state: &mut BalloonState<'a>, count: usize) {
+		let start = state.frames.len() - count.min(MAX_TRANSFER_FRAMES);
+		let frames = state.frames.split_off(start);
+
+		if let Err(mut frames) = self.start_transfer(state, false, frames) {
+			state.frames.append(&mut frames);
+		}
+	}
+
+	/// Passes the page frame numbers of `frames` to the inflate or deflate queue.
+	/// Returns the frames, if the transfer is not possible.
+	fn start_transfer(
+		&self,
+		state: &mut BalloonState<'a>,
+		inflate: bool,
+		frames: Vec<PhysAddr>,
+	) -> Result<(), Vec<PhysAddr>> {
+		let vq = if inflate {
+			state.inflateq.as_mut()
+		} else {
+			state.deflateq.as_mut()
+		};
+		let vq = match vq {
+			Some(vq) if !frames.is_empty() => vq,
+			_ => return Err(frames),
+		};
+
+		let pfns = pfn_array(&frames);
+		let addr = pfns.as_ptr() as usize;
+		let segments: Vec<&[u8]> = virtio::page_segments(addr, pfns.len())
+			.into_iter()
+			.map(|(offset, len)| &pfns[offset..offset + len])
+			.collect();
+		if vq.send_chain(&segments, &[]).is_err() {
+			return Err(frames);
+		}
+		drop(segments);
+
+		state.transfer = Some(Transfer { inflate, pfns });
+		Ok(())
+	}
+
+	/// Waits until the host has processed the reported regions and returns them to the
+	/// physical memory. Returns false, if no regions are reported or the state is locked
+	/// (e.g. by the caller, whose allocation has failed).
+	pub fn reclaim_reported_memory(&self) -> bool {
+		let mut guard = match self.state.try_lock() {
+			Some(guard) => guard,
+			None => return false,
+		};
+		let state = &mut *guard;
+		let vq = match state.reportq.as_mut() {
+			Some(vq) if !state.reports.is_empty() => vq,
+			_ => return false,
+		};
+
+		let id = loop {
+			if let Some((id, _)) = vq.pop_used_buffer() {
+				break id;
+			}
+			spin_loop_hint();
+		};
+		vq.release_chain(id);
+		for region in state.reports.drain(..) {
+			physicalmem::deallocate_reported(region, REPORTING_SIZE);
+		}
+
+		true
+	}
+
+	/// Returns the reported regions to the physical memory and reports further
+	/// free regions, which haven't been reported yet.
+	fn report_free_memory(&self, state: &mut BalloonState<'a>) {
+		let vq = match state.reportq.as_mut() {
+			Some(vq) => vq,
+			None => return,
+		};
+
+		if !state.reports.is_empty() {
+			match vq.pop_used_buffer() {
+				Some((id, _)) => vq.release_chain(id),
+				None => return,
+			}
+			for region in state.reports.drain(..) {
+				physicalmem::deallocate_reported(region, REPORTING_SIZE);
+			}
+		}
+
+		while state.reports.len() < MAX_REPORTED_REGIONS {
+			match physicalmem::allocate_unreported(REPORTING_SIZE) {
+				Ok(region) => state.reports.push(region),
+				Err(()) => break,
+			}
+		}
+		if state.reports.is_empty() {
+			return;
+		}
+
+		let regions: Vec<(PhysAddr, usize)> = state
+			.reports
+			.iter()
+			.map(|region| (*region, REPORTING_SIZE))
+			.collect();
+		if vq.send_physical_chain(&regions).is_err() {
+			for region in state.reports.drain(..) {
+				physicalmem::deallocate(region, REPORTING_SIZE);
+			}
+		}
+	}
+}
+
+impl pci::PciDevice for VirtioBalloonDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	fn handle_interrupt(&self) -> bool {
+		// With MSI-X, the device does not use the ISR status and the interrupt is not shared.
+		// Otherwise, bit 0 signals used buffers and bit 1 a configuration change.
+		if self.msix_table.is_none() {
+			let isr_status = unsafe { ptr::read_volatile(&*self.isr_cfg) };
+			if isr_status & 0x3 == 0 {
+				return false;
+			}
+		}
+
+		// The frames are allocated and released by the balloon thread.
+		self.waiters.wakeup_all();
+
+		true
+	}
+}
+
+extern "C" fn balloon_thread(_arg: usize) {
+	debug!("Enter balloon thread");
+
+	let driver = match pci::get_balloon_driver() {
+		Some(driver) => driver,
+		None => return,
+	};
+	let core_scheduler = core_scheduler();
+	let task = core_scheduler.get_current_task_handle();
+
+	loop {
+		let generation = driver.waiters.generation();
+		driver.process();
+
+		// The task has to be blocked before it registers on the queue.
+		// Otherwise, an interrupt in between would get lost.
+		irqsave(|| {
+			core_scheduler.block_current_task(Some(get_timer_ticks() + REPORTING_DELAY));
+			driver.waiters.register(task);
+
+			if driver.waiters.generation() != generation {
+				core_scheduler.custom_wakeup(task);
+			}
+		});
+
+		// Switch to the next task.
+		core_scheduler.reschedule();
+
+		// We may have been woken up by the timer.
+		driver.waiters.unregister(task.get_id());
+	}
+}
+
+/// PCI driver of virtio memory balloon devices
+pub struct VirtioBalloonPciDriver;
+
+impl pci::PciDriver for VirtioBalloonPciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-balloon"
+	}
+
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x1045)]
+	}
+
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtioballoon_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
+	}
+}
+
+pub fn create_virtioballoon_driver(
+	adapter: &pci::PciAdapter,
+) -> Option<VirtioBalloonDriver<'static>> {
+	// Scan capabilities to get common config, which we need to reset the device and get basic info.
+	let bus = adapter.bus;
+	let device = adapter.devfn;
+	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
+
+	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
+	if status & pci::PCI_STATUS_CAPABILITIES_LIST == 0 {
+		error!("Found virtio device without capability list. Likely legacy-device! Aborting.");
+		return None;
+	}
+
+	// Get pointer to capability list
+	let caplist = pci::read_config(bus, device, pci::PCI_CAPABILITY_LIST_REGISTER) & 0xFF;
+
+	// get common config mapped, cast to virtio_pci_common_cfg
+	let common_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_COMMON_CFG) {
+			Some((cap_common_raw, _)) => unsafe {
+				&mut *(cap_common_raw.as_mut_ptr::<virtio_pci_common_cfg>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_COMMON_CFG. Aborting!");
+				return None;
+			}
+		};
+	// get device config mapped, cast to virtio_balloon_config
+	let device_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_DEVICE_CFG) {
+			Some((cap_device_raw, _)) => unsafe {
+				&mut *(cap_device_raw.as_mut_ptr::<virtio_balloon_config>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_DEVICE_CFG. Aborting!");
+				return None;
+			}
+		};
+	let isr_cfg = match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_ISR_CFG)
+	{
+		Some((cap_isr_raw, _)) => unsafe { &mut *(cap_isr_raw.as_mut_ptr::<u32>()) },
+		None => {
+			error!("Could not find VIRTIO_PCI_CAP_ISR_CFG. Aborting!");
+			return None;
+		}
+	};
+	// get device notifications mapped
+	let (notification_ptr, notify_off_multiplier) =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_NOTIFY_CFG) {
+			Some((cap_notification_raw, notify_off_multiplier)) => (
+				cap_notification_raw.as_mut_ptr::<u16>(),
+				notify_off_multiplier,
+			),
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_NOTIFY_CFG. Aborting!");
+				return None;
+			}
+		};
+	let notify_cfg = VirtioNotification {
+		notification_ptr,
+		notify_off_multiplier,
+	};
+
+	let mut drv = VirtioBalloonDriver {
+		common_cfg,
+		isr_cfg,
+		notify_cfg,
+		features: 0,
+		reporting_queue: 0,
+		msix_table: adapter.enable_msix(),
+		state: SpinlockIrqSave::new(BalloonState {
+			device_cfg,
+			inflateq: None,
+			deflateq: None,
+			reportq: None,
+			frames: Vec::new(),
+			transfer: None,
+			reports: Vec::new(),
+		}),
+		waiters: WaitQueue::new(),
+	};
+
+	trace!("Driver before init: {:?}", drv);
+	drv.init();
+	trace!("Driver after init: {:?}", drv);
+
+	// The thread finds the driver in the list of devices, which is extended after the probe.
+	PerCoreScheduler::spawn(balloon_thread, 0, LOW_PRIO, 0, USER_STACK_SIZE);
+	info!(
+		"Virtio-Balloon device is ready, free page reporting is {}",
+		if drv.features & VIRTIO_BALLOON_F_PAGE_REPORTING != 0 {
+			"enabled"
+		} else {
+			"disabled"
+		}
+	);
+
+	Some(drv)
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn page_frame_numbers() {
+	let frames = [PhysAddr(0x1000), PhysAddr(0x1234_5000)];
+	let transfer = Transfer {
+		inflate: true,
+		pfns: pfn_array(&frames),
+	};
+	assert_eq!(&*transfer.pfns, &[1, 0, 0, 0, 0x45, 0x23, 0x01, 0][..]);
+	assert!(transfer.frames().eq(frames.iter().copied()));
+
+	// the statistics and the free page hinting aren't negotiated
+	assert_eq!(
+		select_features(u64::max_value()),
+		VIRTIO_F_VERSION_1
+			| VIRTIO_F_RING_EVENT_IDX
+			| VIRTIO_F_RING_PACKED
+			| VIRTIO_BALLOON_F_MUST_TELL_HOST
+			| VIRTIO_BALLOON_F_PAGE_REPORTING
+	);
+
+	// the queues of the statistics and the free page hinting precede the reporting queue
+	assert_eq!(reporting_queue_index(VIRTIO_BALLOON_F_PAGE_REPORTING), 2);
+	assert_eq!(
+		reporting_queue_index(VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_PAGE_REPORTING),
+		3
+	);
+	assert_eq!(reporting_queue_index(u64::max_value()), 4);
+}
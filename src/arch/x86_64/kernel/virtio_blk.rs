@@ -0,0 +1,729 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Driver for virtio block devices (see 5.2 Block Device of the virtio specification)
+
+#![allow(unused)]
+
+use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::percore::core_scheduler;
+use crate::arch::x86_64::kernel::virtio::{
+	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
+};
+use crate::arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use crate::drivers::block::{BlockDevice, BlockError};
+use crate::scheduler::task::TaskHandle;
+use crate::synch::spinlock::SpinlockIrqSave;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::{fmt, mem, ptr, slice};
+
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 2;
+const VIRTIO_BLK_F_GEOMETRY: u64 = 4;
+const VIRTIO_BLK_F_RO: u64 = 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 9;
+const VIRTIO_BLK_F_TOPOLOGY: u64 = 10;
+const VIRTIO_BLK_F_CONFIG_WCE: u64 = 11;
+const VIRTIO_BLK_F_MQ: u64 = 12;
+const VIRTIO_BLK_F_DISCARD: u64 = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 14;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// Status of a request, which has not been completed by the device
+const VIRTIO_BLK_S_PENDING: u8 = 0xFF;
+
+/// Requests address the device in sectors of 512 bytes, independent of the block size
+const VIRTIO_BLK_SECTOR_SIZE: usize = 512;
+/// The header and the status of a request occupy one descriptor each
+const VIRTIO_BLK_REQUEST_DESCRIPTORS: usize = 2;
+
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_blk_geometry {
+	cylinders: u16,
+	heads: u8,
+	sectors: u8,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_blk_topology {
+	// # of logical blocks per physical block (log2)
+	physical_block_exp: u8,
+	// offset of first aligned logical block
+	alignment_offset: u8,
+	// suggested minimum I/O size in blocks
+	min_io_size: u16,
+	// optimal (suggested maximum) I/O size in blocks
+	opt_io_size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_blk_config {
+	// capacity in sectors of 512 bytes
+	capacity: u64,
+	size_max: u32,
+	seg_max: u32,
+	geometry: virtio_blk_geometry,
+	blk_size: u32,
+	topology: virtio_blk_topology,
+	writeback: u8,
+	unused0: [u8; 3],
+	max_discard_sectors: u32,
+	max_discard_seg: u32,
+	discard_sector_alignment: u32,
+	max_write_zeroes_sectors: u32,
+	max_write_zeroes_seg: u32,
+	write_zeroes_may_unmap: u8,
+	unused1: [u8; 3],
+}
+
+/// Header of every request, which is followed by the data and the status byte
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_blk_req_header {
+	type_: u32,
+	reserved: u32,
+	sector: u64,
+}
+
+/// Data of discard and write zeroes requests
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_blk_discard_write_zeroes {
+	sector: u64,
+	num_sectors: u32,
+	flags: u32,
+}
+
+/// Returns the memory representation of a device structure.
+fn as_bytes<T>(value: &T) -> &[u8] {
+	unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Splits the buffer at `addr` with `len` bytes into segments, which do not cross a page
+/// boundary and contain at most `max_size` bytes. Returns the lengths of the segments.
+fn segment_lengths(addr: usize, len: usize, max_size: usize) -> Vec<usize> {
+	let mut lengths = Vec::new();
+	let mut addr = addr;
+	let end = addr + len;
+
+	while addr < end {
+		let page_end = align_down!(addr, BasePageSize::SIZE) + BasePageSize::SIZE;
+		let next = page_end.min(end).min(addr + max_size);
+		lengths.push(next - addr);
+		addr = next;
+	}
+
+	lengths
+}
+
+/// Request and its buffers, which have to stay valid until the device has completed it
+struct BlkRequest<'b> {
+	header: Box<virtio_blk_req_header>,
+	/// Device-readable data
+	data_out: Vec<&'b [u8]>,
+	/// Device-writable data
+	data_in: Vec<&'b mut [u8]>,
+	status: Box<u8>,
+}
+
+impl<'b> BlkRequest<'b> {
+	fn new(type_: u32, sector: u64) -> Self {
+		Self {
+			header: Box::new(virtio_blk_req_header {
+				type_,
+				reserved: 0,
+				sector,
+			}),
+			data_out: Vec::new(),
+			data_in: Vec::new(),
+			status: Box::new(VIRTIO_BLK_S_PENDING),
+		}
+	}
+
+	fn result(&self) -> Result<(), BlockError> {
+		match unsafe { ptr::read_volatile(&*self.status) } {
+			VIRTIO_BLK_S_OK => Ok(()),
+			VIRTIO_BLK_S_UNSUPP => Err(BlockError::Unsupported),
+			_ => Err(BlockError::Io),
+		}
+	}
+}
+
+/// Task, which waits for the completion of its requests
+struct Waiter {
+	remaining: usize,
+	task: TaskHandle,
+}
+
+/// Request queue and the requests, which are processed by the device
+struct BlkQueue<'a> {
+	vq: Virtq<'a>,
+	/// Maps the descriptor chain of a submitted request to its waiter
+	in_flight: BTreeMap<u32, usize>,
+	waiters: BTreeMap<usize, Waiter>,
+	next_waiter: usize,
+}
+
+impl BlkQueue<'_> {
+	/// Wakes up the tasks, whose requests have been completed by the device.
+	fn complete_requests(&mut self) {
+		loop {
+			while let Some((id, _)) = self.vq.pop_used_buffer() {
+				self.vq.release_chain(id);
+
+				let key = match self.in_flight.remove(&id) {
+					Some(key) => key,
+					None => {
+						warn!("Virtio-Blk device completed unknown request {}", id);
+						continue;
+					}
+				};
+				let done = match self.waiters.get_mut(&key) {
+					Some(waiter) => {
+						waiter.remaining -= 1;
+						waiter.remaining == 0
+					}
+					None => false,
+				};
+				if done {
+					let waiter = self.waiters.remove(&key).unwrap();
+					core_scheduler().custom_wakeup(waiter.task);
+				}
+			}
+
+			// enable the interrupts again and check for requests, which have been completed in the meantime
+			self.vq.set_polling_mode(false);
+			if !self.vq.has_packet() {
+				break;
+			}
+		}
+	}
+}
+
+pub struct VirtioBlkDriver<'a> {
+	common_cfg: &'a mut virtio_pci_common_cfg,
+	device_cfg: &'a virtio_blk_config,
+	isr_cfg: &'a mut u32,
+	notify_cfg: VirtioNotification,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// MSI-X table of the device, whose first entry is used by the request queue
+	msix_table: Option<pci::MsixTable>,
+	queue: Option<SpinlockIrqSave<BlkQueue<'a>>>,
+	/// Maximum number of data segments of a request
+	max_segments: usize,
+	/// Maximum size of a data segment
+	max_segment_size: usize,
+}
+
+// The request queue is protected by a lock and the configuration is only changed during the initialization.
+unsafe impl Send for VirtioBlkDriver<'_> {}
+unsafe impl Sync for VirtioBlkDriver<'_> {}
+
+impl<'a> fmt::Debug for VirtioBlkDriver<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "VirtioBlkDriver {{ ")?;
+		write!(f, "common_cfg: {:?}, ", self.common_cfg)?;
+		write!(f, "device_cfg: {:?}, ", self.device_cfg)?;
+		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
+		write!(f, "notify_cfg: {:?}, ", self.notify_cfg)?;
+		match &self.queue {
+			None => write!(f, "Uninitialized VQ")?,
+			Some(_) => write!(f, "Initialized VQ")?,
+		}
+		write!(f, " }}")
+	}
+}
+
+/// Returns the feature bits, which the driver accepts from `device_features`.
+fn select_features(device_features: u64) -> u64 {
+	let supported: u64 = VIRTIO_F_VERSION_1
+		| VIRTIO_F_RING_EVENT_IDX
+		| VIRTIO_F_RING_PACKED
+		| (1 << VIRTIO_BLK_F_SIZE_MAX)
+		| (1 << VIRTIO_BLK_F_SEG_MAX)
+		| (1 << VIRTIO_BLK_F_RO)
+		| (1 << VIRTIO_BLK_F_BLK_SIZE)
+		| (1 << VIRTIO_BLK_F_FLUSH)
+		| (1 << VIRTIO_BLK_F_DISCARD);
+
+	device_features & supported
+}
+
+impl<'a> VirtioBlkDriver<'a> {
+	pub fn init_vqs(&mut self) {
+		debug!("Setting up virtqueues...");
+
+		if self.msix_table.is_some() {
+			// we are not interested in configuration changes
+			self.common_cfg.msix_config = VIRTIO_MSI_NO_VECTOR;
+			virtio::set_queue_vector(self.common_cfg, 0, 0);
+		}
+
+		// without VIRTIO_BLK_F_MQ, the device has only one request queue
+		let vq =
+			match Virtq::new_from_common(0, self.common_cfg, &mut self.notify_cfg, self.features) {
+				Some(vq) => vq,
+				None => {
+					error!("Virtio-Blk device has no request queue. Aborting!");
+					return;
+				}
+			};
+		let queue_size = usize::from(self.common_cfg.queue_size);
+
+		// Without indirect descriptors, the descriptors of a request have to fit into the queue.
+		let mut max_segments = queue_size.saturating_sub(VIRTIO_BLK_REQUEST_DESCRIPTORS);
+		if self.features & (1 << VIRTIO_BLK_F_SEG_MAX) != 0 && self.device_cfg.seg_max > 0 {
+			max_segments = max_segments.min(self.device_cfg.seg_max as usize);
+		}
+		self.max_segments = max_segments.max(1);
+		self.max_segment_size =
+			if self.features & (1 << VIRTIO_BLK_F_SIZE_MAX) != 0 && self.device_cfg.size_max > 0 {
+				(self.device_cfg.size_max as usize).min(BasePageSize::SIZE)
+			} else {
+				BasePageSize::SIZE
+			};
+
+		self.queue = Some(SpinlockIrqSave::new(BlkQueue {
+			vq,
+			in_flight: BTreeMap::new(),
+			waiters: BTreeMap::new(),
+			next_waiter: 0,
+		}));
+	}
+
+	pub fn negotiate_features(&mut self) {
+		let common_cfg = &mut self.common_cfg;
+		// Linux kernel reads 2x32 featurebits: https://elixir.bootlin.com/linux/latest/ident/vp_get_features
+		common_cfg.device_feature_select = 0;
+		let mut device_features: u64 = common_cfg.device_feature as u64;
+		common_cfg.device_feature_select = 1;
+		device_features |= (common_cfg.device_feature as u64) << 32;
+
+		let features = select_features(device_features);
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
+
+		info!(
+			"Virtio features: device 0x{:x}, accepted 0x{:x}",
+			device_features, features
+		);
+	}
+
+	/// 3.1 VirtIO Device Initialization
+	pub fn init(&mut self) {
+		// 1. Reset the device.
+		self.common_cfg.device_status = 0;
+
+		// 2. Set the ACKNOWLEDGE status bit: the guest OS has notice the device.
+		self.common_cfg.device_status |= 1;
+
+		// 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+		self.common_cfg.device_status |= 2;
+
+		// 4. Read device feature bits, and write the subset of feature bits understood by the OS and driver to the device.
+		self.negotiate_features();
+
+		// 5. Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
+		self.common_cfg.device_status |= 8;
+
+		// 6. Re-read device status to ensure the FEATURES_OK bit is still set:
+		//   otherwise, the device does not support our subset of features and the device is unusable.
+		if self.common_cfg.device_status & 8 == 0 {
+			error!("Device unset FEATURES_OK, aborting!");
+			return;
+		}
+
+		// 7. Perform device-specific setup, including discovery of virtqueues for the device.
+		self.init_vqs();
+
+		// 8. Set the DRIVER_OK status bit. At this point the device is “live”.
+		self.common_cfg.device_status |= 4;
+	}
+
+	/// Returns the maximum number of bytes, which are transferred by one request.
+	fn get_max_request_size(&self) -> usize {
+		// A buffer, which is not page aligned, needs one additional segment.
+		let size = (self.max_segments - 1).max(1) * self.max_segment_size;
+
+		align_down!(size, self.block_size()).max(self.block_size())
+	}
+
+	/// Checks the range of a transfer and returns its first sector.
+	fn get_sector(&self, block: u64, len: usize) -> Result<u64, BlockError> {
+		let block_size = self.block_size();
+		if len % block_size != 0 {
+			return Err(BlockError::InvalidArgument);
+		}
+
+		match block.checked_add((len / block_size) as u64) {
+			Some(end) if end <= self.block_count() => {
+				Ok(block * (block_size / VIRTIO_BLK_SECTOR_SIZE) as u64)
+			}
+			_ => Err(BlockError::InvalidArgument),
+		}
+	}
+
+	/// Submits the requests and blocks the current task until the device has completed all of them.
+	/// If the queue is full, the requests are submitted in several rounds.
+	fn execute(&self, requests: &mut [BlkRequest<'_>]) -> Result<(), BlockError> {
+		let queue = self.queue.as_ref().ok_or(BlockError::Io)?;
+		let mut next = 0;
+
+		while next < requests.len() {
+			let mut guard = queue.lock();
+			let key = guard.next_waiter;
+			guard.next_waiter = guard.next_waiter.wrapping_add(1);
+
+			let mut submitted = 0;
+			for request in requests[next..].iter_mut() {
+				let mut data_out: Vec<&[u8]> = Vec::with_capacity(request.data_out.len() + 1);
+				data_out.push(as_bytes(&*request.header));
+				data_out.extend(request.data_out.iter().map(|data| &**data));
+				let mut data_in: Vec<&mut [u8]> =
+					request.data_in.iter_mut().map(|data| &mut **data).collect();
+				data_in.push(slice::from_mut(&mut *request.status));
+
+				match guard.vq.send_chain(&data_out, &data_in) {
+					Ok(id) => {
+						guard.in_flight.insert(id, key);
+						submitted += 1;
+					}
+					Err(()) => break,
+				}
+			}
+
+			if submitted == 0 {
+				// the queue is full => wait until other requests are completed
+				drop(guard);
+				core_scheduler().reschedule();
+				continue;
+			}
+			next += submitted;
+
+			// Block the current task, until the interrupt handler has seen all completions.
+			let core_scheduler = core_scheduler();
+			core_scheduler.block_current_task(None);
+			guard.waiters.insert(
+				key,
+				Waiter {
+					remaining: submitted,
+					task: core_scheduler.get_current_task_handle(),
+				},
+			);
+
+			// release lock
+			drop(guard);
+
+			// Switch to the next task.
+			core_scheduler.reschedule();
+		}
+
+		requests.iter().try_for_each(|request| request.result())
+	}
+}
+
+impl BlockDevice for VirtioBlkDriver<'_> {
+	fn block_size(&self) -> usize {
+		if self.features & (1 << VIRTIO_BLK_F_BLK_SIZE) != 0 && self.device_cfg.blk_size > 0 {
+			self.device_cfg.blk_size as usize
+		} else {
+			VIRTIO_BLK_SECTOR_SIZE
+		}
+	}
+
+	fn block_count(&self) -> u64 {
+		let capacity = unsafe { ptr::read_volatile(&self.device_cfg.capacity) };
+
+		capacity / (self.block_size() / VIRTIO_BLK_SECTOR_SIZE) as u64
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.features & (1 << VIRTIO_BLK_F_RO) != 0
+	}
+
+	fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+		let sector = self.get_sector(block, buf.len())?;
+		let max_request_size = self.get_max_request_size();
+
+		let mut requests = Vec::new();
+		for (i, chunk) in buf.chunks_mut(max_request_size).enumerate() {
+			let offset = (i * max_request_size / VIRTIO_BLK_SECTOR_SIZE) as u64;
+			let mut request = BlkRequest::new(VIRTIO_BLK_T_IN, sector + offset);
+
+			let lengths =
+				segment_lengths(chunk.as_ptr() as usize, chunk.len(), self.max_segment_size);
+			let mut rest = chunk;
+			for len in lengths {
+				let (segment, tail) = mem::take(&mut rest).split_at_mut(len);
+				request.data_in.push(segment);
+				rest = tail;
+			}
+
+			requests.push(request);
+		}
+
+		self.execute(&mut requests)
+	}
+
+	fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), BlockError> {
+		if self.is_read_only() {
+			return Err(BlockError::ReadOnly);
+		}
+
+		let sector = self.get_sector(block, buf.len())?;
+		let max_request_size = self.get_max_request_size();
+
+		let mut requests = Vec::new();
+		for (i, chunk) in buf.chunks(max_request_size).enumerate() {
+			let offset = (i * max_request_size / VIRTIO_BLK_SECTOR_SIZE) as u64;
+			let mut request = BlkRequest::new(VIRTIO_BLK_T_OUT, sector + offset);
+
+			let mut rest = chunk;
+			for len in segment_lengths(chunk.as_ptr() as usize, chunk.len(), self.max_segment_size)
+			{
+				let (segment, tail) = rest.split_at(len);
+				request.data_out.push(segment);
+				rest = tail;
+			}
+
+			requests.push(request);
+		}
+
+		self.execute(&mut requests)
+	}
+
+	fn flush(&self) -> Result<(), BlockError> {
+		// Without VIRTIO_BLK_F_FLUSH, the device does not cache written data.
+		if self.features & (1 << VIRTIO_BLK_F_FLUSH) == 0 {
+			return Ok(());
+		}
+
+		self.execute(&mut [BlkRequest::new(VIRTIO_BLK_T_FLUSH, 0)])
+	}
+
+	fn discard(&self, block: u64, count: u64) -> Result<(), BlockError> {
+		if self.features & (1 << VIRTIO_BLK_F_DISCARD) == 0 {
+			return Err(BlockError::Unsupported);
+		}
+		if self.is_read_only() {
+			return Err(BlockError::ReadOnly);
+		}
+
+		let sectors_per_block = (self.block_size() / VIRTIO_BLK_SECTOR_SIZE) as u64;
+		let len = count
+			.checked_mul(self.block_size() as u64)
+			.ok_or(BlockError::InvalidArgument)?;
+		let mut sector = self.get_sector(block, len as usize)?;
+		let end = sector + count * sectors_per_block;
+
+		// every request discards one range, which is limited by max_discard_sectors
+		let max_sectors = match self.device_cfg.max_discard_sectors {
+			0 => u64::from(u32::MAX),
+			max => u64::from(max),
+		};
+		let mut ranges = Vec::new();
+		while sector < end {
+			let num_sectors = (end - sector).min(max_sectors);
+			ranges.push(virtio_blk_discard_write_zeroes {
+				sector,
+				num_sectors: num_sectors as u32,
+				flags: 0,
+			});
+			sector += num_sectors;
+		}
+
+		let mut requests: Vec<BlkRequest<'_>> = ranges
+			.iter()
+			.map(|range| {
+				let mut request = BlkRequest::new(VIRTIO_BLK_T_DISCARD, 0);
+				request.data_out.push(as_bytes(range));
+				request
+			})
+			.collect();
+
+		self.execute(&mut requests)
+	}
+}
+
+impl pci::PciDevice for VirtioBlkDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	fn handle_interrupt(&self) -> bool {
+		// With MSI-X, the device does not use the ISR status and the interrupt is not shared.
+		if self.msix_table.is_none() {
+			let isr_status = unsafe { ptr::read_volatile(&*self.isr_cfg) };
+			if isr_status & 0x1 == 0 {
+				return false;
+			}
+		}
+
+		if let Some(queue) = &self.queue {
+			queue.lock().complete_requests();
+		}
+
+		true
+	}
+
+	/// Writes the volatile cache of the device back to the disk.
+	fn shutdown(&self) {
+		if self.flush().is_err() {
+			warn!("Unable to flush the virtio-blk device");
+		}
+	}
+}
+
+/// PCI driver of virtio block devices
+pub struct VirtioBlkPciDriver;
+
+impl pci::PciDriver for VirtioBlkPciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-blk"
+	}
+
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x1042)]
+	}
+
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtioblk_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
+	}
+}
+
+pub fn create_virtioblk_driver(adapter: &pci::PciAdapter) -> Option<VirtioBlkDriver<'static>> {
+	// Scan capabilities to get common config, which we need to reset the device and get basic info.
+	let bus = adapter.bus;
+	let device = adapter.devfn;
+	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
+
+	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
+	if status & pci::PCI_STATUS_CAPABILITIES_LIST == 0 {
+		error!("Found virtio device without capability list. Likely legacy-device! Aborting.");
+		return None;
+	}
+
+	// Get pointer to capability list
+	let caplist = pci::read_config(bus, device, pci::PCI_CAPABILITY_LIST_REGISTER) & 0xFF;
+
+	// get common config mapped, cast to virtio_pci_common_cfg
+	let common_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_COMMON_CFG) {
+			Some((cap_common_raw, _)) => unsafe {
+				&mut *(cap_common_raw.as_mut_ptr::<virtio_pci_common_cfg>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_COMMON_CFG. Aborting!");
+				return None;
+			}
+		};
+	// get device config mapped, cast to virtio_blk_config
+	let device_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_DEVICE_CFG) {
+			Some((cap_device_raw, _)) => unsafe {
+				&*(cap_device_raw.as_ptr::<virtio_blk_config>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_DEVICE_CFG. Aborting!");
+				return None;
+			}
+		};
+	let isr_cfg = match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_ISR_CFG)
+	{
+		Some((cap_isr_raw, _)) => unsafe { &mut *(cap_isr_raw.as_mut_ptr::<u32>()) },
+		None => {
+			error!("Could not find VIRTIO_PCI_CAP_ISR_CFG. Aborting!");
+			return None;
+		}
+	};
+	// get device notifications mapped
+	let (notification_ptr, notify_off_multiplier) =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_NOTIFY_CFG) {
+			Some((cap_notification_raw, notify_off_multiplier)) => (
+				cap_notification_raw.as_mut_ptr::<u16>(),
+				notify_off_multiplier,
+			),
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_NOTIFY_CFG. Aborting!");
+				return None;
+			}
+		};
+	let notify_cfg = VirtioNotification {
+		notification_ptr,
+		notify_off_multiplier,
+	};
+
+	let mut drv = VirtioBlkDriver {
+		common_cfg,
+		device_cfg,
+		isr_cfg,
+		notify_cfg,
+		features: 0,
+		msix_table: adapter.enable_msix(),
+		queue: None,
+		max_segments: 1,
+		max_segment_size: BasePageSize::SIZE,
+	};
+
+	trace!("Driver before init: {:?}", drv);
+	drv.init();
+	trace!("Driver after init: {:?}", drv);
+
+	info!(
+		"Virtio-Blk device with {} blocks of {} bytes{}",
+		drv.block_count(),
+		drv.block_size(),
+		if drv.is_read_only() {
+			" (read-only)"
+		} else {
+			""
+		}
+	);
+
+	Some(drv)
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn split_segments() {
+	// the buffer crosses two page boundaries
+	assert_eq!(
+		segment_lengths(0x1f00, 0x2200, 0x1000),
+		[0x100, 0x1000, 0x1000, 0x100]
+	);
+	// the device limits the size of a segment
+	assert_eq!(
+		segment_lengths(0x1000, 0x1000, 0x600),
+		[0x600, 0x600, 0x400]
+	);
+	assert!(segment_lengths(0x1000, 0, 0x1000).is_empty());
+}
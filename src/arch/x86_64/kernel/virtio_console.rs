@@ -0,0 +1,923 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Driver for virtio console devices (see 5.3 Console Device of the virtio specification)
+//!
+//! The console port replaces the serial port as output of the kernel and is used as stdin.
+//! With VIRTIO_CONSOLE_F_MULTIPORT, the device provides further named ports (e.g. a channel
+//! to an agent on the host), which are mounted at /virtio-ports.
+
+use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::percore::core_scheduler;
+use crate::arch::x86_64::kernel::virtio::{
+	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
+};
+use crate::arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use crate::arch::x86_64::mm::VirtAddr;
+use crate::scheduler::task::TaskHandle;
+use crate::synch::spinlock::SpinlockIrqSave;
+use crate::syscalls::fs::{self, FileError, FilePerms, PosixFile, PosixFileSystem, SeekWhence};
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::spin_loop_hint;
+use core::{fmt, mem, ptr, slice};
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Queues of the control channel, which exist with VIRTIO_CONSOLE_F_MULTIPORT
+const CONTROL_RECEIVEQ: u16 = 2;
+/// Maximum number of ports, which are supported by the driver
+const MAX_PORTS: u32 = 16;
+/// Number and size of the receive buffers of a channel
+const RX_BUFFERS: usize = 8;
+const RX_BUFFER_SIZE: usize = 1024;
+/// The device doesn't get new receive buffers, while a port holds more unread bytes.
+const MAX_INPUT: usize = 64 * 1024;
+/// Number of transmit buffers of a channel, which have the size of a page
+const TX_BUFFERS: usize = 4;
+/// Number of attempts to find a free transmit descriptor, before the data is dropped
+const TX_RETRIES: usize = 1_000_000;
+/// Mount point of the ports, which are exposed as files
+const PORTS_MOUNT_POINT: &str = "virtio-ports";
+
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_console_config {
+	cols: u16,
+	rows: u16,
+	max_nr_ports: u32,
+	emerg_wr: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct virtio_console_control {
+	// port number
+	id: u32,
+	// the kind of control event
+	event: u16,
+	// extra information for the event
+	value: u16,
+}
+
+/// Returns the index of the receive queue of `port`. The transmit queue follows it.
+fn receive_queue(port: u32) -> u16 {
+	if port == 0 {
+		0
+	} else {
+		(2 + 2 * port) as u16
+	}
+}
+
+/// Page, which is allocated once and holds the data of one transmit descriptor.
+/// In contrast to a `Box`, the page is physically contiguous.
+struct TxBuffer {
+	addr: VirtAddr,
+}
+
+impl TxBuffer {
+	fn new() -> Self {
+		Self {
+			addr: crate::mm::allocate(BasePageSize::SIZE, true),
+		}
+	}
+
+	fn as_slice(&self) -> &[u8] {
+		unsafe { slice::from_raw_parts(self.addr.as_ptr::<u8>(), BasePageSize::SIZE) }
+	}
+
+	fn as_mut_slice(&mut self) -> &mut [u8] {
+		unsafe { slice::from_raw_parts_mut(self.addr.as_mut_ptr::<u8>(), BasePageSize::SIZE) }
+	}
+}
+
+impl Drop for TxBuffer {
+	fn drop(&mut self) {
+		crate::mm::deallocate(self.addr, BasePageSize::SIZE);
+	}
+}
+
+/// Pair of queues with the buffers, which are owned by the device
+struct Channel<'a> {
+	rx: Virtq<'a>,
+	tx: Virtq<'a>,
+	rx_buffers: BTreeMap<u32, Box<[u8]>>,
+	/// Transmit buffers, which are owned by the device, and their descriptors
+	tx_buffers: Vec<(u32, TxBuffer)>,
+	/// Free transmit buffers. Sending doesn't allocate memory, because the kernel
+	/// messages are sent as well (e.g. if an allocation fails).
+	tx_pool: Vec<TxBuffer>,
+	/// Receive buffers, which are held back, while the input of the port is full
+	parked: Vec<Box<[u8]>>,
+}
+
+impl<'a> Channel<'a> {
+	/// Creates the queues `index` and `index + 1`. The receive queue signals its events
+	/// by the MSI-X table entry `vector`.
+	fn new(
+		index: u16,
+		vector: u16,
+		common_cfg: &mut virtio_pci_common_cfg,
+		notify_cfg: &mut VirtioNotification,
+		features: u64,
+	) -> Option<Self> {
+		virtio::set_queue_vector(common_cfg, index, vector);
+		let rx = Virtq::new_from_common(index, common_cfg, notify_cfg, features)?;
+		let mut tx = Virtq::new_from_common(index + 1, common_cfg, notify_cfg, features)?;
+		// sent buffers are reclaimed, when the next data is sent
+		tx.set_polling_mode(true);
+
+		let mut channel = Self {
+			rx,
+			tx,
+			rx_buffers: BTreeMap::new(),
+			tx_buffers: Vec::with_capacity(TX_BUFFERS),
+			tx_pool: (0..TX_BUFFERS).map(|_| TxBuffer::new()).collect(),
+			parked: Vec::new(),
+		};
+		for _ in 0..RX_BUFFERS {
+			channel.post_receive_buffer(vec![0u8; RX_BUFFER_SIZE].into_boxed_slice());
+		}
+
+		Some(channel)
+	}
+
+	fn post_receive_buffer(&mut self, mut buffer: Box<[u8]>) {
+		let addr = buffer.as_ptr() as usize;
+		let mut rest: &mut [u8] = &mut buffer;
+		let mut segments: Vec<&mut [u8]> = Vec::new();
+		for (_, len) in virtio::page_segments(addr, rest.len()) {
+			let (segment, tail) = mem::take(&mut rest).split_at_mut(len);
+			segments.push(segment);
+			rest = tail;
+		}
+
+		match self.rx.send_chain(&[], &segments) {
+			Ok(id) => {
+				drop(segments);
+				self.rx_buffers.insert(id, buffer);
+			}
+			Err(()) => {
+				drop(segments);
+				self.parked.push(buffer);
+			}
+		}
+	}
+
+	/// Passes the received data to `f`. If `repost` is false, the buffers are not
+	/// returned to the device, until `repost_parked` is called.
+	fn receive(&mut self, repost: bool, mut f: impl FnMut(&[u8])) {
+		loop {
+			while let Some((id, len)) = self.rx.pop_used_buffer() {
+				self.rx.release_chain(id);
+				let buffer = match self.rx_buffers.remove(&id) {
+					Some(buffer) => buffer,
+					None => continue,
+				};
+
+				f(&buffer[..(len as usize).min(buffer.len())]);
+				if repost {
+					self.post_receive_buffer(buffer);
+				} else {
+					self.parked.push(buffer);
+				}
+			}
+
+			// enable the interrupts again and check for data, which has been received in the meantime
+			self.rx.set_polling_mode(false);
+			if !self.rx.has_packet() {
+				break;
+			}
+		}
+	}
+
+	fn repost_parked(&mut self) {
+		while let Some(buffer) = self.parked.pop() {
+			self.post_receive_buffer(buffer);
+		}
+	}
+
+	fn reclaim_transmit_buffers(&mut self) -> bool {
+		let mut reclaimed = false;
+		while let Some((id, _)) = self.tx.pop_used_buffer() {
+			self.tx.release_chain(id);
+			if let Some(index) = self.tx_buffers.iter().position(|(chain, _)| *chain == id) {
+				let (_, buffer) = self.tx_buffers.swap_remove(index);
+				self.tx_pool.push(buffer);
+			}
+			reclaimed = true;
+		}
+
+		reclaimed
+	}
+
+	/// Returns a free transmit buffer. If all buffers are owned by the device,
+	/// the function waits until the device has consumed older data.
+	fn get_transmit_buffer(&mut self) -> Option<TxBuffer> {
+		for _ in 0..TX_RETRIES {
+			if let Some(buffer) = self.tx_pool.pop() {
+				return Some(buffer);
+			}
+			if !self.reclaim_transmit_buffers() {
+				spin_loop_hint();
+			}
+		}
+
+		None
+	}
+
+	/// Sends `data` to the device. Returns the number of bytes, which the device has accepted.
+	fn send(&mut self, data: &[u8]) -> usize {
+		self.reclaim_transmit_buffers();
+
+		let mut sent = 0;
+		for chunk in data.chunks(BasePageSize::SIZE) {
+			let mut buffer = match self.get_transmit_buffer() {
+				Some(buffer) => buffer,
+				None => return sent,
+			};
+			buffer.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+			let segment = &buffer.as_slice()[..chunk.len()];
+
+			let mut retries = 0;
+			let id = loop {
+				match self.tx.send_chain(&[segment], &[]) {
+					Ok(id) => break id,
+					Err(()) if retries < TX_RETRIES => {
+						// wait until the device has consumed older data
+						if !self.reclaim_transmit_buffers() {
+							spin_loop_hint();
+						}
+						retries += 1;
+					}
+					Err(()) => {
+						self.tx_pool.push(buffer);
+						return sent;
+					}
+				}
+			};
+
+			self.tx_buffers.push((id, buffer));
+			sent += chunk.len();
+		}
+
+		sent
+	}
+}
+
+struct Port<'a> {
+	channel: Channel<'a>,
+	input: VecDeque<u8>,
+	/// Tasks, which wait for input
+	readers: Vec<TaskHandle>,
+	name: Option<String>,
+	/// The device has announced the port
+	added: bool,
+	/// The host side of the port is connected
+	host_connected: bool,
+	/// Number of open files of the port
+	open_count: usize,
+}
+
+struct ConsoleState<'a> {
+	ports: Vec<Port<'a>>,
+	control: Option<Channel<'a>>,
+	/// Port, which is used as console
+	console: Option<u32>,
+}
+
+impl ConsoleState<'_> {
+	fn send_control(&mut self, id: u32, event: u16, value: u16) {
+		let msg = virtio_console_control { id, event, value };
+		let data = unsafe {
+			slice::from_raw_parts(
+				&msg as *const _ as *const u8,
+				mem::size_of::<virtio_console_control>(),
+			)
+		};
+
+		if let Some(control) = &mut self.control {
+			control.send(data);
+		}
+	}
+
+	fn handle_control(&mut self, msg: virtio_console_control, extra: &[u8]) {
+		let port = self.ports.get_mut(msg.id as usize);
+
+		match msg.event {
+			VIRTIO_CONSOLE_DEVICE_ADD => {
+				// refuse ports, for which no queues have been set up
+				let ready = match port {
+					Some(port) => {
+						port.added = true;
+						1
+					}
+					None => 0,
+				};
+				self.send_control(msg.id, VIRTIO_CONSOLE_PORT_READY, ready);
+			}
+			VIRTIO_CONSOLE_DEVICE_REMOVE => {
+				if let Some(port) = port {
+					port.added = false;
+					port.host_connected = false;
+					port.name = None;
+					// wake up the readers, which get an end of file
+					for task in port.readers.drain(..) {
+						core_scheduler().custom_wakeup(task);
+					}
+				}
+			}
+			VIRTIO_CONSOLE_CONSOLE_PORT => {
+				if port.is_some() {
+					self.console = Some(msg.id);
+					self.send_control(msg.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+				}
+			}
+			VIRTIO_CONSOLE_PORT_OPEN => {
+				if let Some(port) = port {
+					port.host_connected = msg.value != 0;
+				}
+			}
+			VIRTIO_CONSOLE_PORT_NAME => {
+				if let Some(port) = port {
+					let len = extra.iter().position(|c| *c == 0).unwrap_or(extra.len());
+					port.name = Some(String::from_utf8_lossy(&extra[..len]).into_owned());
+				}
+			}
+			// the size of the console isn't used
+			VIRTIO_CONSOLE_RESIZE => {}
+			_ => {}
+		}
+	}
+
+	fn process_control(&mut self) {
+		let mut messages = Vec::new();
+		if let Some(control) = &mut self.control {
+			control.receive(true, |data| {
+				if data.len() >= mem::size_of::<virtio_console_control>() {
+					let msg = unsafe {
+						ptr::read_unaligned(data.as_ptr() as *const virtio_console_control)
+					};
+					let extra = data[mem::size_of::<virtio_console_control>()..].to_vec();
+					messages.push((msg, extra));
+				}
+			});
+		}
+
+		for (msg, extra) in messages {
+			self.handle_control(msg, &extra);
+		}
+	}
+
+	/// Moves the received data to the input of the ports and wakes up waiting readers.
+	fn process_input(&mut self) {
+		for port in self.ports.iter_mut() {
+			let Port {
+				channel,
+				input,
+				readers,
+				..
+			} = port;
+
+			let mut received = false;
+			channel.receive(input.len() < MAX_INPUT, |data| {
+				input.extend(data.iter());
+				received |= !data.is_empty();
+			});
+
+			if received {
+				for task in readers.drain(..) {
+					core_scheduler().custom_wakeup(task);
+				}
+			}
+		}
+	}
+}
+
+pub struct VirtioConsoleDriver<'a> {
+	common_cfg: &'a mut virtio_pci_common_cfg,
+	device_cfg: &'a virtio_console_config,
+	isr_cfg: &'a mut u32,
+	notify_cfg: VirtioNotification,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// MSI-X table of the device, whose first entry is shared by all receive queues
+	msix_table: Option<pci::MsixTable>,
+	state: SpinlockIrqSave<ConsoleState<'a>>,
+}
+
+// The state is protected by a lock and the configuration is only changed during the initialization.
+unsafe impl Send for VirtioConsoleDriver<'_> {}
+unsafe impl Sync for VirtioConsoleDriver<'_> {}
+
+impl<'a> fmt::Debug for VirtioConsoleDriver<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "VirtioConsoleDriver {{ ")?;
+		write!(f, "common_cfg: {:?}, ", self.common_cfg)?;
+		write!(f, "device_cfg: {:?}, ", self.device_cfg)?;
+		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
+		write!(f, "notify_cfg: {:?}, ", self.notify_cfg)?;
+		write!(f, "features: 0x{:x}", self.features)?;
+		write!(f, " }}")
+	}
+}
+
+/// Returns the feature bits, which the driver accepts from `device_features`.
+fn select_features(device_features: u64) -> u64 {
+	let supported: u64 = VIRTIO_F_VERSION_1
+		| VIRTIO_F_RING_EVENT_IDX
+		| VIRTIO_F_RING_PACKED
+		| (1 << VIRTIO_CONSOLE_F_MULTIPORT);
+
+	device_features & supported
+}
+
+impl<'a> VirtioConsoleDriver<'a> {
+	fn is_multiport(&self) -> bool {
+		self.features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0
+	}
+
+	pub fn init_vqs(&mut self) {
+		debug!("Setting up virtqueues...");
+
+		let nr_ports = if self.is_multiport() {
+			self.device_cfg.max_nr_ports.min(MAX_PORTS).max(1)
+		} else {
+			1
+		};
+
+		let vector = if self.msix_table.is_some() {
+			// we are not interested in configuration changes
+			self.common_cfg.msix_config = VIRTIO_MSI_NO_VECTOR;
+			0
+		} else {
+			VIRTIO_MSI_NO_VECTOR
+		};
+
+		// The queues of all ports have to be set up, before the device is live.
+		let mut ports = Vec::new();
+		for port in 0..nr_ports {
+			let channel = match Channel::new(
+				receive_queue(port),
+				vector,
+				self.common_cfg,
+				&mut self.notify_cfg,
+				self.features,
+			) {
+				Some(channel) => channel,
+				None => {
+					error!("Unable to set up the queues of port {}", port);
+					break;
+				}
+			};
+
+			ports.push(Port {
+				channel,
+				input: VecDeque::new(),
+				readers: Vec::new(),
+				name: None,
+				// without multiport, port 0 exists implicitly and is the console
+				added: !self.is_multiport(),
+				host_connected: false,
+				open_count: 0,
+			});
+		}
+
+		let control = if self.is_multiport() {
+			let control = Channel::new(
+				CONTROL_RECEIVEQ,
+				vector,
+				self.common_cfg,
+				&mut self.notify_cfg,
+				self.features,
+			);
+			if control.is_none() {
+				error!("Unable to set up the control queues");
+			}
+			control
+		} else {
+			None
+		};
+
+		let mut state = self.state.lock();
+		state.console = if self.is_multiport() || ports.is_empty() {
+			None
+		} else {
+			Some(0)
+		};
+		state.ports = ports;
+		state.control = control;
+	}
+
+	pub fn negotiate_features(&mut self) {
+		let common_cfg = &mut self.common_cfg;
+		// Linux kernel reads 2x32 featurebits: https://elixir.bootlin.com/linux/latest/ident/vp_get_features
+		common_cfg.device_feature_select = 0;
+		let mut device_features: u64 = common_cfg.device_feature as u64;
+		common_cfg.device_feature_select = 1;
+		device_features |= (common_cfg.device_feature as u64) << 32;
+
+		let features = select_features(device_features);
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
+
+		info!(
+			"Virtio features: device 0x{:x}, accepted 0x{:x}",
+			device_features, features
+		);
+	}
+
+	/// 3.1 VirtIO Device Initialization
+	pub fn init(&mut self) {
+		// 1. Reset the device.
+		self.common_cfg.device_status = 0;
+
+		// 2. Set the ACKNOWLEDGE status bit: the guest OS has notice the device.
+		self.common_cfg.device_status |= 1;
+
+		// 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+		self.common_cfg.device_status |= 2;
+
+		// 4. Read device feature bits, and write the subset of feature bits understood by the OS and driver to the device.
+		self.negotiate_features();
+
+		// 5. Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
+		self.common_cfg.device_status |= 8;
+
+		// 6. Re-read device status to ensure the FEATURES_OK bit is still set:
+		//   otherwise, the device does not support our subset of features and the device is unusable.
+		if self.common_cfg.device_status & 8 == 0 {
+			error!("Device unset FEATURES_OK, aborting!");
+			return;
+		}
+
+		// 7. Perform device-specific setup, including discovery of virtqueues for the device.
+		self.init_vqs();
+
+		// 8. Set the DRIVER_OK status bit. At this point the device is “live”.
+		self.common_cfg.device_status |= 4;
+
+		// 5.3.6.2 Multiport Device Operation: the device announces its ports after this message
+		if self.is_multiport() {
+			self.state
+				.lock()
+				.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+		}
+	}
+
+	/// Writes `buf` to the console port and returns the number of written bytes. Nothing is
+	/// written, if the device has no console port or if the state is locked. The latter happens,
+	/// if a message is printed while the driver itself holds the lock (e.g. in the interrupt
+	/// handler). Then, the caller has to fall back to the serial port instead of deadlocking.
+	pub fn write_console(&self, buf: &[u8]) -> usize {
+		let mut state = match self.state.try_lock() {
+			Some(state) => state,
+			None => return 0,
+		};
+		let console = match state.console {
+			Some(console) => console as usize,
+			None => return 0,
+		};
+
+		state.ports[console].channel.send(buf)
+	}
+
+	/// Reads from the console port, see `read_port`.
+	pub fn read_console(&self, buf: &mut [u8]) -> usize {
+		let console = self.state.lock().console;
+		match console {
+			Some(console) => self.read_port(console, buf),
+			None => 0,
+		}
+	}
+
+	/// Returns the port `name`. Ports without a name are called `vport<id>`.
+	pub fn find_port(&self, name: &str) -> Option<u32> {
+		let state = self.state.lock();
+		state
+			.ports
+			.iter()
+			.enumerate()
+			.filter(|(_, port)| port.added)
+			.find(|(id, port)| match &port.name {
+				Some(port_name) => port_name == name,
+				None => name == format!("vport{}", id),
+			})
+			.map(|(id, _)| id as u32)
+	}
+
+	/// Opens the port for a task and tells the host, that the guest side is connected.
+	pub fn open_port(&self, id: u32) -> Result<(), FileError> {
+		let mut state = self.state.lock();
+		let port = match state.ports.get_mut(id as usize) {
+			Some(port) if port.added => port,
+			_ => return Err(FileError::ENOENT()),
+		};
+
+		port.open_count += 1;
+		if port.open_count == 1 {
+			state.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+		}
+
+		Ok(())
+	}
+
+	pub fn close_port(&self, id: u32) {
+		let mut state = self.state.lock();
+		let port = match state.ports.get_mut(id as usize) {
+			Some(port) => port,
+			None => return,
+		};
+
+		port.open_count = port.open_count.saturating_sub(1);
+		if port.open_count == 0 && port.added {
+			state.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 0);
+		}
+	}
+
+	/// Blocks the current task until data has been received from port `id` and
+	/// copies at most `buf.len()` bytes to `buf`. Returns the number of bytes,
+	/// which is 0 for a removed port.
+	pub fn read_port(&self, id: u32, buf: &mut [u8]) -> usize {
+		if buf.is_empty() {
+			return 0;
+		}
+
+		loop {
+			let mut state = self.state.lock();
+			let port = match state.ports.get_mut(id as usize) {
+				Some(port) if port.added => port,
+				_ => return 0,
+			};
+
+			if !port.input.is_empty() {
+				let len = buf.len().min(port.input.len());
+				for (dst, src) in buf.iter_mut().zip(port.input.drain(..len)) {
+					*dst = src;
+				}
+				if port.input.len() < MAX_INPUT {
+					port.channel.repost_parked();
+				}
+
+				return len;
+			}
+
+			// Block the current task, until the interrupt handler has received data.
+			let core_scheduler = core_scheduler();
+			core_scheduler.block_current_task(None);
+			port.readers.push(core_scheduler.get_current_task_handle());
+
+			// release lock
+			drop(state);
+
+			// Switch to the next task.
+			core_scheduler.reschedule();
+		}
+	}
+
+	/// Sends `buf` to port `id` and returns the number of sent bytes.
+	pub fn write_port(&self, id: u32, buf: &[u8]) -> Result<usize, FileError> {
+		let mut state = self.state.lock();
+		let port = match state.ports.get_mut(id as usize) {
+			Some(port) if port.added => port,
+			_ => return Err(FileError::ENOENT()),
+		};
+
+		// the host would discard the data
+		if !port.host_connected {
+			return Err(FileError::EIO());
+		}
+
+		match port.channel.send(buf) {
+			0 if !buf.is_empty() => Err(FileError::EIO()),
+			sent => Ok(sent),
+		}
+	}
+}
+
+/// Filesystem, which exposes the ports of the console device as files
+pub struct VirtioPorts;
+
+impl PosixFileSystem for VirtioPorts {
+	fn open(&self, path: &str, _perms: FilePerms) -> Result<Box<dyn PosixFile + Send>, FileError> {
+		let driver = pci::get_console_driver().ok_or(FileError::ENOENT())?;
+		let id = driver.find_port(path).ok_or(FileError::ENOENT())?;
+		driver.open_port(id)?;
+
+		Ok(Box::new(PortFile { id }))
+	}
+
+	fn unlink(&self, _path: &str) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+}
+
+struct PortFile {
+	id: u32,
+}
+
+impl PosixFile for PortFile {
+	fn close(&mut self) -> Result<(), FileError> {
+		if let Some(driver) = pci::get_console_driver() {
+			driver.close_port(self.id);
+		}
+
+		Ok(())
+	}
+
+	fn read(&mut self, len: u32) -> Result<Vec<u8>, FileError> {
+		let driver = pci::get_console_driver().ok_or(FileError::ENOENT())?;
+		let mut buf = vec![0u8; len as usize];
+		let len = driver.read_port(self.id, &mut buf);
+		buf.truncate(len);
+
+		Ok(buf)
+	}
+
+	fn write(&mut self, buf: &[u8]) -> Result<u64, FileError> {
+		let driver = pci::get_console_driver().ok_or(FileError::ENOENT())?;
+		let len = driver.write_port(self.id, buf)?;
+
+		Ok(len as u64)
+	}
+
+	fn lseek(&mut self, _offset: isize, _whence: SeekWhence) -> Result<usize, FileError> {
+		// ports are streams
+		Err(FileError::EINVAL())
+	}
+}
+
+impl pci::PciDevice for VirtioConsoleDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	fn handle_interrupt(&self) -> bool {
+		// With MSI-X, the device does not use the ISR status and the interrupt is not shared.
+		if self.msix_table.is_none() {
+			let isr_status = unsafe { ptr::read_volatile(&*self.isr_cfg) };
+			if isr_status & 0x1 == 0 {
+				return false;
+			}
+		}
+
+		let mut state = self.state.lock();
+		state.process_control();
+		state.process_input();
+
+		true
+	}
+}
+
+/// PCI driver of virtio console devices
+pub struct VirtioConsolePciDriver;
+
+impl pci::PciDriver for VirtioConsolePciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-console"
+	}
+
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x1043)]
+	}
+
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtioconsole_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
+	}
+}
+
+pub fn create_virtioconsole_driver(
+	adapter: &pci::PciAdapter,
+) -> Option<VirtioConsoleDriver<'static>> {
+	// Scan capabilities to get common config, which we need to reset the device and get basic info.
+	let bus = adapter.bus;
+	let device = adapter.devfn;
+	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
+
+	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
+	if status & pci::PCI_STATUS_CAPABILITIES_LIST == 0 {
+		error!("Found virtio device without capability list. Likely legacy-device! Aborting.");
+		return None;
+	}
+
+	// Get pointer to capability list
+	let caplist = pci::read_config(bus, device, pci::PCI_CAPABILITY_LIST_REGISTER) & 0xFF;
+
+	// get common config mapped, cast to virtio_pci_common_cfg
+	let common_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_COMMON_CFG) {
+			Some((cap_common_raw, _)) => unsafe {
+				&mut *(cap_common_raw.as_mut_ptr::<virtio_pci_common_cfg>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_COMMON_CFG. Aborting!");
+				return None;
+			}
+		};
+	// get device config mapped, cast to virtio_console_config
+	let device_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_DEVICE_CFG) {
+			Some((cap_device_raw, _)) => unsafe {
+				&*(cap_device_raw.as_ptr::<virtio_console_config>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_DEVICE_CFG. Aborting!");
+				return None;
+			}
+		};
+	let isr_cfg = match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_ISR_CFG)
+	{
+		Some((cap_isr_raw, _)) => unsafe { &mut *(cap_isr_raw.as_mut_ptr::<u32>()) },
+		None => {
+			error!("Could not find VIRTIO_PCI_CAP_ISR_CFG. Aborting!");
+			return None;
+		}
+	};
+	// get device notifications mapped
+	let (notification_ptr, notify_off_multiplier) =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_NOTIFY_CFG) {
+			Some((cap_notification_raw, notify_off_multiplier)) => (
+				cap_notification_raw.as_mut_ptr::<u16>(),
+				notify_off_multiplier,
+			),
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_NOTIFY_CFG. Aborting!");
+				return None;
+			}
+		};
+	let notify_cfg = VirtioNotification {
+		notification_ptr,
+		notify_off_multiplier,
+	};
+
+	let mut drv = VirtioConsoleDriver {
+		common_cfg,
+		device_cfg,
+		isr_cfg,
+		notify_cfg,
+		features: 0,
+		msix_table: adapter.enable_msix(),
+		state: SpinlockIrqSave::new(ConsoleState {
+			ports: Vec::new(),
+			control: None,
+			console: None,
+		}),
+	};
+
+	trace!("Driver before init: {:?}", drv);
+	drv.init();
+	trace!("Driver after init: {:?}", drv);
+
+	if drv.is_multiport() {
+		info!(
+			"Virtio-Console device with up to {} ports, mounting them at /{}",
+			drv.state.lock().ports.len(),
+			PORTS_MOUNT_POINT
+		);
+		if fs::FILESYSTEM
+			.mount(PORTS_MOUNT_POINT, Box::new(VirtioPorts))
+			.is_err()
+		{
+			error!(
+				"Unable to mount the ports at /{}. Duplicate virtio console?",
+				PORTS_MOUNT_POINT
+			);
+		}
+	} else {
+		info!("Virtio-Console device with a single port");
+	}
+
+	Some(drv)
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn port_queues() {
+	assert_eq!(receive_queue(0), 0);
+	assert_eq!(receive_queue(1), 4);
+	assert_eq!(receive_queue(3), 8);
+}
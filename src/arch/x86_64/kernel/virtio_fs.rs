@@ -5,17 +5,90 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::arch::x86_64::kernel::apic;
 use crate::arch::x86_64::kernel::fuse::{self, FuseInterface};
 use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::percore::{core_id, core_scheduler};
+use crate::arch::x86_64::kernel::processor;
 use crate::arch::x86_64::kernel::virtio::{
 	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
 };
+use crate::environment;
+use crate::scheduler::task::TaskHandle;
+use crate::scheduler::CoreId;
+use crate::synch::spinlock::SpinlockIrqSave;
 use crate::syscalls::fs;
 use crate::util;
 
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::vec::Vec;
-use core::{fmt, u32, u8};
+use core::any::Any;
+use core::sync::atomic::spin_loop_hint;
+use core::{fmt, ptr, str, u32, u8};
+
+/// Time in microseconds, which the device has to answer FUSE_INIT. Without virtiofsd
+/// on the host, the request is never completed.
+const FUSE_INIT_TIMEOUT: u64 = 1_000_000;
+
+/// Id of the shared memory region, which is used as DAX window
+const VIRTIO_FS_SHMCAP_ID_CACHE: u8 = 0;
+/// The mapped part of the DAX window is limited, because every page of the window
+/// requires a page table entry.
+const DAX_WINDOW_MAX_SIZE: usize = 1024 * 1024 * 1024;
+/// Maximum number of request queues, which are used by the driver
+const VIRTIO_FS_MAX_REQUEST_QUEUES: usize = 16;
+/// Queue of requests without reply (FUSE_FORGET), which is followed by the request queues
+const VIRTIO_FS_HIPRIO_QUEUE: usize = 0;
+/// Position of the driver instance in the context of a queue interrupt handler
+const QUEUE_CONTEXT_INSTANCE_SHIFT: usize = 16;
+
+/// Options of a share, which are given through the -virtiofs TAG:OPTIONS command line
+/// parameter. OPTIONS is a comma-separated list of `ro`, `rw`, `noauto` and `mountpoint=NAME`.
+/// A share with `noauto` is mounted later by `sys_mount`.
+#[derive(Debug, PartialEq)]
+struct MountOptions {
+	/// Name of the mount point, which is the tag by default
+	mount_point: Option<String>,
+	read_only: bool,
+	/// Whether the share is mounted during the initialization of the device
+	auto: bool,
+}
+
+impl Default for MountOptions {
+	fn default() -> Self {
+		Self {
+			mount_point: None,
+			read_only: false,
+			auto: true,
+		}
+	}
+}
+
+impl MountOptions {
+	fn parse(options: &str) -> Result<Self, &str> {
+		let mut result = Self::default();
+
+		for option in options.split(',').filter(|option| !option.is_empty()) {
+			match option {
+				"ro" => result.read_only = true,
+				"rw" => result.read_only = false,
+				"noauto" => result.auto = false,
+				_ if option.starts_with("mountpoint=") => {
+					let name = &option["mountpoint=".len()..];
+					if name.is_empty() || name.contains('/') {
+						return Err(option);
+					}
+					result.mount_point = Some(String::from(name));
+				}
+				_ => return Err(option),
+			}
+		}
+
+		Ok(result)
+	}
+}
 
 #[repr(C)]
 struct virtio_fs_config {
@@ -30,38 +103,105 @@ impl fmt::Debug for virtio_fs_config {
 		write!(
 			f,
 			"virtio_fs_config {{ tag: '{}', num_request_queues: {} }}",
-			core::str::from_utf8(&self.tag).unwrap(),
+			str::from_utf8(&self.tag[..util::c_strbuflen(&self.tag)]).unwrap_or("<invalid>"),
 			self.num_request_queues
 		)
 	}
 }
 
+/// Virtqueue and the tasks, which wait for the completion of their requests
+struct FsQueue<'a> {
+	vq: Virtq<'a>,
+	/// Maps the descriptor chain of a submitted request to the waiting task
+	in_flight: BTreeMap<u32, TaskHandle>,
+}
+
+impl FsQueue<'_> {
+	/// Wakes up the tasks, whose requests have been completed by the device.
+	fn complete_requests(&mut self) {
+		loop {
+			while let Some((id, _)) = self.vq.pop_used_buffer() {
+				self.vq.release_chain(id);
+
+				match self.in_flight.remove(&id) {
+					Some(task) => core_scheduler().custom_wakeup(task),
+					None => warn!("Virtio-fs device completed unknown request {}", id),
+				}
+			}
+
+			// enable the interrupts again and check for requests, which have been completed in the meantime
+			self.vq.set_polling_mode(false);
+			if !self.vq.has_packet() {
+				break;
+			}
+		}
+	}
+}
+
+/// Returns the number of request queues, which are used by the driver. Every core submits
+/// its requests to one of them and every queue needs its own MSI-X vector.
+fn request_queue_count(
+	device_queues: usize,
+	core_count: usize,
+	msix_vectors: Option<usize>,
+) -> usize {
+	let count = device_queues
+		.min(core_count)
+		.min(VIRTIO_FS_MAX_REQUEST_QUEUES);
+
+	match msix_vectors {
+		// the first vector is used by the high priority queue
+		Some(vectors) => count.min(vectors.saturating_sub(1)),
+		None => count,
+	}
+}
+
 pub struct VirtioFsDriver<'a> {
-	common_cfg: &'a mut virtio_pci_common_cfg,
+	common_cfg: SpinlockIrqSave<&'a mut virtio_pci_common_cfg>,
 	device_cfg: &'a virtio_fs_config,
+	isr_cfg: &'a mut u32,
 	notify_cfg: VirtioNotification,
-	vqueues: Option<Vec<Virtq<'a>>>,
+	/// MSI-X table of the device, whose entries are used by the queues with the same index
+	msix_table: Option<pci::MsixTable>,
+	/// The high priority queue followed by the request queues
+	vqueues: Vec<SpinlockIrqSave<FsQueue<'a>>>,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// Name of the share, which is read from the device configuration
+	tag: String,
+	/// Shared memory, into which the host maps the files (DAX)
+	dax: Option<fuse::Dax>,
 }
 
 impl<'a> fmt::Debug for VirtioFsDriver<'a> {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "VirtioFsDriver {{ ")?;
-		write!(f, "common_cfg: {:?}, ", self.common_cfg)?;
+		write!(f, "common_cfg: {:?}, ", *self.common_cfg.lock())?;
 		write!(f, "device_cfg: {:?}, ", self.device_cfg)?;
+		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
 		write!(f, "nofity_cfg: {:?}, ", self.notify_cfg)?;
-		match &self.vqueues {
-			None => write!(f, "Uninitialized VQs")?,
-			Some(vqs) => write!(f, "Initialized {} VQs", vqs.len())?,
+		write!(f, "tag: {}, ", self.tag)?;
+		write!(f, "dax: {:?}, ", self.dax)?;
+		if self.vqueues.is_empty() {
+			write!(f, "Uninitialized VQs")?;
+		} else {
+			write!(f, "Initialized {} VQs", self.vqueues.len())?;
 		}
 		write!(f, " }}")
 	}
 }
 
+// The queues and the common configuration are protected by locks and the
+// remaining configuration is only changed during the initialization.
+unsafe impl Send for VirtioFsDriver<'_> {}
+unsafe impl Sync for VirtioFsDriver<'_> {}
+
 impl VirtioFsDriver<'_> {
-	pub fn init_vqs(&mut self) {
-		let common_cfg = &mut self.common_cfg;
+	pub fn init_vqs(&mut self) -> bool {
+		let mut common_cfg = self.common_cfg.lock();
 		let device_cfg = &self.device_cfg;
 		let notify_cfg = &mut self.notify_cfg;
+		let features = self.features;
 
 		// 4.1.5.1.3 Virtqueueu configuration
 		// see https://elixir.bootlin.com/linux/latest/ident/virtio_fs_setup_vqs for example
@@ -69,24 +209,53 @@ impl VirtioFsDriver<'_> {
 
 		if device_cfg.num_request_queues == 0 {
 			error!("0 request queues requested from device. Aborting!");
-			return;
+			return false;
+		}
+		let num_request_queues = request_queue_count(
+			device_cfg.num_request_queues as usize,
+			apic::get_detected_core_count(),
+			self.msix_table.as_ref().map(|msix_table| msix_table.len()),
+		);
+		if num_request_queues == 0 {
+			error!("The device has too few MSI-X vectors for its queues. Aborting!");
+			return false;
 		}
+		if self.msix_table.is_some() {
+			// we are not interested in configuration changes
+			common_cfg.msix_config = VIRTIO_MSI_NO_VECTOR;
+		}
+
 		// 1 highprio queue, and n normal request queues
-		let vqnum = device_cfg.num_request_queues + 1;
-		let mut vqueues = Vec::<Virtq>::new();
+		let mut vqueues = Vec::with_capacity(num_request_queues + 1);
 
 		// create the queues and tell device about them
-		for i in 0..vqnum as u16 {
-			// TODO: catch error
-			let vq = Virtq::new_from_common(i, common_cfg, notify_cfg).unwrap();
-			vqueues.push(vq);
+		for i in 0..=num_request_queues as u16 {
+			if self.msix_table.is_some() {
+				virtio::set_queue_vector(&mut common_cfg, i, i);
+			}
+			let mut vq = match Virtq::new_from_common(i, &mut common_cfg, notify_cfg, features) {
+				Some(vq) => vq,
+				None => {
+					error!("Unable to create virtqueue {}. Aborting!", i);
+					return false;
+				}
+			};
+			// FUSE_INIT is completed by polling the used ring, because the interrupt
+			// handlers are installed after the initialization of the device.
+			vq.set_polling_mode(true);
+			vqueues.push(SpinlockIrqSave::new(FsQueue {
+				vq,
+				in_flight: BTreeMap::new(),
+			}));
 		}
 
-		self.vqueues = Some(vqueues);
+		debug!("Using {} request queues", num_request_queues);
+		self.vqueues = vqueues;
+		true
 	}
 
 	pub fn negotiate_features(&mut self) {
-		let common_cfg = &mut self.common_cfg;
+		let mut common_cfg = self.common_cfg.lock();
 		// Linux kernel reads 2x32 featurebits: https://elixir.bootlin.com/linux/latest/ident/vp_get_features
 		common_cfg.device_feature_select = 0;
 		let mut device_features: u64 = common_cfg.device_feature as u64;
@@ -96,16 +265,29 @@ impl VirtioFsDriver<'_> {
 		if device_features & VIRTIO_F_RING_INDIRECT_DESC != 0 {
 			debug!("Device offers feature VIRTIO_F_RING_INDIRECT_DESC, ignoring");
 		}
+		let mut features: u64 = 0;
 		if device_features & VIRTIO_F_RING_EVENT_IDX != 0 {
-			debug!("Device offers feature VIRTIO_F_RING_EVENT_IDX, ignoring");
+			debug!("Device offers feature VIRTIO_F_RING_EVENT_IDX, accepting.");
+			features |= VIRTIO_F_RING_EVENT_IDX;
 		}
 		if device_features & VIRTIO_F_VERSION_1 != 0 {
 			debug!("Device offers feature VIRTIO_F_VERSION_1, accepting.");
-			common_cfg.driver_feature_select = 1;
-			common_cfg.driver_feature = (VIRTIO_F_VERSION_1 >> 32) as u32;
+			features |= VIRTIO_F_VERSION_1;
+		}
+		if device_features & VIRTIO_F_RING_PACKED != 0 {
+			debug!("Device offers feature VIRTIO_F_RING_PACKED, accepting.");
+			features |= VIRTIO_F_RING_PACKED;
 		}
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
 		if device_features
-			& !(VIRTIO_F_RING_INDIRECT_DESC | VIRTIO_F_RING_EVENT_IDX | VIRTIO_F_VERSION_1)
+			& !(VIRTIO_F_RING_INDIRECT_DESC
+				| VIRTIO_F_RING_EVENT_IDX
+				| VIRTIO_F_VERSION_1
+				| VIRTIO_F_RING_PACKED)
 			!= 0
 		{
 			debug!(
@@ -117,7 +299,7 @@ impl VirtioFsDriver<'_> {
 		// TODO: actually check features
 		// currently provided features of virtio-fs:
 		// 0000000000000000000000000000000100110000000000000000000000000000
-		// only accept VIRTIO_F_VERSION_1 for now.
+		// only accept VIRTIO_F_VERSION_1, VIRTIO_F_RING_EVENT_IDX and VIRTIO_F_RING_PACKED for now.
 
 		/*
 		// on failure:
@@ -127,15 +309,16 @@ impl VirtioFsDriver<'_> {
 	}
 
 	/// 3.1 VirtIO Device Initialization
-	pub fn init(&mut self) {
+	/// Returns false and marks the device as failed, if the device is unusable.
+	pub fn init(&mut self) -> bool {
 		// 1.Reset the device.
-		self.common_cfg.device_status = 0;
+		self.common_cfg.lock().device_status = 0;
 
 		// 2.Set the ACKNOWLEDGE status bit: the guest OS has notice the device.
-		self.common_cfg.device_status |= 1;
+		self.common_cfg.lock().device_status |= 1;
 
 		// 3.Set the DRIVER status bit: the guest OS knows how to drive the device.
-		self.common_cfg.device_status |= 2;
+		self.common_cfg.lock().device_status |= 2;
 
 		// 4.Read device feature bits, and write the subset of feature bits understood by the OS and driver to the device.
 		//   During this step the driver MAY read (but MUST NOT write) the device-specific configuration fields to check
@@ -143,27 +326,152 @@ impl VirtioFsDriver<'_> {
 		self.negotiate_features();
 
 		// 5.Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
-		self.common_cfg.device_status |= 8;
+		self.common_cfg.lock().device_status |= 8;
 
 		// 6.Re-read device status to ensure the FEATURES_OK bit is still set:
 		//   otherwise, the device does not support our subset of features and the device is unusable.
-		if self.common_cfg.device_status & 8 == 0 {
+		if self.common_cfg.lock().device_status & 8 == 0 {
 			error!("Device unset FEATURES_OK, aborting!");
-			return;
+			self.common_cfg.lock().device_status |= 128;
+			return false;
 		}
 
 		// 7.Perform device-specific setup, including discovery of virtqueues for the device, optional per-bus setup,
 		//   reading and possibly writing the device’s virtio configuration space, and population of virtqueues.
-		self.init_vqs();
+		if !self.init_vqs() {
+			self.common_cfg.lock().device_status |= 128;
+			return false;
+		}
 
 		// 8.Set the DRIVER_OK status bit. At this point the device is “live”.
-		self.common_cfg.device_status |= 4;
+		self.common_cfg.lock().device_status |= 4;
+		true
+	}
+
+	/// Returns the tag of the share, which is used as default mount point.
+	pub fn tag(&self) -> &str {
+		&self.tag
+	}
+
+	/// Returns the DAX window, if the device offers one and the FUSE server supports it.
+	pub fn dax(&self) -> Option<&fuse::Dax> {
+		self.dax.as_ref()
+	}
+
+	/// Returns the request queue of the current core.
+	fn request_queue(&self) -> usize {
+		1 + core_id() as usize % (self.vqueues.len() - 1)
+	}
+
+	/// Submits a request to the queue `index` and blocks the current task until the device
+	/// has completed it. If the queue is full, the request waits for other completions.
+	fn execute(&self, index: usize, dat: &[&[u8]], rsp_buf: &[&mut [u8]]) {
+		let queue = &self.vqueues[index];
+
+		loop {
+			let mut guard = queue.lock();
+			let id = match guard.vq.send_chain(dat, rsp_buf) {
+				Ok(id) => id,
+				Err(()) => {
+					// the queue is full => wait until other requests are completed
+					drop(guard);
+					core_scheduler().reschedule();
+					continue;
+				}
+			};
+
+			// Block the current task, until the interrupt handler has seen the completion.
+			let core_scheduler = core_scheduler();
+			core_scheduler.block_current_task(None);
+			guard
+				.in_flight
+				.insert(id, core_scheduler.get_current_task_handle());
+
+			// release lock
+			drop(guard);
+
+			// Switch to the next task.
+			core_scheduler.reschedule();
+			return;
+		}
+	}
+
+	/// Creates the FUSE session. In contrast to other requests, FUSE_INIT doesn't wait
+	/// forever, because the device doesn't answer without virtiofsd on the host.
+	fn send_init(&mut self) -> bool {
+		let mut queue = match self.vqueues.get(1) {
+			Some(queue) => queue.lock(),
+			None => return false,
+		};
+		let vq = &mut queue.vq;
+
+		let flags = if self.dax.is_some() {
+			fuse::FUSE_MAP_ALIGNMENT
+		} else {
+			0
+		};
+		let (cmd, mut rsp) = fuse::create_init(flags);
+		let id = match vq.send_chain(&cmd.to_u8buf(), &rsp.to_u8buf_mut()) {
+			Ok(id) => id,
+			Err(_) => return false,
+		};
+
+		let start = processor::get_timer_ticks();
+		loop {
+			if let Some((used_id, _)) = vq.pop_used_buffer() {
+				vq.release_chain(used_id);
+				if used_id == id {
+					break;
+				}
+			} else if processor::get_timer_ticks() - start > FUSE_INIT_TIMEOUT {
+				// the reset guarantees that the device doesn't access the buffers anymore
+				error!(
+					"virtio-fs device {} didn't answer FUSE_INIT. Is virtiofsd running?",
+					self.tag
+				);
+				self.common_cfg.lock().device_status = 0;
+				return false;
+			} else {
+				spin_loop_hint();
+			}
+		}
+		drop(queue);
+
+		trace!("fuse init answer: {:?}", rsp);
+		if rsp.error() != 0 {
+			error!(
+				"virtio-fs device {} rejected FUSE_INIT with error {}",
+				self.tag,
+				-rsp.error()
+			);
+			self.common_cfg.lock().device_status |= 128;
+			return false;
+		}
+
+		if let Some(dax) = self.dax.take() {
+			if rsp.supports_dax() {
+				info!(
+					"virtio-fs device {} uses a DAX window of {} MiB",
+					self.tag,
+					dax.size() >> 20
+				);
+				self.dax = Some(dax);
+			} else {
+				info!(
+					"FUSE server of virtio-fs device {} doesn't support DAX",
+					self.tag
+				);
+				crate::mm::unmap(dax.addr(), dax.size());
+			}
+		}
+
+		true
 	}
 }
 
 impl FuseInterface for VirtioFsDriver<'_> {
 	fn send_command<S, T>(
-		&mut self,
+		&self,
 		cmd: fuse::Cmd<S>,
 		rsp: Option<fuse::Rsp<T>>,
 	) -> Option<fuse::Rsp<T>>
@@ -171,16 +479,21 @@ impl FuseInterface for VirtioFsDriver<'_> {
 		S: fuse::FuseIn + core::fmt::Debug,
 		T: fuse::FuseOut + core::fmt::Debug,
 	{
-		// TODO: cmd/rsp gets deallocated when leaving scope.. maybe not the best idea for DMA, but PoC works with this
-		//       since we are blocking until done anyways.
+		// The buffers of cmd/rsp stay alive, because the current task is blocked
+		// until the device has completed the request.
 		trace!("Sending Fuse Command: {:?}", cmd);
-		if let Some(ref mut vqueues) = self.vqueues {
-			if let Some(mut rsp) = rsp {
-				vqueues[1].send_blocking(&cmd.to_u8buf(), Some(&rsp.to_u8buf_mut()));
-				trace!("Got Fuse Reply: {:?}", rsp);
-				return Some(rsp);
-			}
+		if self.vqueues.is_empty() {
+			return None;
+		}
+
+		if let Some(mut rsp) = rsp {
+			self.execute(self.request_queue(), &cmd.to_u8buf(), &rsp.to_u8buf_mut());
+			trace!("Got Fuse Reply: {:?}", rsp);
+			return Some(rsp);
 		}
+
+		// requests like FUSE_FORGET don't have a reply
+		self.execute(VIRTIO_FS_HIPRIO_QUEUE, &cmd.to_u8buf(), &[]);
 		None
 	}
 
@@ -234,12 +547,26 @@ impl FuseInterface for VirtioFsDriver<'_> {
 	*/
 }
 
+/// Mounts the share `tag` at `/mount_point`. A share with the option noauto is only
+/// reachable after it has been mounted this way.
+pub fn mount(tag: &str, mount_point: &str, read_only: bool) -> Result<(), fs::FileError> {
+	if pci::get_filesystem_driver(tag).is_none() {
+		return Err(fs::FileError::ENOENT());
+	}
+
+	info!("Mounting virtio-fs share {} at /{}", tag, mount_point);
+	let fuse = fuse::Fuse::new(tag, read_only);
+	fs::FILESYSTEM
+		.mount(mount_point, Box::new(fuse))
+		.map_err(|_| fs::FileError::EEXIST())
+}
+
 pub fn create_virtiofs_driver(adapter: &pci::PciAdapter) -> Option<VirtioFsDriver<'static>> {
 	// Scan capabilities to get common config, which we need to reset the device and get basic info.
 	// also see https://elixir.bootlin.com/linux/latest/source/drivers/virtio/virtio_pci_modern.c#L581 (virtio_pci_modern_probe)
 	// Read status register
 	let bus = adapter.bus;
-	let device = adapter.device;
+	let device = adapter.devfn;
 	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
 
 	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
@@ -273,6 +600,14 @@ pub fn create_virtiofs_driver(adapter: &pci::PciAdapter) -> Option<VirtioFsDrive
 				return None;
 			}
 		};
+	let isr_cfg = match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_ISR_CFG)
+	{
+		Some((cap_isr_raw, _)) => unsafe { &mut *(cap_isr_raw.as_mut_ptr::<u32>()) },
+		None => {
+			error!("Could not find VIRTIO_PCI_CAP_ISR_CFG. Aborting!");
+			return None;
+		}
+	};
 	// get device notifications mapped
 	let (notification_ptr, notify_off_multiplier) =
 		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_NOTIFY_CFG) {
@@ -292,31 +627,209 @@ pub fn create_virtiofs_driver(adapter: &pci::PciAdapter) -> Option<VirtioFsDrive
 		notify_off_multiplier,
 	};
 
-	// TODO: also load the other 2 cap types (?).
+	// TODO: also load the other cap type (?).
+
+	let tag = match str::from_utf8(&device_cfg.tag[..util::c_strbuflen(&device_cfg.tag)]) {
+		Ok(tag) if !tag.is_empty() && !tag.contains('/') => String::from(tag),
+		_ => {
+			error!("virtio-fs device has an invalid tag. Aborting!");
+			return None;
+		}
+	};
+	let options = environment::get_command_line_virtiofs_options(&tag).unwrap_or("");
+	let options = match MountOptions::parse(options) {
+		Ok(options) => options,
+		Err(option) => {
+			error!(
+				"Invalid mount option {} for virtio-fs share {}",
+				option, tag
+			);
+			return None;
+		}
+	};
+
+	// map the DAX window, whose pages are mapped to files on demand
+	let dax = virtio::find_shared_memory(adapter, VIRTIO_FS_SHMCAP_ID_CACHE).and_then(
+		|(phys_addr, len)| {
+			let len = align_down!(len.min(DAX_WINDOW_MAX_SIZE), fuse::DAX_RANGE_SIZE);
+			if len == 0 {
+				return None;
+			}
+
+			let virt_addr = crate::mm::map(phys_addr, len, true, true, false);
+			Some(fuse::Dax::new(virt_addr, phys_addr, len))
+		},
+	);
 
-	// Instanciate driver on heap, so it outlives this function
 	let mut drv = VirtioFsDriver {
-		common_cfg,
+		common_cfg: SpinlockIrqSave::new(common_cfg),
 		device_cfg,
+		isr_cfg,
 		notify_cfg,
-		vqueues: None,
+		msix_table: adapter.enable_msix(),
+		vqueues: Vec::new(),
+		features: 0,
+		tag,
+		dax,
 	};
 
 	trace!("Driver before init: {:?}", drv);
-	drv.init();
+	if !drv.init() {
+		return None;
+	}
 	trace!("Driver after init: {:?}", drv);
 
-	// Instanciate global fuse object
-	let fuse = fuse::Fuse::new();
-
 	// send FUSE_INIT to create session
-	fuse.send_init();
+	if !drv.send_init() {
+		return None;
+	}
 
-	let mut fs = fs::FILESYSTEM.lock();
-	let tag = util::c_buf_to_str(&device_cfg.tag);
-	info!("Mounting virtio-fs at /{}", tag);
-	fs.mount(tag, Box::new(fuse))
-		.expect("Mount failed. Duplicate tag?");
+	if options.auto {
+		let mount_point = options.mount_point.as_deref().unwrap_or(drv.tag.as_str());
+		info!(
+			"Mounting virtio-fs share {} at /{}{}",
+			drv.tag,
+			mount_point,
+			if options.read_only {
+				" (read-only)"
+			} else {
+				""
+			}
+		);
+		let fuse = fuse::Fuse::new(&drv.tag, options.read_only);
+		if fs::FILESYSTEM.mount(mount_point, Box::new(fuse)).is_err() {
+			error!(
+				"Unable to mount virtio-fs share {} at /{}",
+				drv.tag, mount_point
+			);
+			drv.common_cfg.lock().device_status = 0;
+			return None;
+		}
+	}
 
 	Some(drv)
 }
+
+impl pci::PciDevice for VirtioFsDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	/// Every queue signals its completions by its own MSI-X vector, which interrupts
+	/// the core submitting to the queue. Otherwise, one interrupt covers all queues.
+	fn install_interrupt_handler(
+		&self,
+		adapter: &pci::PciAdapter,
+		name: &'static str,
+		instance: usize,
+	) {
+		match &self.msix_table {
+			Some(msix_table) => {
+				for queue in 0..self.vqueues.len() {
+					msix_table.install_handler(
+						queue,
+						queue.saturating_sub(1) as CoreId,
+						name,
+						queue_interrupt_handler,
+						(instance << QUEUE_CONTEXT_INSTANCE_SHIFT) | queue,
+					);
+				}
+			}
+			None => {
+				adapter.install_interrupt_handler(name, pci::device_interrupt_handler, instance)
+			}
+		}
+
+		// from now on, the interrupt handlers complete the requests
+		for queue in self.vqueues.iter() {
+			queue.lock().complete_requests();
+		}
+	}
+
+	fn handle_interrupt(&self) -> bool {
+		// With MSI-X, the device does not use the ISR status and the interrupt is not shared.
+		if self.msix_table.is_none() {
+			let isr_status = unsafe { ptr::read_volatile(&*self.isr_cfg) };
+			if isr_status & 0x1 == 0 {
+				return false;
+			}
+		}
+
+		for queue in self.vqueues.iter() {
+			queue.lock().complete_requests();
+		}
+
+		true
+	}
+
+	fn shutdown(&self) {
+		self.common_cfg.lock().device_status = 0;
+	}
+}
+
+/// Interrupt handler of a queue, which signals its completions by MSI-X.
+/// The context consists of the instance of the driver and the queue.
+fn queue_interrupt_handler(context: usize) -> bool {
+	let instance = context >> QUEUE_CONTEXT_INSTANCE_SHIFT;
+	let queue = context & ((1 << QUEUE_CONTEXT_INSTANCE_SHIFT) - 1);
+
+	match pci::get_device_instance::<VirtioFsDriver<'static>>(instance) {
+		Some(driver) => {
+			driver.vqueues[queue].lock().complete_requests();
+			true
+		}
+		None => false,
+	}
+}
+
+/// PCI driver of virtio filesystem devices
+pub struct VirtioFsPciDriver;
+
+impl pci::PciDriver for VirtioFsPciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-fs"
+	}
+
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x105a)]
+	}
+
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtiofs_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
+	}
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn mount_options() {
+	assert_eq!(MountOptions::parse(""), Ok(MountOptions::default()));
+	assert_eq!(
+		MountOptions::parse("ro,mountpoint=code,noauto"),
+		Ok(MountOptions {
+			mount_point: Some(String::from("code")),
+			read_only: true,
+			auto: false,
+		})
+	);
+	assert_eq!(MountOptions::parse("mountpoint=a/b"), Err("mountpoint=a/b"));
+	assert_eq!(MountOptions::parse("rw,sync"), Err("sync"));
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn request_queues() {
+	assert_eq!(request_queue_count(1, 4, None), 1);
+	assert_eq!(request_queue_count(8, 4, None), 4);
+	assert_eq!(
+		request_queue_count(64, 64, None),
+		VIRTIO_FS_MAX_REQUEST_QUEUES
+	);
+	// one vector is left for the high priority queue
+	assert_eq!(request_queue_count(8, 8, Some(4)), 3);
+	assert_eq!(request_queue_count(2, 8, Some(10)), 2);
+	assert_eq!(request_queue_count(2, 8, Some(1)), 0);
+}
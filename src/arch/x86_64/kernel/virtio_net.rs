@@ -7,7 +7,10 @@
 
 #![allow(unused)]
 
+use crate::arch::x86_64::kernel::apic;
 use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::percore::core_id;
+use crate::arch::x86_64::kernel::processor;
 use crate::arch::x86_64::kernel::virtio::{
 	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
 };
@@ -15,15 +18,24 @@ use crate::arch::x86_64::mm::paging::{BasePageSize, PageSize};
 use crate::arch::x86_64::mm::{paging, virtualmem, VirtAddr};
 #[cfg(not(feature = "newlib"))]
 use crate::drivers::net::netwakeup;
+use crate::scheduler::CoreId;
 use crate::synch::spinlock::SpinlockIrqSave;
+use crate::syscalls::{
+	TxOffload, NET_GSO_NONE, NET_GSO_TCPV4, NET_GSO_TCPV6, NET_GSO_UDP, NET_OFFLOAD_RX_CSUM,
+	NET_OFFLOAD_RX_TSO, NET_OFFLOAD_TX_CSUM, NET_OFFLOAD_TX_TSO4, NET_OFFLOAD_TX_TSO6,
+	NET_OFFLOAD_TX_UFO, NET_RX_CSUM_VALID,
+};
 
 use crate::x86::io::*;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::any::Any;
 use core::cell::RefCell;
 use core::convert::TryInto;
-use core::sync::atomic::{fence, Ordering};
-use core::{fmt, mem, slice, u32, u8};
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use core::{fmt, mem, ptr, slice, u32, u8};
 
 const VIRTIO_NET_F_CSUM: u64 = 0;
 const VIRTIO_NET_F_GUEST_CSUM: u64 = 1;
@@ -53,8 +65,6 @@ const VIRTIO_NET_S_LINK_UP: u16 = 1;
 const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
 /*const VIRTIO_NET_HDR_F_NEEDS_CSUM: u32 = 1;
 const VIRTIO_NET_HDR_F_DATA_VALID: u32 = 2;
-const VIRTIO_NET_OK: u32 = 0;
-const VIRTIO_NET_ERR: u32 = 1;
 const VIRTIO_NET_CTRL_RX: u32 = 0;
 const VIRTIO_NET_CTRL_RX_PROMISC: u32 = 0;
 const VIRTIO_NET_CTRL_RX_ALLMULTI: u32 = 1;
@@ -70,13 +80,16 @@ const VIRTIO_NET_CTRL_VLAN_ADD: u32 = 0;
 const VIRTIO_NET_CTRL_VLAN_DEL: u32 = 1;
 const VIRTIO_NET_CTRL_ANNOUNCE: u32 = 3;
 const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u32 = 0;
-const VIRTIO_NET_CTRL_MQ: u32 = 4;
-const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u32 = 0;
 const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u32 = 1;
 const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: u32 = 32768;
 const VIRTIO_NET_CTRL_GUEST_OFFLOADS: u32 = 5;
 const VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET: u32 = 0;*/
 
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+
 /// use csum_start, csum_offset
 const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
 /// csum is valid
@@ -95,10 +108,22 @@ const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
 /// TCP has ECN set
 const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
 
-/// Number of the RX Queue
-const VIRTIO_NET_RX_QUEUE: usize = 0;
-/// Number of the TX Queue
-const VIRTIO_NET_TX_QUEUE: usize = 1;
+/// Offset of the RX Queue within a queue pair
+const VIRTIO_NET_RX_QUEUE: u16 = 0;
+/// Offset of the TX Queue within a queue pair
+const VIRTIO_NET_TX_QUEUE: u16 = 1;
+/// Maximum number of queue pairs, which are used by the driver
+const VIRTIO_NET_MAX_QUEUE_PAIRS: usize = 16;
+/// A TX buffer handle stores the queue pair above this bit
+const TX_HANDLE_QUEUE_SHIFT: usize = 16;
+/// The queue pair is encoded in the upper bits of an RX token
+const RX_TOKEN_QUEUE_SHIFT: usize = 48;
+/// The context of a queue interrupt stores the driver instance above this bit
+const QUEUE_CONTEXT_INSTANCE_SHIFT: usize = 16;
+/// Maximum size of a frame, which is segmented by the device
+const VIRTIO_NET_MAX_GSO_FRAME_SIZE: usize = 65550;
+/// Size of the Ethernet header, which is not included in the MTU
+const ETHERNET_HEADER_SIZE: usize = 14;
 
 #[repr(C)]
 struct virtio_net_config {
@@ -122,9 +147,10 @@ impl fmt::Debug for virtio_net_config {
 	}
 }
 
+/// Header in front of every frame (see 5.1.6 Device Operation of the virtio specification)
 #[derive(Debug, Default)]
 #[repr(C)]
-struct virtio_net_hdr_legacy {
+struct virtio_net_hdr {
 	flags: u8,
 	gso_type: u8,
 	/// Ethernet + IP + tcp/udp hdrs
@@ -135,11 +161,11 @@ struct virtio_net_hdr_legacy {
 	csum_start: u16,
 	/// Offset after that to place checksum
 	csum_offset: u16,
-	// Number of merged rx buffers
+	/// Number of merged rx buffers
 	num_buffers: u16,
 }
 
-impl virtio_net_hdr_legacy {
+impl virtio_net_hdr {
 	pub fn init(&mut self, len: usize) {
 		self.flags = 0;
 		self.gso_type = VIRTIO_NET_HDR_GSO_NONE;
@@ -151,9 +177,10 @@ impl virtio_net_hdr_legacy {
 	}
 }
 
+/// Header of legacy devices, which have not negotiated VIRTIO_NET_F_MRG_RXBUF
 #[derive(Debug, Default)]
 #[repr(C)]
-struct virtio_net_hdr {
+struct virtio_net_hdr_legacy {
 	flags: u8,
 	gso_type: u8,
 	/// Ethernet + IP + tcp/udp hdrs
@@ -166,7 +193,7 @@ struct virtio_net_hdr {
 	csum_offset: u16,
 }
 
-impl virtio_net_hdr {
+impl virtio_net_hdr_legacy {
 	pub fn init(&mut self, len: usize) {
 		self.flags = 0;
 		self.gso_type = VIRTIO_NET_HDR_GSO_NONE;
@@ -226,14 +253,387 @@ impl Drop for TxBuffer {
 	}
 }
 
-pub struct VirtioNetDriver<'a> {
-	tx_buffers: Vec<TxBuffer>,
+/// Received frame, which is owned by the application until it is released
+enum HeldFrame {
+	/// The frame is stored in its receive buffer, which has been replaced in the ring
+	Buffer(RxBuffer),
+	/// The frame has been merged from several receive buffers
+	Merged(Vec<u8>),
+}
+
+/// Receive and transmit queue of one core
+struct NetQueuePair<'a> {
+	/// Index of the queue pair, which is part of the RX tokens
+	queue: usize,
+	rx_queue: Virtq<'a>,
+	tx_queue: Virtq<'a>,
+	/// Receive buffers, indexed by their descriptor
 	rx_buffers: Vec<RxBuffer>,
+	/// Size of each receive buffer
+	rx_buffer_size: usize,
+	/// Released receive buffers, which are used to refill the ring
+	rx_pool: Vec<RxBuffer>,
+	/// Frames, which are held by the application, indexed by their token
+	held_frames: BTreeMap<usize, HeldFrame>,
+	/// Serial number of the next RX token
+	next_serial: usize,
+	/// Number of buffers of an incomplete frame, which are recycled as soon as the device has used them
+	discard_buffers: usize,
+	/// Frame, which is returned by `receive_rx_buffer` until `rx_buffer_consumed` is called
+	pending_frame: Option<(usize, &'static [u8], u8)>,
+	tx_buffers: Vec<TxBuffer>,
+	/// Size of the virtio-net header, which depends on the negotiated features
+	hdr_len: usize,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// The application polls the device => the receive interrupts stay disabled
+	polling: bool,
+}
+
+impl<'a> NetQueuePair<'a> {
+	fn check_used_elements(&mut self) {
+		while let Some(idx) = self.tx_queue.check_used_elements() {
+			self.tx_buffers[idx as usize].in_use = false;
+		}
+
+		fence(Ordering::SeqCst);
+	}
+
+	fn get_tx_buffer(&mut self, len: usize) -> Result<(*mut u8, usize), ()> {
+		// do we have free buffers?
+		if self.tx_buffers.iter().position(|b| !b.in_use).is_none() {
+			// if not, check if we are able to free used elements
+			self.check_used_elements();
+		}
+
+		let index = self.tx_queue.get_available_buffer()? as usize;
+		let hdr_len = self.hdr_len;
+		let buffer = &mut self.tx_buffers[index];
+		if buffer.in_use || len > buffer.len - hdr_len {
+			//warn!("Buffer {} is already in use!", index);
+			return Err(());
+		}
+
+		buffer.in_use = true;
+		// a zeroed header requests neither checksumming nor segmentation
+		unsafe {
+			ptr::write_bytes(buffer.addr.as_mut_ptr::<u8>(), 0, hdr_len);
+		}
+
+		Ok(((buffer.addr + hdr_len).as_mut_ptr::<u8>(), index))
+	}
+
+	/// Returns a TX buffer, which has not been sent, to the driver.
+	fn release_tx_buffer(&mut self, index: usize) -> Result<(), ()> {
+		self.tx_buffers.get_mut(index).ok_or(())?.in_use = false;
+
+		Ok(())
+	}
+
+	/// Sends a TX buffer. If the buffer can't be sent (e.g. the requested offloads
+	/// aren't supported), it is returned to the driver.
+	fn send_tx_buffer(
+		&mut self,
+		index: usize,
+		len: usize,
+		offload: Option<&TxOffload>,
+	) -> Result<(), ()> {
+		let hdr_len = self.hdr_len;
+		let result = self
+			.write_tx_header(index, offload)
+			.and_then(|_| self.tx_queue.send_non_blocking(index, len + hdr_len));
+		if result.is_err() {
+			let _ = self.release_tx_buffer(index);
+		}
+
+		result
+	}
+
+	/// Requests checksumming and segmentation by the device, if it supports them.
+	fn write_tx_header(&self, index: usize, offload: Option<&TxOffload>) -> Result<(), ()> {
+		let buffer = self.tx_buffers.get(index).ok_or(())?;
+
+		if let Some(offload) = offload {
+			let gso_feature = match offload.gso_type {
+				NET_GSO_NONE => None,
+				NET_GSO_TCPV4 => Some(VIRTIO_NET_F_HOST_TSO4),
+				NET_GSO_TCPV6 => Some(VIRTIO_NET_F_HOST_TSO6),
+				NET_GSO_UDP => Some(VIRTIO_NET_F_HOST_UFO),
+				_ => return Err(()),
+			};
+			if offload.csum && self.features & (1 << VIRTIO_NET_F_CSUM) == 0 {
+				return Err(());
+			}
+			if let Some(feature) = gso_feature {
+				// segmentation implies checksumming of each segment
+				if !offload.csum || self.features & (1 << feature) == 0 {
+					return Err(());
+				}
+			}
+
+			let header = unsafe { &mut *buffer.addr.as_mut_ptr::<virtio_net_hdr_legacy>() };
+			if offload.csum {
+				header.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+				header.csum_start = offload.csum_start;
+				header.csum_offset = offload.csum_offset;
+			}
+			if gso_feature.is_some() {
+				header.gso_type = offload.gso_type;
+				header.gso_size = offload.gso_size;
+				header.hdr_len = offload.hdr_len;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Recycles the remaining buffers of an incomplete frame, as far as the device has used them.
+	/// Returns false, if the device has not used all of them yet.
+	fn drain_incomplete_frame(&mut self) -> bool {
+		while self.discard_buffers > 0 {
+			match self.rx_queue.pop_used_buffer() {
+				Some((index, _)) => {
+					self.rx_queue.recycle_buffer(index, None);
+					self.discard_buffers -= 1;
+				}
+				None => return false,
+			}
+		}
+
+		true
+	}
+
+	/// Takes the next frame from the ring. The frame is owned by the caller until
+	/// it is released, while the ring is refilled immediately.
+	///
+	/// A frame, which spans several mergeable receive buffers, is copied into one
+	/// contiguous `Vec` and its buffers are returned to the ring at once.
+	fn take_rx_frame(&mut self) -> Result<(usize, &'static mut [u8], u8), ()> {
+		if !self.drain_incomplete_frame() {
+			// the rest of the frame may arrive after the interrupts have been enabled
+			if !self.polling {
+				self.rx_queue.set_polling_mode(false);
+			}
+			if !self.drain_incomplete_frame() {
+				return Err(());
+			}
+		}
+
+		let (index, len) = match self.rx_queue.pop_used_buffer() {
+			Some(used) => used,
+			None if self.polling => return Err(()),
+			None => {
+				// The interrupt handler has disabled the interrupts of the queue. The queue
+				// is drained now => switch back to interrupts.
+				self.rx_queue.set_polling_mode(false);
+				// a frame may have arrived before the interrupts have been enabled
+				self.rx_queue.pop_used_buffer().ok_or(())?
+			}
+		};
+		let index = index as usize;
+		let hdr_len = self.hdr_len;
+		let header = unsafe { &*(self.rx_buffers[index].addr.as_ptr::<virtio_net_hdr>()) };
+		let (hdr_flags, csum_start, csum_offset) =
+			(header.flags, header.csum_start, header.csum_offset);
+		// the field num_buffers exists only with mergeable receive buffers
+		let num_buffers = if self.features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0 {
+			header.num_buffers.max(1)
+		} else {
+			1
+		};
+		let len = (len as usize).saturating_sub(hdr_len);
+
+		let (frame, data) = if num_buffers == 1 {
+			// hand out the receive buffer and refill the ring with a spare buffer
+			let rx_buffer_size = self.rx_buffer_size;
+			let spare = self
+				.rx_pool
+				.pop()
+				.unwrap_or_else(|| RxBuffer::new(rx_buffer_size));
+			self.rx_queue
+				.recycle_buffer(index as u32, Some((spare.addr, rx_buffer_size)));
+			let buffer = mem::replace(&mut self.rx_buffers[index], spare);
+			let data = unsafe {
+				slice::from_raw_parts_mut((buffer.addr + hdr_len).as_mut_ptr::<u8>(), len)
+			};
+
+			(HeldFrame::Buffer(buffer), data)
+		} else {
+			// copy the frame into one contiguous buffer
+			let mut merged =
+				Vec::with_capacity(len + usize::from(num_buffers - 1) * self.rx_buffer_size);
+			merged.extend_from_slice(unsafe {
+				slice::from_raw_parts((self.rx_buffers[index].addr + hdr_len).as_ptr::<u8>(), len)
+			});
+			self.rx_queue.recycle_buffer(index as u32, None);
+
+			for i in 1..num_buffers {
+				let (index, len) = match self.rx_queue.pop_used_buffer() {
+					Some(used) => used,
+					None => {
+						warn!("Virtio-Net device used less buffers than announced");
+						// Drop the frame. Its remaining buffers must not be taken for a new frame.
+						self.discard_buffers = usize::from(num_buffers - i);
+						self.drain_incomplete_frame();
+						return Err(());
+					}
+				};
+				merged.extend_from_slice(unsafe {
+					slice::from_raw_parts(
+						self.rx_buffers[index as usize].addr.as_ptr::<u8>(),
+						len as usize,
+					)
+				});
+				self.rx_queue.recycle_buffer(index, None);
+			}
+
+			let data = unsafe { slice::from_raw_parts_mut(merged.as_mut_ptr(), merged.len()) };
+
+			(HeldFrame::Merged(merged), data)
+		};
+
+		let flags = if hdr_flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+			// The frame comes from the host and contains only the checksum of the pseudo header.
+			// Complete the checksum, because the receiver does not know about offloading.
+			complete_checksum(data, usize::from(csum_start), usize::from(csum_offset));
+			NET_RX_CSUM_VALID
+		} else if hdr_flags & VIRTIO_NET_HDR_F_DATA_VALID != 0 {
+			NET_RX_CSUM_VALID
+		} else {
+			0
+		};
+
+		let token = (self.queue << RX_TOKEN_QUEUE_SHIFT) | self.next_serial;
+		self.next_serial = (self.next_serial + 1) & ((1 << RX_TOKEN_QUEUE_SHIFT) - 1);
+		self.held_frames.insert(token, frame);
+
+		Ok((token, data, flags))
+	}
+
+	/// Releases a frame, which has been taken by `take_rx_frame`.
+	fn release_rx_frame(&mut self, token: usize) -> Result<(), ()> {
+		match self.held_frames.remove(&token).ok_or(())? {
+			HeldFrame::Buffer(buffer) => {
+				// keep the buffer to refill the ring later, but limit the size of the pool
+				if self.rx_pool.len() < self.rx_buffers.len() {
+					self.rx_pool.push(buffer);
+				}
+			}
+			HeldFrame::Merged(_) => {}
+		}
+
+		Ok(())
+	}
+
+	fn receive_rx_buffer(&mut self) -> Result<(&'static [u8], u8), ()> {
+		let (_, data, flags) = match self.pending_frame {
+			Some(pending) => pending,
+			None => {
+				let (token, data, flags) = self.take_rx_frame()?;
+				let data: &'static [u8] = data;
+				self.pending_frame = Some((token, data, flags));
+				(token, data, flags)
+			}
+		};
+
+		Ok((data, flags))
+	}
+
+	fn rx_buffer_consumed(&mut self) {
+		if let Some((token, _, _)) = self.pending_frame.take() {
+			let _ = self.release_rx_frame(token);
+		}
+	}
+
+	fn has_packet(&self) -> bool {
+		self.pending_frame.is_some() || self.rx_queue.has_packet()
+	}
+}
+
+/// Computes the Internet checksum from `start` to the end of the frame and stores it
+/// at `start + offset`. The checksum field has to contain the checksum of the pseudo header.
+fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) {
+	if start + offset + 2 > frame.len() {
+		warn!("Invalid checksum position in received frame");
+		return;
+	}
+
+	let mut sum: u32 = 0;
+	for chunk in frame[start..].chunks(2) {
+		let word = if chunk.len() == 2 {
+			u16::from_be_bytes([chunk[0], chunk[1]])
+		} else {
+			u16::from_be_bytes([chunk[0], 0])
+		};
+		sum += u32::from(word);
+	}
+	while sum > 0xFFFF {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+
+	let checksum = !(sum as u16);
+	frame[start + offset..start + offset + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Selects the supported subset of the device features. Features, which depend
+/// on a feature the device does not offer, are dropped.
+fn select_features(device_features: u64) -> u64 {
+	let supported: u64 = VIRTIO_F_VERSION_1
+		| VIRTIO_F_RING_EVENT_IDX
+		| VIRTIO_F_RING_PACKED
+		| (1 << VIRTIO_NET_F_MAC)
+		| (1 << VIRTIO_NET_F_STATUS)
+		| (1 << VIRTIO_NET_F_MTU)
+		| (1 << VIRTIO_NET_F_CSUM)
+		| (1 << VIRTIO_NET_F_HOST_TSO4)
+		| (1 << VIRTIO_NET_F_HOST_TSO6)
+		| (1 << VIRTIO_NET_F_HOST_UFO)
+		| (1 << VIRTIO_NET_F_GUEST_CSUM)
+		| (1 << VIRTIO_NET_F_GUEST_TSO4)
+		| (1 << VIRTIO_NET_F_GUEST_TSO6)
+		| (1 << VIRTIO_NET_F_GUEST_UFO)
+		| (1 << VIRTIO_NET_F_MRG_RXBUF)
+		| (1 << VIRTIO_NET_F_CTRL_VQ)
+		| (1 << VIRTIO_NET_F_MQ);
+	let mut features = device_features & supported;
+
+	// see 5.1.3.1 Feature bit requirements
+	if features & (1 << VIRTIO_NET_F_CSUM) == 0 {
+		features &= !((1 << VIRTIO_NET_F_HOST_TSO4)
+			| (1 << VIRTIO_NET_F_HOST_TSO6)
+			| (1 << VIRTIO_NET_F_HOST_UFO));
+	}
+	if features & (1 << VIRTIO_NET_F_GUEST_CSUM) == 0 {
+		features &= !((1 << VIRTIO_NET_F_GUEST_TSO4)
+			| (1 << VIRTIO_NET_F_GUEST_TSO6)
+			| (1 << VIRTIO_NET_F_GUEST_UFO));
+	}
+	// the number of queue pairs is set by a command on the control queue
+	if features & (1 << VIRTIO_NET_F_CTRL_VQ) == 0 {
+		features &= !(1 << VIRTIO_NET_F_MQ);
+	}
+
+	features
+}
+
+pub struct VirtioNetDriver<'a> {
 	common_cfg: &'a mut virtio_pci_common_cfg,
 	device_cfg: &'a virtio_net_config,
 	isr_cfg: &'a mut u32,
 	notify_cfg: VirtioNotification,
-	vqueues: Option<Vec<Virtq<'a>>>,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// MAC address of the device or a random address, if the device has none
+	mac: [u8; 6],
+	/// MSI-X table of the device, if the device supports MSI-X
+	msix_table: Option<pci::MsixTable>,
+	/// Every core uses its own queue pair, if the device supports VIRTIO_NET_F_MQ.
+	/// The queue pairs are locked separately, so that the cores do not serialize on one lock.
+	queue_pairs: Vec<SpinlockIrqSave<NetQueuePair<'a>>>,
+	/// Control queue, which exists if VIRTIO_NET_F_CTRL_VQ is negotiated
+	ctrl_queue: Option<SpinlockIrqSave<Virtq<'a>>>,
+	/// Queue pair of the frame, which a core has received by `receive_rx_buffer`
+	current_rx_queue: Vec<AtomicUsize>,
 }
 
 impl<'a> fmt::Debug for VirtioNetDriver<'a> {
@@ -243,56 +643,109 @@ impl<'a> fmt::Debug for VirtioNetDriver<'a> {
 		write!(f, "device_cfg: {:?}, ", self.device_cfg)?;
 		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
 		write!(f, "nofity_cfg: {:?}, ", self.notify_cfg)?;
-		match &self.vqueues {
-			None => write!(f, "Uninitialized VQs")?,
-			Some(vqs) => write!(f, "Initialized {} VQs", vqs.len())?,
+		write!(f, "features: 0x{:x}, ", self.features)?;
+		if self.queue_pairs.is_empty() {
+			write!(f, "Uninitialized VQs")?;
+		} else {
+			write!(f, "Initialized {} VQ pairs", self.queue_pairs.len())?;
 		}
 		write!(f, "}}")
 	}
 }
 
 impl<'a> VirtioNetDriver<'a> {
-	pub fn init_vqs(&mut self) {
-		let common_cfg = &mut self.common_cfg;
-		let device_cfg = &self.device_cfg;
-		let notify_cfg = &mut self.notify_cfg;
+	/// Creates the virtqueue `index`, which signals its events by the MSI-X table entry `vector`.
+	fn create_vq(&mut self, index: u16, vector: u16) -> Option<Virtq<'a>> {
+		virtio::set_queue_vector(self.common_cfg, index, vector);
+		Virtq::new_from_common(index, self.common_cfg, &mut self.notify_cfg, self.features)
+	}
 
+	pub fn init_vqs(&mut self) {
 		debug!("Setting up virtqueues...");
 
-		let vqnum = 2;
-		let mut vqueues = Vec::<Virtq>::new();
-
-		// create the queues and tell device about them
-		for i in 0..vqnum as u16 {
-			// TODO: catch error
-			let vq = Virtq::new_from_common(i, common_cfg, notify_cfg).unwrap();
-			vqueues.push(vq);
+		let max_pairs = if self.features & (1 << VIRTIO_NET_F_MQ) != 0 {
+			usize::from(self.device_cfg.max_virtqueue_pairs)
+		} else {
+			1
+		};
+		let core_count = apic::get_detected_core_count();
+		let mut num_pairs = max_pairs.min(core_count).min(VIRTIO_NET_MAX_QUEUE_PAIRS);
+		if let Some(msix_table) = &self.msix_table {
+			// every receive queue needs its own vector
+			num_pairs = num_pairs.min(msix_table.len());
+			// we are not interested in configuration changes
+			self.common_cfg.msix_config = VIRTIO_MSI_NO_VECTOR;
 		}
 
-		let vqsize = common_cfg.queue_size as usize;
-		{
-			let buffer_size: usize = 65562;
-			let mut vec_buffer = &mut self.rx_buffers;
-			for i in 0..vqsize {
-				let buffer = RxBuffer::new(buffer_size);
-				let addr = buffer.addr;
-				vqueues[VIRTIO_NET_RX_QUEUE].add_buffer(i, addr, buffer_size, VIRTQ_DESC_F_WRITE);
-				vec_buffer.push(buffer);
+		for pair in 0..num_pairs {
+			let vector = if self.msix_table.is_some() {
+				pair as u16
+			} else {
+				VIRTIO_MSI_NO_VECTOR
+			};
+
+			// TODO: catch error
+			let mut rx_queue = self
+				.create_vq(2 * pair as u16 + VIRTIO_NET_RX_QUEUE, vector)
+				.unwrap();
+			let rx_size = self.common_cfg.queue_size as usize;
+			// used TX buffers are reclaimed lazily => the transmit queues do not need interrupts
+			let mut tx_queue = self
+				.create_vq(2 * pair as u16 + VIRTIO_NET_TX_QUEUE, VIRTIO_MSI_NO_VECTOR)
+				.unwrap();
+			let tx_size = self.common_cfg.queue_size as usize;
+			tx_queue.set_polling_mode(true);
+
+			let rx_buffer_size = self.get_rx_buffer_size();
+			let mut rx_buffers = Vec::with_capacity(rx_size);
+			for i in 0..rx_size {
+				let buffer = RxBuffer::new(rx_buffer_size);
+				rx_queue.add_buffer(i, buffer.addr, rx_buffer_size, VIRTQ_DESC_F_WRITE);
+				rx_buffers.push(buffer);
 			}
-		}
 
-		{
-			let buffer_size: usize = self.get_mtu() as usize;
-			let mut vec_buffer = &mut self.tx_buffers;
-			for i in 0..vqsize {
+			// the device segments large frames, if TSO or UFO is negotiated
+			let buffer_size: usize = if self.get_offloads()
+				& (NET_OFFLOAD_TX_TSO4 | NET_OFFLOAD_TX_TSO6 | NET_OFFLOAD_TX_UFO)
+				!= 0
+			{
+				VIRTIO_NET_MAX_GSO_FRAME_SIZE
+			} else {
+				usize::from(self.get_mtu()) + ETHERNET_HEADER_SIZE
+			};
+			let mut tx_buffers = Vec::with_capacity(tx_size);
+			for i in 0..tx_size {
 				let buffer = TxBuffer::new(buffer_size);
-				let addr = buffer.addr;
-				vqueues[VIRTIO_NET_TX_QUEUE].add_buffer(i, addr, buffer_size, VIRTQ_DESC_F_DEFAULT);
-				vec_buffer.push(buffer);
+				tx_queue.add_buffer(i, buffer.addr, buffer_size, VIRTQ_DESC_F_DEFAULT);
+				tx_buffers.push(buffer);
 			}
+
+			self.queue_pairs.push(SpinlockIrqSave::new(NetQueuePair {
+				queue: pair,
+				rx_queue,
+				tx_queue,
+				rx_buffers,
+				rx_buffer_size,
+				rx_pool: Vec::new(),
+				held_frames: BTreeMap::new(),
+				next_serial: 0,
+				discard_buffers: 0,
+				pending_frame: None,
+				tx_buffers,
+				hdr_len: self.get_header_size(),
+				features: self.features,
+				polling: false,
+			}));
 		}
 
-		self.vqueues = Some(vqueues);
+		// the control queue follows the last queue pair of the device
+		if self.features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0 {
+			self.ctrl_queue = self
+				.create_vq(2 * max_pairs as u16, VIRTIO_MSI_NO_VECTOR)
+				.map(SpinlockIrqSave::new);
+		}
+
+		self.current_rx_queue = (0..core_count).map(|_| AtomicUsize::new(0)).collect();
 	}
 
 	pub fn negotiate_features(&mut self) {
@@ -303,27 +756,92 @@ impl<'a> VirtioNetDriver<'a> {
 		common_cfg.device_feature_select = 1;
 		device_features |= (common_cfg.device_feature as u64) << 32;
 
-		let required: u64 = (1 << VIRTIO_NET_F_MAC)
-			| (1 << VIRTIO_NET_F_STATUS)
-			| (1 << VIRTIO_NET_F_GUEST_UFO)
-			| (1 << VIRTIO_NET_F_GUEST_TSO4)
-			| (1 << VIRTIO_NET_F_GUEST_TSO6)
-			| (1 << VIRTIO_NET_F_GUEST_CSUM)
-			/*| (1 << VIRTIO_NET_F_RSC_EXT) | VIRTIO_F_RING_EVENT_IDX*/;
-
-		if device_features & required == required {
-			common_cfg.driver_feature_select = 1;
-			common_cfg.driver_feature |= required as u32;
-			info!(
-				"Virtio features: device 0x{:x} vs required 0x{:x}",
-				device_features, required
-			);
+		let features = select_features(device_features);
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
+
+		info!(
+			"Virtio features: device 0x{:x}, accepted 0x{:x}",
+			device_features, features
+		);
+		if features & (1 << VIRTIO_NET_F_CSUM) == 0 {
+			info!("Virtio-Net device does not offer checksum offloading");
+		}
+		if features & (1 << VIRTIO_NET_F_MAC) == 0 {
+			info!("Virtio-Net device has no MAC address, using a random address");
+		}
+	}
+
+	/// Returns the size of the virtio-net header in front of every frame.
+	fn get_header_size(&self) -> usize {
+		// Only a legacy device without VIRTIO_NET_F_MRG_RXBUF omits the number of merged buffers.
+		if self.features & (VIRTIO_F_VERSION_1 | (1 << VIRTIO_NET_F_MRG_RXBUF)) != 0 {
+			mem::size_of::<virtio_net_hdr>()
+		} else {
+			mem::size_of::<virtio_net_hdr_legacy>()
+		}
+	}
+
+	/// Returns the size of a receive buffer including the virtio-net header.
+	fn get_rx_buffer_size(&self) -> usize {
+		if self.features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0 {
+			// large frames are spread over several buffers
+			BasePageSize::SIZE
+		} else if self.features
+			& ((1 << VIRTIO_NET_F_GUEST_TSO4)
+				| (1 << VIRTIO_NET_F_GUEST_TSO6)
+				| (1 << VIRTIO_NET_F_GUEST_UFO))
+			!= 0
+		{
+			align_up!(
+				VIRTIO_NET_MAX_GSO_FRAME_SIZE + self.get_header_size(),
+				BasePageSize::SIZE
+			)
+		} else {
+			align_up!(
+				usize::from(self.get_mtu()) + ETHERNET_HEADER_SIZE + self.get_header_size(),
+				BasePageSize::SIZE
+			)
+		}
+	}
+
+	/// Returns the offloading capabilities (NET_OFFLOAD_*), which are
+	/// supported by the device and accepted by the driver.
+	pub fn get_offloads(&self) -> u32 {
+		let mapping = [
+			(VIRTIO_NET_F_CSUM, NET_OFFLOAD_TX_CSUM),
+			(VIRTIO_NET_F_HOST_TSO4, NET_OFFLOAD_TX_TSO4),
+			(VIRTIO_NET_F_HOST_TSO6, NET_OFFLOAD_TX_TSO6),
+			(VIRTIO_NET_F_HOST_UFO, NET_OFFLOAD_TX_UFO),
+			(VIRTIO_NET_F_GUEST_CSUM, NET_OFFLOAD_RX_CSUM),
+			(VIRTIO_NET_F_GUEST_TSO4, NET_OFFLOAD_RX_TSO),
+			(VIRTIO_NET_F_GUEST_TSO6, NET_OFFLOAD_RX_TSO),
+		];
+
+		mapping
+			.iter()
+			.filter(|(feature, _)| self.features & (1 << feature) != 0)
+			.fold(0, |offloads, (_, offload)| offloads | offload)
+	}
+
+	/// Tells the device how many queue pairs are used (see 5.1.6.5.5 Automatic receive steering in multiqueue mode).
+	fn set_queue_pairs(&self, num_pairs: u16) -> Result<(), ()> {
+		let ctrl_queue = self.ctrl_queue.as_ref().ok_or(())?;
+		let header = [VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET];
+		let data = num_pairs.to_le_bytes();
+		let mut ack = [VIRTIO_NET_ERR];
+
+		ctrl_queue
+			.lock()
+			.send_blocking(&[&header[..], &data[..]], Some(&[&mut ack[..]]));
+
+		if unsafe { ptr::read_volatile(&ack[0]) } == VIRTIO_NET_OK {
+			Ok(())
 		} else {
-			error!("Device doesn't offer required feature to support Virtio-Net");
-			error!(
-				"Virtio features: 0x{:x} vs 0x{:x}",
-				device_features, required
-			);
+			Err(())
 		}
 	}
 
@@ -358,98 +876,289 @@ impl<'a> VirtioNetDriver<'a> {
 
 		// 8. Set the DRIVER_OK status bit. At this point the device is “live”.
 		self.common_cfg.device_status |= 4;
+
+		// Without this command, the device receives only on the first queue pair.
+		if self.features & (1 << VIRTIO_NET_F_MQ) != 0 {
+			let num_pairs = self.queue_pairs.len();
+			if self.set_queue_pairs(num_pairs as u16).is_ok() {
+				info!("Virtio-Net uses {} queue pairs", num_pairs);
+			} else {
+				warn!("Unable to enable multiple queue pairs, using only one queue pair");
+				self.queue_pairs.truncate(1);
+			}
+		}
 	}
 
-	pub fn check_used_elements(&mut self) {
-		let mut buffers = &mut self.tx_buffers;
-		while let Some(idx) = (self.vqueues.as_deref_mut().unwrap())[1].check_used_elements() {
-			buffers[idx as usize].in_use = false;
+	/// Disables the interrupts of a receive queue after an interrupt. The interrupts are
+	/// enabled again, when the receive path has drained the queue. Consequently, a burst of
+	/// frames triggers only one interrupt.
+	fn disable_rx_interrupts(&self, queue: usize) {
+		if let Some(queue_pair) = self.queue_pairs.get(queue) {
+			queue_pair.lock().rx_queue.set_polling_mode(true);
 		}
+	}
 
-		fence(Ordering::SeqCst);
+	/// Disables the receive interrupts, while the application polls the device.
+	pub fn set_polling_mode(&self, value: bool) {
+		for queue_pair in &self.queue_pairs {
+			let mut queue_pair = queue_pair.lock();
+			queue_pair.polling = value;
+			queue_pair.rx_queue.set_polling_mode(value);
+		}
+
+		// Frames, which have arrived while polling, do not trigger an interrupt
+		// anymore => wake up the network thread.
+		#[cfg(not(feature = "newlib"))]
+		{
+			if !value && self.has_packet() {
+				netwakeup();
+			}
+		}
 	}
 
-	pub fn handle_interrupt(&mut self) -> bool {
-		let isr_status = *(self.isr_cfg);
-		if (isr_status & 0x1) == 0x1 {
-			// handle incoming packets
-			#[cfg(not(feature = "newlib"))]
-			netwakeup();
+	pub fn get_mac_address(&self) -> [u8; 6] {
+		self.mac
+	}
 
-			return true;
+	pub fn get_mtu(&self) -> u16 {
+		if self.features & (1 << VIRTIO_NET_F_MTU) != 0 {
+			self.device_cfg.mtu
+		} else {
+			1500
 		}
+	}
 
-		false
+	/// Returns the number of queue pairs, which are used by the driver.
+	pub fn get_queue_count(&self) -> usize {
+		self.queue_pairs.len()
 	}
 
-	pub fn set_polling_mode(&mut self, value: bool) {
-		(self.vqueues.as_deref_mut().unwrap())[VIRTIO_NET_RX_QUEUE].set_polling_mode(value);
+	/// Returns the queue pair, which is used by the current core.
+	fn default_queue(&self) -> usize {
+		core_id() as usize % self.queue_pairs.len()
 	}
 
-	pub fn get_mac_address(&self) -> [u8; 6] {
-		self.device_cfg.mac
+	fn queue_pair(&self, queue: usize) -> Result<&SpinlockIrqSave<NetQueuePair<'a>>, ()> {
+		self.queue_pairs.get(queue).ok_or(())
 	}
 
-	pub fn get_mtu(&self) -> u16 {
-		1500 //self.device_cfg.mtu
+	pub fn get_tx_buffer(&self, len: usize) -> Result<(*mut u8, usize), ()> {
+		self.get_tx_buffer_on_queue(self.default_queue(), len)
 	}
 
-	pub fn get_tx_buffer(&mut self, len: usize) -> Result<(*mut u8, usize), ()> {
-		let mut buffers = &mut self.tx_buffers;
+	/// Returns a TX buffer of the given queue pair. The handle identifies
+	/// the buffer as well as the queue pair.
+	pub fn get_tx_buffer_on_queue(&self, queue: usize, len: usize) -> Result<(*mut u8, usize), ()> {
+		let (buffer, index) = self.queue_pair(queue)?.lock().get_tx_buffer(len)?;
 
-		// do we have free buffers?
-		if buffers.iter().position(|b| !b.in_use).is_none() {
-			// if not, check if we are able to free used elements
-			self.check_used_elements();
-		}
+		Ok((buffer, (queue << TX_HANDLE_QUEUE_SHIFT) | index))
+	}
 
-		let index = (self.vqueues.as_ref().unwrap())[VIRTIO_NET_TX_QUEUE].get_available_buffer()?;
-		let index = index as usize;
+	pub fn send_tx_buffer(&self, handle: usize, len: usize) -> Result<(), ()> {
+		let queue = handle >> TX_HANDLE_QUEUE_SHIFT;
+		let index = handle & ((1 << TX_HANDLE_QUEUE_SHIFT) - 1);
+
+		self.queue_pair(queue)?
+			.lock()
+			.send_tx_buffer(index, len, None)
+	}
+
+	/// Returns a TX buffer, which has been reserved by `get_tx_buffer`, without sending it.
+	pub fn release_tx_buffer(&self, handle: usize) -> Result<(), ()> {
+		let queue = handle >> TX_HANDLE_QUEUE_SHIFT;
+		let index = handle & ((1 << TX_HANDLE_QUEUE_SHIFT) - 1);
+
+		self.queue_pair(queue)?.lock().release_tx_buffer(index)
+	}
+
+	/// Sends the TX buffer and requests checksumming and segmentation by the device.
+	pub fn send_tx_buffer_with_offload(
+		&self,
+		handle: usize,
+		len: usize,
+		offload: &TxOffload,
+	) -> Result<(), ()> {
+		let queue = handle >> TX_HANDLE_QUEUE_SHIFT;
+		let index = handle & ((1 << TX_HANDLE_QUEUE_SHIFT) - 1);
+
+		self.queue_pair(queue)?
+			.lock()
+			.send_tx_buffer(index, len, Some(offload))
+	}
+
+	pub fn has_packet(&self) -> bool {
+		self.queue_pairs
+			.iter()
+			.any(|queue_pair| queue_pair.lock().has_packet())
+	}
+
+	/// Receives a frame from any queue pair. The search starts with the queue pair of the current core.
+	pub fn receive_rx_buffer(&self) -> Result<(&'static [u8], u8), ()> {
+		let first = self.default_queue();
+		let count = self.queue_pairs.len();
 
-		let mut buffers = &mut self.tx_buffers;
-		if !buffers[index].in_use {
-			buffers[index].in_use = true;
-			let header = buffers[index].addr.as_mut_ptr::<virtio_net_hdr>();
-			unsafe {
-				(*header).init(len);
+		for i in 0..count {
+			let queue = (first + i) % count;
+			if let Ok(buffer) = self.receive_rx_buffer_on_queue(queue) {
+				if let Some(current) = self.current_rx_queue.get(core_id() as usize) {
+					current.store(queue, Ordering::Relaxed);
+				}
+
+				return Ok(buffer);
 			}
+		}
 
-			Ok((
-				(buffers[index].addr + mem::size_of::<virtio_net_hdr>()).as_mut_ptr::<u8>(),
-				index,
-			))
-		} else {
-			//warn!("Buffer {} is already in use!", index);
-			Err(())
+		Err(())
+	}
+
+	/// Receives a frame and its flags (NET_RX_*) from the given queue pair.
+	pub fn receive_rx_buffer_on_queue(&self, queue: usize) -> Result<(&'static [u8], u8), ()> {
+		self.queue_pair(queue)?.lock().receive_rx_buffer()
+	}
+
+	/// Returns the frame, which the current core has received by `receive_rx_buffer`, to the device.
+	pub fn rx_buffer_consumed(&self) -> Result<(), ()> {
+		let queue = self
+			.current_rx_queue
+			.get(core_id() as usize)
+			.map_or(0, |current| current.load(Ordering::Relaxed));
+
+		self.rx_buffer_consumed_on_queue(queue)
+	}
+
+	pub fn rx_buffer_consumed_on_queue(&self, queue: usize) -> Result<(), ()> {
+		self.queue_pair(queue)?.lock().rx_buffer_consumed();
+
+		Ok(())
+	}
+
+	/// Takes a frame from any queue pair without copying it. The frame stays valid
+	/// until it is returned by `release_rx_buffer`. Returns the token of the frame,
+	/// the frame itself and its flags (NET_RX_*).
+	pub fn take_rx_buffer(&self) -> Result<(usize, &'static mut [u8], u8), ()> {
+		let first = self.default_queue();
+		let count = self.queue_pairs.len();
+
+		(0..count)
+			.map(|i| (first + i) % count)
+			.find_map(|queue| self.take_rx_buffer_on_queue(queue).ok())
+			.ok_or(())
+	}
+
+	/// Takes a frame from the given queue pair without copying it.
+	pub fn take_rx_buffer_on_queue(
+		&self,
+		queue: usize,
+	) -> Result<(usize, &'static mut [u8], u8), ()> {
+		self.queue_pair(queue)?.lock().take_rx_frame()
+	}
+
+	/// Returns a frame, which has been taken by `take_rx_buffer`, to the driver.
+	pub fn release_rx_buffer(&self, token: usize) -> Result<(), ()> {
+		self.queue_pair(token >> RX_TOKEN_QUEUE_SHIFT)?
+			.lock()
+			.release_rx_frame(token)
+	}
+}
+
+/// Returns a random, locally administered unicast MAC address.
+fn random_mac_address() -> [u8; 6] {
+	let random = processor::generate_random_number64().unwrap_or_else(processor::get_timestamp);
+	let bytes = random.to_le_bytes();
+
+	[0x02, bytes[0], bytes[1], bytes[2], bytes[3], bytes[4]]
+}
+
+// The queue pairs are protected by locks and the configuration is only changed during the initialization.
+unsafe impl Send for VirtioNetDriver<'_> {}
+unsafe impl Sync for VirtioNetDriver<'_> {}
+
+impl pci::PciDevice for VirtioNetDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	/// With MSI-X, every receive queue gets its own interrupt, which is steered
+	/// to the core owning the queue pair.
+	fn install_interrupt_handler(
+		&self,
+		adapter: &pci::PciAdapter,
+		name: &'static str,
+		instance: usize,
+	) {
+		match &self.msix_table {
+			Some(msix_table) => {
+				for pair in 0..self.queue_pairs.len() {
+					msix_table.install_handler(
+						pair,
+						pair as CoreId,
+						name,
+						queue_interrupt_handler,
+						(instance << QUEUE_CONTEXT_INSTANCE_SHIFT) | pair,
+					);
+				}
+			}
+			None => {
+				adapter.install_interrupt_handler(name, pci::device_interrupt_handler, instance)
+			}
 		}
 	}
 
-	pub fn send_tx_buffer(&mut self, index: usize, len: usize) -> Result<(), ()> {
-		(self.vqueues.as_deref_mut().unwrap())[VIRTIO_NET_TX_QUEUE]
-			.send_non_blocking(index, len + mem::size_of::<virtio_net_hdr>())
+	fn handle_interrupt(&self) -> bool {
+		let isr_status = *(self.isr_cfg);
+		if (isr_status & 0x1) == 0x1 {
+			for queue in 0..self.queue_pairs.len() {
+				self.disable_rx_interrupts(queue);
+			}
+
+			// handle incoming packets
+			#[cfg(not(feature = "newlib"))]
+			netwakeup();
+
+			return true;
+		}
+
+		false
 	}
+}
 
-	pub fn has_packet(&self) -> bool {
-		(self.vqueues.as_ref().unwrap())[VIRTIO_NET_RX_QUEUE].has_packet()
+/// PCI driver of virtio network devices
+pub struct VirtioNetPciDriver;
+
+impl pci::PciDriver for VirtioNetPciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-net"
 	}
 
-	pub fn receive_rx_buffer(&self) -> Result<&'static [u8], ()> {
-		let (idx, len) = (self.vqueues.as_ref().unwrap())[VIRTIO_NET_RX_QUEUE].get_used_buffer()?;
-		let addr = self.rx_buffers[idx as usize].addr;
-		let virtio_net_hdr = unsafe { &*(addr.as_ptr::<virtio_net_hdr>()) };
-		let rx_buffer_slice = unsafe {
-			slice::from_raw_parts(
-				(addr + mem::size_of::<virtio_net_hdr>()).as_ptr::<u8>(),
-				len as usize,
-			)
-		};
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x1041)]
+	}
 
-		Ok(rx_buffer_slice)
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtionet_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
 	}
+}
 
-	pub fn rx_buffer_consumed(&mut self) {
-		(self.vqueues.as_deref_mut().unwrap())[VIRTIO_NET_RX_QUEUE].buffer_consumed();
+/// Interrupt handler of a receive queue, which signals its events by MSI-X.
+/// The context consists of the instance of the driver and the queue pair.
+fn queue_interrupt_handler(context: usize) -> bool {
+	let instance = context >> QUEUE_CONTEXT_INSTANCE_SHIFT;
+	let queue = context & ((1 << QUEUE_CONTEXT_INSTANCE_SHIFT) - 1);
+	trace!("Receive interrupt of virtio-net queue {}", queue);
+
+	if let Some(driver) = pci::get_device_instance::<VirtioNetDriver<'static>>(instance) {
+		driver.disable_rx_interrupts(queue);
 	}
+
+	// handle incoming packets
+	#[cfg(not(feature = "newlib"))]
+	netwakeup();
+
+	true
 }
 
 pub fn create_virtionet_driver(adapter: &pci::PciAdapter) -> Option<VirtioNetDriver<'static>> {
@@ -457,7 +1166,7 @@ pub fn create_virtionet_driver(adapter: &pci::PciAdapter) -> Option<VirtioNetDri
 	// also see https://elixir.bootlin.com/linux/latest/source/drivers/virtio/virtio_pci_modern.c#L581 (virtio_pci_modern_probe)
 	// Read status register
 	let bus = adapter.bus;
-	let device = adapter.device;
+	let device = adapter.devfn;
 	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
 
 	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
@@ -522,20 +1231,32 @@ pub fn create_virtionet_driver(adapter: &pci::PciAdapter) -> Option<VirtioNetDri
 
 	// Instanciate driver on heap, so it outlives this function
 	let mut drv = VirtioNetDriver {
-		tx_buffers: Vec::new(),
-		rx_buffers: Vec::new(),
 		common_cfg,
 		device_cfg,
 		isr_cfg,
 		notify_cfg,
-		vqueues: None,
+		features: 0,
+		mac: [0; 6],
+		msix_table: adapter.enable_msix(),
+		queue_pairs: Vec::new(),
+		ctrl_queue: None,
+		current_rx_queue: Vec::new(),
 	};
 
 	trace!("Driver before init: {:?}", drv);
 	drv.init();
 	trace!("Driver after init: {:?}", drv);
 
-	if device_cfg.status & VIRTIO_NET_S_LINK_UP == VIRTIO_NET_S_LINK_UP {
+	drv.mac = if drv.features & (1 << VIRTIO_NET_F_MAC) != 0 {
+		device_cfg.mac
+	} else {
+		random_mac_address()
+	};
+
+	// Without VIRTIO_NET_F_STATUS, the link is assumed to be up.
+	if drv.features & (1 << VIRTIO_NET_F_STATUS) == 0
+		|| device_cfg.status & VIRTIO_NET_S_LINK_UP == VIRTIO_NET_S_LINK_UP
+	{
 		info!("Virtio-Net link is up");
 	} else {
 		info!("Virtio-Net link is down");
@@ -544,3 +1265,35 @@ pub fn create_virtionet_driver(adapter: &pci::PciAdapter) -> Option<VirtioNetDri
 
 	Some(drv)
 }
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn select_offered_features() {
+	// TSO without checksum offloading and MQ without control queue are not allowed.
+	let offered =
+		(1 << VIRTIO_NET_F_HOST_TSO4) | (1 << VIRTIO_NET_F_GUEST_CSUM) | (1 << VIRTIO_NET_F_MQ);
+	assert_eq!(select_features(offered), 1 << VIRTIO_NET_F_GUEST_CSUM);
+
+	let offered = VIRTIO_F_VERSION_1
+		| (1 << VIRTIO_NET_F_CSUM)
+		| (1 << VIRTIO_NET_F_HOST_TSO4)
+		| (1 << VIRTIO_NET_F_CTRL_VQ)
+		| (1 << VIRTIO_NET_F_MQ)
+		| (1 << VIRTIO_NET_F_CTRL_VLAN);
+	assert_eq!(
+		select_features(offered),
+		offered & !(1 << VIRTIO_NET_F_CTRL_VLAN)
+	);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn complete_partial_checksum() {
+	// UDP header with the checksum of the pseudo header 0x1234 and a payload of three bytes
+	let mut frame = [
+		0x00, 0x35, 0x00, 0x35, 0x00, 0x0b, 0x12, 0x34, 0x01, 0x02, 0x03,
+	];
+	complete_checksum(&mut frame, 0, 6);
+	// 0x0035 + 0x0035 + 0x000b + 0x1234 + 0x0102 + 0x0300 = 0x16ab
+	assert_eq!(frame[6..8], [0xe9, 0x54]);
+}
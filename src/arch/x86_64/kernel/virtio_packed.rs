@@ -0,0 +1,433 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Packed virtqueues (see 2.7 Packed Virtqueues of the virtio specification 1.1)
+//!
+//! Descriptors are added to and removed from one ring. The position of a descriptor
+//! in the ring is independent of its buffer id, which is returned by the device.
+
+use crate::arch::x86_64::kernel::virtio::consts::*;
+use crate::arch::x86_64::kernel::virtio::{
+	virtio_pci_common_cfg, vring_need_event, VirtioNotification,
+};
+use crate::arch::x86_64::mm::paging;
+use crate::arch::x86_64::mm::{PhysAddr, VirtAddr};
+use crate::config::VIRTIO_MAX_QUEUE_SIZE;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ptr;
+use core::sync::atomic::spin_loop_hint;
+use core::sync::atomic::{fence, Ordering};
+
+/// The descriptor is available, if this flag is equal to the wrap counter of the driver
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+/// The descriptor is used, if this flag and VIRTQ_DESC_F_AVAIL are equal to the wrap counter of the device
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+/// Notifications are enabled
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+/// Notifications are disabled
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+/// Notifications are enabled for the descriptor, which is specified by off_wrap
+/// (only with VIRTIO_F_RING_EVENT_IDX)
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+
+/// Position of the wrap counter in off_wrap
+const RING_EVENT_WRAP_SHIFT: u16 = 15;
+
+// Packed virtqueue descriptor: 16 bytes
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Debug, Default)]
+struct pvirtq_desc {
+	// Buffer address (guest-physical)
+	addr: u64,
+	// Buffer length
+	len: u32,
+	// Buffer id
+	id: u16,
+	// The flags as indicated above (VIRTQ_DESC_F_*)
+	flags: u16,
+}
+
+// Event suppression structure of the driver and of the device
+#[repr(C, align(4))]
+#[derive(Clone, Copy, Debug, Default)]
+struct pvirtq_event_suppress {
+	// Descriptor ring change event offset and wrap counter (only with RING_EVENT_FLAGS_DESC)
+	off_wrap: u16,
+	// Descriptor ring change event flags (RING_EVENT_FLAGS_*)
+	flags: u16,
+}
+
+pub struct PackedVirtq<'a> {
+	index: u16,  // Index of vq in common config
+	vqsize: u16, // Elements in the ring
+	ring: &'a mut [pvirtq_desc],
+	// Written by the driver to suppress used buffer notifications
+	driver_event: &'a mut pvirtq_event_suppress,
+	// Written by the device to suppress available buffer notifications
+	device_event: &'a pvirtq_event_suppress,
+	// Address where queue index is written to on notify
+	queue_notify_address: &'a mut u16,
+	// The device specifies the descriptor, which triggers a notification (VIRTIO_F_RING_EVENT_IDX)
+	event_idx: bool,
+	// Position and wrap counter of the next available descriptor
+	next_avail: u16,
+	avail_wrap_counter: bool,
+	// Position and wrap counter of the next used descriptor
+	next_used: u16,
+	used_wrap_counter: bool,
+	// Number of descriptors, which are not owned by the device
+	num_free: u16,
+	// Buffers, which are registered by add_buffer (guest-physical address, length, flags), indexed by id
+	buffers: Vec<(u64, u32, u16)>,
+	// Number of descriptors of the outstanding buffers, indexed by id
+	chain_len: Vec<u16>,
+	// Ids, which are not used by an outstanding chain of send_chain
+	free_ids: Vec<u16>,
+}
+
+impl<'a> PackedVirtq<'a> {
+	pub fn new_from_common(
+		index: u16,
+		common_cfg: &mut virtio_pci_common_cfg,
+		notify_cfg: &mut VirtioNotification,
+		features: u64,
+	) -> Option<Self> {
+		// 4.1.5.1.3 Virtqueue Configuration
+		common_cfg.queue_select = index;
+
+		// The queue size of a packed virtqueue does not have to be a power of 2.
+		if common_cfg.queue_size == 0 {
+			return None;
+		} else if common_cfg.queue_size > VIRTIO_MAX_QUEUE_SIZE {
+			common_cfg.queue_size = VIRTIO_MAX_QUEUE_SIZE;
+		}
+		let vqsize = common_cfg.queue_size;
+
+		info!(
+			"Initializing packed virtqueue {}, of size {}",
+			index, vqsize
+		);
+
+		// Leak memory so it wont get deallocated
+		// The descriptor ring has to be 16 byte aligned, the event suppression structures 4 byte aligned.
+		let ring = Box::leak(vec![pvirtq_desc::default(); usize::from(vqsize)].into_boxed_slice());
+		let driver_event = Box::leak(Box::new(pvirtq_event_suppress::default()));
+		let device_event = Box::leak(Box::new(pvirtq_event_suppress::default()));
+
+		// The descriptor area contains the ring, the driver area and the device area the event suppression structures.
+		common_cfg.queue_select = index;
+		common_cfg.queue_desc = paging::virt_to_phys(VirtAddr(ring.as_ptr() as u64)).as_u64();
+		common_cfg.queue_avail =
+			paging::virt_to_phys(VirtAddr(driver_event as *mut _ as u64)).as_u64();
+		common_cfg.queue_used =
+			paging::virt_to_phys(VirtAddr(device_event as *const _ as u64)).as_u64();
+		common_cfg.queue_enable = 1;
+
+		debug!(
+			"desc 0x{:x}, driver 0x{:x}, device 0x{:x}",
+			common_cfg.queue_desc, common_cfg.queue_avail, common_cfg.queue_used
+		);
+
+		Some(PackedVirtq {
+			index,
+			vqsize,
+			ring,
+			driver_event,
+			device_event,
+			queue_notify_address: notify_cfg.get_notify_addr(common_cfg.queue_notify_off as u32),
+			event_idx: features & VIRTIO_F_RING_EVENT_IDX != 0,
+			next_avail: 0,
+			avail_wrap_counter: true,
+			next_used: 0,
+			used_wrap_counter: true,
+			num_free: vqsize,
+			buffers: vec![(0, 0, 0); usize::from(vqsize)],
+			chain_len: vec![0; usize::from(vqsize)],
+			free_ids: (0..vqsize).rev().collect(),
+		})
+	}
+
+	fn notify_device(&mut self) {
+		trace!("Notifying device of updated virtqueue ({})...!", self.index);
+		*self.queue_notify_address = self.index;
+	}
+
+	/// Sends an available buffer notification to the device, if such notifications are not suppressed.
+	/// `added` is the number of descriptors, which have been added since the last notification.
+	fn kick(&mut self, added: u16) {
+		// The driver has to make the descriptors visible before it checks for notification suppression.
+		fence(Ordering::SeqCst);
+
+		// 2.7.10 Driver and Device Event Suppression
+		let flags = unsafe { ptr::read_volatile(&self.device_event.flags) };
+		let should_notify = if self.event_idx && flags == RING_EVENT_FLAGS_DESC {
+			let off_wrap = unsafe { ptr::read_volatile(&self.device_event.off_wrap) };
+			let new_idx = self.next_avail;
+			let old_idx = new_idx.wrapping_sub(added);
+			let mut event_idx = off_wrap & !(1 << RING_EVENT_WRAP_SHIFT);
+			// the event refers to the previous turn of the ring
+			if (off_wrap >> RING_EVENT_WRAP_SHIFT != 0) != self.avail_wrap_counter {
+				event_idx = event_idx.wrapping_sub(self.vqsize);
+			}
+
+			vring_need_event(event_idx, new_idx, old_idx)
+		} else {
+			flags != RING_EVENT_FLAGS_DISABLE
+		};
+
+		if should_notify {
+			self.notify_device();
+		}
+	}
+
+	/// Returns the flags, which mark a descriptor as available in the current turn of the ring.
+	fn avail_flags(&self) -> u16 {
+		if self.avail_wrap_counter {
+			VIRTQ_DESC_F_AVAIL
+		} else {
+			VIRTQ_DESC_F_USED
+		}
+	}
+
+	/// Returns true, if the descriptor flags mark a used descriptor in the current turn of the ring.
+	fn is_used(&self, flags: u16) -> bool {
+		let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+		let used = flags & VIRTQ_DESC_F_USED != 0;
+
+		avail == used && used == self.used_wrap_counter
+	}
+
+	/// Adds a chain of buffers (guest-physical address, length, flags) with the buffer id `id` to the ring.
+	fn add_chain(&mut self, id: u16, elements: &[(u64, u32, u16)]) -> Result<(), ()> {
+		if elements.is_empty() || elements.len() > usize::from(self.num_free) {
+			return Err(());
+		}
+
+		let head = usize::from(self.next_avail);
+		let mut head_flags = 0;
+		for (i, &(addr, len, flags)) in elements.iter().enumerate() {
+			let mut flags = (flags & !VIRTQ_DESC_F_NEXT) | self.avail_flags();
+			if i + 1 < elements.len() {
+				flags |= VIRTQ_DESC_F_NEXT;
+			}
+
+			let desc = &mut self.ring[usize::from(self.next_avail)];
+			desc.addr = addr;
+			desc.len = len;
+			desc.id = id;
+			// The flags of the head are written at last, because they pass the whole chain to the device.
+			if i == 0 {
+				head_flags = flags;
+			} else {
+				desc.flags = flags;
+			}
+
+			self.next_avail += 1;
+			if self.next_avail == self.vqsize {
+				self.next_avail = 0;
+				self.avail_wrap_counter = !self.avail_wrap_counter;
+			}
+		}
+
+		self.num_free -= elements.len() as u16;
+		self.chain_len[usize::from(id)] = elements.len() as u16;
+
+		// The driver performs a suitable memory barrier to ensure the device sees the
+		// descriptors before the flags of the head.
+		fence(Ordering::SeqCst);
+		unsafe {
+			ptr::write_volatile(&mut self.ring[head].flags, head_flags);
+		}
+
+		Ok(())
+	}
+
+	/// Returns the next used descriptor (buffer id, written length) without removing it from the ring.
+	fn peek_used(&self) -> Option<(u16, u32)> {
+		let desc = &self.ring[usize::from(self.next_used)];
+		let flags = unsafe { ptr::read_volatile(&desc.flags) };
+		if !self.is_used(flags) {
+			return None;
+		}
+
+		// The id and the length are valid only after the flags have been read.
+		fence(Ordering::SeqCst);
+		let id = unsafe { ptr::read_volatile(&desc.id) };
+		let len = unsafe { ptr::read_volatile(&desc.len) };
+
+		Some((id, len))
+	}
+
+	pub fn send_non_blocking(&mut self, index: usize, len: usize) -> Result<(), ()> {
+		// data is already stored in the registered buffer => we have only to inform the host
+		// that a new buffer is available
+		if usize::from(self.next_avail) != index {
+			warn!(
+				"Available index {} is different from buffer index {}",
+				self.next_avail, index
+			);
+		}
+
+		let (addr, _, flags) = self.buffers[index];
+		self.add_chain(
+			index.try_into().unwrap(),
+			&[(addr, len.try_into().unwrap(), flags)],
+		)?;
+		self.kick(1);
+
+		Ok(())
+	}
+
+	// Places dat in virtq, waits until buffer is used and response is in rsp_buf.
+	pub fn send_blocking(&mut self, dat: &[&[u8]], rsp_buf: Option<&[&mut [u8]]>) {
+		let id = match self.send_chain(dat, rsp_buf.unwrap_or(&[])) {
+			Ok(id) => id,
+			Err(()) => {
+				error!("Unable to send request on virtqueue {}", self.index);
+				return;
+			}
+		};
+
+		// wait until done (placed in used buffer)
+		let used_id = loop {
+			if let Some((used_id, _)) = self.pop_used_buffer() {
+				break used_id;
+			}
+			spin_loop_hint();
+		};
+		assert_eq!(used_id, id);
+
+		self.release_chain(id);
+	}
+
+	pub fn send_chain(&mut self, dat: &[&[u8]], rsp_buf: &[&mut [u8]]) -> Result<u32, ()> {
+		let mut elements = Vec::with_capacity(dat.len() + rsp_buf.len());
+		for dat in dat {
+			let addr = paging::virt_to_phys(VirtAddr(dat.as_ptr() as u64)).as_u64();
+			elements.push((addr, dat.len() as u32, VIRTQ_DESC_F_DEFAULT));
+		}
+		// if we want to receive a reply, we have to chain further descriptors, which declare VIRTQ_DESC_F_WRITE
+		for dat in rsp_buf {
+			let addr = paging::virt_to_phys(VirtAddr(dat.as_ptr() as u64)).as_u64();
+			elements.push((addr, dat.len() as u32, VIRTQ_DESC_F_WRITE));
+		}
+
+		self.send_elements(&elements)
+	}
+
+	pub fn send_physical_chain(&mut self, rsp_buf: &[(PhysAddr, usize)]) -> Result<u32, ()> {
+		let elements: Vec<_> = rsp_buf
+			.iter()
+			.map(|&(addr, len)| (addr.as_u64(), len as u32, VIRTQ_DESC_F_WRITE))
+			.collect();
+
+		self.send_elements(&elements)
+	}
+
+	fn send_elements(&mut self, elements: &[(u64, u32, u16)]) -> Result<u32, ()> {
+		// The buffer id has to be unique among all outstanding chains.
+		let id = self.free_ids.pop().ok_or(())?;
+		if self.add_chain(id, elements).is_err() {
+			self.free_ids.push(id);
+			return Err(());
+		}
+		self.kick(elements.len() as u16);
+
+		Ok(u32::from(id))
+	}
+
+	pub fn release_chain(&mut self, id: u32) {
+		self.free_ids.push(id.try_into().unwrap());
+	}
+
+	pub fn check_used_elements(&mut self) -> Option<u32> {
+		self.pop_used_buffer().map(|(id, _)| id)
+	}
+
+	pub fn add_buffer(&mut self, index: usize, addr: VirtAddr, len: usize, flags: u16) {
+		let addr = paging::virt_to_phys(addr).as_u64();
+		self.buffers[index] = (addr, len.try_into().unwrap(), flags);
+
+		// device-writable buffers are passed to the device immediately
+		if flags != 0 {
+			let _ = self.add_chain(index.try_into().unwrap(), &[self.buffers[index]]);
+		}
+	}
+
+	pub fn set_polling_mode(&mut self, value: bool) {
+		let flags = if value {
+			RING_EVENT_FLAGS_DISABLE
+		} else {
+			RING_EVENT_FLAGS_ENABLE
+		};
+		unsafe {
+			ptr::write_volatile(&mut self.driver_event.flags, flags);
+		}
+
+		// publish the setting before the caller checks the ring again
+		fence(Ordering::SeqCst);
+	}
+
+	pub fn has_packet(&self) -> bool {
+		self.peek_used().is_some()
+	}
+
+	pub fn get_available_buffer(&self) -> Result<u32, ()> {
+		Ok(u32::from(self.next_avail))
+	}
+
+	pub fn get_used_buffer(&self) -> Result<(u32, u32), ()> {
+		self.peek_used()
+			.map(|(id, len)| (u32::from(id), len))
+			.ok_or(())
+	}
+
+	pub fn buffer_consumed(&mut self) {
+		if let Some((index, _)) = self.pop_used_buffer() {
+			self.recycle_buffer(index, None);
+		}
+	}
+
+	pub fn pop_used_buffer(&mut self) -> Option<(u32, u32)> {
+		let (id, len) = self.peek_used()?;
+
+		// the device has used the whole chain => skip its descriptors
+		let count = self.chain_len[usize::from(id)];
+		self.num_free += count;
+		self.next_used += count;
+		if self.next_used >= self.vqsize {
+			self.next_used -= self.vqsize;
+			self.used_wrap_counter = !self.used_wrap_counter;
+		}
+
+		Some((u32::from(id), len))
+	}
+
+	pub fn recycle_buffer(&mut self, index: u32, buffer: Option<(VirtAddr, usize)>) {
+		let index = index as usize;
+		if let Some((addr, len)) = buffer {
+			self.buffers[index].0 = paging::virt_to_phys(addr).as_u64();
+			self.buffers[index].1 = len.try_into().unwrap();
+		}
+
+		if self
+			.add_chain(index.try_into().unwrap(), &[self.buffers[index]])
+			.is_ok()
+		{
+			self.kick(1);
+		} else {
+			warn!(
+				"Virtqueue {} has no free descriptor for buffer {}",
+				self.index, index
+			);
+		}
+	}
+}
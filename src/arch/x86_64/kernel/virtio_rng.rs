@@ -0,0 +1,310 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Driver for virtio entropy devices (see 5.4 Entropy Device of the virtio specification)
+//!
+//! The device fills buffers with random bytes of the host, which are added to the
+//! entropy pool of the kernel. Buffers are only requested, while the pool is not full.
+
+use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::virtio::{
+	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
+};
+use crate::entropy;
+use crate::synch::spinlock::SpinlockIrqSave;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::{fmt, mem, ptr};
+
+/// Number of random bytes, which are requested at once
+const REQUEST_SIZE: usize = 64;
+
+struct RngState<'a> {
+	vq: Option<Virtq<'a>>,
+	/// Buffers, which are filled by the device
+	buffers: BTreeMap<u32, Box<[u8]>>,
+}
+
+pub struct VirtioRngDriver<'a> {
+	common_cfg: &'a mut virtio_pci_common_cfg,
+	isr_cfg: &'a mut u32,
+	notify_cfg: VirtioNotification,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// MSI-X table of the device, whose first entry is used by the request queue
+	msix_table: Option<pci::MsixTable>,
+	state: SpinlockIrqSave<RngState<'a>>,
+}
+
+// The state is protected by a lock and the configuration is only changed during the initialization.
+unsafe impl Send for VirtioRngDriver<'_> {}
+unsafe impl Sync for VirtioRngDriver<'_> {}
+
+impl<'a> fmt::Debug for VirtioRngDriver<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "VirtioRngDriver {{ ")?;
+		write!(f, "common_cfg: {:?}, ", self.common_cfg)?;
+		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
+		write!(f, "notify_cfg: {:?}, ", self.notify_cfg)?;
+		write!(f, "features: 0x{:x}", self.features)?;
+		write!(f, " }}")
+	}
+}
+
+/// Returns the feature bits, which the driver accepts from `device_features`.
+fn select_features(device_features: u64) -> u64 {
+	let supported: u64 = VIRTIO_F_VERSION_1 | VIRTIO_F_RING_EVENT_IDX | VIRTIO_F_RING_PACKED;
+
+	device_features & supported
+}
+
+impl<'a> VirtioRngDriver<'a> {
+	pub fn init_vqs(&mut self) {
+		debug!("Setting up virtqueues...");
+
+		let vector = if self.msix_table.is_some() {
+			// we are not interested in configuration changes
+			self.common_cfg.msix_config = VIRTIO_MSI_NO_VECTOR;
+			0
+		} else {
+			VIRTIO_MSI_NO_VECTOR
+		};
+
+		virtio::set_queue_vector(self.common_cfg, 0, vector);
+		let vq = Virtq::new_from_common(0, self.common_cfg, &mut self.notify_cfg, self.features);
+		if vq.is_none() {
+			error!("Unable to set up the request queue of the entropy device");
+		}
+
+		self.state.lock().vq = vq;
+	}
+
+	pub fn negotiate_features(&mut self) {
+		let common_cfg = &mut self.common_cfg;
+		// Linux kernel reads 2x32 featurebits: https://elixir.bootlin.com/linux/latest/ident/vp_get_features
+		common_cfg.device_feature_select = 0;
+		let mut device_features: u64 = common_cfg.device_feature as u64;
+		common_cfg.device_feature_select = 1;
+		device_features |= (common_cfg.device_feature as u64) << 32;
+
+		let features = select_features(device_features);
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
+
+		info!(
+			"Virtio features: device 0x{:x}, accepted 0x{:x}",
+			device_features, features
+		);
+	}
+
+	/// 3.1 VirtIO Device Initialization
+	pub fn init(&mut self) {
+		// 1. Reset the device.
+		self.common_cfg.device_status = 0;
+
+		// 2. Set the ACKNOWLEDGE status bit: the guest OS has notice the device.
+		self.common_cfg.device_status |= 1;
+
+		// 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+		self.common_cfg.device_status |= 2;
+
+		// 4. Read device feature bits, and write the subset of feature bits understood by the OS and driver to the device.
+		self.negotiate_features();
+
+		// 5. Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
+		self.common_cfg.device_status |= 8;
+
+		// 6. Re-read device status to ensure the FEATURES_OK bit is still set:
+		//   otherwise, the device does not support our subset of features and the device is unusable.
+		if self.common_cfg.device_status & 8 == 0 {
+			error!("Device unset FEATURES_OK, aborting!");
+			return;
+		}
+
+		// 7. Perform device-specific setup, including discovery of virtqueues for the device.
+		self.init_vqs();
+
+		// 8. Set the DRIVER_OK status bit. At this point the device is “live”.
+		self.common_cfg.device_status |= 4;
+	}
+
+	/// Asks the device for random bytes, if no request is pending. The bytes are
+	/// added to the entropy pool by the interrupt handler.
+	pub fn request_entropy(&self) {
+		let mut guard = self.state.lock();
+		let state = &mut *guard;
+		let vq = match &mut state.vq {
+			Some(vq) if state.buffers.is_empty() => vq,
+			_ => return,
+		};
+
+		let mut buffer = vec![0u8; REQUEST_SIZE].into_boxed_slice();
+		let addr = buffer.as_ptr() as usize;
+		let mut rest: &mut [u8] = &mut buffer;
+		let mut segments: Vec<&mut [u8]> = Vec::new();
+		for (_, len) in virtio::page_segments(addr, rest.len()) {
+			let (segment, tail) = mem::take(&mut rest).split_at_mut(len);
+			segments.push(segment);
+			rest = tail;
+		}
+
+		let result = vq.send_chain(&[], &segments);
+		drop(segments);
+		if let Ok(id) = result {
+			state.buffers.insert(id, buffer);
+		}
+	}
+
+	/// Returns the random bytes, which the device has provided.
+	fn receive_entropy(&self) -> Vec<u8> {
+		let mut data = Vec::new();
+		let mut guard = self.state.lock();
+		let state = &mut *guard;
+		let vq = match &mut state.vq {
+			Some(vq) => vq,
+			None => return data,
+		};
+
+		while let Some((id, len)) = vq.pop_used_buffer() {
+			vq.release_chain(id);
+			if let Some(buffer) = state.buffers.remove(&id) {
+				data.extend_from_slice(&buffer[..(len as usize).min(buffer.len())]);
+			}
+		}
+
+		data
+	}
+}
+
+impl pci::PciDevice for VirtioRngDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	fn handle_interrupt(&self) -> bool {
+		// With MSI-X, the device does not use the ISR status and the interrupt is not shared.
+		if self.msix_table.is_none() {
+			let isr_status = unsafe { ptr::read_volatile(&*self.isr_cfg) };
+			if isr_status & 0x1 == 0 {
+				return false;
+			}
+		}
+
+		// The pool is locked separately, because it requests entropy from the driver.
+		let data = self.receive_entropy();
+		if !data.is_empty() {
+			entropy::add_entropy(&data, 8 * data.len());
+		}
+		if entropy::needs_entropy() {
+			self.request_entropy();
+		}
+
+		true
+	}
+}
+
+/// PCI driver of virtio entropy devices
+pub struct VirtioRngPciDriver;
+
+impl pci::PciDriver for VirtioRngPciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-rng"
+	}
+
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x1044)]
+	}
+
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtiorng_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
+	}
+}
+
+pub fn create_virtiorng_driver(adapter: &pci::PciAdapter) -> Option<VirtioRngDriver<'static>> {
+	// Scan capabilities to get common config, which we need to reset the device and get basic info.
+	let bus = adapter.bus;
+	let device = adapter.devfn;
+	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
+
+	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
+	if status & pci::PCI_STATUS_CAPABILITIES_LIST == 0 {
+		error!("Found virtio device without capability list. Likely legacy-device! Aborting.");
+		return None;
+	}
+
+	// Get pointer to capability list
+	let caplist = pci::read_config(bus, device, pci::PCI_CAPABILITY_LIST_REGISTER) & 0xFF;
+
+	// get common config mapped, cast to virtio_pci_common_cfg
+	let common_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_COMMON_CFG) {
+			Some((cap_common_raw, _)) => unsafe {
+				&mut *(cap_common_raw.as_mut_ptr::<virtio_pci_common_cfg>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_COMMON_CFG. Aborting!");
+				return None;
+			}
+		};
+	// the entropy device has no device specific configuration
+	let isr_cfg = match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_ISR_CFG)
+	{
+		Some((cap_isr_raw, _)) => unsafe { &mut *(cap_isr_raw.as_mut_ptr::<u32>()) },
+		None => {
+			error!("Could not find VIRTIO_PCI_CAP_ISR_CFG. Aborting!");
+			return None;
+		}
+	};
+	// get device notifications mapped
+	let (notification_ptr, notify_off_multiplier) =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_NOTIFY_CFG) {
+			Some((cap_notification_raw, notify_off_multiplier)) => (
+				cap_notification_raw.as_mut_ptr::<u16>(),
+				notify_off_multiplier,
+			),
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_NOTIFY_CFG. Aborting!");
+				return None;
+			}
+		};
+	let notify_cfg = VirtioNotification {
+		notification_ptr,
+		notify_off_multiplier,
+	};
+
+	let mut drv = VirtioRngDriver {
+		common_cfg,
+		isr_cfg,
+		notify_cfg,
+		features: 0,
+		msix_table: adapter.enable_msix(),
+		state: SpinlockIrqSave::new(RngState {
+			vq: None,
+			buffers: BTreeMap::new(),
+		}),
+	};
+
+	trace!("Driver before init: {:?}", drv);
+	drv.init();
+	trace!("Driver after init: {:?}", drv);
+
+	// the first entropy of the host is added, as soon as the interrupts are enabled
+	drv.request_entropy();
+	info!("Virtio-Rng device feeds the entropy pool");
+
+	Some(drv)
+}
@@ -0,0 +1,1197 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Driver for virtio socket devices (see 5.10 Socket Device of the virtio specification)
+//!
+//! The device connects stream sockets of the guest with sockets of the host, which
+//! are addressed by a context id (CID) and a port. The driver implements the connection
+//! handling and the credit based flow control of the protocol. The sockets are exposed
+//! to applications as file descriptors of the address family AF_VSOCK.
+
+use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::virtio::{
+	self, consts::*, virtio_pci_common_cfg, VirtioNotification, Virtq,
+};
+use crate::arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use crate::errno::*;
+use crate::synch::spinlock::SpinlockIrqSave;
+use crate::synch::waitqueue::WaitQueue;
+use crate::syscalls::{POLLERR, POLLHUP, POLLIN, POLLOUT, POLLRDHUP};
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::convert::TryInto;
+use core::{fmt, mem, ptr};
+
+const VIRTIO_VSOCK_F_STREAM: u64 = 0;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The peer won't receive more data
+pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+/// The peer won't send more data
+pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+const VIRTIO_VSOCK_SHUTDOWN_BOTH: u32 = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const EVENT_QUEUE: u16 = 2;
+
+/// Size of the packet header on the wire
+const HEADER_SIZE: usize = 44;
+/// Number and payload size of the receive buffers
+const RX_BUFFERS: usize = 64;
+const RX_BUFFER_SIZE: usize = 4096;
+/// Number and size of the buffers of the event queue
+const EVENT_BUFFERS: usize = 4;
+const EVENT_SIZE: usize = mem::size_of::<u32>();
+/// Receive buffer of a connection, which is announced to the peer
+const BUFFER_SIZE: u32 = 256 * 1024;
+/// Ports below are reserved for services and not assigned to unbound sockets.
+const FIRST_EPHEMERAL_PORT: u32 = 1024;
+
+#[repr(C)]
+#[derive(Debug)]
+struct virtio_vsock_config {
+	guest_cid: u64,
+}
+
+/// Header of a packet (see 5.10.6 Device Operation). The header is serialized
+/// explicitly, because its fields are not naturally aligned.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct PacketHeader {
+	src_cid: u64,
+	dst_cid: u64,
+	src_port: u32,
+	dst_port: u32,
+	len: u32,
+	type_: u16,
+	op: u16,
+	flags: u32,
+	buf_alloc: u32,
+	fwd_cnt: u32,
+}
+
+impl PacketHeader {
+	fn parse(data: &[u8]) -> Option<Self> {
+		if data.len() < HEADER_SIZE {
+			return None;
+		}
+
+		let u16_at =
+			|offset: usize| u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap());
+		let u32_at =
+			|offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+		let u64_at =
+			|offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+		Some(Self {
+			src_cid: u64_at(0),
+			dst_cid: u64_at(8),
+			src_port: u32_at(16),
+			dst_port: u32_at(20),
+			len: u32_at(24),
+			type_: u16_at(28),
+			op: u16_at(30),
+			flags: u32_at(32),
+			buf_alloc: u32_at(36),
+			fwd_cnt: u32_at(40),
+		})
+	}
+
+	fn write(&self, data: &mut [u8]) {
+		data[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+		data[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+		data[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+		data[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+		data[24..28].copy_from_slice(&self.len.to_le_bytes());
+		data[28..30].copy_from_slice(&self.type_.to_le_bytes());
+		data[30..32].copy_from_slice(&self.op.to_le_bytes());
+		data[32..36].copy_from_slice(&self.flags.to_le_bytes());
+		data[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+		data[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+	}
+}
+
+/// Identifies a connection by the local port and the address of the peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConnectionKey {
+	pub local_port: u32,
+	pub peer_cid: u64,
+	pub peer_port: u32,
+}
+
+struct Connection {
+	/// The peer has accepted the connection
+	connected: bool,
+	/// Error of a refused or reset connection
+	error: Option<i32>,
+	input: VecDeque<u8>,
+	/// Shutdown flags of the local side and of the peer
+	shutdown: u32,
+	peer_shutdown: u32,
+	/// Number of bytes, which have been sent to the peer
+	tx_cnt: u32,
+	/// Number of received bytes, which have been consumed by the application
+	fwd_cnt: u32,
+	/// `fwd_cnt`, which has been announced to the peer last
+	last_fwd_cnt: u32,
+	peer_buf_alloc: u32,
+	peer_fwd_cnt: u32,
+	/// A credit request is outstanding
+	credit_requested: bool,
+}
+
+impl Connection {
+	fn new(connected: bool, peer_buf_alloc: u32, peer_fwd_cnt: u32) -> Self {
+		Self {
+			connected,
+			error: None,
+			input: VecDeque::new(),
+			shutdown: 0,
+			peer_shutdown: 0,
+			tx_cnt: 0,
+			fwd_cnt: 0,
+			last_fwd_cnt: 0,
+			peer_buf_alloc,
+			peer_fwd_cnt,
+			credit_requested: false,
+		}
+	}
+
+	/// Returns the number of bytes, which the peer is able to buffer.
+	fn peer_credit(&self) -> u32 {
+		let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+		self.peer_buf_alloc.saturating_sub(in_flight)
+	}
+
+	/// Handles a packet of the peer and returns the operation and flags of the reply.
+	fn receive(&mut self, header: &PacketHeader, payload: &[u8]) -> Option<(u16, u32)> {
+		// every packet announces the current credit of the peer
+		self.peer_buf_alloc = header.buf_alloc;
+		self.peer_fwd_cnt = header.fwd_cnt;
+		self.credit_requested = false;
+
+		match header.op {
+			VIRTIO_VSOCK_OP_RESPONSE if !self.connected && self.error.is_none() => {
+				self.connected = true;
+				None
+			}
+			VIRTIO_VSOCK_OP_RW if self.connected && self.error.is_none() => {
+				if self.input.len() + payload.len() > BUFFER_SIZE as usize {
+					// the peer has exceeded our credit => drop the data and reset the connection
+					self.error = Some(-ECONNRESET);
+					return Some((VIRTIO_VSOCK_OP_RST, 0));
+				}
+
+				if self.shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0 {
+					self.input.extend(payload.iter());
+				} else {
+					// nobody reads the data anymore
+					self.fwd_cnt = self.fwd_cnt.wrapping_add(payload.len() as u32);
+				}
+				None
+			}
+			VIRTIO_VSOCK_OP_CREDIT_UPDATE => None,
+			VIRTIO_VSOCK_OP_CREDIT_REQUEST => Some((VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0)),
+			VIRTIO_VSOCK_OP_SHUTDOWN => {
+				self.peer_shutdown |= header.flags & VIRTIO_VSOCK_SHUTDOWN_BOTH;
+				// the connection is closed by the peer, which waits for our reset
+				if self.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_BOTH {
+					Some((VIRTIO_VSOCK_OP_RST, 0))
+				} else {
+					None
+				}
+			}
+			VIRTIO_VSOCK_OP_RST => {
+				if !self.connected {
+					self.error = Some(-ECONNREFUSED);
+				} else if self.peer_shutdown != VIRTIO_VSOCK_SHUTDOWN_BOTH {
+					self.error = Some(-ECONNRESET);
+				}
+				None
+			}
+			_ => {
+				// the peer violates the protocol
+				self.error = Some(-ECONNRESET);
+				Some((VIRTIO_VSOCK_OP_RST, 0))
+			}
+		}
+	}
+}
+
+struct Listener {
+	backlog: usize,
+	/// Established connections, which have not been accepted yet
+	pending: VecDeque<ConnectionKey>,
+}
+
+/// State of the connections and listeners, which is independent of the virtqueues.
+/// Outgoing packets are collected in `outbox` and sent by the driver.
+struct Sockets {
+	guest_cid: u64,
+	connections: BTreeMap<ConnectionKey, Connection>,
+	listeners: BTreeMap<u32, Listener>,
+	next_port: u32,
+	outbox: VecDeque<Box<[u8]>>,
+}
+
+impl Sockets {
+	fn new() -> Self {
+		Self {
+			guest_cid: 0,
+			connections: BTreeMap::new(),
+			listeners: BTreeMap::new(),
+			next_port: FIRST_EPHEMERAL_PORT,
+			outbox: VecDeque::new(),
+		}
+	}
+
+	fn port_in_use(&self, port: u32) -> bool {
+		self.listeners.contains_key(&port)
+			|| self.connections.keys().any(|key| key.local_port == port)
+	}
+
+	fn allocate_port(&mut self) -> u32 {
+		loop {
+			let port = self.next_port;
+			self.next_port = if port >= u32::MAX - 1 {
+				FIRST_EPHEMERAL_PORT
+			} else {
+				port + 1
+			};
+
+			if !self.port_in_use(port) {
+				return port;
+			}
+		}
+	}
+
+	/// Queues a packet for the connection `key`, which announces the consumed data of the connection.
+	fn send_packet(&mut self, key: &ConnectionKey, op: u16, flags: u32, payload: &[u8]) {
+		let fwd_cnt = match self.connections.get_mut(key) {
+			Some(connection) => {
+				connection.last_fwd_cnt = connection.fwd_cnt;
+				connection.fwd_cnt
+			}
+			None => 0,
+		};
+
+		let header = PacketHeader {
+			src_cid: self.guest_cid,
+			dst_cid: key.peer_cid,
+			src_port: key.local_port,
+			dst_port: key.peer_port,
+			len: payload.len() as u32,
+			type_: VIRTIO_VSOCK_TYPE_STREAM,
+			op,
+			flags,
+			buf_alloc: BUFFER_SIZE,
+			fwd_cnt,
+		};
+
+		let mut packet = vec![0u8; HEADER_SIZE + payload.len()].into_boxed_slice();
+		header.write(&mut packet[..HEADER_SIZE]);
+		packet[HEADER_SIZE..].copy_from_slice(payload);
+		self.outbox.push_back(packet);
+	}
+
+	fn handle_packet(&mut self, data: &[u8]) {
+		let header = match PacketHeader::parse(data) {
+			Some(header) => header,
+			None => return,
+		};
+		let payload_len = (header.len as usize).min(data.len() - HEADER_SIZE);
+		let payload = &data[HEADER_SIZE..HEADER_SIZE + payload_len];
+		let key = ConnectionKey {
+			local_port: header.dst_port,
+			peer_cid: header.src_cid,
+			peer_port: header.src_port,
+		};
+
+		if header.type_ != VIRTIO_VSOCK_TYPE_STREAM || header.dst_cid != self.guest_cid {
+			if header.op != VIRTIO_VSOCK_OP_RST {
+				self.send_packet(&key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+			}
+			return;
+		}
+
+		let reply = match self.connections.get_mut(&key) {
+			Some(connection) => connection.receive(&header, payload),
+			None if header.op == VIRTIO_VSOCK_OP_REQUEST => {
+				match self.listeners.get_mut(&header.dst_port) {
+					Some(listener) if listener.pending.len() < listener.backlog => {
+						listener.pending.push_back(key);
+						self.connections
+							.insert(key, Connection::new(true, header.buf_alloc, header.fwd_cnt));
+						Some((VIRTIO_VSOCK_OP_RESPONSE, 0))
+					}
+					_ => Some((VIRTIO_VSOCK_OP_RST, 0)),
+				}
+			}
+			// a reset must not be answered by a reset
+			None if header.op == VIRTIO_VSOCK_OP_RST => None,
+			None => Some((VIRTIO_VSOCK_OP_RST, 0)),
+		};
+
+		if let Some((op, flags)) = reply {
+			self.send_packet(&key, op, flags, &[]);
+		}
+	}
+
+	/// The device has lost all connections, e.g. after the migration of the guest.
+	fn reset(&mut self, guest_cid: u64) {
+		self.guest_cid = guest_cid;
+		self.outbox.clear();
+		for connection in self.connections.values_mut() {
+			if connection.error.is_none() {
+				connection.error = Some(-ECONNRESET);
+			}
+		}
+	}
+
+	fn listen(&mut self, port: Option<u32>, backlog: usize) -> Result<u32, i32> {
+		let port = match port {
+			Some(port) if self.port_in_use(port) => return Err(-EADDRINUSE),
+			Some(port) => port,
+			None => self.allocate_port(),
+		};
+
+		self.listeners.insert(
+			port,
+			Listener {
+				backlog: backlog.max(1),
+				pending: VecDeque::new(),
+			},
+		);
+
+		Ok(port)
+	}
+
+	fn unlisten(&mut self, port: u32) {
+		if let Some(listener) = self.listeners.remove(&port) {
+			// refuse the connections, which have not been accepted
+			for key in listener.pending {
+				self.close(&key);
+			}
+		}
+	}
+
+	fn accept(&mut self, port: u32) -> Result<ConnectionKey, i32> {
+		self.listeners
+			.get_mut(&port)
+			.ok_or(-EINVAL)?
+			.pending
+			.pop_front()
+			.ok_or(-EAGAIN)
+	}
+
+	fn listener_events(&self, port: u32) -> i16 {
+		match self.listeners.get(&port) {
+			Some(listener) if !listener.pending.is_empty() => POLLIN,
+			Some(_) => 0,
+			None => POLLERR,
+		}
+	}
+
+	fn connect(
+		&mut self,
+		local_port: Option<u32>,
+		peer_cid: u64,
+		peer_port: u32,
+	) -> Result<ConnectionKey, i32> {
+		let local_port = match local_port {
+			Some(port) if self.listeners.contains_key(&port) => return Err(-EADDRINUSE),
+			Some(port) => port,
+			None => self.allocate_port(),
+		};
+		let key = ConnectionKey {
+			local_port,
+			peer_cid,
+			peer_port,
+		};
+		if self.connections.contains_key(&key) {
+			return Err(-EADDRINUSE);
+		}
+
+		self.connections.insert(key, Connection::new(false, 0, 0));
+		self.send_packet(&key, VIRTIO_VSOCK_OP_REQUEST, 0, &[]);
+
+		Ok(key)
+	}
+
+	/// Returns true, if the connection has been established, and false, while it is pending.
+	fn is_connected(&self, key: &ConnectionKey) -> Result<bool, i32> {
+		let connection = self.connections.get(key).ok_or(-ENOTCONN)?;
+		match connection.error {
+			Some(error) if !connection.connected => Err(error),
+			_ => Ok(connection.connected),
+		}
+	}
+
+	fn connection_events(&self, key: &ConnectionKey) -> i16 {
+		let connection = match self.connections.get(key) {
+			Some(connection) => connection,
+			None => return POLLERR | POLLHUP,
+		};
+
+		let mut events = 0;
+		if !connection.input.is_empty() {
+			events |= POLLIN;
+		}
+		if connection.error.is_some() {
+			return events | POLLIN | POLLERR | POLLHUP;
+		}
+		if !connection.connected {
+			return events;
+		}
+
+		if connection.shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+			|| connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+		{
+			events |= POLLIN | POLLRDHUP;
+		}
+		if connection.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_BOTH {
+			events |= POLLHUP;
+		}
+		if connection.shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+			|| connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+			|| connection.peer_credit() > 0
+		{
+			// writes don't block anymore
+			events |= POLLOUT;
+		}
+
+		events
+	}
+
+	fn recv(&mut self, key: &ConnectionKey, buf: &mut [u8]) -> Result<usize, i32> {
+		let connection = self.connections.get_mut(key).ok_or(-ENOTCONN)?;
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		if connection.input.is_empty() {
+			return if let Some(error) = connection.error {
+				Err(error)
+			} else if !connection.connected {
+				Err(-ENOTCONN)
+			} else if connection.shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+				|| connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+			{
+				Ok(0)
+			} else {
+				Err(-EAGAIN)
+			};
+		}
+
+		let len = buf.len().min(connection.input.len());
+		for (dst, src) in buf.iter_mut().zip(connection.input.drain(..len)) {
+			*dst = src;
+		}
+		connection.fwd_cnt = connection.fwd_cnt.wrapping_add(len as u32);
+
+		// Announce the free space, before the peer runs out of credit.
+		let consumed = connection.fwd_cnt.wrapping_sub(connection.last_fwd_cnt);
+		if consumed >= BUFFER_SIZE / 4
+			&& connection.error.is_none()
+			&& connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND == 0
+		{
+			self.send_packet(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+		}
+
+		Ok(len)
+	}
+
+	fn send(&mut self, key: &ConnectionKey, buf: &[u8]) -> Result<usize, i32> {
+		let connection = self.connections.get_mut(key).ok_or(-ENOTCONN)?;
+		if let Some(error) = connection.error {
+			return Err(error);
+		}
+		if !connection.connected {
+			return Err(-ENOTCONN);
+		}
+		if connection.shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+			|| connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+		{
+			return Err(-EPIPE);
+		}
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		let credit = connection.peer_credit();
+		if credit == 0 {
+			if !connection.credit_requested {
+				connection.credit_requested = true;
+				self.send_packet(key, VIRTIO_VSOCK_OP_CREDIT_REQUEST, 0, &[]);
+			}
+			return Err(-EAGAIN);
+		}
+
+		let len = buf.len().min(credit as usize);
+		connection.tx_cnt = connection.tx_cnt.wrapping_add(len as u32);
+		for chunk in buf[..len].chunks(BasePageSize::SIZE) {
+			self.send_packet(key, VIRTIO_VSOCK_OP_RW, 0, chunk);
+		}
+
+		Ok(len)
+	}
+
+	fn shutdown(&mut self, key: &ConnectionKey, flags: u32) -> Result<(), i32> {
+		let connection = self.connections.get_mut(key).ok_or(-ENOTCONN)?;
+		if !connection.connected {
+			return Err(-ENOTCONN);
+		}
+
+		let changed = flags & !connection.shutdown != 0;
+		connection.shutdown |= flags;
+		let flags = connection.shutdown;
+		if changed && connection.error.is_none() {
+			self.send_packet(key, VIRTIO_VSOCK_OP_SHUTDOWN, flags, &[]);
+		}
+
+		Ok(())
+	}
+
+	fn close(&mut self, key: &ConnectionKey) {
+		let connection = match self.connections.get(key) {
+			Some(connection) => connection,
+			None => return,
+		};
+
+		if connection.error.is_none() && connection.peer_shutdown != VIRTIO_VSOCK_SHUTDOWN_BOTH {
+			// a pending connection is aborted, an established one is closed gracefully
+			if connection.connected {
+				self.send_packet(
+					key,
+					VIRTIO_VSOCK_OP_SHUTDOWN,
+					VIRTIO_VSOCK_SHUTDOWN_BOTH,
+					&[],
+				);
+			} else {
+				self.send_packet(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+			}
+		}
+
+		self.connections.remove(key);
+	}
+}
+
+/// Passes a buffer to the device, which writes into it.
+fn post_buffer(vq: &mut Virtq<'_>, buffers: &mut BTreeMap<u32, Box<[u8]>>, mut buffer: Box<[u8]>) {
+	let addr = buffer.as_ptr() as usize;
+	let mut rest: &mut [u8] = &mut buffer;
+	let mut segments: Vec<&mut [u8]> = Vec::new();
+	for (_, len) in virtio::page_segments(addr, rest.len()) {
+		let (segment, tail) = mem::take(&mut rest).split_at_mut(len);
+		segments.push(segment);
+		rest = tail;
+	}
+
+	let result = vq.send_chain(&[], &segments);
+	drop(segments);
+	match result {
+		Ok(id) => {
+			buffers.insert(id, buffer);
+		}
+		Err(()) => warn!("Virtqueue of the vsock device is full, dropping a buffer"),
+	}
+}
+
+/// Passes the data of the used buffers of `vq` to `f` and returns the buffers to the device.
+fn receive(
+	vq: &mut Virtq<'_>,
+	buffers: &mut BTreeMap<u32, Box<[u8]>>,
+	mut f: impl FnMut(&[u8]),
+) -> bool {
+	let mut received = false;
+
+	loop {
+		while let Some((id, len)) = vq.pop_used_buffer() {
+			vq.release_chain(id);
+			if let Some(buffer) = buffers.remove(&id) {
+				f(&buffer[..(len as usize).min(buffer.len())]);
+				post_buffer(vq, buffers, buffer);
+				received = true;
+			}
+		}
+
+		// enable the interrupts again and check for data, which has been received in the meantime
+		vq.set_polling_mode(false);
+		if !vq.has_packet() {
+			return received;
+		}
+	}
+}
+
+struct Queues<'a> {
+	rx: Virtq<'a>,
+	tx: Virtq<'a>,
+	event: Virtq<'a>,
+	rx_buffers: BTreeMap<u32, Box<[u8]>>,
+	tx_buffers: BTreeMap<u32, Box<[u8]>>,
+	event_buffers: BTreeMap<u32, Box<[u8]>>,
+}
+
+impl Queues<'_> {
+	/// Reclaims the sent packets and passes the packets of `outbox` to the device,
+	/// as long as it has free descriptors.
+	fn flush(&mut self, outbox: &mut VecDeque<Box<[u8]>>) {
+		while let Some((id, _)) = self.tx.pop_used_buffer() {
+			self.tx.release_chain(id);
+			self.tx_buffers.remove(&id);
+		}
+
+		loop {
+			let id = {
+				let packet = match outbox.front() {
+					Some(packet) => packet,
+					None => break,
+				};
+				let segments: Vec<&[u8]> =
+					virtio::page_segments(packet.as_ptr() as usize, packet.len())
+						.into_iter()
+						.map(|(offset, len)| &packet[offset..offset + len])
+						.collect();
+
+				// the remaining packets are sent after the device has consumed older ones
+				match self.tx.send_chain(&segments, &[]) {
+					Ok(id) => id,
+					Err(()) => break,
+				}
+			};
+
+			let packet = outbox.pop_front().unwrap();
+			self.tx_buffers.insert(id, packet);
+		}
+	}
+}
+
+struct VsockState<'a> {
+	queues: Option<Queues<'a>>,
+	sockets: Sockets,
+}
+
+pub struct VirtioVsockDriver<'a> {
+	common_cfg: &'a mut virtio_pci_common_cfg,
+	device_cfg: &'a virtio_vsock_config,
+	isr_cfg: &'a mut u32,
+	notify_cfg: VirtioNotification,
+	/// Feature bits, which are accepted by the driver
+	features: u64,
+	/// MSI-X table of the device, whose first entry is shared by all queues
+	msix_table: Option<pci::MsixTable>,
+	state: SpinlockIrqSave<VsockState<'a>>,
+	/// Tasks, which wait for a change of a connection or a listener
+	waiters: WaitQueue,
+}
+
+// The state is protected by a lock and the configuration is only changed during the initialization.
+unsafe impl Send for VirtioVsockDriver<'_> {}
+unsafe impl Sync for VirtioVsockDriver<'_> {}
+
+impl<'a> fmt::Debug for VirtioVsockDriver<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "VirtioVsockDriver {{ ")?;
+		write!(f, "common_cfg: {:?}, ", self.common_cfg)?;
+		write!(f, "device_cfg: {:?}, ", self.device_cfg)?;
+		write!(f, "isr_cfg: 0x{:x}, ", self.isr_cfg)?;
+		write!(f, "notify_cfg: {:?}, ", self.notify_cfg)?;
+		write!(f, "features: 0x{:x}", self.features)?;
+		write!(f, " }}")
+	}
+}
+
+/// Returns the feature bits, which the driver accepts from `device_features`.
+fn select_features(device_features: u64) -> u64 {
+	let supported: u64 = VIRTIO_F_VERSION_1
+		| VIRTIO_F_RING_EVENT_IDX
+		| VIRTIO_F_RING_PACKED
+		| (1 << VIRTIO_VSOCK_F_STREAM);
+
+	device_features & supported
+}
+
+impl<'a> VirtioVsockDriver<'a> {
+	fn read_guest_cid(&self) -> u64 {
+		unsafe { ptr::read_volatile(&self.device_cfg.guest_cid) }
+	}
+
+	fn create_queue(&mut self, index: u16, vector: u16) -> Option<Virtq<'a>> {
+		virtio::set_queue_vector(self.common_cfg, index, vector);
+		Virtq::new_from_common(index, self.common_cfg, &mut self.notify_cfg, self.features)
+	}
+
+	pub fn init_vqs(&mut self) {
+		debug!("Setting up virtqueues...");
+
+		let vector = if self.msix_table.is_some() {
+			// we are not interested in configuration changes
+			self.common_cfg.msix_config = VIRTIO_MSI_NO_VECTOR;
+			0
+		} else {
+			VIRTIO_MSI_NO_VECTOR
+		};
+
+		let (rx, tx, event) = match (
+			self.create_queue(RX_QUEUE, vector),
+			self.create_queue(TX_QUEUE, vector),
+			self.create_queue(EVENT_QUEUE, vector),
+		) {
+			(Some(rx), Some(tx), Some(event)) => (rx, tx, event),
+			_ => {
+				error!("Unable to set up the virtqueues of the vsock device");
+				return;
+			}
+		};
+
+		let mut queues = Queues {
+			rx,
+			tx,
+			event,
+			rx_buffers: BTreeMap::new(),
+			tx_buffers: BTreeMap::new(),
+			event_buffers: BTreeMap::new(),
+		};
+		for _ in 0..RX_BUFFERS {
+			let buffer = vec![0u8; HEADER_SIZE + RX_BUFFER_SIZE].into_boxed_slice();
+			post_buffer(&mut queues.rx, &mut queues.rx_buffers, buffer);
+		}
+		for _ in 0..EVENT_BUFFERS {
+			let buffer = vec![0u8; EVENT_SIZE].into_boxed_slice();
+			post_buffer(&mut queues.event, &mut queues.event_buffers, buffer);
+		}
+
+		let guest_cid = self.read_guest_cid();
+		let mut state = self.state.lock();
+		state.queues = Some(queues);
+		state.sockets.guest_cid = guest_cid;
+	}
+
+	pub fn negotiate_features(&mut self) {
+		let common_cfg = &mut self.common_cfg;
+		// Linux kernel reads 2x32 featurebits: https://elixir.bootlin.com/linux/latest/ident/vp_get_features
+		common_cfg.device_feature_select = 0;
+		let mut device_features: u64 = common_cfg.device_feature as u64;
+		common_cfg.device_feature_select = 1;
+		device_features |= (common_cfg.device_feature as u64) << 32;
+
+		let features = select_features(device_features);
+		common_cfg.driver_feature_select = 0;
+		common_cfg.driver_feature = features as u32;
+		common_cfg.driver_feature_select = 1;
+		common_cfg.driver_feature = (features >> 32) as u32;
+		self.features = features;
+
+		info!(
+			"Virtio features: device 0x{:x}, accepted 0x{:x}",
+			device_features, features
+		);
+	}
+
+	/// 3.1 VirtIO Device Initialization
+	pub fn init(&mut self) {
+		// 1. Reset the device.
+		self.common_cfg.device_status = 0;
+
+		// 2. Set the ACKNOWLEDGE status bit: the guest OS has notice the device.
+		self.common_cfg.device_status |= 1;
+
+		// 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+		self.common_cfg.device_status |= 2;
+
+		// 4. Read device feature bits, and write the subset of feature bits understood by the OS and driver to the device.
+		self.negotiate_features();
+
+		// 5. Set the FEATURES_OK status bit. The driver MUST NOT accept new feature bits after this step.
+		self.common_cfg.device_status |= 8;
+
+		// 6. Re-read device status to ensure the FEATURES_OK bit is still set:
+		//   otherwise, the device does not support our subset of features and the device is unusable.
+		if self.common_cfg.device_status & 8 == 0 {
+			error!("Device unset FEATURES_OK, aborting!");
+			return;
+		}
+
+		// 7. Perform device-specific setup, including discovery of virtqueues for the device.
+		self.init_vqs();
+
+		// 8. Set the DRIVER_OK status bit. At this point the device is “live”.
+		self.common_cfg.device_status |= 4;
+	}
+
+	/// Calls `f` with the state of the sockets and sends the queued packets afterwards.
+	fn with_sockets<T>(&self, f: impl FnOnce(&mut Sockets) -> T) -> T {
+		let mut state = self.state.lock();
+		let VsockState { queues, sockets } = &mut *state;
+		let result = f(sockets);
+		match queues {
+			Some(queues) => queues.flush(&mut sockets.outbox),
+			None => sockets.outbox.clear(),
+		}
+
+		result
+	}
+
+	/// Returns the context id, by which the host addresses the guest.
+	pub fn guest_cid(&self) -> u64 {
+		self.state.lock().sockets.guest_cid
+	}
+
+	/// Tasks, which wait for a socket, are woken up by every change of the connections.
+	pub fn waiters(&self) -> &WaitQueue {
+		&self.waiters
+	}
+
+	/// Accepts connections to `port` or to an unused port, if `port` is None. Returns the port.
+	pub fn listen(&self, port: Option<u32>, backlog: usize) -> Result<u32, i32> {
+		self.with_sockets(|sockets| sockets.listen(port, backlog))
+	}
+
+	/// Stops listening and resets the connections, which have not been accepted.
+	pub fn unlisten(&self, port: u32) {
+		self.with_sockets(|sockets| sockets.unlisten(port))
+	}
+
+	/// Returns the next established connection of the listener or `-EAGAIN`.
+	pub fn accept(&self, port: u32) -> Result<ConnectionKey, i32> {
+		self.with_sockets(|sockets| sockets.accept(port))
+	}
+
+	pub fn listener_events(&self, port: u32) -> i16 {
+		self.state.lock().sockets.listener_events(port)
+	}
+
+	/// Sends a connection request to the peer. The connection is established,
+	/// when `is_connected` returns true.
+	pub fn connect(
+		&self,
+		local_port: Option<u32>,
+		peer_cid: u64,
+		peer_port: u32,
+	) -> Result<ConnectionKey, i32> {
+		self.with_sockets(|sockets| sockets.connect(local_port, peer_cid, peer_port))
+	}
+
+	pub fn is_connected(&self, key: &ConnectionKey) -> Result<bool, i32> {
+		self.state.lock().sockets.is_connected(key)
+	}
+
+	/// Returns the events (POLLIN, POLLOUT, ...), which are signalled by the connection.
+	pub fn connection_events(&self, key: &ConnectionKey) -> i16 {
+		self.state.lock().sockets.connection_events(key)
+	}
+
+	/// Copies received data to `buf` without blocking. Returns `-EAGAIN`, if no data is available.
+	pub fn recv(&self, key: &ConnectionKey, buf: &mut [u8]) -> Result<usize, i32> {
+		self.with_sockets(|sockets| sockets.recv(key, buf))
+	}
+
+	/// Sends as much of `buf` as the peer is able to buffer. Returns `-EAGAIN`,
+	/// if the peer has no free space.
+	pub fn send(&self, key: &ConnectionKey, buf: &[u8]) -> Result<usize, i32> {
+		self.with_sockets(|sockets| sockets.send(key, buf))
+	}
+
+	/// Shuts down the receiving (VIRTIO_VSOCK_SHUTDOWN_RCV) or sending side of the connection.
+	pub fn shutdown(&self, key: &ConnectionKey, flags: u32) -> Result<(), i32> {
+		self.with_sockets(|sockets| sockets.shutdown(key, flags))
+	}
+
+	pub fn close(&self, key: &ConnectionKey) {
+		self.with_sockets(|sockets| sockets.close(key))
+	}
+}
+
+impl pci::PciDevice for VirtioVsockDriver<'static> {
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+
+	fn msix_table(&self) -> Option<&pci::MsixTable> {
+		self.msix_table.as_ref()
+	}
+
+	fn handle_interrupt(&self) -> bool {
+		// With MSI-X, the device does not use the ISR status and the interrupt is not shared.
+		if self.msix_table.is_none() {
+			let isr_status = unsafe { ptr::read_volatile(&*self.isr_cfg) };
+			if isr_status & 0x1 == 0 {
+				return false;
+			}
+		}
+
+		let guest_cid = self.read_guest_cid();
+		let mut state = self.state.lock();
+		let VsockState { queues, sockets } = &mut *state;
+		let queues = match queues {
+			Some(queues) => queues,
+			None => return true,
+		};
+
+		let mut changed = receive(&mut queues.event, &mut queues.event_buffers, |data| {
+			if data.len() >= EVENT_SIZE
+				&& u32::from_le_bytes(data[..EVENT_SIZE].try_into().unwrap())
+					== VIRTIO_VSOCK_EVENT_TRANSPORT_RESET
+			{
+				info!("Transport of the vsock device has been reset");
+				sockets.reset(guest_cid);
+			}
+		});
+		changed |= receive(&mut queues.rx, &mut queues.rx_buffers, |data| {
+			sockets.handle_packet(data)
+		});
+		queues.flush(&mut sockets.outbox);
+		drop(state);
+
+		if changed {
+			self.waiters.wakeup_all();
+		}
+
+		true
+	}
+}
+
+/// PCI driver of virtio socket devices
+pub struct VirtioVsockPciDriver;
+
+impl pci::PciDriver for VirtioVsockPciDriver {
+	fn name(&self) -> &'static str {
+		"virtio-vsock"
+	}
+
+	fn id_table(&self) -> &'static [pci::PciDeviceId] {
+		&[pci::PciDeviceId::new(VIRTIO_VENDOR_ID, 0x1053)]
+	}
+
+	fn probe(&self, adapter: &pci::PciAdapter) -> Option<Box<dyn pci::PciDevice>> {
+		create_virtiovsock_driver(adapter).map(|drv| Box::new(drv) as Box<dyn pci::PciDevice>)
+	}
+}
+
+pub fn create_virtiovsock_driver(adapter: &pci::PciAdapter) -> Option<VirtioVsockDriver<'static>> {
+	// Scan capabilities to get common config, which we need to reset the device and get basic info.
+	let bus = adapter.bus;
+	let device = adapter.devfn;
+	let status = pci::read_config(bus, device, pci::PCI_COMMAND_REGISTER) >> 16;
+
+	// non-legacy virtio device always specifies capability list, so it can tell us in which bar we find the virtio-config-space
+	if status & pci::PCI_STATUS_CAPABILITIES_LIST == 0 {
+		error!("Found virtio device without capability list. Likely legacy-device! Aborting.");
+		return None;
+	}
+
+	// Get pointer to capability list
+	let caplist = pci::read_config(bus, device, pci::PCI_CAPABILITY_LIST_REGISTER) & 0xFF;
+
+	// get common config mapped, cast to virtio_pci_common_cfg
+	let common_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_COMMON_CFG) {
+			Some((cap_common_raw, _)) => unsafe {
+				&mut *(cap_common_raw.as_mut_ptr::<virtio_pci_common_cfg>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_COMMON_CFG. Aborting!");
+				return None;
+			}
+		};
+	// get device config mapped, cast to virtio_vsock_config
+	let device_cfg =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_DEVICE_CFG) {
+			Some((cap_device_raw, _)) => unsafe {
+				&*(cap_device_raw.as_ptr::<virtio_vsock_config>())
+			},
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_DEVICE_CFG. Aborting!");
+				return None;
+			}
+		};
+	let isr_cfg = match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_ISR_CFG)
+	{
+		Some((cap_isr_raw, _)) => unsafe { &mut *(cap_isr_raw.as_mut_ptr::<u32>()) },
+		None => {
+			error!("Could not find VIRTIO_PCI_CAP_ISR_CFG. Aborting!");
+			return None;
+		}
+	};
+	// get device notifications mapped
+	let (notification_ptr, notify_off_multiplier) =
+		match virtio::map_virtiocap(bus, device, adapter, caplist, VIRTIO_PCI_CAP_NOTIFY_CFG) {
+			Some((cap_notification_raw, notify_off_multiplier)) => (
+				cap_notification_raw.as_mut_ptr::<u16>(),
+				notify_off_multiplier,
+			),
+			None => {
+				error!("Could not find VIRTIO_PCI_CAP_NOTIFY_CFG. Aborting!");
+				return None;
+			}
+		};
+	let notify_cfg = VirtioNotification {
+		notification_ptr,
+		notify_off_multiplier,
+	};
+
+	let mut drv = VirtioVsockDriver {
+		common_cfg,
+		device_cfg,
+		isr_cfg,
+		notify_cfg,
+		features: 0,
+		msix_table: adapter.enable_msix(),
+		state: SpinlockIrqSave::new(VsockState {
+			queues: None,
+			sockets: Sockets::new(),
+		}),
+		waiters: WaitQueue::new(),
+	};
+
+	trace!("Driver before init: {:?}", drv);
+	drv.init();
+	trace!("Driver after init: {:?}", drv);
+
+	info!("Virtio-Vsock device with guest CID {}", drv.guest_cid());
+
+	Some(drv)
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn stream_connection() {
+	let mut sockets = Sockets::new();
+	sockets.guest_cid = 3;
+	assert_eq!(sockets.listen(Some(1234), 1), Ok(1234));
+	assert_eq!(sockets.listen(Some(1234), 1), Err(-EADDRINUSE));
+
+	// the host connects to the listener
+	let mut request = PacketHeader {
+		src_cid: 2,
+		dst_cid: 3,
+		src_port: 5000,
+		dst_port: 1234,
+		type_: VIRTIO_VSOCK_TYPE_STREAM,
+		op: VIRTIO_VSOCK_OP_REQUEST,
+		buf_alloc: 8,
+		..Default::default()
+	};
+	let mut packet = [0u8; HEADER_SIZE + 4];
+	request.write(&mut packet);
+	sockets.handle_packet(&packet[..HEADER_SIZE]);
+
+	let response = PacketHeader::parse(&sockets.outbox.pop_front().unwrap()).unwrap();
+	assert_eq!(response.op, VIRTIO_VSOCK_OP_RESPONSE);
+	assert_eq!((response.src_cid, response.src_port), (3, 1234));
+	assert_eq!((response.dst_cid, response.dst_port), (2, 5000));
+	assert_eq!(response.buf_alloc, BUFFER_SIZE);
+
+	let key = sockets.accept(1234).unwrap();
+	assert_eq!(sockets.accept(1234), Err(-EAGAIN));
+	assert_eq!(sockets.is_connected(&key), Ok(true));
+
+	// the data of the host is buffered until it is read
+	request.op = VIRTIO_VSOCK_OP_RW;
+	request.len = 4;
+	request.write(&mut packet);
+	packet[HEADER_SIZE..].copy_from_slice(b"ping");
+	sockets.handle_packet(&packet);
+	assert_eq!(sockets.connection_events(&key) & POLLIN, POLLIN);
+	let mut buf = [0u8; 8];
+	assert_eq!(sockets.recv(&key, &mut buf), Ok(4));
+	assert_eq!(&buf[..4], b"ping");
+	assert_eq!(sockets.recv(&key, &mut buf), Err(-EAGAIN));
+
+	// the host buffers at most 8 bytes
+	assert_eq!(sockets.send(&key, b"0123456789"), Ok(8));
+	assert_eq!(sockets.send(&key, b"89"), Err(-EAGAIN));
+	let data = sockets.outbox.pop_front().unwrap();
+	assert_eq!(PacketHeader::parse(&data).unwrap().len, 8);
+	assert_eq!(&data[HEADER_SIZE..], b"01234567");
+	let credit_request = PacketHeader::parse(&sockets.outbox.pop_front().unwrap()).unwrap();
+	assert_eq!(credit_request.op, VIRTIO_VSOCK_OP_CREDIT_REQUEST);
+	assert_eq!(credit_request.fwd_cnt, 4);
+
+	// the host has consumed the data
+	request.op = VIRTIO_VSOCK_OP_CREDIT_UPDATE;
+	request.len = 0;
+	request.fwd_cnt = 8;
+	request.write(&mut packet);
+	sockets.handle_packet(&packet[..HEADER_SIZE]);
+	assert_eq!(sockets.connection_events(&key) & POLLOUT, POLLOUT);
+
+	// the host closes the connection and waits for our reset
+	request.op = VIRTIO_VSOCK_OP_SHUTDOWN;
+	request.flags = VIRTIO_VSOCK_SHUTDOWN_BOTH;
+	request.write(&mut packet);
+	sockets.handle_packet(&packet[..HEADER_SIZE]);
+	let reset = PacketHeader::parse(&sockets.outbox.pop_front().unwrap()).unwrap();
+	assert_eq!(reset.op, VIRTIO_VSOCK_OP_RST);
+	assert_eq!(sockets.recv(&key, &mut buf), Ok(0));
+	sockets.close(&key);
+	assert!(sockets.outbox.is_empty());
+
+	// connections to other ports are refused
+	request.op = VIRTIO_VSOCK_OP_REQUEST;
+	request.dst_port = 4321;
+	request.write(&mut packet);
+	sockets.handle_packet(&packet[..HEADER_SIZE]);
+	let reset = PacketHeader::parse(&sockets.outbox.pop_front().unwrap()).unwrap();
+	assert_eq!(reset.op, VIRTIO_VSOCK_OP_RST);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn receive_overflow() {
+	let mut sockets = Sockets::new();
+	sockets.guest_cid = 3;
+	sockets.listen(Some(1234), 1).unwrap();
+
+	let mut request = PacketHeader {
+		src_cid: 2,
+		dst_cid: 3,
+		src_port: 5000,
+		dst_port: 1234,
+		type_: VIRTIO_VSOCK_TYPE_STREAM,
+		op: VIRTIO_VSOCK_OP_REQUEST,
+		buf_alloc: 8,
+		..Default::default()
+	};
+	let mut packet = [0u8; HEADER_SIZE + RX_BUFFER_SIZE];
+	request.write(&mut packet);
+	sockets.handle_packet(&packet[..HEADER_SIZE]);
+	sockets.outbox.clear();
+	let key = sockets.accept(1234).unwrap();
+
+	// the host fills the announced buffer without waiting for its consumption
+	request.op = VIRTIO_VSOCK_OP_RW;
+	request.len = RX_BUFFER_SIZE as u32;
+	request.write(&mut packet);
+	for _ in 0..BUFFER_SIZE as usize / RX_BUFFER_SIZE {
+		sockets.handle_packet(&packet);
+	}
+	assert!(sockets.outbox.is_empty());
+
+	// more data exceeds the credit
+	sockets.handle_packet(&packet);
+	let reset = PacketHeader::parse(&sockets.outbox.pop_front().unwrap()).unwrap();
+	assert_eq!(reset.op, VIRTIO_VSOCK_OP_RST);
+
+	// the buffered data is still readable
+	let mut buf = [0u8; RX_BUFFER_SIZE];
+	assert_eq!(sockets.recv(&key, &mut buf), Ok(RX_BUFFER_SIZE));
+}
@@ -6,9 +6,11 @@
 // copied, modified, or distributed except according to those terms.
 
 use core::convert::TryInto;
+use core::mem;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use multiboot::{MemoryType, Multiboot};
 
+use crate::arch::x86_64::kernel::pci;
 use crate::arch::x86_64::kernel::{get_limit, get_mbinfo};
 use crate::arch::x86_64::mm::paddr_to_slice;
 use crate::arch::x86_64::mm::paging::{BasePageSize, PageSize};
@@ -18,6 +20,9 @@ use crate::mm::freelist::{FreeList, FreeListEntry};
 use crate::synch::spinlock::*;
 
 static PHYSICAL_FREE_LIST: SpinlockIrqSave<FreeList> = SpinlockIrqSave::new(FreeList::new());
+/// Free memory, which has been reported to the host by the balloon driver. The host
+/// has possibly reclaimed it, so it is only used, if the other free memory is exhausted.
+static REPORTED_FREE_LIST: SpinlockIrqSave<FreeList> = SpinlockIrqSave::new(FreeList::new());
 static TOTAL_MEMORY: AtomicUsize = AtomicUsize::new(0);
 
 fn detect_from_multiboot_info() -> Result<(), ()> {
@@ -95,11 +100,7 @@ pub fn allocate(size: usize) -> Result<PhysAddr, ()> {
 	);
 
 	Ok(PhysAddr(
-		PHYSICAL_FREE_LIST
-			.lock()
-			.allocate(size, None)?
-			.try_into()
-			.unwrap(),
+		allocate_from_lists(size, None)?.try_into().unwrap(),
 	))
 }
 
@@ -121,10 +122,60 @@ pub fn allocate_aligned(size: usize, alignment: usize) -> Result<PhysAddr, ()> {
 		BasePageSize::SIZE
 	);
 
+	Ok(PhysAddr(
+		allocate_from_lists(size, Some(alignment))?
+			.try_into()
+			.unwrap(),
+	))
+}
+
+fn allocate_from_lists(size: usize, alignment: Option<usize>) -> Result<usize, ()> {
+	loop {
+		if let Ok(address) = allocate_from_free_lists(size, alignment) {
+			return Ok(address);
+		}
+
+		// The regions, which are currently reported to the host, are free as well.
+		// However, they can't be used until the host has processed them.
+		let reclaimed =
+			pci::get_balloon_driver().map_or(false, |driver| driver.reclaim_reported_memory());
+		if !reclaimed {
+			return Err(());
+		}
+	}
+}
+
+fn allocate_from_free_lists(size: usize, alignment: Option<usize>) -> Result<usize, ()> {
+	if let Ok(address) = PHYSICAL_FREE_LIST.lock().allocate(size, alignment) {
+		return Ok(address);
+	}
+	if let Ok(address) = REPORTED_FREE_LIST.lock().allocate(size, alignment) {
+		return Ok(address);
+	}
+
+	// The requested region may span memory of both lists.
+	let mut reported = mem::replace(&mut *REPORTED_FREE_LIST.lock(), FreeList::new());
+	let mut free_list = PHYSICAL_FREE_LIST.lock();
+	free_list.merge(&mut reported);
+	free_list.allocate(size, alignment)
+}
+
+/// Allocates a free region of `size` bytes, which is aligned to its size and
+/// hasn't been reported to the host yet. Returns an error, if no such region exists.
+pub fn allocate_unreported(size: usize) -> Result<PhysAddr, ()> {
+	assert!(size > 0);
+	assert_eq!(
+		size % BasePageSize::SIZE,
+		0,
+		"Size {:#X} is not a multiple of {:#X}",
+		size,
+		BasePageSize::SIZE
+	);
+
 	Ok(PhysAddr(
 		PHYSICAL_FREE_LIST
 			.lock()
-			.allocate(size, Some(alignment))?
+			.allocate(size, Some(size))?
 			.try_into()
 			.unwrap(),
 	))
@@ -152,8 +203,20 @@ pub fn deallocate(physical_address: PhysAddr, size: usize) {
 		.deallocate(physical_address.as_usize(), size);
 }
 
+/// Returns a region, which has been allocated by `allocate_unreported` and has been
+/// reported to the host. It is used again, if the other free memory is exhausted.
+pub fn deallocate_reported(physical_address: PhysAddr, size: usize) {
+	REPORTED_FREE_LIST
+		.lock()
+		.deallocate(physical_address.as_usize(), size);
+}
+
 pub fn print_information() {
 	PHYSICAL_FREE_LIST
 		.lock()
 		.print_information(" PHYSICAL MEMORY FREE LIST ");
+	let reported = REPORTED_FREE_LIST.lock();
+	if !reported.list.is_empty() {
+		reported.print_information(" REPORTED MEMORY FREE LIST ");
+	}
 }
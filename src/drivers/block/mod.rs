@@ -0,0 +1,58 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Block device layer, on which filesystems are built.
+
+/// Errors of a block device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockError {
+	/// The device has reported an I/O error
+	Io,
+	/// The device does not support the request
+	Unsupported,
+	/// The blocks are out of range or the buffer is not a multiple of the block size
+	InvalidArgument,
+	/// The device is read-only
+	ReadOnly,
+}
+
+/// Interface of a block device. All transfers are specified in blocks of
+/// `block_size` bytes and may be issued by several tasks at the same time.
+pub trait BlockDevice: Sync {
+	/// Returns the size of a block in bytes.
+	fn block_size(&self) -> usize;
+
+	/// Returns the number of blocks of the device.
+	fn block_count(&self) -> u64;
+
+	/// Returns true, if the device rejects write requests.
+	fn is_read_only(&self) -> bool;
+
+	/// Reads `buf.len() / block_size()` blocks starting at block `block`.
+	fn read_blocks(&self, block: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+	/// Writes `buf.len() / block_size()` blocks starting at block `block`.
+	fn write_blocks(&self, block: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+	/// Writes back all data, which is cached by the device.
+	fn flush(&self) -> Result<(), BlockError>;
+
+	/// Tells the device that `count` blocks starting at block `block` are unused.
+	fn discard(&self, block: u64, count: u64) -> Result<(), BlockError>;
+}
+
+/// Returns the block device `index`. The devices are numbered in the order of their detection.
+pub fn get_block_device(index: usize) -> Option<&'static dyn BlockDevice> {
+	crate::arch::kernel::pci::get_block_driver(index).map(|driver| driver as &dyn BlockDevice)
+}
+
+/// Returns the number of detected block devices.
+pub fn get_block_device_count() -> usize {
+	(0..)
+		.take_while(|index| get_block_device(*index).is_some())
+		.count()
+}
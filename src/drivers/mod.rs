@@ -5,5 +5,6 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+pub mod block;
 #[cfg(not(feature = "newlib"))]
 pub mod net;
@@ -17,15 +17,12 @@ static NIC_QUEUE: SpinlockIrqSave<BTreeMap<usize, TaskHandle>> =
 	SpinlockIrqSave::new(BTreeMap::new());
 static POLLING: AtomicBool = AtomicBool::new(false);
 
-/// period (in usec) to check, if the driver should still use the polling mode
-const POLL_PERIOD: u64 = 20_000;
-
 /// set driver in polling mode and threads will not be blocked
 fn set_polling_mode(value: bool) {
 	// is the driver already in polling mode?
 	if POLLING.swap(value, Ordering::SeqCst) != value {
 		if let Some(driver) = crate::arch::kernel::pci::get_network_driver() {
-			driver.lock().set_polling_mode(value);
+			driver.set_polling_mode(value);
 		}
 
 		// wakeup network thread to sleep for longer time
@@ -49,37 +46,18 @@ pub fn netwait_and_wakeup(handles: &[usize], millis: Option<u64>) {
 		}
 	}
 
-	let mut reset_nic = false;
-
-	// check if the driver should be in the polling mode
-	while POLLING.swap(false, Ordering::SeqCst) == true {
-		reset_nic = true;
-
-		let core_scheduler = core_scheduler();
-		let wakeup_time = Some(crate::arch::processor::get_timer_ticks() + POLL_PERIOD);
-
-		core_scheduler.block_current_task(wakeup_time);
-
-		// Switch to the next task.
-		core_scheduler.reschedule();
-	}
-
-	if reset_nic {
-		if let Some(driver) = crate::arch::kernel::pci::get_network_driver() {
-			driver.lock().set_polling_mode(false);
-		};
-	} else {
-		NET_SEM.acquire(millis);
-	}
+	NET_SEM.acquire(millis);
 }
 
 pub fn netwait(handle: usize, millis: Option<u64>) {
 	// smoltcp want to poll the nic
 	let is_polling = if let Some(t) = millis { t == 0 } else { false };
 
-	if is_polling {
-		set_polling_mode(true);
-	} else {
+	// Leave the polling mode, as soon as the application blocks again. Hence, the
+	// network thread does not have to check periodically for the end of the polling.
+	set_polling_mode(is_polling);
+
+	if !is_polling {
 		let wakeup_time = match millis {
 			Some(ms) => Some(crate::arch::processor::get_timer_ticks() + ms * 1000),
 			_ => None,
@@ -0,0 +1,335 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Entropy pool and cryptographically secure random number generator of the kernel
+//!
+//! The entropy sources (RDSEED, RDRAND, virtio-rng and the timing jitter of the processor)
+//! are mixed into a pool. As soon as the pool has collected 256 bits, it seeds a generator,
+//! which is based on ChaCha20 (RFC 8439). The generator is reseeded from the pool after
+//! 1 MiB of output. After every request, the generator replaces its key by its own output,
+//! so former output can't be reconstructed from the state.
+//!
+//! The generator is seeded by the processor (RDSEED or RDRAND) or the host (virtio-rng).
+//! The timing jitter isn't health tested and is therefore credited with a single bit
+//! per round. Only if neither the processor nor the host provide random numbers, the
+//! kernel falls back to the timing jitter and collects many rounds to (re)seed the generator.
+
+use crate::arch;
+use crate::arch::percore::core_scheduler;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86_64::kernel::pci;
+use crate::synch::spinlock::SpinlockIrqSave;
+use core::convert::TryInto;
+use core::sync::atomic::spin_loop_hint;
+
+/// Number of bits, which the pool has to collect before it (re)seeds the generator
+const SEED_BITS: usize = 256;
+/// Number of generated bytes, after which the generator is reseeded
+const RESEED_INTERVAL: usize = 1 << 20;
+/// Number of bytes, which are generated while the lock is held
+const MAX_REQUEST: usize = 4096;
+/// Number of timing samples of a jitter round and the entropy, which is credited for them
+const JITTER_SAMPLES: usize = 1024;
+const JITTER_BITS: usize = 1;
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const CHACHA_BLOCK_SIZE: usize = 64;
+
+static ENTROPY: SpinlockIrqSave<EntropyState> = SpinlockIrqSave::new(EntropyState::new());
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(16);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(12);
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(8);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// ChaCha20 block function (RFC 8439, section 2.3)
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+	let mut state = [0u32; 16];
+	state[..4].copy_from_slice(&CHACHA_CONSTANTS);
+	state[4..12].copy_from_slice(key);
+	state[12] = counter;
+	state[13..].copy_from_slice(nonce);
+
+	let mut working = state;
+	for _ in 0..10 {
+		// column rounds
+		quarter_round(&mut working, 0, 4, 8, 12);
+		quarter_round(&mut working, 1, 5, 9, 13);
+		quarter_round(&mut working, 2, 6, 10, 14);
+		quarter_round(&mut working, 3, 7, 11, 15);
+		// diagonal rounds
+		quarter_round(&mut working, 0, 5, 10, 15);
+		quarter_round(&mut working, 1, 6, 11, 12);
+		quarter_round(&mut working, 2, 7, 8, 13);
+		quarter_round(&mut working, 3, 4, 9, 14);
+	}
+
+	for (word, input) in working.iter_mut().zip(state.iter()) {
+		*word = word.wrapping_add(*input);
+	}
+
+	working
+}
+
+struct EntropyState {
+	/// Accumulates the input of the entropy sources
+	pool: [u32; 8],
+	/// Number of inputs, which have been mixed into the pool
+	inputs: u64,
+	/// Estimated entropy of the pool in bits
+	pool_bits: usize,
+	/// Key of the generator
+	key: [u32; 8],
+	seeded: bool,
+	/// Number of bytes, which have been generated since the last reseed
+	generated: usize,
+}
+
+impl EntropyState {
+	const fn new() -> Self {
+		Self {
+			pool: [0; 8],
+			inputs: 0,
+			pool_bits: 0,
+			key: [0; 8],
+			seeded: false,
+			generated: 0,
+		}
+	}
+
+	/// Mixes `data` into the pool. The pool and the input are the key of a ChaCha20 block,
+	/// whose output replaces the pool.
+	fn mix(&mut self, data: &[u8]) {
+		for chunk in data.chunks(32) {
+			let mut input = [0u8; 32];
+			input[..chunk.len()].copy_from_slice(chunk);
+
+			let mut key = self.pool;
+			for (word, bytes) in key.iter_mut().zip(input.chunks(4)) {
+				*word ^= u32::from_le_bytes(bytes.try_into().unwrap());
+			}
+
+			let nonce = [self.inputs as u32, (self.inputs >> 32) as u32, 0];
+			self.inputs = self.inputs.wrapping_add(1);
+			self.pool
+				.copy_from_slice(&chacha20_block(&key, 0, &nonce)[..8]);
+		}
+	}
+
+	fn add(&mut self, data: &[u8], bits: usize) {
+		self.mix(data);
+		self.pool_bits = (self.pool_bits + bits).min(SEED_BITS);
+
+		if self.pool_bits >= SEED_BITS && (!self.seeded || self.generated >= RESEED_INTERVAL) {
+			self.reseed();
+		}
+	}
+
+	/// Derives a new key from the current key and the pool, which is emptied.
+	fn reseed(&mut self) {
+		let mut key = self.key;
+		for (word, pool) in key.iter_mut().zip(self.pool.iter()) {
+			*word ^= *pool;
+		}
+
+		// the nonce separates the derivation from the output of the generator
+		let output = chacha20_block(&key, 0, &[0, 0, 1]);
+		self.key.copy_from_slice(&output[..8]);
+		self.pool.copy_from_slice(&output[8..]);
+		self.pool_bits = 0;
+		self.seeded = true;
+		self.generated = 0;
+	}
+
+	/// Fills `buf` with the output of the generator. The first block replaces the key afterwards.
+	fn generate(&mut self, buf: &mut [u8]) {
+		let nonce = [0u32; 3];
+		let next_key = chacha20_block(&self.key, 0, &nonce);
+
+		for (counter, chunk) in buf.chunks_mut(CHACHA_BLOCK_SIZE).enumerate() {
+			let block = chacha20_block(&self.key, counter as u32 + 1, &nonce);
+			for (bytes, word) in chunk.chunks_mut(4).zip(block.iter()) {
+				bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+			}
+		}
+
+		self.key.copy_from_slice(&next_key[..8]);
+		self.generated = self.generated.saturating_add(buf.len());
+	}
+}
+
+/// Adds the output of an entropy source to the pool. `bits` is the entropy of the data.
+pub fn add_entropy(data: &[u8], bits: usize) {
+	ENTROPY.lock().add(data, bits);
+}
+
+/// Returns true, if the pool is able to take further entropy.
+pub fn needs_entropy() -> bool {
+	ENTROPY.lock().pool_bits < SEED_BITS
+}
+
+/// Fills `buf` with random bytes. Returns false, if the generator hasn't been seeded yet.
+pub fn try_fill(buf: &mut [u8]) -> bool {
+	let mut reseed = false;
+
+	for chunk in buf.chunks_mut(MAX_REQUEST) {
+		let mut state = ENTROPY.lock();
+		if !state.seeded {
+			return false;
+		}
+
+		state.generate(chunk);
+		reseed |= state.generated >= RESEED_INTERVAL;
+	}
+
+	if reseed && !add_source_entropy() {
+		add_fallback_entropy();
+	}
+
+	true
+}
+
+/// Fills `buf` with random bytes. If the generator hasn't been seeded yet, the function
+/// blocks until the processor or the host has provided enough entropy. Only if none of
+/// them is available, the generator is seeded by the timing jitter of the processor.
+pub fn fill(buf: &mut [u8]) {
+	while !try_fill(buf) {
+		if add_source_entropy() {
+			// the host provides the entropy asynchronously
+			core_scheduler().reschedule();
+		} else {
+			warn!("No source of random numbers available, falling back to the timing jitter");
+			add_fallback_entropy();
+		}
+	}
+}
+
+/// Collects entropy of the processor and the host. Returns false, if none of them is available.
+fn add_source_entropy() -> bool {
+	let hardware = add_hardware_entropy();
+	let device = request_device_entropy();
+	hardware || device
+}
+
+/// Adds the timing jitter to the pool, until the generator is able to (re)seed.
+fn add_fallback_entropy() {
+	while needs_entropy() {
+		add_jitter_entropy();
+	}
+}
+
+/// Adds random numbers of the processor (RDSEED or RDRAND) to the pool.
+/// Returns false, if the processor doesn't support them.
+fn add_hardware_entropy() -> bool {
+	let mut seed = [0u8; SEED_BITS / 8];
+	for bytes in seed.chunks_mut(8) {
+		let value = match arch::processor::generate_random_seed64()
+			.or_else(arch::processor::generate_random_number64)
+		{
+			Some(value) => value,
+			None => return false,
+		};
+		bytes.copy_from_slice(&value.to_le_bytes());
+	}
+
+	add_entropy(&seed, SEED_BITS);
+	true
+}
+
+/// Asks the random number generators of the host for entropy, which is added asynchronously.
+/// Returns false, if the host doesn't provide one.
+fn request_device_entropy() -> bool {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if let Some(driver) = pci::get_rng_driver() {
+			driver.request_entropy();
+			return true;
+		}
+	}
+
+	false
+}
+
+/// Adds the timing jitter of short loops to the pool (similar to jitterentropy).
+/// Only a single bit is credited for all samples of a round.
+fn add_jitter_entropy() {
+	let mut samples = [0u8; JITTER_SAMPLES];
+	let mut last = arch::processor::get_timestamp();
+
+	for (i, sample) in samples.iter_mut().enumerate() {
+		for _ in 0..(i % 7) + 1 {
+			spin_loop_hint();
+		}
+
+		let now = arch::processor::get_timestamp();
+		*sample = now.wrapping_sub(last) as u8;
+		last = now;
+	}
+
+	add_entropy(&samples, JITTER_BITS);
+}
+
+pub fn init() {
+	// The timestamp distinguishes instances, but isn't credited as entropy.
+	add_entropy(&arch::processor::get_timestamp().to_le_bytes(), 0);
+	add_source_entropy();
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn chacha20() {
+	// test vector of RFC 8439, section 2.3.2
+	let mut key = [0u32; 8];
+	for (i, word) in key.iter_mut().enumerate() {
+		let byte = 4 * i as u8;
+		*word = u32::from_le_bytes([byte, byte + 1, byte + 2, byte + 3]);
+	}
+	let block = chacha20_block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+	assert_eq!(
+		block,
+		[
+			0xe4e7_f110,
+			0x1559_3bd1,
+			0x1fdd_0f50,
+			0xc471_20a3,
+			0xc7f4_d1c7,
+			0x0368_c033,
+			0x9aaa_2204,
+			0x4e6c_d4c3,
+			0x4664_82d2,
+			0x09aa_9f07,
+			0x05d7_c214,
+			0xa202_8bd9,
+			0xd19c_12b5,
+			0xb94e_16de,
+			0xe883_d0cb,
+			0x4e3c_50a2,
+		]
+	);
+
+	// the generator is seeded, as soon as the pool contains 256 bits
+	let mut state = EntropyState::new();
+	state.add(&[1; 32], 128);
+	assert!(!state.seeded);
+	state.add(&[2; 32], 128);
+	assert!(state.seeded);
+	assert_eq!(state.pool_bits, 0);
+
+	// the output changes with every request
+	let mut first = [0u8; 100];
+	let mut second = [0u8; 100];
+	state.generate(&mut first);
+	state.generate(&mut second);
+	assert_ne!(first[..], second[..]);
+	assert_eq!(state.generated, 200);
+}
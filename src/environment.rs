@@ -34,6 +34,28 @@ static mut COMMAND_LINE_CPU_FREQUENCY: u16 = 0;
 static mut IS_PROXY: bool = false;
 static mut COMMAND_LINE_APPLICATION: Option<Vec<String>> = None;
 static mut COMMAND_LINE_PATH: Option<String> = None;
+static mut COMMAND_LINE_IP: Option<[u8; 4]> = None;
+static mut COMMAND_LINE_GATEWAY: Option<[u8; 4]> = None;
+static mut COMMAND_LINE_MASK: Option<[u8; 4]> = None;
+static mut COMMAND_LINE_NIC: Option<String> = None;
+static mut COMMAND_LINE_VIRTIOFS: Vec<(String, String)> = Vec::new();
+static mut COMMAND_LINE_MOUNTS: Vec<(String, String)> = Vec::new();
+
+/// Parses an IPv4 address in dotted-decimal notation.
+fn parse_ipv4_address(address: &str) -> Option<[u8; 4]> {
+	let mut result = [0u8; 4];
+	let mut octets = address.split('.');
+
+	for octet in result.iter_mut() {
+		*octet = octets.next()?.parse().ok()?;
+	}
+
+	if octets.next().is_none() {
+		Some(result)
+	} else {
+		None
+	}
+}
 
 unsafe fn parse_command_line() {
 	let cmdsize = get_cmdsize();
@@ -62,6 +84,45 @@ unsafe fn parse_command_line() {
 			"-proxy" => {
 				IS_PROXY = true;
 			}
+			"-ip" => {
+				let ip_str = tokeniter.next().expect("Invalid -ip command line");
+				COMMAND_LINE_IP = Some(
+					parse_ipv4_address(&ip_str)
+						.expect("Could not parse -ip command line as address"),
+				);
+			}
+			"-gateway" => {
+				let gateway_str = tokeniter.next().expect("Invalid -gateway command line");
+				COMMAND_LINE_GATEWAY = Some(
+					parse_ipv4_address(&gateway_str)
+						.expect("Could not parse -gateway command line as address"),
+				);
+			}
+			"-mask" => {
+				let mask_str = tokeniter.next().expect("Invalid -mask command line");
+				COMMAND_LINE_MASK = Some(
+					parse_ipv4_address(&mask_str)
+						.expect("Could not parse -mask command line as address"),
+				);
+			}
+			"-nic" => {
+				let nic_str = tokeniter.next().expect("Invalid -nic command line");
+				COMMAND_LINE_NIC = Some(nic_str);
+			}
+			"-virtiofs" => {
+				let share_str = tokeniter.next().expect("Invalid -virtiofs command line");
+				let mut split = share_str.splitn(2, ':');
+				let tag = split.next().unwrap();
+				let options = split.next().unwrap_or("");
+				COMMAND_LINE_VIRTIOFS.push((String::from(tag), String::from(options)));
+			}
+			"-mount" => {
+				let mount_str = tokeniter.next().expect("Invalid -mount command line");
+				let mut split = mount_str.splitn(2, ':');
+				let mntpath = split.next().unwrap();
+				let source = split.next().expect("Invalid -mount command line");
+				COMMAND_LINE_MOUNTS.push((String::from(mntpath), String::from(source)));
+			}
 			"--" => {
 				// Collect remaining arguments as applications argv
 				//ToDo -> we know the length here, so we could (should convert this into a safe
@@ -110,6 +171,40 @@ pub fn get_command_line_cpu_frequency() -> u16 {
 	unsafe { COMMAND_LINE_CPU_FREQUENCY }
 }
 
+/// IPv4 address of the network interface if given through the -ip command-line parameter.
+pub fn get_command_line_ip() -> Option<[u8; 4]> {
+	unsafe { COMMAND_LINE_IP }
+}
+
+/// IPv4 address of the default gateway if given through the -gateway command-line parameter.
+pub fn get_command_line_gateway() -> Option<[u8; 4]> {
+	unsafe { COMMAND_LINE_GATEWAY }
+}
+
+/// IPv4 network mask if given through the -mask command-line parameter.
+pub fn get_command_line_mask() -> Option<[u8; 4]> {
+	unsafe { COMMAND_LINE_MASK }
+}
+
+/// Instance name of the network device (e.g. "virtio-net1") if given through the -nic command-line parameter.
+pub fn get_command_line_nic() -> Option<&'static str> {
+	unsafe { COMMAND_LINE_NIC.as_deref() }
+}
+
+/// Mount options of the virtio-fs share `tag` if given through the -virtiofs TAG:OPTIONS
+/// command-line parameter.
+pub fn get_command_line_virtiofs_options(tag: &str) -> Option<&'static str> {
+	unsafe { COMMAND_LINE_VIRTIOFS.iter() }
+		.find(|(share, _)| share == tag)
+		.map(|(_, options)| options.as_str())
+}
+
+/// Mount points and sources of the disk filesystems given through the -mount MOUNTPOINT:SOURCE
+/// command-line parameters.
+pub fn get_command_line_mounts() -> &'static [(String, String)] {
+	unsafe { &COMMAND_LINE_MOUNTS }
+}
+
 /// Whether HermitCore shall communicate with the "proxy" application over a network interface.
 /// Only valid after calling init()!
 pub fn is_proxy() -> bool {
@@ -0,0 +1,292 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::fs::SectorAccess;
+use crate::syscalls::fs::FileError;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+
+struct CachedSector {
+	data: Box<[u8]>,
+	dirty: bool,
+	last_use: u64,
+}
+
+/// Write-back cache of the sectors of a device, which provides byte-granular access.
+/// If the cache is full, the least recently used sector is evicted.
+pub struct SectorCache {
+	device: Box<dyn SectorAccess>,
+	sector_size: usize,
+	capacity: usize,
+	sectors: BTreeMap<u64, CachedSector>,
+	clock: u64,
+}
+
+impl SectorCache {
+	/// Creates a cache, which holds up to `capacity` sectors of `device`.
+	pub fn new(device: Box<dyn SectorAccess>, capacity: usize) -> Self {
+		let sector_size = device.sector_size();
+
+		Self {
+			device,
+			sector_size,
+			capacity: capacity.max(1),
+			sectors: BTreeMap::new(),
+			clock: 0,
+		}
+	}
+
+	/// Reads `buf.len()` bytes starting at byte `offset` of the device.
+	pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FileError> {
+		if buf.is_empty() {
+			return Ok(());
+		}
+		let first = offset / self.sector_size as u64;
+		let last = (offset + buf.len() as u64 - 1) / self.sector_size as u64;
+		self.load(first, last)?;
+
+		let mut done = 0;
+		while done < buf.len() {
+			let pos = offset + done as u64;
+			let sector_offset = (pos % self.sector_size as u64) as usize;
+			let len = (self.sector_size - sector_offset).min(buf.len() - done);
+			let sector = self.get(pos / self.sector_size as u64, true)?;
+			buf[done..done + len].copy_from_slice(&sector.data[sector_offset..sector_offset + len]);
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	/// Writes `buf` starting at byte `offset` of the device. The data reaches
+	/// the device, when the sector is evicted or the cache is flushed.
+	pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), FileError> {
+		let mut done = 0;
+		while done < buf.len() {
+			let pos = offset + done as u64;
+			let sector_offset = (pos % self.sector_size as u64) as usize;
+			let len = (self.sector_size - sector_offset).min(buf.len() - done);
+			// a completely overwritten sector doesn't have to be read from the device
+			let load = len != self.sector_size;
+			let sector = self.get(pos / self.sector_size as u64, load)?;
+			sector.data[sector_offset..sector_offset + len].copy_from_slice(&buf[done..done + len]);
+			sector.dirty = true;
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	pub fn read_u16(&mut self, offset: u64) -> Result<u16, FileError> {
+		let mut buf = [0u8; 2];
+		self.read(offset, &mut buf)?;
+		Ok(u16::from_le_bytes(buf))
+	}
+
+	pub fn read_u32(&mut self, offset: u64) -> Result<u32, FileError> {
+		let mut buf = [0u8; 4];
+		self.read(offset, &mut buf)?;
+		Ok(u32::from_le_bytes(buf))
+	}
+
+	pub fn write_u16(&mut self, offset: u64, value: u16) -> Result<(), FileError> {
+		self.write(offset, &value.to_le_bytes())
+	}
+
+	pub fn write_u32(&mut self, offset: u64, value: u32) -> Result<(), FileError> {
+		self.write(offset, &value.to_le_bytes())
+	}
+
+	/// Fills `len` bytes starting at byte `offset` of the device with zeros.
+	pub fn zero(&mut self, offset: u64, len: usize) -> Result<(), FileError> {
+		let zeros = vec![0u8; self.sector_size];
+		let mut done = 0;
+		while done < len {
+			let chunk = (len - done).min(self.sector_size);
+			self.write(offset + done as u64, &zeros[..chunk])?;
+			done += chunk;
+		}
+
+		Ok(())
+	}
+
+	/// Writes all dirty sectors back and flushes the device.
+	pub fn flush(&mut self) -> Result<(), FileError> {
+		for (index, sector) in self.sectors.iter_mut() {
+			if sector.dirty {
+				self.device.write_sectors(*index, &sector.data)?;
+				sector.dirty = false;
+			}
+		}
+
+		self.device.flush()
+	}
+
+	/// Loads the sectors `first..=last`, which aren't cached yet. Adjacent sectors
+	/// are read by a single access of the device (e.g. a whole cluster or block).
+	fn load(&mut self, first: u64, last: u64) -> Result<(), FileError> {
+		let mut index = first;
+		while index <= last {
+			if self.sectors.contains_key(&index) {
+				index += 1;
+				continue;
+			}
+
+			let mut count = 1;
+			while index + count <= last
+				&& count < self.capacity as u64
+				&& !self.sectors.contains_key(&(index + count))
+			{
+				count += 1;
+			}
+
+			let mut data = vec![0u8; count as usize * self.sector_size];
+			self.device.read_sectors(index, &mut data)?;
+			for (i, sector) in data.chunks(self.sector_size).enumerate() {
+				self.insert(index + i as u64, sector.into())?;
+			}
+			index += count;
+		}
+
+		Ok(())
+	}
+
+	fn insert(&mut self, index: u64, data: Box<[u8]>) -> Result<(), FileError> {
+		if self.sectors.len() >= self.capacity {
+			self.evict()?;
+		}
+
+		self.clock += 1;
+		self.sectors.insert(
+			index,
+			CachedSector {
+				data,
+				dirty: false,
+				last_use: self.clock,
+			},
+		);
+
+		Ok(())
+	}
+
+	fn get(&mut self, index: u64, load: bool) -> Result<&mut CachedSector, FileError> {
+		if !self.sectors.contains_key(&index) {
+			let mut data = vec![0u8; self.sector_size].into_boxed_slice();
+			if load {
+				self.device.read_sectors(index, &mut data)?;
+			}
+			self.insert(index, data)?;
+		}
+
+		self.clock += 1;
+		let sector = self.sectors.get_mut(&index).unwrap();
+		sector.last_use = self.clock;
+		Ok(sector)
+	}
+
+	/// Writes the least recently used sector back and removes it from the cache.
+	/// If the sector can't be written, it stays in the cache.
+	fn evict(&mut self) -> Result<(), FileError> {
+		let index = match self
+			.sectors
+			.iter()
+			.min_by_key(|(_, sector)| sector.last_use)
+			.map(|(index, _)| *index)
+		{
+			Some(index) => index,
+			None => return Ok(()),
+		};
+
+		let sector = &self.sectors[&index];
+		if sector.dirty {
+			self.device.write_sectors(index, &sector.data)?;
+		}
+		self.sectors.remove(&index);
+
+		Ok(())
+	}
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn write_back() {
+	use alloc::rc::Rc;
+	use alloc::vec::Vec;
+	use core::cell::{Cell, RefCell};
+
+	struct Memory {
+		data: Rc<RefCell<Vec<u8>>>,
+		reads: Rc<Cell<usize>>,
+		broken: Rc<Cell<bool>>,
+	}
+
+	// the test runs single-threaded
+	unsafe impl Send for Memory {}
+
+	impl SectorAccess for Memory {
+		fn sector_size(&self) -> usize {
+			16
+		}
+
+		fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), FileError> {
+			let start = sector as usize * 16;
+			buf.copy_from_slice(&self.data.borrow()[start..start + buf.len()]);
+			self.reads.set(self.reads.get() + 1);
+			Ok(())
+		}
+
+		fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), FileError> {
+			if self.broken.get() {
+				return Err(FileError::EIO());
+			}
+			let start = sector as usize * 16;
+			self.data.borrow_mut()[start..start + buf.len()].copy_from_slice(buf);
+			Ok(())
+		}
+
+		fn flush(&mut self) -> Result<(), FileError> {
+			Ok(())
+		}
+	}
+
+	let memory = Rc::new(RefCell::new((0..64u8).collect::<Vec<u8>>()));
+	let reads = Rc::new(Cell::new(0));
+	let broken = Rc::new(Cell::new(false));
+	let device = Memory {
+		data: memory.clone(),
+		reads: reads.clone(),
+		broken: broken.clone(),
+	};
+	let mut cache = SectorCache::new(Box::new(device), 2);
+
+	// both sectors are read at once
+	let mut buf = [0u8; 20];
+	cache.read(10, &mut buf).unwrap();
+	assert_eq!(buf[0], 10);
+	assert_eq!(buf[19], 29);
+	assert_eq!(reads.get(), 1);
+
+	// the write stays in the cache until the sector is evicted
+	cache.write(14, &[0xff; 4]).unwrap();
+	assert_eq!(memory.borrow()[14], 14);
+	cache.read(32, &mut buf[..1]).unwrap();
+	cache.read(48, &mut buf[..1]).unwrap();
+	assert_eq!(memory.borrow()[14..18], [0xff; 4]);
+
+	cache.zero(40, 8).unwrap();
+	cache.flush().unwrap();
+	assert_eq!(memory.borrow()[39..49], [39, 0, 0, 0, 0, 0, 0, 0, 0, 48]);
+
+	// a sector, which can't be written back, stays in the cache
+	cache.write(0, &[0xee; 2]).unwrap();
+	cache.read(16, &mut buf[..1]).unwrap();
+	broken.set(true);
+	assert!(cache.read(32, &mut buf[..1]).is_err());
+	broken.set(false);
+	cache.flush().unwrap();
+	assert_eq!(memory.borrow()[..3], [0xee, 0xee, 2]);
+}
@@ -0,0 +1,902 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Second extended filesystem, see "The Second Extended File System" by Dave Poirier.
+
+use crate::fs::cache::SectorCache;
+use crate::fs::{components, split_parent};
+use crate::synch::mutex::Mutex;
+use crate::syscalls::fs::{FileError, FilePerms, PosixFile, PosixFileSystem, SeekWhence};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GROUP_DESC_SIZE: u64 = 32;
+
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const SUPPORTED_INCOMPAT: u32 = FEATURE_INCOMPAT_FILETYPE;
+const SUPPORTED_RO_COMPAT: u32 = FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE;
+
+// offsets in the superblock
+const S_FREE_BLOCKS_COUNT: u64 = 12;
+const S_FREE_INODES_COUNT: u64 = 16;
+const S_WTIME: u64 = 48;
+const S_FEATURE_RO_COMPAT: u64 = 100;
+
+// offsets in a group descriptor
+const BG_BLOCK_BITMAP: u64 = 0;
+const BG_INODE_BITMAP: u64 = 4;
+const BG_INODE_TABLE: u64 = 8;
+const BG_FREE_BLOCKS_COUNT: u64 = 12;
+const BG_FREE_INODES_COUNT: u64 = 14;
+
+// offsets in an inode
+const I_MODE: u64 = 0;
+const I_SIZE: u64 = 4;
+const I_ATIME: u64 = 8;
+const I_CTIME: u64 = 12;
+const I_MTIME: u64 = 16;
+const I_DTIME: u64 = 20;
+const I_LINKS_COUNT: u64 = 26;
+const I_BLOCKS: u64 = 28;
+const I_FLAGS: u64 = 32;
+const I_BLOCK: u64 = 40;
+const I_SIZE_HIGH: u64 = 108;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+/// The directory is indexed by a hash tree
+const INDEX_FL: u32 = 0x1000;
+
+const DIRECT_BLOCKS: u64 = 12;
+const INDIRECT_BLOCK: u64 = 12;
+const DOUBLE_INDIRECT_BLOCK: u64 = 13;
+const TRIPLE_INDIRECT_BLOCK: u64 = 14;
+
+const DIR_ENTRY_HEADER: u64 = 8;
+const FT_REG_FILE: u8 = 1;
+const MAX_NAME_LEN: usize = 255;
+
+/// Returns the space, which a directory entry with a name of `name_len` bytes requires.
+fn rec_len(name_len: usize) -> u64 {
+	(DIR_ENTRY_HEADER + name_len as u64 + 3) & !3
+}
+
+fn now() -> u32 {
+	(crate::syscalls::get_realtime_nanos() / 1_000_000_000) as u32
+}
+
+/// Directory entry, as it has been read from a directory
+struct DirEntry {
+	inode: u32,
+	/// Disk offset of the entry
+	offset: u64,
+	/// Disk offset of the previous entry in the same block
+	prev: Option<u64>,
+}
+
+struct Volume {
+	cache: SectorCache,
+	block_size: u64,
+	blocks_count: u32,
+	first_data_block: u32,
+	blocks_per_group: u32,
+	inodes_per_group: u32,
+	group_count: u32,
+	inode_size: u64,
+	first_ino: u32,
+	rev_level: u32,
+	ro_compat: u32,
+	filetype: bool,
+	read_only: bool,
+}
+
+impl Volume {
+	fn group_desc(&self, group: u32) -> u64 {
+		(u64::from(self.first_data_block) + 1) * self.block_size
+			+ u64::from(group) * GROUP_DESC_SIZE
+	}
+
+	/// Adds `delta` to the free counters of the superblock and of `group`.
+	fn adjust_free_count(
+		&mut self,
+		group: u32,
+		sb_field: u64,
+		bg_field: u64,
+		delta: i32,
+	) -> Result<(), FileError> {
+		let offset = SUPERBLOCK_OFFSET + sb_field;
+		let count = self.cache.read_u32(offset)?;
+		self.cache
+			.write_u32(offset, (count as i64 + i64::from(delta)) as u32)?;
+
+		let offset = self.group_desc(group) + bg_field;
+		let count = self.cache.read_u16(offset)?;
+		self.cache
+			.write_u16(offset, (i32::from(count) + delta) as u16)
+	}
+
+	/// Finds and sets a cleared bit in a bitmap of `bits` bits, which starts at bit `first`.
+	fn allocate_bit(
+		&mut self,
+		bitmap: u64,
+		first: u32,
+		bits: u32,
+	) -> Result<Option<u32>, FileError> {
+		let mut data = vec![0u8; self.block_size as usize];
+		self.cache.read(bitmap * self.block_size, &mut data)?;
+
+		for bit in first..bits.min(self.block_size as u32 * 8) {
+			let (byte, mask) = ((bit / 8) as usize, 1u8 << (bit % 8));
+			if data[byte] & mask == 0 {
+				self.cache
+					.write(bitmap * self.block_size + byte as u64, &[data[byte] | mask])?;
+				return Ok(Some(bit));
+			}
+		}
+
+		Ok(None)
+	}
+
+	fn clear_bit(&mut self, bitmap: u64, bit: u32) -> Result<(), FileError> {
+		let offset = bitmap * self.block_size + u64::from(bit / 8);
+		let mut byte = [0u8; 1];
+		self.cache.read(offset, &mut byte)?;
+		if byte[0] & (1 << (bit % 8)) == 0 {
+			warn!("Freeing bit {} of bitmap {} twice", bit, bitmap);
+			return Err(FileError::EIO());
+		}
+		self.cache.write(offset, &[byte[0] & !(1 << (bit % 8))])
+	}
+
+	/// Allocates a zeroed block, preferably in `goal_group`.
+	fn allocate_block(&mut self, goal_group: u32) -> Result<u32, FileError> {
+		for i in 0..self.group_count {
+			let group = (goal_group + i) % self.group_count;
+			let desc = self.group_desc(group);
+			if self.cache.read_u16(desc + BG_FREE_BLOCKS_COUNT)? == 0 {
+				continue;
+			}
+
+			// the last group may be shorter
+			let first_block = self.first_data_block + group * self.blocks_per_group;
+			let blocks = (self.blocks_count - first_block).min(self.blocks_per_group);
+			let bitmap = u64::from(self.cache.read_u32(desc + BG_BLOCK_BITMAP)?);
+			if let Some(bit) = self.allocate_bit(bitmap, 0, blocks)? {
+				self.adjust_free_count(group, S_FREE_BLOCKS_COUNT, BG_FREE_BLOCKS_COUNT, -1)?;
+				let block = first_block + bit;
+				self.cache
+					.zero(u64::from(block) * self.block_size, self.block_size as usize)?;
+				return Ok(block);
+			}
+		}
+
+		Err(FileError::ENOSPC())
+	}
+
+	fn free_block(&mut self, block: u32) -> Result<(), FileError> {
+		if block < self.first_data_block || block >= self.blocks_count {
+			warn!("Freeing invalid block {}", block);
+			return Err(FileError::EIO());
+		}
+
+		let group = (block - self.first_data_block) / self.blocks_per_group;
+		let bitmap = u64::from(
+			self.cache
+				.read_u32(self.group_desc(group) + BG_BLOCK_BITMAP)?,
+		);
+		self.clear_bit(
+			bitmap,
+			(block - self.first_data_block) % self.blocks_per_group,
+		)?;
+		self.adjust_free_count(group, S_FREE_BLOCKS_COUNT, BG_FREE_BLOCKS_COUNT, 1)
+	}
+
+	fn allocate_inode(&mut self, goal_group: u32) -> Result<u32, FileError> {
+		for i in 0..self.group_count {
+			let group = (goal_group + i) % self.group_count;
+			let desc = self.group_desc(group);
+			if self.cache.read_u16(desc + BG_FREE_INODES_COUNT)? == 0 {
+				continue;
+			}
+
+			// the first inodes are reserved
+			let first = if group == 0 { self.first_ino - 1 } else { 0 };
+			let bitmap = u64::from(self.cache.read_u32(desc + BG_INODE_BITMAP)?);
+			if let Some(bit) = self.allocate_bit(bitmap, first, self.inodes_per_group)? {
+				self.adjust_free_count(group, S_FREE_INODES_COUNT, BG_FREE_INODES_COUNT, -1)?;
+				return Ok(group * self.inodes_per_group + bit + 1);
+			}
+		}
+
+		Err(FileError::ENOSPC())
+	}
+
+	fn free_inode(&mut self, inode: u32) -> Result<(), FileError> {
+		let group = (inode - 1) / self.inodes_per_group;
+		let bitmap = u64::from(
+			self.cache
+				.read_u32(self.group_desc(group) + BG_INODE_BITMAP)?,
+		);
+		self.clear_bit(bitmap, (inode - 1) % self.inodes_per_group)?;
+		self.adjust_free_count(group, S_FREE_INODES_COUNT, BG_FREE_INODES_COUNT, 1)
+	}
+
+	fn inode_group(&self, inode: u32) -> u32 {
+		(inode - 1) / self.inodes_per_group
+	}
+
+	/// Returns the disk offset of `inode`.
+	fn inode_offset(&mut self, inode: u32) -> Result<u64, FileError> {
+		if inode == 0 || inode > self.group_count * self.inodes_per_group {
+			warn!("Invalid inode {}", inode);
+			return Err(FileError::EIO());
+		}
+
+		let group = self.inode_group(inode);
+		let table = u64::from(
+			self.cache
+				.read_u32(self.group_desc(group) + BG_INODE_TABLE)?,
+		);
+		let index = u64::from((inode - 1) % self.inodes_per_group);
+		Ok(table * self.block_size + index * self.inode_size)
+	}
+
+	fn inode_mode(&mut self, inode: u32) -> Result<u16, FileError> {
+		let offset = self.inode_offset(inode)?;
+		self.cache.read_u16(offset + I_MODE)
+	}
+
+	fn inode_size(&mut self, inode: u32) -> Result<u64, FileError> {
+		let offset = self.inode_offset(inode)?;
+		let mut size = u64::from(self.cache.read_u32(offset + I_SIZE)?);
+		// the upper bits of the size of directories are used for ACLs by revision 0
+		if self.rev_level > GOOD_OLD_REV
+			&& self.cache.read_u16(offset + I_MODE)? & S_IFMT == S_IFREG
+		{
+			size |= u64::from(self.cache.read_u32(offset + I_SIZE_HIGH)?) << 32;
+		}
+
+		Ok(size)
+	}
+
+	fn set_inode_size(&mut self, inode: u32, size: u64) -> Result<(), FileError> {
+		let offset = self.inode_offset(inode)?;
+		self.cache.write_u32(offset + I_SIZE, size as u32)?;
+		if self.rev_level > GOOD_OLD_REV
+			&& self.cache.read_u16(offset + I_MODE)? & S_IFMT == S_IFREG
+		{
+			self.cache
+				.write_u32(offset + I_SIZE_HIGH, (size >> 32) as u32)?;
+			if size > i32::MAX as u64 && self.ro_compat & FEATURE_RO_COMPAT_LARGE_FILE == 0 {
+				self.ro_compat |= FEATURE_RO_COMPAT_LARGE_FILE;
+				self.cache
+					.write_u32(SUPERBLOCK_OFFSET + S_FEATURE_RO_COMPAT, self.ro_compat)?;
+			}
+		}
+
+		let time = now();
+		self.cache.write_u32(offset + I_MTIME, time)?;
+		self.cache.write_u32(offset + I_CTIME, time)
+	}
+
+	fn max_file_size(&self) -> u64 {
+		let per_block = self.block_size / 4;
+		let blocks =
+			DIRECT_BLOCKS + per_block + per_block * per_block + per_block * per_block * per_block;
+		if self.rev_level > GOOD_OLD_REV {
+			blocks * self.block_size
+		} else {
+			(blocks * self.block_size).min(i32::MAX as u64)
+		}
+	}
+
+	/// Returns the block, which is referenced by the block pointer at `slot`. If it
+	/// doesn't exist and `allocate` is true, a zeroed block is allocated for `inode`.
+	fn slot_block(
+		&mut self,
+		inode: u32,
+		slot: u64,
+		allocate: bool,
+	) -> Result<Option<u32>, FileError> {
+		let block = self.cache.read_u32(slot)?;
+		if block != 0 {
+			return Ok(Some(block));
+		} else if !allocate {
+			return Ok(None);
+		}
+
+		let block = self.allocate_block(self.inode_group(inode))?;
+		self.cache.write_u32(slot, block)?;
+
+		// the number of blocks is counted in 512 byte units
+		let offset = self.inode_offset(inode)? + I_BLOCKS;
+		let blocks = self.cache.read_u32(offset)?;
+		self.cache
+			.write_u32(offset, blocks + (self.block_size / 512) as u32)?;
+
+		Ok(Some(block))
+	}
+
+	/// Maps the logical block `index` of `inode` to a block of the filesystem.
+	fn map_block(
+		&mut self,
+		inode: u32,
+		index: u64,
+		allocate: bool,
+	) -> Result<Option<u32>, FileError> {
+		let per_block = self.block_size / 4;
+		let (root, path) = if index < DIRECT_BLOCKS {
+			(index, Vec::new())
+		} else if index - DIRECT_BLOCKS < per_block {
+			(INDIRECT_BLOCK, vec![index - DIRECT_BLOCKS])
+		} else if index - DIRECT_BLOCKS - per_block < per_block * per_block {
+			let index = index - DIRECT_BLOCKS - per_block;
+			(
+				DOUBLE_INDIRECT_BLOCK,
+				vec![index / per_block, index % per_block],
+			)
+		} else {
+			let index = index - DIRECT_BLOCKS - per_block - per_block * per_block;
+			if index >= per_block * per_block * per_block {
+				return Err(FileError::EINVAL());
+			}
+			(
+				TRIPLE_INDIRECT_BLOCK,
+				vec![
+					index / (per_block * per_block),
+					(index / per_block) % per_block,
+					index % per_block,
+				],
+			)
+		};
+
+		let mut slot = self.inode_offset(inode)? + I_BLOCK + root * 4;
+		for index in path {
+			match self.slot_block(inode, slot, allocate)? {
+				Some(block) => slot = u64::from(block) * self.block_size + index * 4,
+				None => return Ok(None),
+			}
+		}
+
+		self.slot_block(inode, slot, allocate)
+	}
+
+	/// Releases `block` and all blocks, which are referenced by it up to `depth` levels.
+	fn free_tree(&mut self, block: u32, depth: u32) -> Result<(), FileError> {
+		if depth > 0 {
+			let offset = u64::from(block) * self.block_size;
+			for i in 0..self.block_size / 4 {
+				let child = self.cache.read_u32(offset + i * 4)?;
+				if child != 0 {
+					self.free_tree(child, depth - 1)?;
+				}
+			}
+		}
+
+		self.free_block(block)
+	}
+
+	/// Releases all blocks of `inode`.
+	fn truncate(&mut self, inode: u32) -> Result<(), FileError> {
+		let offset = self.inode_offset(inode)?;
+		for i in 0..=TRIPLE_INDIRECT_BLOCK {
+			let slot = offset + I_BLOCK + i * 4;
+			let block = self.cache.read_u32(slot)?;
+			if block != 0 {
+				let depth = i.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+				self.free_tree(block, depth)?;
+				self.cache.write_u32(slot, 0)?;
+			}
+		}
+
+		self.cache.write_u32(offset + I_BLOCKS, 0)?;
+		self.set_inode_size(inode, 0)
+	}
+
+	fn read_file(&mut self, inode: u32, offset: u64, buf: &mut [u8]) -> Result<(), FileError> {
+		let mut done = 0;
+		while done < buf.len() {
+			let pos = offset + done as u64;
+			let block_offset = pos % self.block_size;
+			let len = ((self.block_size - block_offset) as usize).min(buf.len() - done);
+			match self.map_block(inode, pos / self.block_size, false)? {
+				Some(block) => {
+					let disk_offset = u64::from(block) * self.block_size + block_offset;
+					self.cache.read(disk_offset, &mut buf[done..done + len])?;
+				}
+				// holes of sparse files contain zeros
+				None => buf[done..done + len].iter_mut().for_each(|b| *b = 0),
+			}
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	fn write_file(&mut self, inode: u32, offset: u64, buf: &[u8]) -> Result<(), FileError> {
+		let mut done = 0;
+		while done < buf.len() {
+			let pos = offset + done as u64;
+			let block_offset = pos % self.block_size;
+			let len = ((self.block_size - block_offset) as usize).min(buf.len() - done);
+			let block = self.map_block(inode, pos / self.block_size, true)?.unwrap();
+			let disk_offset = u64::from(block) * self.block_size + block_offset;
+			self.cache.write(disk_offset, &buf[done..done + len])?;
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	/// Reads the name length of the directory entry at `offset`. Without the
+	/// file type feature, the length is a 16 bit value.
+	fn name_len(&mut self, offset: u64) -> Result<u64, FileError> {
+		if self.filetype {
+			let mut len = [0u8; 1];
+			self.cache.read(offset + 6, &mut len)?;
+			Ok(u64::from(len[0]))
+		} else {
+			Ok(u64::from(self.cache.read_u16(offset + 6)?))
+		}
+	}
+
+	/// Calls `f` for the entries of the directory `dir`, until it returns true.
+	fn walk_dir(
+		&mut self,
+		dir: u32,
+		mut f: impl FnMut(&mut Self, u64, Option<u64>) -> Result<bool, FileError>,
+	) -> Result<bool, FileError> {
+		let blocks = (self.inode_size(dir)? + self.block_size - 1) / self.block_size;
+		for index in 0..blocks {
+			let block = match self.map_block(dir, index, false)? {
+				Some(block) => u64::from(block) * self.block_size,
+				None => continue,
+			};
+
+			let mut prev = None;
+			let mut pos = 0;
+			while pos < self.block_size {
+				let offset = block + pos;
+				let len = u64::from(self.cache.read_u16(offset + 4)?);
+				if len < DIR_ENTRY_HEADER || pos + len > self.block_size || len % 4 != 0 {
+					warn!("Corrupted directory entry in inode {}", dir);
+					return Err(FileError::EIO());
+				}
+
+				if f(self, offset, prev)? {
+					return Ok(true);
+				}
+				prev = Some(offset);
+				pos += len;
+			}
+		}
+
+		Ok(false)
+	}
+
+	fn find(&mut self, dir: u32, name: &str) -> Result<Option<DirEntry>, FileError> {
+		let mut found = None;
+		self.walk_dir(dir, |volume, offset, prev| {
+			let inode = volume.cache.read_u32(offset)?;
+			let name_len = volume.name_len(offset)?;
+			if inode == 0 || name_len != name.len() as u64 {
+				return Ok(false);
+			}
+
+			let mut entry_name = vec![0u8; name.len()];
+			volume
+				.cache
+				.read(offset + DIR_ENTRY_HEADER, &mut entry_name)?;
+			if entry_name == name.as_bytes() {
+				found = Some(DirEntry {
+					inode,
+					offset,
+					prev,
+				});
+				Ok(true)
+			} else {
+				Ok(false)
+			}
+		})?;
+
+		Ok(found)
+	}
+
+	/// Returns the inode of the directory `path`.
+	fn lookup_dir(&mut self, path: &str) -> Result<u32, FileError> {
+		let mut dir = ROOT_INODE;
+		for name in components(path) {
+			dir = self.find(dir, name)?.ok_or(FileError::ENOENT())?.inode;
+			if self.inode_mode(dir)? & S_IFMT != S_IFDIR {
+				return Err(FileError::ENOTDIR());
+			}
+		}
+
+		Ok(dir)
+	}
+
+	fn write_dir_entry(
+		&mut self,
+		offset: u64,
+		inode: u32,
+		len: u64,
+		name: &str,
+		file_type: u8,
+	) -> Result<(), FileError> {
+		self.cache.write_u32(offset, inode)?;
+		self.cache.write_u16(offset + 4, len as u16)?;
+		if self.filetype {
+			self.cache
+				.write(offset + 6, &[name.len() as u8, file_type])?;
+		} else {
+			self.cache.write_u16(offset + 6, name.len() as u16)?;
+		}
+		self.cache.write(offset + DIR_ENTRY_HEADER, name.as_bytes())
+	}
+
+	/// Adds the entry `name` for `inode` to the directory `dir`.
+	fn add_entry(
+		&mut self,
+		dir: u32,
+		name: &str,
+		inode: u32,
+		file_type: u8,
+	) -> Result<(), FileError> {
+		let needed = rec_len(name.len());
+
+		// the hash tree isn't updated, so that the directory has to be searched linearly
+		let flags_offset = self.inode_offset(dir)? + I_FLAGS;
+		let flags = self.cache.read_u32(flags_offset)?;
+		if flags & INDEX_FL != 0 {
+			self.cache.write_u32(flags_offset, flags & !INDEX_FL)?;
+		}
+
+		let added = self.walk_dir(dir, |volume, offset, _| {
+			let len = u64::from(volume.cache.read_u16(offset + 4)?);
+			let used = if volume.cache.read_u32(offset)? == 0 {
+				0
+			} else {
+				rec_len(volume.name_len(offset)? as usize)
+			};
+			if len.saturating_sub(used) < needed {
+				return Ok(false);
+			}
+
+			if used > 0 {
+				volume.cache.write_u16(offset + 4, used as u16)?;
+			}
+			volume.write_dir_entry(offset + used, inode, len - used, name, file_type)?;
+			Ok(true)
+		})?;
+
+		if !added {
+			let size = self.inode_size(dir)?;
+			let block = self.map_block(dir, size / self.block_size, true)?.unwrap();
+			self.write_dir_entry(
+				u64::from(block) * self.block_size,
+				inode,
+				self.block_size,
+				name,
+				file_type,
+			)?;
+			self.set_inode_size(dir, size + self.block_size)?;
+		}
+
+		Ok(())
+	}
+
+	/// Creates an empty regular file `name` in the directory `dir`.
+	fn create(&mut self, dir: u32, name: &str, mode: u32) -> Result<u32, FileError> {
+		if name.len() > MAX_NAME_LEN || name.contains('\0') {
+			return Err(FileError::EINVAL());
+		}
+
+		let inode = self.allocate_inode(self.inode_group(dir))?;
+		let offset = self.inode_offset(inode)?;
+		let time = now();
+		self.cache.zero(offset, self.inode_size as usize)?;
+		self.cache
+			.write_u16(offset + I_MODE, S_IFREG | (mode & 0o7777) as u16)?;
+		self.cache.write_u32(offset + I_ATIME, time)?;
+		self.cache.write_u32(offset + I_CTIME, time)?;
+		self.cache.write_u32(offset + I_MTIME, time)?;
+		self.cache.write_u16(offset + I_LINKS_COUNT, 1)?;
+
+		self.add_entry(dir, name, inode, FT_REG_FILE)?;
+		Ok(inode)
+	}
+
+	/// Removes the directory entry and releases the inode with its last link.
+	fn remove(&mut self, entry: &DirEntry) -> Result<(), FileError> {
+		match entry.prev {
+			// merge the entry into its predecessor
+			Some(prev) => {
+				let len = self.cache.read_u16(prev + 4)? + self.cache.read_u16(entry.offset + 4)?;
+				self.cache.write_u16(prev + 4, len)?;
+			}
+			None => self.cache.write_u32(entry.offset, 0)?,
+		}
+
+		let offset = self.inode_offset(entry.inode)?;
+		let links = self
+			.cache
+			.read_u16(offset + I_LINKS_COUNT)?
+			.saturating_sub(1);
+		self.cache.write_u16(offset + I_LINKS_COUNT, links)?;
+		if links == 0 {
+			self.truncate(entry.inode)?;
+			self.cache.write_u32(offset + I_DTIME, now())?;
+			self.free_inode(entry.inode)?;
+		}
+
+		Ok(())
+	}
+
+	fn sync(&mut self) -> Result<(), FileError> {
+		if !self.read_only {
+			self.cache.write_u32(SUPERBLOCK_OFFSET + S_WTIME, now())?;
+		}
+
+		self.cache.flush()
+	}
+}
+
+/// ext2 filesystem backend
+pub struct Ext2 {
+	volume: Arc<Mutex<Volume>>,
+}
+
+impl Ext2 {
+	/// Checks, if the device contains an ext2 superblock.
+	pub fn probe(cache: &mut SectorCache) -> Result<bool, FileError> {
+		Ok(cache.read_u16(SUPERBLOCK_OFFSET + 56)? == EXT2_MAGIC)
+	}
+
+	pub fn new(mut cache: SectorCache) -> Result<Self, FileError> {
+		if !Self::probe(&mut cache)? {
+			return Err(FileError::EINVAL());
+		}
+
+		let mut sb = [0u8; 1024];
+		cache.read(SUPERBLOCK_OFFSET, &mut sb)?;
+		let field = |offset: usize| -> u32 {
+			u32::from_le_bytes([sb[offset], sb[offset + 1], sb[offset + 2], sb[offset + 3]])
+		};
+
+		let blocks_count = field(4);
+		let first_data_block = field(20);
+		let log_block_size = field(24);
+		let blocks_per_group = field(32);
+		let inodes_per_group = field(40);
+		let rev_level = field(76);
+		let incompat = if rev_level > GOOD_OLD_REV {
+			field(96)
+		} else {
+			0
+		};
+		let ro_compat = if rev_level > GOOD_OLD_REV {
+			field(100)
+		} else {
+			0
+		};
+
+		if incompat & !SUPPORTED_INCOMPAT != 0 {
+			warn!(
+				"ext2 filesystem uses unsupported features {:#x}",
+				incompat & !SUPPORTED_INCOMPAT
+			);
+			return Err(FileError::EINVAL());
+		}
+		if log_block_size > 6
+			|| blocks_per_group == 0
+			|| inodes_per_group == 0
+			|| blocks_count <= first_data_block
+		{
+			warn!("Invalid ext2 superblock");
+			return Err(FileError::EINVAL());
+		}
+
+		let (inode_size, first_ino) = if rev_level > GOOD_OLD_REV {
+			(u64::from(u16::from_le_bytes([sb[88], sb[89]])), field(84))
+		} else {
+			(GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO)
+		};
+		let block_size = 1024u64 << log_block_size;
+		let group_count =
+			(blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group;
+
+		// filesystems with unknown features may be read, but not modified
+		let read_only = ro_compat & !SUPPORTED_RO_COMPAT != 0;
+		if read_only {
+			info!(
+				"ext2 filesystem uses unsupported features {:#x}, mounting read-only",
+				ro_compat & !SUPPORTED_RO_COMPAT
+			);
+		}
+
+		info!(
+			"ext2 filesystem with {} blocks of {} bytes in {} groups",
+			blocks_count, block_size, group_count
+		);
+
+		let volume = Volume {
+			cache,
+			block_size,
+			blocks_count,
+			first_data_block,
+			blocks_per_group,
+			inodes_per_group,
+			group_count,
+			inode_size,
+			first_ino,
+			rev_level,
+			ro_compat,
+			filetype: incompat & FEATURE_INCOMPAT_FILETYPE != 0,
+			read_only,
+		};
+
+		Ok(Self {
+			volume: Arc::new(Mutex::new(volume)),
+		})
+	}
+}
+
+impl PosixFileSystem for Ext2 {
+	fn open(&self, path: &str, perms: FilePerms) -> Result<Box<dyn PosixFile + Send>, FileError> {
+		let (parent, name) = match split_parent(path) {
+			Ok(split) => split,
+			Err(_) => return Err(FileError::EISDIR()),
+		};
+
+		let mut volume = self.volume.lock();
+		if volume.read_only && (perms.write || perms.creat) {
+			return Err(FileError::EROFS());
+		}
+
+		let dir = volume.lookup_dir(parent)?;
+		let inode = match volume.find(dir, name)? {
+			Some(_) if perms.creat && perms.excl => return Err(FileError::EEXIST()),
+			Some(entry) => {
+				match volume.inode_mode(entry.inode)? & S_IFMT {
+					S_IFREG => {}
+					S_IFDIR => return Err(FileError::EISDIR()),
+					_ => return Err(FileError::EINVAL()),
+				}
+				if perms.trunc && perms.write {
+					volume.truncate(entry.inode)?;
+				}
+				entry.inode
+			}
+			None if perms.creat => volume.create(dir, name, perms.mode)?,
+			None => return Err(FileError::ENOENT()),
+		};
+		if perms.write || perms.creat {
+			volume.sync()?;
+		}
+
+		Ok(Box::new(Ext2File {
+			volume: self.volume.clone(),
+			inode,
+			offset: 0,
+			write: perms.write,
+			append: perms.append,
+		}))
+	}
+
+	fn unlink(&self, path: &str) -> Result<(), FileError> {
+		let (parent, name) = split_parent(path)?;
+
+		let mut volume = self.volume.lock();
+		if volume.read_only {
+			return Err(FileError::EROFS());
+		}
+
+		let dir = volume.lookup_dir(parent)?;
+		let entry = volume.find(dir, name)?.ok_or(FileError::ENOENT())?;
+		if volume.inode_mode(entry.inode)? & S_IFMT == S_IFDIR {
+			return Err(FileError::EISDIR());
+		}
+		volume.remove(&entry)?;
+		volume.sync()
+	}
+}
+
+struct Ext2File {
+	volume: Arc<Mutex<Volume>>,
+	inode: u32,
+	offset: u64,
+	write: bool,
+	append: bool,
+}
+
+impl PosixFile for Ext2File {
+	fn close(&mut self) -> Result<(), FileError> {
+		if self.write {
+			self.volume.lock().sync()?;
+		}
+
+		Ok(())
+	}
+
+	fn read(&mut self, len: u32) -> Result<Vec<u8>, FileError> {
+		let mut volume = self.volume.lock();
+		let size = volume.inode_size(self.inode)?;
+		let len = (len as u64).min(size.saturating_sub(self.offset)) as usize;
+
+		let mut buf = vec![0u8; len];
+		volume.read_file(self.inode, self.offset, &mut buf)?;
+		self.offset += len as u64;
+		Ok(buf)
+	}
+
+	fn write(&mut self, buf: &[u8]) -> Result<u64, FileError> {
+		if !self.write {
+			return Err(FileError::EINVAL());
+		}
+
+		let mut volume = self.volume.lock();
+		let size = volume.inode_size(self.inode)?;
+		if self.append {
+			self.offset = size;
+		}
+		let end = self.offset + buf.len() as u64;
+		if end > volume.max_file_size() {
+			return Err(FileError::ENOSPC());
+		}
+
+		// a gap behind the end of the file becomes a hole
+		volume.write_file(self.inode, self.offset, buf)?;
+		volume.set_inode_size(self.inode, end.max(size))?;
+
+		self.offset = end;
+		Ok(buf.len() as u64)
+	}
+
+	fn lseek(&mut self, offset: isize, whence: SeekWhence) -> Result<usize, FileError> {
+		let base = match whence {
+			SeekWhence::Set => 0,
+			SeekWhence::Cur => self.offset as isize,
+			SeekWhence::End => self.volume.lock().inode_size(self.inode)? as isize,
+			// the file is treated as data up to its end, which is followed by a hole
+			SeekWhence::Data | SeekWhence::Hole => {
+				let size = self.volume.lock().inode_size(self.inode)? as isize;
+				if offset < 0 || offset >= size {
+					return Err(FileError::ENXIO());
+				}
+				match whence {
+					SeekWhence::Data => 0,
+					_ => size - offset,
+				}
+			}
+		};
+		if base + offset < 0 {
+			return Err(FileError::EINVAL());
+		}
+
+		self.offset = (base + offset) as u64;
+		Ok(self.offset as usize)
+	}
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn dir_entry_len() {
+	assert_eq!(rec_len(1), 12);
+	assert_eq!(rec_len(4), 12);
+	assert_eq!(rec_len(5), 16);
+	assert_eq!(rec_len(MAX_NAME_LEN), 264);
+}
@@ -0,0 +1,933 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! FAT32 filesystem, see Microsoft's "FAT: General Overview of On-Disk Format".
+
+use crate::fs::cache::SectorCache;
+use crate::fs::{components, split_parent};
+use crate::synch::mutex::Mutex;
+use crate::syscalls::fs::{FileError, FilePerms, PosixFile, PosixFileSystem, SeekWhence};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const DIR_ENTRY_SIZE: u64 = 32;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+/// Marks the last long name entry of a name (which is stored first)
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Characters per long name entry
+const LONG_NAME_CHARS: usize = 13;
+const MAX_NAME_LEN: usize = 255;
+/// First byte of a deleted directory entry
+const ENTRY_FREE: u8 = 0xe5;
+/// First byte of the entry, which terminates a directory
+const ENTRY_END: u8 = 0x00;
+/// Case flags of Windows NT in the short entry
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FAT_BAD_CLUSTER: u32 = 0x0fff_fff7;
+const FAT_END_OF_CHAIN: u32 = 0x0fff_ffff;
+const FIRST_CLUSTER: u32 = 2;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// Directory entry, as it has been read from a directory
+struct DirEntry {
+	name: String,
+	short_name: [u8; 11],
+	attr: u8,
+	first_cluster: u32,
+	/// Disk offsets of the long name entries and the short entry (last)
+	slots: Vec<u64>,
+}
+
+impl DirEntry {
+	fn offset(&self) -> u64 {
+		*self.slots.last().unwrap()
+	}
+
+	fn is_dir(&self) -> bool {
+		self.attr & ATTR_DIRECTORY != 0
+	}
+}
+
+struct Volume {
+	cache: SectorCache,
+	cluster_size: u64,
+	/// Disk offset of the first FAT
+	fat_offset: u64,
+	/// Size of a FAT in bytes
+	fat_size: u64,
+	num_fats: u64,
+	/// Disk offset of the first cluster
+	data_offset: u64,
+	cluster_count: u32,
+	root_cluster: u32,
+	/// Disk offset of the FSInfo sector
+	fsinfo_offset: Option<u64>,
+	free_count: Option<u32>,
+	next_free: u32,
+	fsinfo_dirty: bool,
+}
+
+impl Volume {
+	fn cluster_offset(&self, cluster: u32) -> u64 {
+		self.data_offset + u64::from(cluster - FIRST_CLUSTER) * self.cluster_size
+	}
+
+	fn is_valid_cluster(&self, cluster: u32) -> bool {
+		cluster >= FIRST_CLUSTER && cluster < self.cluster_count + FIRST_CLUSTER
+	}
+
+	fn fat_entry(&mut self, cluster: u32) -> Result<u32, FileError> {
+		Ok(self
+			.cache
+			.read_u32(self.fat_offset + u64::from(cluster) * 4)?
+			& FAT_ENTRY_MASK)
+	}
+
+	/// Updates the entry of `cluster` in all FATs. The upper 4 bits are reserved and preserved.
+	fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FileError> {
+		for fat in 0..self.num_fats {
+			let offset = self.fat_offset + fat * self.fat_size + u64::from(cluster) * 4;
+			let old = self.cache.read_u32(offset)?;
+			self.cache
+				.write_u32(offset, (old & !FAT_ENTRY_MASK) | (value & FAT_ENTRY_MASK))?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the successor of `cluster` or `None` at the end of the chain.
+	fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FileError> {
+		let next = self.fat_entry(cluster)?;
+		if next > FAT_BAD_CLUSTER {
+			Ok(None)
+		} else if self.is_valid_cluster(next) {
+			Ok(Some(next))
+		} else {
+			warn!("Invalid cluster {} in chain of cluster {}", next, cluster);
+			Err(FileError::EIO())
+		}
+	}
+
+	/// Allocates a zeroed cluster and appends it to the chain ending with `prev`.
+	fn allocate_cluster(&mut self, prev: Option<u32>) -> Result<u32, FileError> {
+		if self.free_count == Some(0) {
+			return Err(FileError::ENOSPC());
+		}
+
+		let start = if self.is_valid_cluster(self.next_free) {
+			self.next_free
+		} else {
+			FIRST_CLUSTER
+		};
+		let mut cluster = start;
+		loop {
+			if self.fat_entry(cluster)? == 0 {
+				break;
+			}
+
+			cluster += 1;
+			if !self.is_valid_cluster(cluster) {
+				cluster = FIRST_CLUSTER;
+			}
+			if cluster == start {
+				self.free_count = Some(0);
+				self.fsinfo_dirty = true;
+				return Err(FileError::ENOSPC());
+			}
+		}
+
+		self.set_fat_entry(cluster, FAT_END_OF_CHAIN)?;
+		if let Some(prev) = prev {
+			self.set_fat_entry(prev, cluster)?;
+		}
+		let offset = self.cluster_offset(cluster);
+		self.cache.zero(offset, self.cluster_size as usize)?;
+
+		self.free_count = self.free_count.map(|count| count.saturating_sub(1));
+		self.next_free = cluster + 1;
+		self.fsinfo_dirty = true;
+
+		Ok(cluster)
+	}
+
+	/// Releases all clusters of the chain starting with `cluster`.
+	fn free_chain(&mut self, cluster: u32) -> Result<(), FileError> {
+		let mut next = if self.is_valid_cluster(cluster) {
+			Some(cluster)
+		} else {
+			None
+		};
+
+		while let Some(cluster) = next {
+			next = self.next_cluster(cluster)?;
+			self.set_fat_entry(cluster, 0)?;
+			self.free_count = self.free_count.map(|count| count + 1);
+			self.fsinfo_dirty = true;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the `index`-th cluster of the chain starting with `first`. Missing
+	/// clusters are appended, if `allocate` is true.
+	fn chain_cluster(
+		&mut self,
+		first: u32,
+		index: u64,
+		allocate: bool,
+	) -> Result<Option<u32>, FileError> {
+		let mut cluster = first;
+		for _ in 0..index {
+			cluster = match self.next_cluster(cluster)? {
+				Some(next) => next,
+				None if allocate => self.allocate_cluster(Some(cluster))?,
+				None => return Ok(None),
+			};
+		}
+
+		Ok(Some(cluster))
+	}
+
+	/// Returns the disk offsets of all entries of the directory starting with `cluster`.
+	fn dir_slots(&mut self, cluster: u32) -> Result<Vec<u64>, FileError> {
+		let mut slots = Vec::new();
+		let mut next = Some(cluster);
+		while let Some(cluster) = next {
+			let offset = self.cluster_offset(cluster);
+			slots.extend(
+				(0..self.cluster_size / DIR_ENTRY_SIZE).map(|i| offset + i * DIR_ENTRY_SIZE),
+			);
+			next = self.next_cluster(cluster)?;
+		}
+
+		Ok(slots)
+	}
+
+	fn read_dir(&mut self, cluster: u32) -> Result<Vec<DirEntry>, FileError> {
+		let mut entries = Vec::new();
+		let mut long_name: Vec<u16> = Vec::new();
+		let mut long_slots: Vec<u64> = Vec::new();
+		// sequence number of the last long name entry and its checksum
+		let mut long_state: Option<(u8, u8)> = None;
+
+		for slot in self.dir_slots(cluster)? {
+			let mut raw = [0u8; DIR_ENTRY_SIZE as usize];
+			self.cache.read(slot, &mut raw)?;
+
+			if raw[0] == ENTRY_END {
+				break;
+			} else if raw[0] == ENTRY_FREE {
+				long_state = None;
+			} else if raw[11] & 0x3f == ATTR_LONG_NAME {
+				let seq = raw[0] & 0x1f;
+				let expected = match long_state {
+					_ if raw[0] & LAST_LONG_ENTRY != 0 => {
+						long_name.clear();
+						long_slots.clear();
+						seq
+					}
+					Some((prev, checksum)) if checksum == raw[13] => prev - 1,
+					_ => 0,
+				};
+				if seq == 0 || seq != expected {
+					long_state = None;
+					continue;
+				}
+
+				// the parts of the name are stored in reverse order
+				let mut part: Vec<u16> = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+					.iter()
+					.map(|i| u16::from_le_bytes([raw[*i], raw[*i + 1]]))
+					.collect();
+				part.extend(long_name.iter());
+				long_name = part;
+				long_slots.push(slot);
+				long_state = Some((seq, raw[13]));
+			} else if raw[11] & ATTR_VOLUME_ID != 0 {
+				long_state = None;
+			} else {
+				let mut short_name = [0u8; 11];
+				short_name.copy_from_slice(&raw[..11]);
+				if short_name[0] == 0x05 {
+					short_name[0] = ENTRY_FREE;
+				}
+
+				let mut slots = Vec::new();
+				let name = match long_state {
+					Some((1, checksum)) if checksum == short_name_checksum(&short_name) => {
+						slots.append(&mut long_slots);
+						let len = long_name
+							.iter()
+							.position(|c| *c == 0)
+							.unwrap_or_else(|| long_name.len());
+						core::char::decode_utf16(long_name[..len].iter().cloned())
+							.map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+							.collect()
+					}
+					_ => display_short_name(&short_name, raw[12]),
+				};
+				slots.push(slot);
+				long_state = None;
+
+				let first_cluster = u32::from(u16::from_le_bytes([raw[20], raw[21]])) << 16
+					| u32::from(u16::from_le_bytes([raw[26], raw[27]]));
+				entries.push(DirEntry {
+					name,
+					short_name,
+					attr: raw[11],
+					first_cluster,
+					slots,
+				});
+			}
+		}
+
+		Ok(entries)
+	}
+
+	fn find(&mut self, dir: u32, name: &str) -> Result<Option<DirEntry>, FileError> {
+		Ok(self
+			.read_dir(dir)?
+			.into_iter()
+			.find(|entry| entry.name.eq_ignore_ascii_case(name)))
+	}
+
+	/// Returns the first cluster of the directory `path`.
+	fn lookup_dir(&mut self, path: &str) -> Result<u32, FileError> {
+		let mut dir = self.root_cluster;
+		for name in components(path) {
+			let entry = self.find(dir, name)?.ok_or(FileError::ENOENT())?;
+			if !entry.is_dir() {
+				return Err(FileError::ENOTDIR());
+			}
+
+			// ".." of a subdirectory of the root directory points to cluster 0
+			dir = if entry.first_cluster == 0 {
+				self.root_cluster
+			} else {
+				entry.first_cluster
+			};
+		}
+
+		Ok(dir)
+	}
+
+	/// Finds `count` consecutive free entries in the directory and extends it, if required.
+	fn find_free_slots(&mut self, dir: u32, count: usize) -> Result<Vec<u64>, FileError> {
+		let mut run = Vec::new();
+		let mut end = false;
+		for slot in self.dir_slots(dir)? {
+			let mut first = [0u8; 1];
+			self.cache.read(slot, &mut first)?;
+
+			// all entries behind the end of the directory are free
+			end = end || first[0] == ENTRY_END;
+			if end || first[0] == ENTRY_FREE {
+				run.push(slot);
+				if run.len() == count {
+					return Ok(run);
+				}
+			} else {
+				run.clear();
+			}
+		}
+
+		let mut last = dir;
+		while let Some(next) = self.next_cluster(last)? {
+			last = next;
+		}
+		while run.len() < count {
+			last = self.allocate_cluster(Some(last))?;
+			let offset = self.cluster_offset(last);
+			run.extend(
+				(0..self.cluster_size / DIR_ENTRY_SIZE)
+					.map(|i| offset + i * DIR_ENTRY_SIZE)
+					.take(count - run.len()),
+			);
+		}
+
+		Ok(run)
+	}
+
+	/// Creates an empty file `name` in the directory `dir`.
+	fn create(&mut self, dir: u32, name: &str) -> Result<DirEntry, FileError> {
+		if name.encode_utf16().count() > MAX_NAME_LEN
+			|| name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+		{
+			return Err(FileError::EINVAL());
+		}
+
+		let entries = self.read_dir(dir)?;
+		let (short_name, long_name) = match exact_short_name(name) {
+			Some(short_name) => (short_name, None),
+			None => {
+				let short_name = numbered_short_name(name, |candidate| {
+					entries.iter().any(|entry| entry.short_name == *candidate)
+				})
+				.ok_or(FileError::EEXIST())?;
+				(short_name, Some(name.encode_utf16().collect::<Vec<u16>>()))
+			}
+		};
+
+		let long_count = long_name.as_ref().map_or(0, |name| {
+			(name.len() + LONG_NAME_CHARS - 1) / LONG_NAME_CHARS
+		});
+		let slots = self.find_free_slots(dir, long_count + 1)?;
+
+		if let Some(long_name) = long_name {
+			let checksum = short_name_checksum(&short_name);
+			for (i, slot) in slots[..long_count].iter().enumerate() {
+				let seq = long_count - i;
+				let mut raw = [0u8; DIR_ENTRY_SIZE as usize];
+				raw[0] = seq as u8 | if i == 0 { LAST_LONG_ENTRY } else { 0 };
+				raw[11] = ATTR_LONG_NAME;
+				raw[13] = checksum;
+				let part = (seq - 1) * LONG_NAME_CHARS;
+				let positions = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+				for (j, pos) in positions.iter().enumerate() {
+					// the name is terminated by 0 and padded with 0xffff
+					let c = match (part + j).cmp(&long_name.len()) {
+						core::cmp::Ordering::Less => long_name[part + j],
+						core::cmp::Ordering::Equal => 0,
+						core::cmp::Ordering::Greater => 0xffff,
+					};
+					raw[*pos..*pos + 2].copy_from_slice(&c.to_le_bytes());
+				}
+				self.cache.write(*slot, &raw)?;
+			}
+		}
+
+		let (date, time) = fat_timestamp(crate::syscalls::get_realtime_nanos() / 1_000_000_000);
+		let mut raw = [0u8; DIR_ENTRY_SIZE as usize];
+		raw[..11].copy_from_slice(&short_name);
+		if raw[0] == ENTRY_FREE {
+			raw[0] = 0x05;
+		}
+		raw[11] = ATTR_ARCHIVE;
+		raw[14..16].copy_from_slice(&time.to_le_bytes());
+		raw[16..18].copy_from_slice(&date.to_le_bytes());
+		raw[18..20].copy_from_slice(&date.to_le_bytes());
+		raw[22..24].copy_from_slice(&time.to_le_bytes());
+		raw[24..26].copy_from_slice(&date.to_le_bytes());
+		self.cache.write(slots[long_count], &raw)?;
+
+		Ok(DirEntry {
+			name: name.into(),
+			short_name,
+			attr: ATTR_ARCHIVE,
+			first_cluster: 0,
+			slots,
+		})
+	}
+
+	/// Returns the first cluster and the size of the file with the short entry at `entry`.
+	fn file_info(&mut self, entry: u64) -> Result<(u32, u64), FileError> {
+		let mut raw = [0u8; DIR_ENTRY_SIZE as usize];
+		self.cache.read(entry, &mut raw)?;
+		if raw[0] == ENTRY_FREE {
+			// the file has been unlinked
+			return Err(FileError::ENOENT());
+		}
+
+		let first_cluster = u32::from(u16::from_le_bytes([raw[20], raw[21]])) << 16
+			| u32::from(u16::from_le_bytes([raw[26], raw[27]]));
+		let size = u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]);
+		Ok((first_cluster, u64::from(size)))
+	}
+
+	fn set_first_cluster(&mut self, entry: u64, cluster: u32) -> Result<(), FileError> {
+		self.cache.write_u16(entry + 20, (cluster >> 16) as u16)?;
+		self.cache.write_u16(entry + 26, cluster as u16)
+	}
+
+	/// Updates the size and the modification time of the file with the short entry at `entry`.
+	fn set_size(&mut self, entry: u64, size: u64) -> Result<(), FileError> {
+		let (date, time) = fat_timestamp(crate::syscalls::get_realtime_nanos() / 1_000_000_000);
+		self.cache.write_u16(entry + 22, time)?;
+		self.cache.write_u16(entry + 24, date)?;
+		self.cache.write_u32(entry + 28, size as u32)
+	}
+
+	fn truncate(&mut self, entry: u64) -> Result<(), FileError> {
+		let (first_cluster, _) = self.file_info(entry)?;
+		self.free_chain(first_cluster)?;
+		self.set_first_cluster(entry, 0)?;
+		self.set_size(entry, 0)
+	}
+
+	/// Returns the `index`-th cluster of the file with the short entry at `entry`.
+	fn file_cluster(
+		&mut self,
+		entry: u64,
+		index: u64,
+		allocate: bool,
+	) -> Result<Option<u32>, FileError> {
+		let (mut first_cluster, _) = self.file_info(entry)?;
+		if first_cluster == 0 {
+			if !allocate {
+				return Ok(None);
+			}
+			first_cluster = self.allocate_cluster(None)?;
+			self.set_first_cluster(entry, first_cluster)?;
+		}
+
+		self.chain_cluster(first_cluster, index, allocate)
+	}
+
+	fn read_file(&mut self, entry: u64, offset: u64, buf: &mut [u8]) -> Result<(), FileError> {
+		let mut done = 0;
+		while done < buf.len() {
+			let pos = offset + done as u64;
+			let cluster_offset = pos % self.cluster_size;
+			let len = ((self.cluster_size - cluster_offset) as usize).min(buf.len() - done);
+			let cluster = self
+				.file_cluster(entry, pos / self.cluster_size, false)?
+				.ok_or(FileError::EIO())?;
+			let disk_offset = self.cluster_offset(cluster) + cluster_offset;
+			self.cache.read(disk_offset, &mut buf[done..done + len])?;
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	fn write_file(&mut self, entry: u64, offset: u64, buf: &[u8]) -> Result<(), FileError> {
+		let mut done = 0;
+		while done < buf.len() {
+			let pos = offset + done as u64;
+			let cluster_offset = pos % self.cluster_size;
+			let len = ((self.cluster_size - cluster_offset) as usize).min(buf.len() - done);
+			let cluster = self
+				.file_cluster(entry, pos / self.cluster_size, true)?
+				.unwrap();
+			let disk_offset = self.cluster_offset(cluster) + cluster_offset;
+			self.cache.write(disk_offset, &buf[done..done + len])?;
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	fn remove(&mut self, entry: &DirEntry) -> Result<(), FileError> {
+		self.free_chain(entry.first_cluster)?;
+		for slot in entry.slots.iter() {
+			self.cache.write(*slot, &[ENTRY_FREE])?;
+		}
+
+		Ok(())
+	}
+
+	fn sync(&mut self) -> Result<(), FileError> {
+		if self.fsinfo_dirty {
+			if let Some(offset) = self.fsinfo_offset {
+				self.cache
+					.write_u32(offset + 488, self.free_count.unwrap_or(FSINFO_UNKNOWN))?;
+				self.cache.write_u32(offset + 492, self.next_free)?;
+			}
+			self.fsinfo_dirty = false;
+		}
+
+		self.cache.flush()
+	}
+}
+
+/// FAT32 filesystem backend
+pub struct Fat32 {
+	volume: Arc<Mutex<Volume>>,
+}
+
+impl Fat32 {
+	/// Checks, if the boot sector describes a FAT32 filesystem.
+	pub fn probe(cache: &mut SectorCache) -> Result<bool, FileError> {
+		let mut boot = [0u8; 512];
+		cache.read(0, &mut boot)?;
+
+		let bytes_per_sector = u16::from_le_bytes([boot[11], boot[12]]);
+		let sectors_per_cluster = boot[13];
+		let root_entry_count = u16::from_le_bytes([boot[17], boot[18]]);
+		let fat_size_16 = u16::from_le_bytes([boot[22], boot[23]]);
+		let fat_size_32 = u32::from_le_bytes([boot[36], boot[37], boot[38], boot[39]]);
+
+		Ok(boot[510] == 0x55
+			&& boot[511] == 0xaa
+			&& (boot[0] == 0xeb || boot[0] == 0xe9)
+			&& bytes_per_sector.is_power_of_two()
+			&& bytes_per_sector >= 512
+			&& sectors_per_cluster.is_power_of_two()
+			&& boot[16] > 0
+			&& root_entry_count == 0
+			&& fat_size_16 == 0
+			&& fat_size_32 != 0)
+	}
+
+	pub fn new(mut cache: SectorCache) -> Result<Self, FileError> {
+		if !Self::probe(&mut cache)? {
+			return Err(FileError::EINVAL());
+		}
+
+		let mut boot = [0u8; 512];
+		cache.read(0, &mut boot)?;
+		let bytes_per_sector = u64::from(u16::from_le_bytes([boot[11], boot[12]]));
+		let sectors_per_cluster = u64::from(boot[13]);
+		let reserved_sectors = u64::from(u16::from_le_bytes([boot[14], boot[15]]));
+		let num_fats = u64::from(boot[16]);
+		let total_sectors = match u16::from_le_bytes([boot[19], boot[20]]) {
+			0 => u64::from(u32::from_le_bytes([boot[32], boot[33], boot[34], boot[35]])),
+			total => u64::from(total),
+		};
+		let fat_sectors = u64::from(u32::from_le_bytes([boot[36], boot[37], boot[38], boot[39]]));
+		let root_cluster = u32::from_le_bytes([boot[44], boot[45], boot[46], boot[47]]);
+		let fsinfo_sector = u64::from(u16::from_le_bytes([boot[48], boot[49]]));
+
+		let data_sector = reserved_sectors + num_fats * fat_sectors;
+		if total_sectors <= data_sector {
+			warn!("FAT32 filesystem without data area");
+			return Err(FileError::EINVAL());
+		}
+		// the FAT may be larger than required
+		let cluster_count = ((total_sectors - data_sector) / sectors_per_cluster)
+			.min(fat_sectors * bytes_per_sector / 4 - u64::from(FIRST_CLUSTER))
+			as u32;
+
+		let mut volume = Volume {
+			cache,
+			cluster_size: sectors_per_cluster * bytes_per_sector,
+			fat_offset: reserved_sectors * bytes_per_sector,
+			fat_size: fat_sectors * bytes_per_sector,
+			num_fats,
+			data_offset: data_sector * bytes_per_sector,
+			cluster_count,
+			root_cluster,
+			fsinfo_offset: None,
+			free_count: None,
+			next_free: FIRST_CLUSTER,
+			fsinfo_dirty: false,
+		};
+		if !volume.is_valid_cluster(root_cluster) {
+			warn!("Invalid root cluster {}", root_cluster);
+			return Err(FileError::EINVAL());
+		}
+
+		if fsinfo_sector != 0 && fsinfo_sector < reserved_sectors {
+			let offset = fsinfo_sector * bytes_per_sector;
+			if volume.cache.read_u32(offset)? == FSINFO_LEAD_SIGNATURE
+				&& volume.cache.read_u32(offset + 484)? == FSINFO_STRUCT_SIGNATURE
+			{
+				let free_count = volume.cache.read_u32(offset + 488)?;
+				if free_count <= cluster_count {
+					volume.free_count = Some(free_count);
+				}
+				volume.next_free = volume.cache.read_u32(offset + 492)?;
+				volume.fsinfo_offset = Some(offset);
+			}
+		}
+
+		info!(
+			"FAT32 filesystem with {} clusters of {} bytes",
+			cluster_count, volume.cluster_size
+		);
+
+		Ok(Self {
+			volume: Arc::new(Mutex::new(volume)),
+		})
+	}
+}
+
+impl PosixFileSystem for Fat32 {
+	fn open(&self, path: &str, perms: FilePerms) -> Result<Box<dyn PosixFile + Send>, FileError> {
+		let (parent, name) = match split_parent(path) {
+			Ok(split) => split,
+			Err(_) => return Err(FileError::EISDIR()),
+		};
+
+		let mut volume = self.volume.lock();
+		let dir = volume.lookup_dir(parent)?;
+		let entry = match volume.find(dir, name)? {
+			Some(_) if perms.creat && perms.excl => return Err(FileError::EEXIST()),
+			Some(entry) if entry.is_dir() => return Err(FileError::EISDIR()),
+			Some(entry) => {
+				if perms.trunc && perms.write {
+					volume.truncate(entry.offset())?;
+				}
+				entry.offset()
+			}
+			None if perms.creat => volume.create(dir, name)?.offset(),
+			None => return Err(FileError::ENOENT()),
+		};
+		if perms.write || perms.creat {
+			volume.sync()?;
+		}
+
+		Ok(Box::new(FatFile {
+			volume: self.volume.clone(),
+			entry,
+			offset: 0,
+			write: perms.write,
+			append: perms.append,
+		}))
+	}
+
+	fn unlink(&self, path: &str) -> Result<(), FileError> {
+		let (parent, name) = split_parent(path)?;
+
+		let mut volume = self.volume.lock();
+		let dir = volume.lookup_dir(parent)?;
+		let entry = volume.find(dir, name)?.ok_or(FileError::ENOENT())?;
+		if entry.is_dir() {
+			return Err(FileError::EISDIR());
+		}
+		volume.remove(&entry)?;
+		volume.sync()
+	}
+}
+
+struct FatFile {
+	volume: Arc<Mutex<Volume>>,
+	/// Disk offset of the short directory entry
+	entry: u64,
+	offset: u64,
+	write: bool,
+	append: bool,
+}
+
+impl PosixFile for FatFile {
+	fn close(&mut self) -> Result<(), FileError> {
+		if self.write {
+			self.volume.lock().sync()?;
+		}
+
+		Ok(())
+	}
+
+	fn read(&mut self, len: u32) -> Result<Vec<u8>, FileError> {
+		let mut volume = self.volume.lock();
+		let (_, size) = volume.file_info(self.entry)?;
+		let len = (len as u64).min(size.saturating_sub(self.offset)) as usize;
+
+		let mut buf = vec![0u8; len];
+		volume.read_file(self.entry, self.offset, &mut buf)?;
+		self.offset += len as u64;
+		Ok(buf)
+	}
+
+	fn write(&mut self, buf: &[u8]) -> Result<u64, FileError> {
+		if !self.write {
+			return Err(FileError::EINVAL());
+		}
+
+		let mut volume = self.volume.lock();
+		let (_, size) = volume.file_info(self.entry)?;
+		if self.append {
+			self.offset = size;
+		}
+		let end = self.offset + buf.len() as u64;
+		if end > u64::from(u32::MAX) {
+			return Err(FileError::ENOSPC());
+		}
+
+		// fill a gap behind the end of the file with zeros
+		if self.offset > size {
+			let zeros = vec![0u8; (self.offset - size) as usize];
+			volume.write_file(self.entry, size, &zeros)?;
+		}
+		volume.write_file(self.entry, self.offset, buf)?;
+		volume.set_size(self.entry, end.max(size))?;
+
+		self.offset = end;
+		Ok(buf.len() as u64)
+	}
+
+	fn lseek(&mut self, offset: isize, whence: SeekWhence) -> Result<usize, FileError> {
+		let base = match whence {
+			SeekWhence::Set => 0,
+			SeekWhence::Cur => self.offset as isize,
+			SeekWhence::End => self.volume.lock().file_info(self.entry)?.1 as isize,
+			// the file is treated as data up to its end, which is followed by a hole
+			SeekWhence::Data | SeekWhence::Hole => {
+				let size = self.volume.lock().file_info(self.entry)?.1 as isize;
+				if offset < 0 || offset >= size {
+					return Err(FileError::ENXIO());
+				}
+				match whence {
+					SeekWhence::Data => 0,
+					_ => size - offset,
+				}
+			}
+		};
+		if base + offset < 0 {
+			return Err(FileError::EINVAL());
+		}
+
+		self.offset = (base + offset) as u64;
+		Ok(self.offset as usize)
+	}
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+	short_name
+		.iter()
+		.fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+/// Formats a short name as `NAME.EXT` and applies the case flags of Windows NT.
+fn display_short_name(short_name: &[u8; 11], case: u8) -> String {
+	let convert = |part: &[u8], lower: bool| -> String {
+		part.iter()
+			.map(|c| {
+				if lower {
+					c.to_ascii_lowercase() as char
+				} else {
+					*c as char
+				}
+			})
+			.collect::<String>()
+			.trim_end()
+			.into()
+	};
+
+	let mut name = convert(&short_name[..8], case & CASE_LOWER_BASE != 0);
+	let ext = convert(&short_name[8..], case & CASE_LOWER_EXT != 0);
+	if !ext.is_empty() {
+		name.push('.');
+		name.push_str(&ext);
+	}
+
+	name
+}
+
+fn is_short_name_char(c: char) -> bool {
+	c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Returns the short name of `name`, if it can be stored without a long name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+	let (base, ext) = match name.rfind('.') {
+		Some(index) => (&name[..index], &name[index + 1..]),
+		None => (name, ""),
+	};
+	if base.is_empty()
+		|| base.len() > 8
+		|| ext.len() > 3
+		|| !base.chars().chain(ext.chars()).all(is_short_name_char)
+	{
+		return None;
+	}
+
+	let mut short_name = [b' '; 11];
+	short_name[..base.len()].copy_from_slice(base.as_bytes());
+	short_name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+	Some(short_name)
+}
+
+/// Generates a unique short name `BASE~N.EXT` for a long name.
+fn numbered_short_name(name: &str, exists: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+	let convert = |part: &str| -> Vec<u8> {
+		part.chars()
+			.filter(|c| *c != ' ' && *c != '.')
+			.map(|c| {
+				let c = c.to_ascii_uppercase();
+				if is_short_name_char(c) {
+					c as u8
+				} else {
+					b'_'
+				}
+			})
+			.collect()
+	};
+
+	let name = name.trim_start_matches('.');
+	let (base, ext) = match name.rfind('.') {
+		Some(index) => (convert(&name[..index]), convert(&name[index + 1..])),
+		None => (convert(name), Vec::new()),
+	};
+
+	let mut short_name = [b' '; 11];
+	let ext_len = ext.len().min(3);
+	short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+
+	for n in 1..1_000_000u32 {
+		let tail = format!("~{}", n);
+		let base_len = base.len().min(8 - tail.len());
+		short_name[..8].copy_from_slice(&[b' '; 8]);
+		short_name[..base_len].copy_from_slice(&base[..base_len]);
+		short_name[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+
+		if !exists(&short_name) {
+			return Some(short_name);
+		}
+	}
+
+	None
+}
+
+/// Converts seconds since the epoch to the date and time format of FAT.
+fn fat_timestamp(seconds: u64) -> (u16, u16) {
+	let days = (seconds / 86400) as i64;
+	let secs = seconds % 86400;
+
+	// civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+	let z = days + 719_468;
+	let era = z / 146_097;
+	let doe = z - era * 146_097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	// FAT dates start at 1980
+	if year < 1980 {
+		return ((1 << 5) | 1, 0);
+	}
+
+	let date = (((year - 1980).min(127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+	let time =
+		((secs / 3600) as u16) << 11 | (((secs / 60) % 60) as u16) << 5 | (secs % 60 / 2) as u16;
+	(date, time)
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn short_names() {
+	assert_eq!(exact_short_name("README.TXT"), Some(*b"README  TXT"));
+	assert_eq!(exact_short_name("readme.txt"), None);
+	assert_eq!(exact_short_name("LONGFILENAME"), None);
+
+	let short_name =
+		numbered_short_name("My long file.name.text", |name| name == b"MYLONG~1TEX").unwrap();
+	assert_eq!(&short_name, b"MYLONG~2TEX");
+	assert_eq!(
+		display_short_name(&short_name, CASE_LOWER_EXT),
+		"MYLONG~2.tex"
+	);
+
+	assert_eq!(short_name_checksum(b"FILE    TXT"), 0x19);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn timestamps() {
+	// 2020-03-01 12:34:56
+	let (date, time) = fat_timestamp(1_583_066_096);
+	assert_eq!(date, (40 << 9) | (3 << 5) | 1);
+	assert_eq!(time, (12 << 11) | (34 << 5) | 28);
+	assert_eq!(fat_timestamp(0), ((1 << 5) | 1, 0));
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Disk filesystems, which are mounted into the virtual filesystem of
+//! `syscalls::fs`. The sectors of a filesystem are read either from a block
+//! device or from an image file on an already mounted filesystem.
+
+pub mod cache;
+pub mod ext2;
+pub mod fat;
+
+use crate::drivers::block::{get_block_device, BlockDevice, BlockError};
+use crate::environment;
+use crate::fs::cache::SectorCache;
+use crate::syscalls::fs::{
+	FileError, FilePerms, PosixFile, PosixFileSystem, SeekWhence, FILESYSTEM,
+};
+use alloc::boxed::Box;
+
+/// Number of sectors, which are cached per mounted filesystem
+const CACHE_SECTORS: usize = 1024;
+
+/// Size of the sectors of an image file
+const IMAGE_SECTOR_SIZE: usize = 512;
+
+/// Minimal interface to the sectors of a device, on which a filesystem is stored.
+pub trait SectorAccess: Send {
+	/// Returns the size of a sector in bytes.
+	fn sector_size(&self) -> usize;
+
+	/// Reads `buf.len() / sector_size()` sectors starting at sector `sector`.
+	fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), FileError>;
+
+	/// Writes `buf.len() / sector_size()` sectors starting at sector `sector`.
+	fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), FileError>;
+
+	/// Writes back all data, which is cached below this interface.
+	fn flush(&mut self) -> Result<(), FileError>;
+}
+
+/// Sector access to a disk image, which is stored as file on another filesystem.
+pub struct ImageFile {
+	file: Box<dyn PosixFile + Send>,
+}
+
+impl ImageFile {
+	/// Opens the image at given path (/MOUNTPOINT/internal-path).
+	pub fn open(path: &str, writable: bool) -> Result<Self, FileError> {
+		let perms = FilePerms {
+			write: writable,
+			// O_RDWR or O_RDONLY
+			raw: if writable { 0o2 } else { 0 },
+			..Default::default()
+		};
+		let file = FILESYSTEM.open_unassigned(path, perms)?;
+
+		Ok(Self { file })
+	}
+
+	fn seek(&mut self, sector: u64) -> Result<(), FileError> {
+		let offset = sector as usize * IMAGE_SECTOR_SIZE;
+		self.file.lseek(offset as isize, SeekWhence::Set)?;
+		Ok(())
+	}
+}
+
+impl SectorAccess for ImageFile {
+	fn sector_size(&self) -> usize {
+		IMAGE_SECTOR_SIZE
+	}
+
+	fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), FileError> {
+		self.seek(sector)?;
+
+		// the backend may return less data than requested
+		let mut done = 0;
+		while done < buf.len() {
+			let data = self.file.read((buf.len() - done) as u32)?;
+			if data.is_empty() {
+				warn!("Read behind the end of the disk image");
+				return Err(FileError::EIO());
+			}
+			buf[done..done + data.len()].copy_from_slice(&data);
+			done += data.len();
+		}
+
+		Ok(())
+	}
+
+	fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), FileError> {
+		self.seek(sector)?;
+
+		let mut done = 0;
+		while done < buf.len() {
+			let len = self.file.write(&buf[done..])? as usize;
+			if len == 0 {
+				return Err(FileError::EIO());
+			}
+			done += len;
+		}
+
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), FileError> {
+		Ok(())
+	}
+}
+
+impl Drop for ImageFile {
+	fn drop(&mut self) {
+		let _ = self.file.close();
+	}
+}
+
+/// Sector access to a block device.
+pub struct BlockDeviceSectors {
+	device: &'static dyn BlockDevice,
+}
+
+impl BlockDeviceSectors {
+	pub fn new(device: &'static dyn BlockDevice) -> Self {
+		Self { device }
+	}
+}
+
+fn block_error(error: BlockError) -> FileError {
+	match error {
+		BlockError::ReadOnly => FileError::EROFS(),
+		BlockError::InvalidArgument => FileError::EINVAL(),
+		BlockError::Io | BlockError::Unsupported => FileError::EIO(),
+	}
+}
+
+impl SectorAccess for BlockDeviceSectors {
+	fn sector_size(&self) -> usize {
+		self.device.block_size()
+	}
+
+	fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), FileError> {
+		self.device.read_blocks(sector, buf).map_err(block_error)
+	}
+
+	fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), FileError> {
+		self.device.write_blocks(sector, buf).map_err(block_error)
+	}
+
+	fn flush(&mut self) -> Result<(), FileError> {
+		self.device.flush().map_err(block_error)
+	}
+}
+
+/// Detects the filesystem on `device` and creates its backend.
+fn probe(
+	device: Box<dyn SectorAccess>,
+) -> Result<Box<dyn PosixFileSystem + Send + Sync>, FileError> {
+	let mut cache = SectorCache::new(device, CACHE_SECTORS);
+
+	if ext2::Ext2::probe(&mut cache)? {
+		Ok(Box::new(ext2::Ext2::new(cache)?))
+	} else if fat::Fat32::probe(&mut cache)? {
+		Ok(Box::new(fat::Fat32::new(cache)?))
+	} else {
+		warn!("Unable to detect a FAT32 or ext2 filesystem");
+		Err(FileError::EINVAL())
+	}
+}
+
+fn mount(mntpath: &str, device: Box<dyn SectorAccess>) -> Result<(), FileError> {
+	let fs = probe(device)?;
+	FILESYSTEM
+		.mount(mntpath, fs)
+		.map_err(|_| FileError::EEXIST())
+}
+
+/// Mounts the FAT32 or ext2 image `image` (/MOUNTPOINT/internal-path) at `mntpath`.
+pub fn mount_image(mntpath: &str, image: &str, writable: bool) -> Result<(), FileError> {
+	info!("Mounting image {} at {}", image, mntpath);
+	let file = ImageFile::open(image, writable)?;
+	mount(mntpath, Box::new(file))
+}
+
+/// Mounts the FAT32 or ext2 filesystem of the block device `index` at `mntpath`.
+pub fn mount_block_device(mntpath: &str, index: usize) -> Result<(), FileError> {
+	info!("Mounting block device {} at {}", index, mntpath);
+	let device = get_block_device(index).ok_or(FileError::ENOENT())?;
+	mount(mntpath, Box::new(BlockDeviceSectors::new(device)))
+}
+
+/// Mounts the filesystems given through the -mount MOUNTPOINT:SOURCE command-line parameters.
+/// SOURCE is either `blkN` for the block device N or the path (/MOUNTPOINT/internal-path)
+/// of an image file. An image is mounted read-only, if its path is followed by `:ro`.
+pub fn init() {
+	for (mntpath, source) in environment::get_command_line_mounts() {
+		let result = match source.strip_prefix("blk").map(str::parse) {
+			Some(Ok(index)) => mount_block_device(mntpath, index),
+			_ => match source.strip_suffix(":ro") {
+				Some(image) => mount_image(mntpath, image, false),
+				None => mount_image(mntpath, source, true),
+			},
+		};
+
+		if let Err(e) = result {
+			error!("Unable to mount {} at {}: {:?}", source, mntpath, e);
+		}
+	}
+}
+
+/// Splits a path into its components and ignores empty components and `.`.
+pub(crate) fn components(path: &str) -> impl Iterator<Item = &str> {
+	path.split('/')
+		.filter(|name| !name.is_empty() && *name != ".")
+}
+
+/// Splits a path into the path of the parent directory and the file name.
+pub(crate) fn split_parent(path: &str) -> Result<(&str, &str), FileError> {
+	let path = path.trim_end_matches('/');
+	let (parent, name) = match path.rfind('/') {
+		Some(index) => (&path[..index], &path[index + 1..]),
+		None => ("", path),
+	};
+
+	if name.is_empty() || name == "." || name == ".." {
+		Err(FileError::EINVAL())
+	} else {
+		Ok((parent, name))
+	}
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn path_splitting() {
+	use alloc::vec::Vec;
+
+	assert_eq!(
+		components("/a//b/./c/").collect::<Vec<_>>(),
+		["a", "b", "c"]
+	);
+	assert_eq!(split_parent("a/b/c").unwrap(), ("a/b", "c"));
+	assert_eq!(split_parent("c/").unwrap(), ("", "c"));
+	assert!(split_parent("a/..").is_err());
+}
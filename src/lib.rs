@@ -89,10 +89,14 @@ mod collections;
 mod config;
 mod console;
 mod drivers;
+mod entropy;
 pub mod environment;
 mod errno;
+mod fs;
 mod kernel_message_buffer;
 mod mm;
+#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+mod net;
 #[cfg(target_os = "hermit")]
 mod runtime_glue;
 mod scheduler;
@@ -286,8 +290,15 @@ extern "C" fn initd(_arg: usize) {
 	#[cfg(target_arch = "x86_64")]
 	x86_64::kernel::pci::init_drivers();
 
+	// Mount the disk filesystems, which are stored on the block devices or on the virtio-fs shares.
+	fs::init();
+
 	syscalls::init();
 
+	// Start the in-kernel TCP/IP stack on top of the network driver.
+	#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+	net::init();
+
 	// Get the application arguments and environment variables.
 	#[cfg(not(test))]
 	let (argc, argv, environ) = syscalls::get_application_parameters();
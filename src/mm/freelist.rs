@@ -154,6 +154,13 @@ impl FreeList {
 		self.list.push_back(new_element);
 	}
 
+	/// Moves all regions of `other` into this list, where adjacent regions are reunited.
+	pub fn merge(&mut self, other: &mut FreeList) {
+		while let Some(node) = other.list.pop_front() {
+			self.deallocate(node.start, node.end - node.start);
+		}
+	}
+
 	pub fn print_information(&self, header: &str) {
 		infoheader!(header);
 
@@ -234,3 +241,26 @@ fn deallocate() {
 		cursor.move_next();
 	}
 }
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn merge() {
+	let mut freelist = FreeList::new();
+	freelist
+		.list
+		.push_back(FreeListEntry::new(0x10000, 0x20000));
+	freelist
+		.list
+		.push_back(FreeListEntry::new(0x40000, 0x50000));
+
+	let mut other = FreeList::new();
+	other.list.push_back(FreeListEntry::new(0x20000, 0x40000));
+	other.list.push_back(FreeListEntry::new(0x60000, 0x70000));
+	freelist.merge(&mut other);
+
+	assert!(other.list.is_empty());
+	let mut regions = freelist.list.iter().map(|node| (node.start, node.end));
+	assert_eq!(regions.next(), Some((0x10000, 0x50000)));
+	assert_eq!(regions.next(), Some((0x60000, 0x70000)));
+	assert_eq!(regions.next(), None);
+}
@@ -290,7 +290,6 @@ pub fn map(
 	virtual_address
 }
 
-#[allow(dead_code)]
 /// unmaps virtual address, without 'freeing' physical memory it is mapped to!
 pub fn unmap(virtual_address: VirtAddr, sz: usize) {
 	let size = align_up!(sz, BasePageSize::SIZE);
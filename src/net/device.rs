@@ -0,0 +1,99 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Glue between smoltcp and the raw frame interface of the network driver.
+
+use crate::syscalls::{
+	__sys_get_mtu, __sys_get_tx_buffer, __sys_release_rx_buffer, __sys_release_tx_buffer,
+	__sys_send_tx_buffer, __sys_take_rx_buffer,
+};
+use core::slice;
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+
+/// Size of the Ethernet header, which is not included in the MTU of the driver
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Network device, which forwards all frames to the driver of the NIC
+pub struct HermitNet {
+	mtu: u16,
+}
+
+impl HermitNet {
+	pub fn new() -> Self {
+		Self {
+			mtu: __sys_get_mtu().unwrap_or(1500),
+		}
+	}
+}
+
+impl<'a> Device<'a> for HermitNet {
+	type RxToken = RxToken;
+	type TxToken = TxToken;
+
+	fn capabilities(&self) -> DeviceCapabilities {
+		let mut capabilities = DeviceCapabilities::default();
+		capabilities.max_transmission_unit = usize::from(self.mtu) + ETHERNET_HEADER_SIZE;
+		capabilities
+	}
+
+	fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+		// The driver has already refilled its ring => the frame can be processed in place.
+		let (token, buffer, _) = __sys_take_rx_buffer().ok()?;
+
+		Some((RxToken { token, buffer }, TxToken))
+	}
+
+	fn transmit(&'a mut self) -> Option<Self::TxToken> {
+		Some(TxToken)
+	}
+}
+
+/// Received frame, which is returned to the driver, when the token is dropped
+pub struct RxToken {
+	token: usize,
+	buffer: &'static mut [u8],
+}
+
+impl phy::RxToken for RxToken {
+	fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+	where
+		F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+	{
+		f(&mut self.buffer[..])
+	}
+}
+
+impl Drop for RxToken {
+	fn drop(&mut self) {
+		if __sys_release_rx_buffer(self.token).is_err() {
+			warn!("Unable to release the received frame {:#x}", self.token);
+		}
+	}
+}
+
+pub struct TxToken;
+
+impl phy::TxToken for TxToken {
+	fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+	where
+		F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+	{
+		let (buffer, handle) = __sys_get_tx_buffer(len).map_err(|_| smoltcp::Error::Exhausted)?;
+		let result = match f(unsafe { slice::from_raw_parts_mut(buffer, len) }) {
+			Ok(result) => result,
+			Err(err) => {
+				// the frame isn't sent => the buffer has to be returned to the driver
+				let _ = __sys_release_tx_buffer(handle);
+				return Err(err);
+			}
+		};
+
+		__sys_send_tx_buffer(handle, len).map_err(|_| smoltcp::Error::Exhausted)?;
+		Ok(result)
+	}
+}
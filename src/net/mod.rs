@@ -0,0 +1,354 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! In-kernel TCP/IP stack based on smoltcp.
+//!
+//! If the kernel is built with the feature `tcp`, it takes over the network interface
+//! and provides a BSD socket interface to the applications. A kernel thread polls the
+//! interface whenever the NIC receives a frame or a timer of the stack (e.g. a
+//! retransmission) expires.
+//! Applications must not use the raw frame interface (sys_get_tx_buffer, ...) in this case.
+
+mod device;
+mod socket;
+
+pub use self::socket::Socket;
+
+use self::device::HermitNet;
+use crate::arch;
+use crate::arch::percore::*;
+use crate::collections::irqsave;
+use crate::config::USER_STACK_SIZE;
+use crate::drivers::net::{netwait_and_wakeup, netwakeup};
+use crate::environment;
+use crate::errno::*;
+use crate::scheduler::task::NORMAL_PRIO;
+use crate::scheduler::PerCoreScheduler;
+use crate::synch::spinlock::{Spinlock, SpinlockIrqSave};
+use crate::synch::waitqueue::WaitQueue;
+use crate::syscalls::{__sys_get_mac_address, POLLNVAL};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address, Ipv6Address};
+
+/// Default IPv4 configuration, which matches the user-mode network of QEMU
+const DEFAULT_IP: [u8; 4] = [10, 0, 2, 15];
+const DEFAULT_GATEWAY: [u8; 4] = [10, 0, 2, 2];
+const DEFAULT_MASK: [u8; 4] = [255, 255, 255, 0];
+
+/// First port number, which is used for ephemeral ports (see RFC 6335)
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// File descriptors of sockets are marked by this bit to distinguish them from files.
+pub const SOCKET_FD_BIT: i32 = 1 << 30;
+
+/// The stack is only used by tasks. Hence, the interrupts stay enabled, while it processes frames.
+static NETWORK: Spinlock<Option<NetworkState>> = Spinlock::new(None);
+/// Tasks, which wait for an event on one of the sockets
+pub static WAITERS: WaitQueue = WaitQueue::new();
+/// Events of the open sockets and their generation, which is incremented, whenever
+/// the events of the socket change. poll and epoll read them without locking the stack.
+static SOCKET_EVENTS: SpinlockIrqSave<BTreeMap<i32, (i16, u64)>> =
+	SpinlockIrqSave::new(BTreeMap::new());
+
+pub struct NetworkState {
+	iface: EthernetInterface<'static, 'static, 'static, HermitNet>,
+	sockets: SocketSet<'static, 'static, 'static>,
+	/// Open sockets, indexed by their file descriptor
+	descriptors: BTreeMap<i32, Socket>,
+	/// TCP sockets, which have been closed by the application, but still exchange
+	/// the remaining data and the FIN handshake with the remote side
+	closing: Vec<SocketHandle>,
+	next_fd: i32,
+	next_port: u16,
+}
+
+impl NetworkState {
+	/// Process all received frames and expired timers of the stack.
+	fn poll(&mut self) {
+		let changed = match self.iface.poll(&mut self.sockets, now()) {
+			Ok(changed) => changed,
+			Err(e) => {
+				// A single malformed or unsupported frame is not a reason to give up.
+				debug!("Failed to process a network frame: {}", e);
+				true
+			}
+		};
+
+		// Release the sockets, which have finished their closing handshake.
+		let sockets = &mut self.sockets;
+		self.closing.retain(|handle| {
+			let is_open = sockets.get::<TcpSocket>(*handle).is_open();
+			if !is_open {
+				sockets.remove(*handle);
+			}
+			is_open
+		});
+
+		// The waiting tasks check their socket again and block once more
+		// if their event has not occurred.
+		if self.update_events() || changed {
+			WAITERS.wakeup_all();
+		}
+	}
+
+	/// Updates the events of all sockets. Returns true, if the events of a socket have changed.
+	fn update_events(&mut self) -> bool {
+		let fds: Vec<i32> = self.descriptors.keys().copied().collect();
+		let current: Vec<(i32, i16)> = fds
+			.into_iter()
+			.map(|fd| (fd, self.get_socket_events(fd).unwrap_or(POLLNVAL)))
+			.collect();
+
+		let mut cache = SOCKET_EVENTS.lock();
+		let count = cache.len();
+		cache.retain(|fd, _| self.descriptors.contains_key(fd));
+		let mut changed = cache.len() != count;
+		for (fd, events) in current {
+			match cache.get_mut(&fd) {
+				Some(entry) if entry.0 == events => {}
+				Some(entry) => {
+					*entry = (events, entry.1.wrapping_add(1));
+					changed = true;
+				}
+				None => {
+					cache.insert(fd, (events, 0));
+					changed = true;
+				}
+			}
+		}
+
+		changed
+	}
+
+	fn allocate_fd(&mut self) -> i32 {
+		let fd = self.next_fd;
+		self.next_fd += 1;
+		fd | SOCKET_FD_BIT
+	}
+
+	fn allocate_ephemeral_port(&mut self) -> u16 {
+		let port = self.next_port;
+		self.next_port = if port == u16::MAX {
+			EPHEMERAL_PORT_START
+		} else {
+			port + 1
+		};
+		port
+	}
+}
+
+#[inline]
+fn now() -> Instant {
+	Instant::from_millis((arch::processor::get_timer_ticks() / 1000) as i64)
+}
+
+/// Returns true if the file descriptor refers to a socket of the network stack.
+#[inline]
+pub fn is_socket(fd: i32) -> bool {
+	fd >= 0 && fd & SOCKET_FD_BIT != 0
+}
+
+/// Executes `f` on the network stack, which must not block.
+pub fn with_network<T, F>(f: F) -> Result<T, i32>
+where
+	F: FnOnce(&mut NetworkState) -> Result<T, i32>,
+{
+	let result = {
+		let mut guard = NETWORK.lock();
+		let state = guard.as_mut().ok_or(-ENETDOWN)?;
+		let result = f(state);
+		if state.update_events() {
+			WAITERS.wakeup_all();
+		}
+		result
+	};
+
+	// The operation may have queued packets or changed a timer of the stack.
+	netwakeup();
+	result
+}
+
+/// Executes `f` on the network stack until it returns a result.
+///
+/// In between, the current task is blocked until the stack has processed new frames
+/// or the timeout (in milliseconds) has elapsed. A timeout of zero never blocks.
+pub fn block_on_network<T, F>(timeout: Option<u64>, mut f: F) -> Result<T, i32>
+where
+	F: FnMut(&mut NetworkState) -> Option<Result<T, i32>>,
+{
+	let wakeup_time = timeout.map(|ms| arch::processor::get_timer_ticks() + ms * 1000);
+	let core_scheduler = core_scheduler();
+	let task_id = core_scheduler.get_current_task_id();
+
+	loop {
+		let mut guard = NETWORK.lock();
+		let state = guard.as_mut().ok_or(-ENETDOWN)?;
+
+		// We may have been woken up by the timer. Then, we are still registered as waiter.
+		WAITERS.unregister(task_id);
+		state.poll();
+
+		if let Some(result) = f(state) {
+			if state.update_events() {
+				WAITERS.wakeup_all();
+			}
+			drop(guard);
+			netwakeup();
+			return result;
+		}
+
+		if let Some(wt) = wakeup_time {
+			if arch::processor::get_timer_ticks() >= wt {
+				return Err(-EAGAIN);
+			}
+		}
+
+		// Block the current task and add it to the waiters.
+		irqsave(|| {
+			core_scheduler.block_current_task(wakeup_time);
+			WAITERS.register(core_scheduler.get_current_task_handle());
+		});
+
+		// release lock
+		drop(guard);
+
+		// Let the network thread send pending frames, while we wait.
+		netwakeup();
+
+		// Switch to the next task.
+		core_scheduler.reschedule();
+	}
+}
+
+/// Returns the events (POLLIN, POLLOUT, ...), which are currently signalled by the socket.
+pub fn get_socket_events(fd: i32) -> Result<i16, i32> {
+	NETWORK
+		.lock()
+		.as_mut()
+		.ok_or(-ENETDOWN)?
+		.get_socket_events(fd)
+}
+
+/// Returns the events of the socket and their generation, which have been observed after the
+/// last operation on the network stack. In contrast to `get_socket_events`, the stack isn't locked.
+pub fn get_cached_socket_events(fd: i32) -> Option<(i16, u64)> {
+	SOCKET_EVENTS.lock().get(&fd).copied()
+}
+
+/// Determine the IPv4 configuration from uhyve or the command line.
+fn get_ipv4_config() -> ([u8; 4], [u8; 4], [u8; 4]) {
+	#[cfg(target_arch = "x86_64")]
+	{
+		use crate::arch::x86_64::kernel::{uhyve_get_gateway, uhyve_get_ip, uhyve_get_mask};
+
+		if environment::is_uhyve() {
+			return (uhyve_get_ip(), uhyve_get_gateway(), uhyve_get_mask());
+		}
+	}
+
+	(
+		environment::get_command_line_ip().unwrap_or(DEFAULT_IP),
+		environment::get_command_line_gateway().unwrap_or(DEFAULT_GATEWAY),
+		environment::get_command_line_mask().unwrap_or(DEFAULT_MASK),
+	)
+}
+
+/// Derive the IPv6 link-local address from the MAC address (modified EUI-64, see RFC 4291).
+fn get_ipv6_link_local_address(mac: &[u8; 6]) -> Ipv6Address {
+	Ipv6Address::new(
+		0xfe80,
+		0,
+		0,
+		0,
+		u16::from_be_bytes([mac[0] ^ 0x02, mac[1]]),
+		u16::from_be_bytes([mac[2], 0xff]),
+		u16::from_be_bytes([0xfe, mac[3]]),
+		u16::from_be_bytes([mac[4], mac[5]]),
+	)
+}
+
+extern "C" fn network_thread(_arg: usize) {
+	debug!("Enter network thread");
+
+	loop {
+		let delay = {
+			let mut guard = NETWORK.lock();
+			let state = guard.as_mut().unwrap();
+
+			state.poll();
+			state
+				.iface
+				.poll_delay(&state.sockets, now())
+				.map(|delay| delay.total_millis())
+		};
+
+		// Sleep until the NIC receives a frame or the next timer of the stack expires.
+		netwait_and_wakeup(&[], delay);
+	}
+}
+
+pub fn init() {
+	let mac = match __sys_get_mac_address() {
+		Ok(mac) => mac,
+		Err(_) => {
+			info!("No network interface available, TCP/IP stack is disabled");
+			return;
+		}
+	};
+
+	let (ip, gateway, mask) = get_ipv4_config();
+	let prefix_len = mask.iter().map(|octet| octet.count_ones()).sum::<u32>() as u8;
+	let ipv4_address = Ipv4Address(ip);
+	let ipv6_address = get_ipv6_link_local_address(&mac);
+
+	let mut routes = Routes::new(BTreeMap::new());
+	routes.add_default_ipv4_route(Ipv4Address(gateway)).unwrap();
+
+	let iface = EthernetInterfaceBuilder::new(HermitNet::new())
+		.ethernet_addr(EthernetAddress(mac))
+		.neighbor_cache(NeighborCache::new(BTreeMap::new()))
+		.ip_addrs(vec![
+			IpCidr::new(ipv4_address.into(), prefix_len),
+			IpCidr::new(ipv6_address.into(), 64),
+		])
+		.routes(routes)
+		.finalize();
+
+	info!(
+		"TCP/IP stack uses MAC address {}, IPv4 address {}/{} (gateway {}) and IPv6 address {}/64",
+		EthernetAddress(mac),
+		ipv4_address,
+		prefix_len,
+		Ipv4Address(gateway),
+		ipv6_address
+	);
+
+	*NETWORK.lock() = Some(NetworkState {
+		iface,
+		sockets: SocketSet::new(Vec::new()),
+		descriptors: BTreeMap::new(),
+		closing: Vec::new(),
+		next_fd: 0,
+		next_port: EPHEMERAL_PORT_START,
+	});
+
+	PerCoreScheduler::spawn(network_thread, 0, NORMAL_PRIO, 0, USER_STACK_SIZE);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn derive_ipv6_link_local_address() {
+	let address = get_ipv6_link_local_address(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+	assert_eq!(
+		address,
+		Ipv6Address::new(0xfe80, 0, 0, 0, 0x5054, 0x00ff, 0xfe12, 0x3456)
+	);
+}
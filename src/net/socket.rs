@@ -0,0 +1,579 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! BSD-like sockets on top of the smoltcp sockets.
+//!
+//! Operations, which may have to wait for the network, return `None` if they cannot
+//! complete yet. They are supposed to be called through `block_on_network`.
+
+use super::NetworkState;
+use crate::errno::*;
+use crate::syscalls::{POLLHUP, POLLIN, POLLOUT, POLLRDHUP};
+use alloc::vec::Vec;
+use smoltcp::socket::{
+	SocketHandle, TcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket,
+	UdpSocketBuffer,
+};
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+/// Size of the receive and transmit buffer of a TCP socket
+const TCP_BUFFER_SIZE: usize = 65_536;
+/// Size of the receive and transmit buffer of a UDP socket
+const UDP_BUFFER_SIZE: usize = 65_536;
+/// Maximum number of datagrams in the receive and transmit buffer of a UDP socket
+const UDP_PACKET_COUNT: usize = 32;
+/// Upper limit of pending connections of a listening socket
+const MAX_BACKLOG: usize = 32;
+/// Interval of keep-alive packets, if SO_KEEPALIVE is enabled
+const KEEP_ALIVE_INTERVAL: u64 = 75;
+
+enum SocketKind {
+	/// TCP socket, which is not listening
+	Tcp {
+		handle: SocketHandle,
+		local_endpoint: Option<IpEndpoint>,
+	},
+	/// Listening TCP socket with a backlog of smoltcp sockets waiting for connections
+	TcpListener {
+		backlog: Vec<SocketHandle>,
+		local_endpoint: IpEndpoint,
+	},
+	Udp {
+		handle: SocketHandle,
+		remote_endpoint: Option<IpEndpoint>,
+	},
+}
+
+pub struct Socket {
+	kind: SocketKind,
+	/// Timeout (in milliseconds) for receiving data
+	pub recv_timeout: Option<u64>,
+	/// Timeout (in milliseconds) for sending data
+	pub send_timeout: Option<u64>,
+	/// Operations return -EAGAIN instead of blocking
+	pub nonblocking: bool,
+	/// The application does not want to receive any more data
+	read_shutdown: bool,
+}
+
+impl Socket {
+	fn new(kind: SocketKind) -> Self {
+		Self {
+			kind,
+			recv_timeout: None,
+			send_timeout: None,
+			nonblocking: false,
+			read_shutdown: false,
+		}
+	}
+}
+
+fn new_tcp_socket() -> TcpSocket<'static> {
+	TcpSocket::new(
+		TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+		TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+	)
+}
+
+fn new_udp_socket() -> UdpSocket<'static, 'static> {
+	UdpSocket::new(
+		UdpSocketBuffer::new(
+			vec![UdpPacketMetadata::EMPTY; UDP_PACKET_COUNT],
+			vec![0; UDP_BUFFER_SIZE],
+		),
+		UdpSocketBuffer::new(
+			vec![UdpPacketMetadata::EMPTY; UDP_PACKET_COUNT],
+			vec![0; UDP_BUFFER_SIZE],
+		),
+	)
+}
+
+/// Returns true if a socket of the backlog has established a connection, which can be accepted.
+fn is_established(socket: &TcpSocket) -> bool {
+	socket.is_active() && socket.state() != TcpState::SynReceived
+}
+
+impl NetworkState {
+	pub fn get_socket(&mut self, fd: i32) -> Result<&mut Socket, i32> {
+		self.descriptors.get_mut(&fd).ok_or(-EBADF)
+	}
+
+	/// Creates a new TCP (`stream == true`) or UDP socket and returns its file descriptor.
+	pub fn socket(&mut self, stream: bool) -> i32 {
+		let kind = if stream {
+			SocketKind::Tcp {
+				handle: self.sockets.add(new_tcp_socket()),
+				local_endpoint: None,
+			}
+		} else {
+			SocketKind::Udp {
+				handle: self.sockets.add(new_udp_socket()),
+				remote_endpoint: None,
+			}
+		};
+
+		let fd = self.allocate_fd();
+		self.descriptors.insert(fd, Socket::new(kind));
+		fd
+	}
+
+	fn is_port_in_use(&mut self, port: u16) -> bool {
+		let sockets = &mut self.sockets;
+		self.descriptors.values().any(|socket| match socket.kind {
+			SocketKind::Tcp {
+				local_endpoint: Some(endpoint),
+				..
+			}
+			| SocketKind::TcpListener {
+				local_endpoint: endpoint,
+				..
+			} => endpoint.port == port,
+			SocketKind::Udp { handle, .. } => {
+				sockets.get::<UdpSocket>(handle).endpoint().port == port
+			}
+			_ => false,
+		})
+	}
+
+	fn get_local_endpoint(&mut self, endpoint: Option<IpEndpoint>) -> IpEndpoint {
+		match endpoint {
+			Some(endpoint) if endpoint.port != 0 => endpoint,
+			Some(endpoint) => IpEndpoint::new(endpoint.addr, self.allocate_ephemeral_port()),
+			None => IpEndpoint::new(IpAddress::Unspecified, self.allocate_ephemeral_port()),
+		}
+	}
+
+	pub fn bind(&mut self, fd: i32, endpoint: IpEndpoint) -> Result<(), i32> {
+		if endpoint.port != 0 && self.is_port_in_use(endpoint.port) {
+			return Err(-EADDRINUSE);
+		}
+
+		let endpoint = self.get_local_endpoint(Some(endpoint));
+		let sockets = &mut self.sockets;
+		match self.descriptors.get_mut(&fd).ok_or(-EBADF)?.kind {
+			SocketKind::Tcp {
+				ref mut local_endpoint,
+				..
+			} => {
+				if local_endpoint.is_some() {
+					return Err(-EINVAL);
+				}
+				*local_endpoint = Some(endpoint);
+				Ok(())
+			}
+			SocketKind::TcpListener { .. } => Err(-EINVAL),
+			SocketKind::Udp { handle, .. } => sockets
+				.get::<UdpSocket>(handle)
+				.bind(endpoint)
+				.map_err(|_| -EINVAL),
+		}
+	}
+
+	pub fn listen(&mut self, fd: i32, backlog: usize) -> Result<(), i32> {
+		let (handle, local_endpoint) = match self.get_socket(fd)?.kind {
+			SocketKind::Tcp {
+				handle,
+				local_endpoint,
+			} => (handle, local_endpoint),
+			SocketKind::TcpListener { .. } => return Ok(()),
+			SocketKind::Udp { .. } => return Err(-EOPNOTSUPP),
+		};
+		let local_endpoint = self.get_local_endpoint(local_endpoint);
+
+		// Every smoltcp socket accepts a single connection.
+		// Thus, create a socket for each pending connection in the backlog.
+		let mut sockets = vec![handle];
+		for _ in 1..backlog.max(1).min(MAX_BACKLOG) {
+			sockets.push(self.sockets.add(new_tcp_socket()));
+		}
+
+		for handle in sockets.iter() {
+			self.sockets
+				.get::<TcpSocket>(*handle)
+				.listen(local_endpoint)
+				.map_err(|_| -EINVAL)?;
+		}
+
+		self.get_socket(fd)?.kind = SocketKind::TcpListener {
+			backlog: sockets,
+			local_endpoint,
+		};
+		Ok(())
+	}
+
+	/// Returns the file descriptor and the remote endpoint of an established connection.
+	pub fn accept(&mut self, fd: i32) -> Option<Result<(i32, IpEndpoint), i32>> {
+		let (position, handle, local_endpoint) = match self.descriptors.get(&fd) {
+			Some(Socket {
+				kind: SocketKind::TcpListener {
+					backlog,
+					local_endpoint,
+				},
+				..
+			}) => {
+				let sockets = &mut self.sockets;
+				let position = backlog
+					.iter()
+					.position(|handle| is_established(&sockets.get::<TcpSocket>(*handle)))?;
+				(position, backlog[position], *local_endpoint)
+			}
+			Some(_) => return Some(Err(-EINVAL)),
+			None => return Some(Err(-EBADF)),
+		};
+
+		// Replace the accepted socket in the backlog by a new listening one.
+		let mut socket = new_tcp_socket();
+		if socket.listen(local_endpoint).is_err() {
+			return Some(Err(-ENOBUFS));
+		}
+		let new_handle = self.sockets.add(socket);
+		if let Some(Socket {
+			kind: SocketKind::TcpListener { backlog, .. },
+			..
+		}) = self.descriptors.get_mut(&fd)
+		{
+			backlog[position] = new_handle;
+		}
+
+		let remote_endpoint = self.sockets.get::<TcpSocket>(handle).remote_endpoint();
+		let new_fd = self.allocate_fd();
+		self.descriptors.insert(
+			new_fd,
+			Socket::new(SocketKind::Tcp {
+				handle,
+				local_endpoint: Some(local_endpoint),
+			}),
+		);
+
+		Some(Ok((new_fd, remote_endpoint)))
+	}
+
+	/// Initiates a connection to the remote endpoint.
+	/// Use `is_connected` to wait for the establishment of a TCP connection.
+	pub fn connect(&mut self, fd: i32, remote_endpoint: IpEndpoint) -> Result<(), i32> {
+		let kind = &self.get_socket(fd)?.kind;
+		match *kind {
+			SocketKind::Tcp {
+				handle,
+				local_endpoint,
+			} => {
+				let local_endpoint = self.get_local_endpoint(local_endpoint);
+				self.sockets
+					.get::<TcpSocket>(handle)
+					.connect(remote_endpoint, local_endpoint)
+					.map_err(|e| match e {
+						smoltcp::Error::Illegal => -EISCONN,
+						_ => -EINVAL,
+					})?;
+
+				if let SocketKind::Tcp {
+					local_endpoint: ref mut endpoint,
+					..
+				} = self.get_socket(fd)?.kind
+				{
+					*endpoint = Some(local_endpoint);
+				}
+				Ok(())
+			}
+			SocketKind::TcpListener { .. } => Err(-EISCONN),
+			SocketKind::Udp { handle, .. } => {
+				if !self.sockets.get::<UdpSocket>(handle).is_open() {
+					let local_endpoint = self.get_local_endpoint(None);
+					self.sockets
+						.get::<UdpSocket>(handle)
+						.bind(local_endpoint)
+						.map_err(|_| -EINVAL)?;
+				}
+
+				if let SocketKind::Udp {
+					remote_endpoint: ref mut endpoint,
+					..
+				} = self.get_socket(fd)?.kind
+				{
+					*endpoint = Some(remote_endpoint);
+				}
+				Ok(())
+			}
+		}
+	}
+
+	pub fn is_connected(&mut self, fd: i32) -> Option<Result<(), i32>> {
+		let handle = match self.descriptors.get(&fd) {
+			Some(Socket {
+				kind: SocketKind::Tcp { handle, .. },
+				..
+			}) => *handle,
+			Some(_) => return Some(Ok(())),
+			None => return Some(Err(-EBADF)),
+		};
+
+		match self.sockets.get::<TcpSocket>(handle).state() {
+			TcpState::SynSent | TcpState::SynReceived => None,
+			TcpState::Closed => Some(Err(-ECONNREFUSED)),
+			_ => Some(Ok(())),
+		}
+	}
+
+	/// Sends data to the connected peer or the given remote endpoint (only UDP).
+	pub fn send(
+		&mut self,
+		fd: i32,
+		buffer: &[u8],
+		remote_endpoint: Option<IpEndpoint>,
+	) -> Option<Result<usize, i32>> {
+		let kind = match self.descriptors.get(&fd) {
+			Some(socket) => &socket.kind,
+			None => return Some(Err(-EBADF)),
+		};
+
+		match *kind {
+			SocketKind::Tcp { handle, .. } => {
+				let mut socket = self.sockets.get::<TcpSocket>(handle);
+				if socket.can_send() {
+					match socket.send_slice(buffer) {
+						Ok(len) if len > 0 || buffer.is_empty() => return Some(Ok(len)),
+						Ok(_) => {}
+						Err(_) => return Some(Err(-EIO)),
+					}
+				}
+
+				match socket.state() {
+					TcpState::SynSent | TcpState::SynReceived => None,
+					_ if socket.may_send() => None,
+					TcpState::Closed | TcpState::Listen => Some(Err(-ENOTCONN)),
+					_ => Some(Err(-EPIPE)),
+				}
+			}
+			SocketKind::TcpListener { .. } => Some(Err(-ENOTCONN)),
+			SocketKind::Udp {
+				handle,
+				remote_endpoint: connected_endpoint,
+			} => {
+				let remote_endpoint = match remote_endpoint.or(connected_endpoint) {
+					Some(endpoint) => endpoint,
+					None => return Some(Err(-EDESTADDRREQ)),
+				};
+
+				if !self.sockets.get::<UdpSocket>(handle).is_open() {
+					let local_endpoint = self.get_local_endpoint(None);
+					if self
+						.sockets
+						.get::<UdpSocket>(handle)
+						.bind(local_endpoint)
+						.is_err()
+					{
+						return Some(Err(-EINVAL));
+					}
+				}
+
+				match self
+					.sockets
+					.get::<UdpSocket>(handle)
+					.send_slice(buffer, remote_endpoint)
+				{
+					Ok(()) => Some(Ok(buffer.len())),
+					Err(smoltcp::Error::Exhausted) => None,
+					Err(smoltcp::Error::Truncated) => Some(Err(-EMSGSIZE)),
+					Err(_) => Some(Err(-EINVAL)),
+				}
+			}
+		}
+	}
+
+	/// Receives data and returns the number of bytes along with the remote endpoint.
+	/// A length of zero signals that the peer has closed the connection.
+	pub fn recv(
+		&mut self,
+		fd: i32,
+		buffer: &mut [u8],
+		peek: bool,
+	) -> Option<Result<(usize, IpEndpoint), i32>> {
+		let (kind, read_shutdown) = match self.descriptors.get(&fd) {
+			Some(socket) => (&socket.kind, socket.read_shutdown),
+			None => return Some(Err(-EBADF)),
+		};
+
+		match *kind {
+			SocketKind::Tcp { handle, .. } => {
+				let mut socket = self.sockets.get::<TcpSocket>(handle);
+				let remote_endpoint = socket.remote_endpoint();
+
+				if read_shutdown {
+					Some(Ok((0, remote_endpoint)))
+				} else if socket.can_recv() {
+					let result = if peek {
+						socket.peek_slice(buffer)
+					} else {
+						socket.recv_slice(buffer)
+					};
+					Some(result.map(|len| (len, remote_endpoint)).map_err(|_| -EIO))
+				} else if socket.may_recv() {
+					None
+				} else {
+					match socket.state() {
+						TcpState::Closed | TcpState::Listen => Some(Err(-ENOTCONN)),
+						_ => Some(Ok((0, remote_endpoint))),
+					}
+				}
+			}
+			SocketKind::TcpListener { .. } => Some(Err(-ENOTCONN)),
+			SocketKind::Udp { handle, .. } => {
+				let mut socket = self.sockets.get::<UdpSocket>(handle);
+
+				if read_shutdown {
+					Some(Ok((0, IpEndpoint::default())))
+				} else if !socket.can_recv() {
+					None
+				} else if peek {
+					Some(
+						socket
+							.peek()
+							.map(|(data, endpoint)| {
+								let len = data.len().min(buffer.len());
+								buffer[..len].copy_from_slice(&data[..len]);
+								(len, *endpoint)
+							})
+							.map_err(|_| -EIO),
+					)
+				} else {
+					Some(socket.recv_slice(buffer).map_err(|_| -EIO))
+				}
+			}
+		}
+	}
+
+	pub fn shutdown(&mut self, fd: i32, read: bool, write: bool) -> Result<(), i32> {
+		let socket = self.descriptors.get_mut(&fd).ok_or(-EBADF)?;
+		if read {
+			socket.read_shutdown = true;
+		}
+
+		if write {
+			match socket.kind {
+				SocketKind::Tcp { handle, .. } => {
+					// Send a FIN to the peer, but keep receiving data.
+					self.sockets.get::<TcpSocket>(handle).close();
+				}
+				SocketKind::TcpListener { .. } => return Err(-ENOTCONN),
+				SocketKind::Udp { .. } => {}
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn close(&mut self, fd: i32) -> Result<(), i32> {
+		let socket = self.descriptors.remove(&fd).ok_or(-EBADF)?;
+
+		match socket.kind {
+			SocketKind::Tcp { handle, .. } => {
+				// Keep the socket until the remaining data is sent and the connection is closed.
+				self.sockets.get::<TcpSocket>(handle).close();
+				self.closing.push(handle);
+			}
+			SocketKind::TcpListener { backlog, .. } => {
+				for handle in backlog {
+					self.sockets.get::<TcpSocket>(handle).abort();
+					self.sockets.remove(handle);
+				}
+			}
+			SocketKind::Udp { handle, .. } => {
+				self.sockets.remove(handle);
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn set_keep_alive(&mut self, fd: i32, enable: bool) -> Result<(), i32> {
+		let interval = if enable {
+			Some(Duration::from_secs(KEEP_ALIVE_INTERVAL))
+		} else {
+			None
+		};
+
+		match self.descriptors.get(&fd).ok_or(-EBADF)?.kind {
+			SocketKind::Tcp { handle, .. } => {
+				self.sockets
+					.get::<TcpSocket>(handle)
+					.set_keep_alive(interval);
+				Ok(())
+			}
+			SocketKind::TcpListener { ref backlog, .. } => {
+				for handle in backlog.iter() {
+					self.sockets
+						.get::<TcpSocket>(*handle)
+						.set_keep_alive(interval);
+				}
+				Ok(())
+			}
+			SocketKind::Udp { .. } => Err(-ENOPROTOOPT),
+		}
+	}
+
+	/// Returns the events (POLLIN, POLLOUT, ...), which are currently signalled by the socket.
+	pub fn get_socket_events(&mut self, fd: i32) -> Result<i16, i32> {
+		let socket = self.descriptors.get(&fd).ok_or(-EBADF)?;
+		let sockets = &mut self.sockets;
+
+		let events = match socket.kind {
+			SocketKind::Tcp { handle, .. } => {
+				let tcp = sockets.get::<TcpSocket>(handle);
+				match tcp.state() {
+					TcpState::SynSent | TcpState::SynReceived => 0,
+					// The socket is not connected (anymore).
+					TcpState::Closed | TcpState::Listen => POLLOUT | POLLHUP,
+					_ => {
+						let mut events = 0;
+						if tcp.can_recv() || !tcp.may_recv() || socket.read_shutdown {
+							events |= POLLIN;
+						}
+						if !tcp.may_recv() {
+							events |= POLLRDHUP;
+						}
+						if tcp.can_send() || !tcp.may_send() {
+							events |= POLLOUT;
+						}
+						events
+					}
+				}
+			}
+			SocketKind::TcpListener { ref backlog, .. } => {
+				if backlog
+					.iter()
+					.any(|handle| is_established(&sockets.get::<TcpSocket>(*handle)))
+				{
+					POLLIN
+				} else {
+					0
+				}
+			}
+			SocketKind::Udp { handle, .. } => {
+				let udp = sockets.get::<UdpSocket>(handle);
+				let mut events = 0;
+				if udp.can_recv() || socket.read_shutdown {
+					events |= POLLIN;
+				}
+				if udp.can_send() || !udp.is_open() {
+					events |= POLLOUT;
+				}
+				events
+			}
+		};
+
+		Ok(events)
+	}
+
+	/// Returns true if the socket is a TCP socket.
+	pub fn is_stream(&mut self, fd: i32) -> Result<bool, i32> {
+		Ok(match self.get_socket(fd)?.kind {
+			SocketKind::Udp { .. } => false,
+			_ => true,
+		})
+	}
+}
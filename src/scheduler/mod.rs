@@ -10,7 +10,7 @@ use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::rc::Rc;
 use core::cell::RefCell;
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 use crate::arch;
 use crate::arch::irq;
@@ -66,6 +66,10 @@ pub struct PerCoreScheduler {
 	blocked_tasks: BlockedTaskQueue,
 	/// Queues to handle incoming requests from the other cores
 	input: SpinlockIrqSave<SchedulerInput>,
+	/// Time (in nanoseconds) this core has spent in an idle state
+	idle_time: AtomicU64,
+	/// Timestamp (in nanoseconds), at which this core has entered the idle state
+	idle_since: Option<u64>,
 }
 
 impl PerCoreScheduler {
@@ -382,18 +386,46 @@ impl PerCoreScheduler {
 		// do housekeeping
 		let wakeup_tasks = self.cleanup_tasks();
 
-		// Reenable interrupts and simultaneously set the CPU into the HALT state to only wake up at the next interrupt.
-		// This atomic operation guarantees that we cannot miss a wakeup interrupt in between.
 		if !wakeup_tasks {
-			irq::enable_and_wait();
+			// Program the One-Shot Timer for the next blocked task, so that the core
+			// is not woken up before there is something to do.
+			let wakeup_time = self.blocked_tasks.get_next_wakeup_time();
+			arch::set_oneshot_timer(wakeup_time);
+
+			// Reenable interrupts and simultaneously put the CPU into an idle state to only wake up at the next interrupt.
+			// This atomic operation guarantees that we cannot miss a wakeup interrupt in between.
+			self.idle_since = Some(arch::processor::get_timer_nanos());
+			arch::processor::idle(wakeup_time);
+
+			// The interrupt, which has woken us up, may already have accounted the idle time.
+			irqsave(|| self.account_idle_time());
 		} else {
 			irq::enable();
 		}
 	}
 
+	/// Adds the time since entering the idle state to the idle time of this core.
+	/// Interrupt flag must be cleared before calling this function.
+	fn account_idle_time(&mut self) {
+		if let Some(idle_since) = self.idle_since.take() {
+			let idle_time = arch::processor::get_timer_nanos().saturating_sub(idle_since);
+			self.idle_time.fetch_add(idle_time, Ordering::Relaxed);
+		}
+	}
+
+	/// Returns the time (in nanoseconds) this core has spent in an idle state.
+	#[inline]
+	pub fn get_idle_time(&self) -> u64 {
+		self.idle_time.load(Ordering::Relaxed)
+	}
+
 	/// Triggers the scheduler to reschedule the tasks.
 	/// Interrupt flag must be cleared before calling this function.
 	pub fn scheduler(&mut self) {
+		// If we are called from an interrupt, which has woken up the idle task,
+		// the idle state ends here.
+		self.account_idle_time();
+
 		// Someone wants to give up the CPU
 		// => we have time to cleanup the system
 		let _ = self.cleanup_tasks();
@@ -528,6 +560,8 @@ pub fn add_current_core() {
 		finished_tasks: VecDeque::new(),
 		blocked_tasks: BlockedTaskQueue::new(),
 		input: SpinlockIrqSave::new(SchedulerInput::new()),
+		idle_time: AtomicU64::new(0),
+		idle_since: None,
 	});
 
 	let scheduler = Box::into_raw(boxed_scheduler);
@@ -550,6 +584,12 @@ fn get_scheduler(core_id: CoreId) -> &'static PerCoreScheduler {
 	}
 }
 
+/// Returns the time (in nanoseconds) the given core has spent in an idle state,
+/// if a scheduler has been initialized for it.
+pub fn get_idle_time(core_id: CoreId) -> Option<u64> {
+	unsafe { SCHEDULERS.get(&core_id) }.map(|scheduler| scheduler.get_idle_time())
+}
+
 pub fn join(id: TaskId) -> Result<(), ()> {
 	let core_scheduler = core_scheduler();
 
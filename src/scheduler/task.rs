@@ -576,6 +576,12 @@ impl BlockedTaskQueue {
 		}
 	}
 
+	/// Returns the earliest wakeup time of all blocked tasks (if any).
+	/// As the list is sorted by wakeup time, this is the one of the first task.
+	pub fn get_next_wakeup_time(&self) -> Option<u64> {
+		self.list.front().and_then(|node| node.wakeup_time)
+	}
+
 	/// Manually wake up a blocked task.
 	pub fn custom_wakeup(&mut self, task: TaskHandle) {
 		let mut first_task = true;
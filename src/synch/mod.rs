@@ -8,6 +8,8 @@
 
 //! Synchronization primitives
 
+pub mod mutex;
 pub mod recmutex;
 pub mod semaphore;
 pub mod spinlock;
+pub mod waitqueue;
@@ -0,0 +1,82 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::synch::semaphore::Semaphore;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut, Drop};
+
+/// A mutual exclusion primitive, which blocks the current task while the lock is held by another one.
+///
+/// In contrast to `Spinlock`, the lock may be held across operations, which block the task
+/// (e.g. the I/O of a virtio device). Therefore, it must not be used by interrupt handlers.
+///
+/// # Simple examples
+///
+/// ```
+/// let mutex = synch::Mutex::new(0);
+///
+/// // Modify the data
+/// {
+///     let mut data = mutex.lock();
+///     *data = 2;
+/// }
+/// ```
+pub struct Mutex<T> {
+	semaphore: Semaphore,
+	data: UnsafeCell<T>,
+}
+
+/// A guard to which the protected data can be accessed
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct MutexGuard<'a, T: 'a> {
+	semaphore: &'a Semaphore,
+	data: &'a mut T,
+}
+
+// Same unsafe impls as `Spinlock`
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+	pub const fn new(user_data: T) -> Self {
+		Self {
+			semaphore: Semaphore::new(1),
+			data: UnsafeCell::new(user_data),
+		}
+	}
+
+	/// Blocks the current task until the lock is acquired.
+	pub fn lock(&self) -> MutexGuard<'_, T> {
+		self.semaphore.acquire(None);
+		MutexGuard {
+			semaphore: &self.semaphore,
+			data: unsafe { &mut *self.data.get() },
+		}
+	}
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&*self.data
+	}
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut *self.data
+	}
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+	/// The dropping of the MutexGuard will release the lock it was created from.
+	fn drop(&mut self) {
+		self.semaphore.release();
+	}
+}
@@ -229,6 +229,35 @@ impl<T: ?Sized> SpinlockIrqSave<T> {
 			data: unsafe { &mut *self.data.get() },
 		}
 	}
+
+	/// Acquires the lock only if it is free. Otherwise, None is returned without busy waiting.
+	pub fn try_lock(&self) -> Option<SpinlockIrqSaveGuard<T>> {
+		let irq = irq::nested_disable();
+
+		// the lock is free, if the last ticket has already been served
+		let ticket = self.dequeue.load(Ordering::SeqCst);
+		if self
+			.queue
+			.compare_exchange(
+				ticket.wrapping_sub(1),
+				ticket,
+				Ordering::SeqCst,
+				Ordering::SeqCst,
+			)
+			.is_err()
+		{
+			irq::nested_enable(irq);
+			return None;
+		}
+
+		self.irq.store(irq, Ordering::SeqCst);
+		Some(SpinlockIrqSaveGuard {
+			//queue: &self.queue,
+			dequeue: &self.dequeue,
+			irq: &self.irq,
+			data: unsafe { &mut *self.data.get() },
+		})
+	}
 }
 
 impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinlockIrqSave<T> {
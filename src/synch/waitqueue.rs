@@ -0,0 +1,63 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::arch::percore::*;
+use crate::scheduler::task::{TaskHandle, TaskId};
+use crate::synch::spinlock::SpinlockIrqSave;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A queue of tasks, which wait for an event of a kernel object (e.g. a pipe).
+///
+/// In contrast to a semaphore, a task may wait on several queues at the same time.
+/// A task has to block itself before it registers on the queues.
+/// Every wakeup removes all tasks from the queue. If the event of a task has not
+/// occurred yet, the task has to check the state of the object and register again.
+pub struct WaitQueue {
+	tasks: SpinlockIrqSave<Vec<TaskHandle>>,
+	/// Number of notifications, which is used to detect new events (see EPOLLET)
+	generation: AtomicU64,
+}
+
+impl WaitQueue {
+	pub const fn new() -> Self {
+		Self {
+			tasks: SpinlockIrqSave::new(Vec::new()),
+			generation: AtomicU64::new(0),
+		}
+	}
+
+	/// Adds the task to the queue, if it is not already registered.
+	pub fn register(&self, task: TaskHandle) {
+		let mut tasks = self.tasks.lock();
+		if !tasks.iter().any(|t| t.get_id() == task.get_id()) {
+			tasks.push(task);
+		}
+	}
+
+	/// Removes the task from the queue, e.g. after its timeout has expired.
+	pub fn unregister(&self, id: TaskId) {
+		self.tasks.lock().retain(|t| t.get_id() != id);
+	}
+
+	/// Returns the number of notifications since the creation of the queue.
+	pub fn generation(&self) -> u64 {
+		self.generation.load(Ordering::SeqCst)
+	}
+
+	/// Wakes up all registered tasks.
+	pub fn wakeup_all(&self) {
+		self.generation.fetch_add(1, Ordering::SeqCst);
+		let tasks = mem::take(&mut *self.tasks.lock());
+
+		let core_scheduler = core_scheduler();
+		for task in tasks {
+			core_scheduler.custom_wakeup(task);
+		}
+	}
+}
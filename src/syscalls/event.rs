@@ -0,0 +1,792 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Kernel objects, which are accessed through file descriptors (pipes, eventfds and timerfds).
+//!
+//! All objects implement `ObjectInterface`, which describes their readiness and
+//! allows tasks to wait for a change of it. poll and epoll are built on top of it.
+
+use crate::arch;
+use crate::arch::percore::*;
+use crate::collections::irqsave;
+use crate::errno::*;
+use crate::scheduler::task::{TaskHandle, TaskId};
+use crate::synch::spinlock::SpinlockIrqSave;
+use crate::synch::waitqueue::WaitQueue;
+use crate::syscalls::poll::{POLLERR, POLLHUP, POLLIN, POLLOUT};
+use crate::syscalls::timer::{
+	get_realtime_nanos, itimerspec, timespec, CLOCK_MONOTONIC, CLOCK_REALTIME,
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::any::Any;
+use core::slice;
+
+/// File descriptors of event objects are marked by this bit to distinguish them from files.
+pub const EVENT_FD_BIT: i32 = 1 << 29;
+
+pub const O_NONBLOCK: i32 = 0o4000;
+pub const O_CLOEXEC: i32 = 0o2000000;
+pub const EFD_SEMAPHORE: i32 = 1;
+pub const EFD_NONBLOCK: i32 = O_NONBLOCK;
+pub const EFD_CLOEXEC: i32 = O_CLOEXEC;
+pub const TFD_NONBLOCK: i32 = O_NONBLOCK;
+pub const TFD_CLOEXEC: i32 = O_CLOEXEC;
+pub const TFD_TIMER_ABSTIME: i32 = 1;
+
+/// Number of bytes, which a pipe can buffer
+const PIPE_CAPACITY: usize = 65_536;
+
+static OBJECTS: SpinlockIrqSave<ObjectTable> = SpinlockIrqSave::new(ObjectTable::new());
+
+/// Interface of all objects, which can be used with poll and epoll.
+pub trait ObjectInterface: Send + Sync {
+	/// Returns the events (POLLIN, POLLOUT, ...), which are currently signalled.
+	fn poll(&self) -> i16;
+
+	/// Registers a task, which is woken up when the readiness of the object changes.
+	fn register(&self, task: TaskHandle);
+
+	fn unregister(&self, id: TaskId);
+
+	/// Returns a number, which changes whenever the object signals a new event.
+	fn generation(&self) -> u64;
+
+	/// Returns the time (in microseconds since boot), at which the object becomes ready
+	/// without waking up the registered tasks (e.g. the expiration of a timer).
+	fn deadline(&self) -> Option<u64> {
+		None
+	}
+
+	/// Reads data without blocking. Returns `-EAGAIN` if no data is available.
+	fn read(&self, _buf: &mut [u8]) -> Result<usize, i32> {
+		Err(-EINVAL)
+	}
+
+	/// Writes data without blocking. Returns `-EAGAIN` if the object cannot accept data.
+	fn write(&self, _buf: &[u8]) -> Result<usize, i32> {
+		Err(-EINVAL)
+	}
+
+	fn as_any(&self) -> &dyn Any;
+}
+
+struct Descriptor {
+	object: Arc<dyn ObjectInterface>,
+	nonblocking: bool,
+}
+
+struct ObjectTable {
+	descriptors: BTreeMap<i32, Descriptor>,
+	next_fd: i32,
+}
+
+impl ObjectTable {
+	const fn new() -> Self {
+		Self {
+			descriptors: BTreeMap::new(),
+			next_fd: 0,
+		}
+	}
+}
+
+/// Returns true if the file descriptor refers to an event object.
+#[inline]
+pub fn is_event_object(fd: i32) -> bool {
+	fd >= 0 && fd & EVENT_FD_BIT != 0
+}
+
+/// Adds an object to the descriptor table and returns its file descriptor.
+pub fn insert_object(object: Arc<dyn ObjectInterface>, nonblocking: bool) -> i32 {
+	let mut table = OBJECTS.lock();
+	let fd = table.next_fd | EVENT_FD_BIT;
+	table.next_fd += 1;
+	table.descriptors.insert(
+		fd,
+		Descriptor {
+			object,
+			nonblocking,
+		},
+	);
+	fd
+}
+
+/// Returns the object and its blocking mode.
+pub fn get_object(fd: i32) -> Result<(Arc<dyn ObjectInterface>, bool), i32> {
+	OBJECTS
+		.lock()
+		.descriptors
+		.get(&fd)
+		.map(|descriptor| (descriptor.object.clone(), descriptor.nonblocking))
+		.ok_or(-EBADF)
+}
+
+pub fn set_nonblocking(fd: i32, nonblocking: bool) -> Result<(), i32> {
+	let mut table = OBJECTS.lock();
+	let descriptor = table.descriptors.get_mut(&fd).ok_or(-EBADF)?;
+	descriptor.nonblocking = nonblocking;
+	Ok(())
+}
+
+/// Blocks the current task until `is_ready` returns true or the deadline
+/// (in microseconds since boot) has expired. Returns false in the latter case.
+///
+/// `is_ready` is evaluated whenever one of the objects signals a change.
+pub fn wait_for_event<F>(
+	objects: &[Arc<dyn ObjectInterface>],
+	deadline: Option<u64>,
+	mut is_ready: F,
+) -> bool
+where
+	F: FnMut() -> bool,
+{
+	let core_scheduler = core_scheduler();
+	let task = core_scheduler.get_current_task_handle();
+
+	loop {
+		if is_ready() {
+			return true;
+		}
+
+		if let Some(deadline) = deadline {
+			if arch::processor::get_timer_ticks() >= deadline {
+				return false;
+			}
+		}
+
+		// Objects like timers become ready without a notification.
+		let wakeup_time = objects
+			.iter()
+			.filter_map(|object| object.deadline())
+			.chain(deadline)
+			.min();
+
+		// The task has to be blocked before it registers on the objects.
+		// Otherwise, a notification in between would get lost.
+		irqsave(|| {
+			core_scheduler.block_current_task(wakeup_time);
+			for object in objects {
+				object.register(task);
+			}
+
+			// The event may have occurred before we have been registered.
+			if is_ready() {
+				core_scheduler.custom_wakeup(task);
+			}
+		});
+
+		// Switch to the next task.
+		core_scheduler.reschedule();
+
+		// We may have been woken up by the timer or by another object.
+		for object in objects {
+			object.unregister(task.get_id());
+		}
+	}
+}
+
+/// Calls `f` until it does not return `-EAGAIN` anymore or the object is in non-blocking mode.
+pub(crate) fn block_on_object<T, F>(
+	object: &Arc<dyn ObjectInterface>,
+	nonblocking: bool,
+	events: i16,
+	mut f: F,
+) -> Result<T, i32>
+where
+	F: FnMut() -> Result<T, i32>,
+{
+	loop {
+		match f() {
+			Err(e) if e == -EAGAIN && !nonblocking => {
+				wait_for_event(slice::from_ref(object), None, || {
+					object.poll() & (events | POLLERR | POLLHUP) != 0
+				});
+			}
+			result => return result,
+		}
+	}
+}
+
+/// Called by sys_read for file descriptors of event objects
+pub fn event_read(fd: i32, buf: *mut u8, len: usize) -> isize {
+	let (object, nonblocking) = match get_object(fd) {
+		Ok(result) => result,
+		Err(e) => return e as isize,
+	};
+	let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+
+	block_on_object(&object, nonblocking, POLLIN, || object.read(buffer))
+		.map(|len| len as isize)
+		.unwrap_or_else(|e| e as isize)
+}
+
+/// Called by sys_write for file descriptors of event objects
+pub fn event_write(fd: i32, buf: *const u8, len: usize) -> isize {
+	let (object, nonblocking) = match get_object(fd) {
+		Ok(result) => result,
+		Err(e) => return e as isize,
+	};
+	let buffer = unsafe { slice::from_raw_parts(buf, len) };
+
+	block_on_object(&object, nonblocking, POLLOUT, || object.write(buffer))
+		.map(|len| len as isize)
+		.unwrap_or_else(|e| e as isize)
+}
+
+/// Called by sys_close for file descriptors of event objects
+pub fn event_close(fd: i32) -> i32 {
+	// The object is destroyed as soon as no task uses it anymore.
+	match OBJECTS.lock().descriptors.remove(&fd) {
+		Some(_) => 0,
+		None => -EBADF,
+	}
+}
+
+struct PipeState {
+	buffer: VecDeque<u8>,
+	readers: usize,
+	writers: usize,
+}
+
+struct Pipe {
+	state: SpinlockIrqSave<PipeState>,
+	queue: WaitQueue,
+}
+
+/// Read end of a pipe
+struct PipeReader(Arc<Pipe>);
+/// Write end of a pipe
+struct PipeWriter(Arc<Pipe>);
+
+impl Drop for PipeReader {
+	fn drop(&mut self) {
+		self.0.state.lock().readers -= 1;
+		self.0.queue.wakeup_all();
+	}
+}
+
+impl Drop for PipeWriter {
+	fn drop(&mut self) {
+		self.0.state.lock().writers -= 1;
+		self.0.queue.wakeup_all();
+	}
+}
+
+impl ObjectInterface for PipeReader {
+	fn poll(&self) -> i16 {
+		let state = self.0.state.lock();
+		let mut events = 0;
+		if !state.buffer.is_empty() {
+			events |= POLLIN;
+		}
+		if state.writers == 0 {
+			events |= POLLHUP;
+		}
+		events
+	}
+
+	fn register(&self, task: TaskHandle) {
+		self.0.queue.register(task);
+	}
+
+	fn unregister(&self, id: TaskId) {
+		self.0.queue.unregister(id);
+	}
+
+	fn generation(&self) -> u64 {
+		self.0.queue.generation()
+	}
+
+	fn read(&self, buf: &mut [u8]) -> Result<usize, i32> {
+		let len = {
+			let mut state = self.0.state.lock();
+			if state.buffer.is_empty() {
+				// A pipe without writers signals the end of the file.
+				return if state.writers == 0 {
+					Ok(0)
+				} else {
+					Err(-EAGAIN)
+				};
+			}
+
+			let len = buf.len().min(state.buffer.len());
+			for (dst, src) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+				*dst = src;
+			}
+			len
+		};
+
+		self.0.queue.wakeup_all();
+		Ok(len)
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+impl ObjectInterface for PipeWriter {
+	fn poll(&self) -> i16 {
+		let state = self.0.state.lock();
+		let mut events = 0;
+		if state.buffer.len() < PIPE_CAPACITY {
+			events |= POLLOUT;
+		}
+		if state.readers == 0 {
+			events |= POLLERR;
+		}
+		events
+	}
+
+	fn register(&self, task: TaskHandle) {
+		self.0.queue.register(task);
+	}
+
+	fn unregister(&self, id: TaskId) {
+		self.0.queue.unregister(id);
+	}
+
+	fn generation(&self) -> u64 {
+		self.0.queue.generation()
+	}
+
+	fn write(&self, buf: &[u8]) -> Result<usize, i32> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		let len = {
+			let mut state = self.0.state.lock();
+			if state.readers == 0 {
+				return Err(-EPIPE);
+			}
+
+			let len = buf.len().min(PIPE_CAPACITY - state.buffer.len());
+			if len == 0 {
+				return Err(-EAGAIN);
+			}
+
+			state.buffer.extend(buf[..len].iter());
+			len
+		};
+
+		self.0.queue.wakeup_all();
+		Ok(len)
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+struct EventFd {
+	counter: SpinlockIrqSave<u64>,
+	/// Every read decrements the counter by one instead of resetting it.
+	semaphore: bool,
+	queue: WaitQueue,
+}
+
+impl ObjectInterface for EventFd {
+	fn poll(&self) -> i16 {
+		let counter = *self.counter.lock();
+		let mut events = 0;
+		if counter > 0 {
+			events |= POLLIN;
+		}
+		if counter < u64::MAX - 1 {
+			events |= POLLOUT;
+		}
+		events
+	}
+
+	fn register(&self, task: TaskHandle) {
+		self.queue.register(task);
+	}
+
+	fn unregister(&self, id: TaskId) {
+		self.queue.unregister(id);
+	}
+
+	fn generation(&self) -> u64 {
+		self.queue.generation()
+	}
+
+	fn read(&self, buf: &mut [u8]) -> Result<usize, i32> {
+		if buf.len() < 8 {
+			return Err(-EINVAL);
+		}
+
+		let value = {
+			let mut counter = self.counter.lock();
+			if *counter == 0 {
+				return Err(-EAGAIN);
+			}
+
+			let value = if self.semaphore { 1 } else { *counter };
+			*counter -= value;
+			value
+		};
+
+		buf[..8].copy_from_slice(&value.to_ne_bytes());
+		self.queue.wakeup_all();
+		Ok(8)
+	}
+
+	fn write(&self, buf: &[u8]) -> Result<usize, i32> {
+		if buf.len() < 8 {
+			return Err(-EINVAL);
+		}
+
+		let mut bytes = [0u8; 8];
+		bytes.copy_from_slice(&buf[..8]);
+		let value = u64::from_ne_bytes(bytes);
+		if value == u64::MAX {
+			return Err(-EINVAL);
+		}
+
+		{
+			let mut counter = self.counter.lock();
+			// The maximum value of the counter is 0xfffffffffffffffe.
+			if u64::MAX - 1 - *counter < value {
+				return Err(-EAGAIN);
+			}
+			*counter += value;
+		}
+
+		self.queue.wakeup_all();
+		Ok(8)
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+struct TimerState {
+	clock_id: u64,
+	/// Next expiration (in microseconds since boot) or `None` if the timer is disarmed
+	deadline: Option<u64>,
+	/// Period of the timer in microseconds or zero for a one-shot timer
+	interval: u64,
+	/// Number of expirations since the last read
+	expirations: u64,
+	/// Number of expirations since the creation of the timer
+	total_expirations: u64,
+}
+
+impl TimerState {
+	/// Account all expirations, which have happened until now.
+	fn update(&mut self, now: u64) {
+		if let Some(deadline) = self.deadline {
+			if now >= deadline {
+				let expirations = if self.interval > 0 {
+					let expirations = (now - deadline) / self.interval + 1;
+					self.deadline = Some(deadline + expirations * self.interval);
+					expirations
+				} else {
+					self.deadline = None;
+					1
+				};
+
+				self.expirations += expirations;
+				self.total_expirations += expirations;
+			}
+		}
+	}
+}
+
+pub struct TimerFd {
+	state: SpinlockIrqSave<TimerState>,
+	queue: WaitQueue,
+}
+
+fn timespec_to_microseconds(ts: &timespec) -> Result<u64, i32> {
+	if ts.tv_sec < 0 || ts.tv_nsec < 0 || ts.tv_nsec > 999_999_999 {
+		return Err(-EINVAL);
+	}
+
+	Ok(ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000)
+}
+
+fn microseconds_to_timespec(microseconds: u64) -> timespec {
+	timespec {
+		tv_sec: (microseconds / 1_000_000) as i64,
+		tv_nsec: ((microseconds % 1_000_000) * 1000) as i64,
+	}
+}
+
+impl TimerFd {
+	/// Arms (or disarms, if `value` is zero) the timer and returns the previous setting.
+	fn set(&self, absolute: bool, value: &itimerspec) -> Result<itimerspec, i32> {
+		let interval = timespec_to_microseconds(&value.it_interval)?;
+		let value = timespec_to_microseconds(&value.it_value)?;
+		let old_value = self.get();
+
+		{
+			let now = arch::processor::get_timer_ticks();
+			let mut state = self.state.lock();
+
+			state.deadline = if value == 0 {
+				None
+			} else if !absolute {
+				Some(now + value)
+			} else if state.clock_id == CLOCK_REALTIME {
+				// Later changes of the wall clock do not influence the timer.
+				let realtime = get_realtime_nanos() / 1000;
+				Some((now + value).saturating_sub(realtime))
+			} else {
+				Some(value)
+			};
+			state.interval = interval;
+			state.expirations = 0;
+		}
+
+		// Tasks, which wait for the timer, have to adjust their wakeup time.
+		self.queue.wakeup_all();
+		Ok(old_value)
+	}
+
+	/// Returns the time until the next expiration and the interval of the timer.
+	fn get(&self) -> itimerspec {
+		let now = arch::processor::get_timer_ticks();
+		let mut state = self.state.lock();
+		state.update(now);
+
+		itimerspec {
+			it_interval: microseconds_to_timespec(state.interval),
+			it_value: microseconds_to_timespec(state.deadline.map_or(0, |deadline| deadline - now)),
+		}
+	}
+}
+
+impl ObjectInterface for TimerFd {
+	fn poll(&self) -> i16 {
+		let mut state = self.state.lock();
+		state.update(arch::processor::get_timer_ticks());
+		if state.expirations > 0 {
+			POLLIN
+		} else {
+			0
+		}
+	}
+
+	fn register(&self, task: TaskHandle) {
+		self.queue.register(task);
+	}
+
+	fn unregister(&self, id: TaskId) {
+		self.queue.unregister(id);
+	}
+
+	fn generation(&self) -> u64 {
+		let mut state = self.state.lock();
+		state.update(arch::processor::get_timer_ticks());
+		self.queue.generation() + state.total_expirations
+	}
+
+	fn deadline(&self) -> Option<u64> {
+		self.state.lock().deadline
+	}
+
+	fn read(&self, buf: &mut [u8]) -> Result<usize, i32> {
+		if buf.len() < 8 {
+			return Err(-EINVAL);
+		}
+
+		let mut state = self.state.lock();
+		state.update(arch::processor::get_timer_ticks());
+		if state.expirations == 0 {
+			return Err(-EAGAIN);
+		}
+
+		buf[..8].copy_from_slice(&state.expirations.to_ne_bytes());
+		state.expirations = 0;
+		Ok(8)
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+fn get_timer(fd: i32) -> Result<Arc<dyn ObjectInterface>, i32> {
+	let (object, _) = get_object(fd)?;
+	if object.as_any().is::<TimerFd>() {
+		Ok(object)
+	} else {
+		Err(-EINVAL)
+	}
+}
+
+fn __sys_pipe2(fds: *mut i32, flags: i32) -> i32 {
+	if fds.is_null() {
+		return -EFAULT;
+	}
+	if flags & !(O_NONBLOCK | O_CLOEXEC) != 0 {
+		return -EINVAL;
+	}
+
+	let pipe = Arc::new(Pipe {
+		state: SpinlockIrqSave::new(PipeState {
+			buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+			readers: 1,
+			writers: 1,
+		}),
+		queue: WaitQueue::new(),
+	});
+
+	let nonblocking = flags & O_NONBLOCK != 0;
+	let fds = unsafe { slice::from_raw_parts_mut(fds, 2) };
+	fds[0] = insert_object(Arc::new(PipeReader(pipe.clone())), nonblocking);
+	fds[1] = insert_object(Arc::new(PipeWriter(pipe)), nonblocking);
+	0
+}
+
+#[no_mangle]
+pub extern "C" fn sys_pipe2(fds: *mut i32, flags: i32) -> i32 {
+	kernel_function!(__sys_pipe2(fds, flags))
+}
+
+#[no_mangle]
+pub extern "C" fn sys_pipe(fds: *mut i32) -> i32 {
+	kernel_function!(__sys_pipe2(fds, 0))
+}
+
+fn __sys_eventfd(initval: u64, flags: i32) -> i32 {
+	if flags & !(EFD_SEMAPHORE | EFD_NONBLOCK | EFD_CLOEXEC) != 0 || initval == u64::MAX {
+		return -EINVAL;
+	}
+
+	let eventfd = EventFd {
+		counter: SpinlockIrqSave::new(initval),
+		semaphore: flags & EFD_SEMAPHORE != 0,
+		queue: WaitQueue::new(),
+	};
+
+	insert_object(Arc::new(eventfd), flags & EFD_NONBLOCK != 0)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_eventfd(initval: u64, flags: i32) -> i32 {
+	kernel_function!(__sys_eventfd(initval, flags))
+}
+
+fn __sys_timerfd_create(clock_id: u64, flags: i32) -> i32 {
+	if clock_id != CLOCK_REALTIME && clock_id != CLOCK_MONOTONIC {
+		return -EINVAL;
+	}
+	if flags & !(TFD_NONBLOCK | TFD_CLOEXEC) != 0 {
+		return -EINVAL;
+	}
+
+	let timer = TimerFd {
+		state: SpinlockIrqSave::new(TimerState {
+			clock_id,
+			deadline: None,
+			interval: 0,
+			expirations: 0,
+			total_expirations: 0,
+		}),
+		queue: WaitQueue::new(),
+	};
+
+	insert_object(Arc::new(timer), flags & TFD_NONBLOCK != 0)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_timerfd_create(clock_id: u64, flags: i32) -> i32 {
+	kernel_function!(__sys_timerfd_create(clock_id, flags))
+}
+
+fn __sys_timerfd_settime(
+	fd: i32,
+	flags: i32,
+	new_value: *const itimerspec,
+	old_value: *mut itimerspec,
+) -> i32 {
+	if new_value.is_null() {
+		return -EFAULT;
+	}
+	if flags & !TFD_TIMER_ABSTIME != 0 {
+		return -EINVAL;
+	}
+
+	let object = match get_timer(fd) {
+		Ok(object) => object,
+		Err(e) => return e,
+	};
+	let timer = object.as_any().downcast_ref::<TimerFd>().unwrap();
+
+	match timer.set(flags & TFD_TIMER_ABSTIME != 0, unsafe { &*new_value }) {
+		Ok(value) => {
+			if !old_value.is_null() {
+				unsafe {
+					*old_value = value;
+				}
+			}
+			0
+		}
+		Err(e) => e,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_timerfd_settime(
+	fd: i32,
+	flags: i32,
+	new_value: *const itimerspec,
+	old_value: *mut itimerspec,
+) -> i32 {
+	kernel_function!(__sys_timerfd_settime(fd, flags, new_value, old_value))
+}
+
+fn __sys_timerfd_gettime(fd: i32, curr_value: *mut itimerspec) -> i32 {
+	if curr_value.is_null() {
+		return -EFAULT;
+	}
+
+	match get_timer(fd) {
+		Ok(object) => {
+			let timer = object.as_any().downcast_ref::<TimerFd>().unwrap();
+			unsafe {
+				*curr_value = timer.get();
+			}
+			0
+		}
+		Err(e) => e,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_timerfd_gettime(fd: i32, curr_value: *mut itimerspec) -> i32 {
+	kernel_function!(__sys_timerfd_gettime(fd, curr_value))
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn account_timer_expirations() {
+	let mut state = TimerState {
+		clock_id: CLOCK_MONOTONIC,
+		deadline: Some(1000),
+		interval: 500,
+		expirations: 0,
+		total_expirations: 0,
+	};
+
+	state.update(999);
+	assert_eq!(state.expirations, 0);
+
+	state.update(2200);
+	assert_eq!(state.expirations, 3);
+	assert_eq!(state.deadline, Some(2500));
+
+	state.interval = 0;
+	state.update(2500);
+	assert_eq!(state.expirations, 4);
+	assert_eq!(state.total_expirations, 4);
+	assert_eq!(state.deadline, None);
+}
@@ -5,13 +5,15 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::errno::*;
+use crate::synch::mutex::Mutex;
 use crate::synch::spinlock::Spinlock;
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::ops::Deref;
 
 /*
 Design:
@@ -19,6 +21,9 @@ Design:
 - want to support multiple mounted filesystems at once.
 - for simplicity: no overlays. All 'folders' in / are mountpoints!
 - manage all files in a global map. Do not hand out references, let syscalls operate by passing in closures (fd_op())
+- every file has its own sleeping lock, so that the closures don't hold the lock of the map. Tasks, which block on
+  the I/O of a file, don't delay the operations of other tasks.
+- the mounted filesystems are shared by Arc. The mount table is only locked to look up a filesystem.
 
 - we internally treat all file systems as posix filesystems.
 - Have two traits. One representing a filesystem, another a file: PosixFileSystem and PosixFile
@@ -28,6 +33,8 @@ Design:
 - have a FUSE filesystem, which implements both PosixFileSystem and PosixFile
 - fuse can have various FuseInterface backends. These only have to provide fuse command send/receive capabilites.
 - virtiofs implements FuseInterface and sends commands via virtio queues.
+- disk filesystems (FAT32, ext2) in crate::fs implement both traits on top of a sector cache.
+  Their sectors are read from a block device or from an image file, which is opened via open_unassigned().
 
 - fd management is only relevant for "user" facing code. We don't care how fuse etc. manages nodes internally.
 - But we still want to have a list of open files and mounted filesystems (here in fs.rs).
@@ -52,52 +59,54 @@ TODO:
 */
 
 // TODO: lazy static could be replaced with explicit init on OS boot.
-pub static FILESYSTEM: Spinlock<Filesystem> = Spinlock::new(Filesystem::new());
+pub static FILESYSTEM: Filesystem = Filesystem::new();
 
+/// Mounted filesystem, which is used without holding the lock of the mount table
+type MountedFileSystem = Arc<dyn PosixFileSystem + Send + Sync>;
+
+/// Open file, which is locked independently from the filesystem. The I/O of a file may
+/// block the task (e.g. on a virtio device), so it is protected by a sleeping lock.
+type OpenFile = Arc<Mutex<Box<dyn PosixFile + Send>>>;
+
+/// The tables are locked separately and only while they are accessed. The operations
+/// of a filesystem or of a file are performed without holding these locks.
 pub struct Filesystem {
 	// Keep track of mount-points
-	mounts: BTreeMap<String, Box<dyn PosixFileSystem + Send>>,
+	mounts: Spinlock<BTreeMap<String, MountedFileSystem>>,
 
 	// Keep track of open files
-	files: BTreeMap<u64, Box<dyn PosixFile + Send>>,
+	files: Spinlock<BTreeMap<u64, OpenFile>>,
+
+	// Keep track of mapped files by their address
+	mappings: Spinlock<BTreeMap<usize, Box<dyn FileMapping + Send>>>,
 }
 
 impl Filesystem {
 	pub const fn new() -> Self {
 		Self {
-			mounts: BTreeMap::new(),
-			files: BTreeMap::new(),
-		}
-	}
-
-	/// Returns next free file-descriptor. We map index in files BTreeMap as fd's.
-	/// Done determining the current biggest stored index.
-	/// This is efficient, since BTreeMap's iter() calculates min and max key directly.
-	/// see https://github.com/rust-lang/rust/issues/62924
-	fn assign_new_fd(&self) -> u64 {
-		// BTreeMap has efficient max/min index calculation. One way to access these is the following iter.
-		// Add 1 to get next never-assigned fd num
-		if let Some((fd, _)) = self.files.iter().next_back() {
-			fd + 1
-		} else {
-			3 // start at 3, to reserve stdin/out/err
+			mounts: Spinlock::new(BTreeMap::new()),
+			files: Spinlock::new(BTreeMap::new()),
+			mappings: Spinlock::new(BTreeMap::new()),
 		}
 	}
 
 	/// Gets a new fd for a file and inserts it into open files.
 	/// Returns file descriptor
-	fn add_file(&mut self, file: Box<dyn PosixFile + Send>) -> u64 {
-		let fd = self.assign_new_fd();
-		self.files.insert(fd, file);
+	fn add_file(&self, file: Box<dyn PosixFile + Send>) -> u64 {
+		let mut files = self.files.lock();
+		// BTreeMap has efficient max/min index calculation. Add 1 to get next never-assigned fd num.
+		// see https://github.com/rust-lang/rust/issues/62924
+		let fd = match files.iter().next_back() {
+			Some((fd, _)) => fd + 1,
+			None => 3, // start at 3, to reserve stdin/out/err
+		};
+		files.insert(fd, Arc::new(Mutex::new(file)));
 		fd
 	}
 
 	/// parses path `/MOUNTPOINT/internal-path` into mount-filesystem and internal_path
 	/// Returns (PosixFileSystem, internal_path) or Error on failure.
-	fn parse_path<'a, 'b>(
-		&'a self,
-		path: &'b str,
-	) -> Result<(&'a (dyn PosixFileSystem + Send), &'b str), FileError> {
+	fn parse_path<'b>(&self, path: &'b str) -> Result<(MountedFileSystem, &'b str), FileError> {
 		// assert start with / (no pwd relative!), split path at /, look first element. Determine backing fs. If non existent, -ENOENT
 		if !path.starts_with('/') {
 			warn!("Relative paths not allowed!");
@@ -106,10 +115,11 @@ impl Filesystem {
 		let mut pathsplit = path.splitn(3, '/');
 		pathsplit.next(); // always empty, since first char is /
 		let mount = pathsplit.next().unwrap();
-		let internal_path = pathsplit.next().unwrap(); //TODO: this can fail from userspace, eg when passing "/test"
+		// the root directory of a mount point, e.g. "/test", has an empty internal path
+		let internal_path = pathsplit.next().unwrap_or("");
 
-		if let Some(fs) = self.mounts.get(mount) {
-			Ok((fs.deref(), internal_path))
+		if let Some(fs) = self.mounts.lock().get(mount) {
+			Ok((fs.clone(), internal_path))
 		} else {
 			info!(
 				"Trying to open file on non-existing mount point '{}'!",
@@ -122,23 +132,39 @@ impl Filesystem {
 	/// Tries to open file at given path (/MOUNTPOINT/internal-path).
 	/// Looks up MOUNTPOINT in mounted dirs, passes internal-path to filesystem backend
 	/// Returns the file descriptor of the newly opened file, or an error on failure
-	pub fn open(&mut self, path: &str, perms: FilePerms) -> Result<u64, FileError> {
+	pub fn open(&self, path: &str, perms: FilePerms) -> Result<u64, FileError> {
 		debug!("Opening file {} {:?}", path, perms);
-		let (fs, internal_path) = self.parse_path(path)?;
-		let file = fs.open(internal_path, perms)?;
+		let file: Box<dyn PosixFile + Send> = if perms.directory {
+			Box::new(Directory::new(self.readdir(path)?))
+		} else {
+			self.open_unassigned(path, perms)?
+		};
 		Ok(self.add_file(file))
 	}
 
-	pub fn close(&mut self, fd: u64) {
+	/// Opens the file at given path (/MOUNTPOINT/internal-path) without assigning a file descriptor.
+	/// This allows the kernel to use a file as backing store, e.g. a disk image of another filesystem.
+	pub fn open_unassigned(
+		&self,
+		path: &str,
+		perms: FilePerms,
+	) -> Result<Box<dyn PosixFile + Send>, FileError> {
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.open(internal_path, perms)
+	}
+
+	/// Closes the file. The descriptor is released, even if writing back the file has failed.
+	pub fn close(&self, fd: u64) -> Result<(), FileError> {
 		debug!("Closing fd {}", fd);
-		if let Some(file) = self.files.get_mut(&fd) {
-			file.close().unwrap(); // TODO: handle error
+		let file = self.files.lock().remove(&fd);
+		match file {
+			Some(file) => file.lock().close(),
+			None => Ok(()),
 		}
-		self.files.remove(&fd);
 	}
 
 	/// Unlinks a file given by path
-	pub fn unlink(&mut self, path: &str) -> Result<(), FileError> {
+	pub fn unlink(&self, path: &str) -> Result<(), FileError> {
 		info!("Unlinking file {}", path);
 		let (fs, internal_path) = self.parse_path(path)?;
 		fs.unlink(internal_path)?;
@@ -147,9 +173,9 @@ impl Filesystem {
 
 	/// Create new backing-fs at mountpoint mntpath
 	pub fn mount(
-		&mut self,
+		&self,
 		mntpath: &str,
-		mntobj: Box<dyn PosixFileSystem + Send>,
+		mntobj: Box<dyn PosixFileSystem + Send + Sync>,
 	) -> Result<(), ()> {
 		info!("Mounting {}", mntpath);
 		if mntpath.contains('/') {
@@ -161,31 +187,438 @@ impl Filesystem {
 		}
 
 		// if mounts contains path already abort
-		if self.mounts.contains_key(mntpath) {
+		let mut mounts = self.mounts.lock();
+		if mounts.contains_key(mntpath) {
 			warn!("Mountpoint already exists!");
 			return Err(());
 		}
 
 		// insert filesystem into mounts, done
-		self.mounts.insert(mntpath.to_owned(), mntobj);
+		mounts.insert(mntpath.to_owned(), Arc::from(mntobj));
 		Ok(())
 	}
 
-	/// Run closure on file referenced by file descriptor.
-	pub fn fd_op(&mut self, fd: u64, f: impl FnOnce(&mut Box<dyn PosixFile + Send>)) {
-		f(self.files.get_mut(&fd).unwrap());
+	/// Returns the file referenced by file descriptor or EBADF.
+	fn get_file(&self, fd: u64) -> Result<OpenFile, FileError> {
+		self.files
+			.lock()
+			.get(&fd)
+			.cloned()
+			.ok_or(FileError::EBADF())
+	}
+
+	/// Returns true if the file descriptor refers to an open file.
+	pub fn is_open_file(&self, fd: u64) -> bool {
+		self.files.lock().contains_key(&fd)
+	}
+
+	/// Parses two paths, which have to be located on the same filesystem.
+	fn parse_paths<'b>(
+		&self,
+		from: &'b str,
+		to: &'b str,
+	) -> Result<(MountedFileSystem, &'b str, &'b str), FileError> {
+		let (fs, from) = self.parse_path(from)?;
+		let (to_fs, to) = self.parse_path(to)?;
+		if !Arc::ptr_eq(&fs, &to_fs) {
+			return Err(FileError::EXDEV());
+		}
+
+		Ok((fs, from, to))
+	}
+
+	/// Returns the attributes of the file at given path (/MOUNTPOINT/internal-path).
+	pub fn stat(&self, path: &str) -> Result<FileAttr, FileError> {
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.stat(internal_path)
+	}
+
+	/// Creates the directory at given path (/MOUNTPOINT/internal-path).
+	pub fn mkdir(&self, path: &str, mode: u32) -> Result<(), FileError> {
+		info!("Creating directory {}", path);
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.mkdir(internal_path, mode)
+	}
+
+	/// Removes the empty directory at given path (/MOUNTPOINT/internal-path).
+	pub fn rmdir(&self, path: &str) -> Result<(), FileError> {
+		info!("Removing directory {}", path);
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.rmdir(internal_path)
+	}
+
+	/// Renames a file. Both paths have to be located on the same mount point.
+	pub fn rename(&self, from: &str, to: &str) -> Result<(), FileError> {
+		info!("Renaming {} to {}", from, to);
+		let (fs, from, to) = self.parse_paths(from, to)?;
+		fs.rename(from, to)
+	}
+
+	/// Creates a symbolic link at `path`, which points to `target`.
+	pub fn symlink(&self, target: &str, path: &str) -> Result<(), FileError> {
+		info!("Creating symbolic link {} to {}", path, target);
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.symlink(target, internal_path)
+	}
+
+	/// Returns the target of the symbolic link at given path.
+	pub fn readlink(&self, path: &str) -> Result<String, FileError> {
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.readlink(internal_path)
+	}
+
+	/// Creates a hard link `to` of `from`. Both paths have to be located on the same mount point.
+	pub fn link(&self, from: &str, to: &str) -> Result<(), FileError> {
+		info!("Creating link {} to {}", to, from);
+		let (fs, from, to) = self.parse_paths(from, to)?;
+		fs.link(from, to)
+	}
+
+	/// Returns the entries of the directory at given path.
+	pub fn readdir(&self, path: &str) -> Result<Vec<DirEntry>, FileError> {
+		let (fs, internal_path) = self.parse_path(path)?;
+		fs.readdir(internal_path)
+	}
+
+	/// Returns the statistics of the filesystem, on which the path is located.
+	pub fn statfs(&self, path: &str) -> Result<FsStat, FileError> {
+		let (fs, _) = self.parse_path(path)?;
+		fs.statfs()
+	}
+
+	/// Maps `len` bytes of the file at `offset` into memory and returns the address of the mapping.
+	/// The mapping stays valid after the file is closed until it is removed by `munmap`.
+	pub fn mmap(
+		&self,
+		fd: u64,
+		offset: u64,
+		len: usize,
+		writable: bool,
+	) -> Result<usize, FileError> {
+		let mapping = self.get_file(fd)?.lock().mmap(offset, len, writable)?;
+		let addr = mapping.addr();
+		self.mappings.lock().insert(addr, mapping);
+		Ok(addr)
+	}
+
+	/// Removes the mapping at `addr`, which has been created by `mmap`.
+	pub fn munmap(&self, addr: usize) -> Result<(), FileError> {
+		// the mapping is removed, when it is dropped after the table has been unlocked
+		let mapping = self.mappings.lock().remove(&addr);
+		mapping.map(|_| ()).ok_or(FileError::EINVAL())
 	}
 }
 
+/// Returns true if the file descriptor refers to an open file.
+pub fn is_open_file(fd: u64) -> bool {
+	FILESYSTEM.is_open_file(fd)
+}
+
+/// Run closure on file referenced by file descriptor.
+/// The filesystem is only locked while the file is looked up.
+pub fn fd_op(fd: u64, f: impl FnOnce(&mut Box<dyn PosixFile + Send>)) {
+	let file = FILESYSTEM.get_file(fd).unwrap();
+	let mut guard = file.lock();
+	f(&mut *guard);
+}
+
+/// Run closure on file referenced by file descriptor and return its result.
+/// In contrast to `fd_op`, an unknown file descriptor is reported as EBADF.
+pub fn fd_call<T>(
+	fd: u64,
+	f: impl FnOnce(&mut Box<dyn PosixFile + Send>) -> Result<T, FileError>,
+) -> Result<T, FileError> {
+	let file = FILESYSTEM.get_file(fd)?;
+	let mut guard = file.lock();
+	f(&mut *guard)
+}
+
 #[derive(Debug)]
 pub enum FileError {
 	ENOENT(),
 	ENOSYS(),
+	EIO(),
+	EEXIST(),
+	EISDIR(),
+	ENOTDIR(),
+	ENOSPC(),
+	EINVAL(),
+	EROFS(),
+	EPERM(),
+	EACCES(),
+	EBADF(),
+	EBUSY(),
+	EXDEV(),
+	ENOTEMPTY(),
+	ENAMETOOLONG(),
+	ENXIO(),
+	EFBIG(),
+	EMLINK(),
+	ELOOP(),
+	EOPNOTSUPP(),
+	ENOMEM(),
+	ENODEV(),
+}
+
+impl FileError {
+	/// Converts an errno value, e.g. from the reply of a FUSE server.
+	/// Values without a corresponding error are reported as EIO.
+	pub fn from_errno(errno: i32) -> Self {
+		match errno {
+			ENOENT => FileError::ENOENT(),
+			ENOSYS => FileError::ENOSYS(),
+			EEXIST => FileError::EEXIST(),
+			EISDIR => FileError::EISDIR(),
+			ENOTDIR => FileError::ENOTDIR(),
+			ENOSPC => FileError::ENOSPC(),
+			EINVAL => FileError::EINVAL(),
+			EROFS => FileError::EROFS(),
+			EPERM => FileError::EPERM(),
+			EACCES => FileError::EACCES(),
+			EBADF => FileError::EBADF(),
+			EBUSY => FileError::EBUSY(),
+			EXDEV => FileError::EXDEV(),
+			ENOTEMPTY => FileError::ENOTEMPTY(),
+			ENAMETOOLONG => FileError::ENAMETOOLONG(),
+			ENXIO => FileError::ENXIO(),
+			EFBIG => FileError::EFBIG(),
+			EMLINK => FileError::EMLINK(),
+			ELOOP => FileError::ELOOP(),
+			EOPNOTSUPP => FileError::EOPNOTSUPP(),
+			ENOMEM => FileError::ENOMEM(),
+			ENODEV => FileError::ENODEV(),
+			EIO => FileError::EIO(),
+			_ => {
+				debug!("Unknown errno {}, reporting EIO", errno);
+				FileError::EIO()
+			}
+		}
+	}
+
+	/// Returns the errno value of the error.
+	pub fn errno(&self) -> i32 {
+		match self {
+			FileError::ENOENT() => ENOENT,
+			FileError::ENOSYS() => ENOSYS,
+			FileError::EIO() => EIO,
+			FileError::EEXIST() => EEXIST,
+			FileError::EISDIR() => EISDIR,
+			FileError::ENOTDIR() => ENOTDIR,
+			FileError::ENOSPC() => ENOSPC,
+			FileError::EINVAL() => EINVAL,
+			FileError::EROFS() => EROFS,
+			FileError::EPERM() => EPERM,
+			FileError::EACCES() => EACCES,
+			FileError::EBADF() => EBADF,
+			FileError::EBUSY() => EBUSY,
+			FileError::EXDEV() => EXDEV,
+			FileError::ENOTEMPTY() => ENOTEMPTY,
+			FileError::ENAMETOOLONG() => ENAMETOOLONG,
+			FileError::ENXIO() => ENXIO,
+			FileError::EFBIG() => EFBIG,
+			FileError::EMLINK() => EMLINK,
+			FileError::ELOOP() => ELOOP,
+			FileError::EOPNOTSUPP() => EOPNOTSUPP,
+			FileError::ENOMEM() => ENOMEM,
+			FileError::ENODEV() => ENODEV,
+		}
+	}
+}
+
+/// Attributes of a file. The layout matches `struct stat` of the applications.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileAttr {
+	pub st_dev: u64,
+	pub st_ino: u64,
+	pub st_nlink: u64,
+	pub st_mode: u32,
+	pub st_uid: u32,
+	pub st_gid: u32,
+	pub st_rdev: u64,
+	pub st_size: u64,
+	pub st_blksize: i64,
+	pub st_blocks: i64,
+	pub st_atime: i64,
+	pub st_atime_nsec: i64,
+	pub st_mtime: i64,
+	pub st_mtime_nsec: i64,
+	pub st_ctime: i64,
+	pub st_ctime_nsec: i64,
+}
+
+/// Entry of a directory
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+	pub ino: u64,
+	/// Type of the file as DT_* value, e.g. 4 for directories
+	pub file_type: u8,
+	pub name: String,
+}
+
+/// Size of the fixed part of a `linux_dirent64` record in front of the name
+const DIRENT64_HEADER_SIZE: usize = 19;
+
+/// Directory, which has been opened with O_DIRECTORY. Its entries are read, when it is
+/// opened, and are returned by `getdents`.
+struct Directory {
+	entries: Vec<DirEntry>,
+	/// Index of the next entry, which is returned by `getdents`
+	position: usize,
+}
+
+impl Directory {
+	fn new(entries: Vec<DirEntry>) -> Self {
+		Self {
+			entries,
+			position: 0,
+		}
+	}
+}
+
+impl PosixFile for Directory {
+	fn close(&mut self) -> Result<(), FileError> {
+		Ok(())
+	}
+
+	fn read(&mut self, _len: u32) -> Result<Vec<u8>, FileError> {
+		Err(FileError::EISDIR())
+	}
+
+	fn write(&mut self, _buf: &[u8]) -> Result<u64, FileError> {
+		Err(FileError::EISDIR())
+	}
+
+	fn lseek(&mut self, offset: isize, whence: SeekWhence) -> Result<usize, FileError> {
+		// the offset of a directory is the index of an entry, like d_off of `getdents`
+		match whence {
+			SeekWhence::Set if offset >= 0 => {
+				self.position = (offset as usize).min(self.entries.len());
+				Ok(self.position)
+			}
+			_ => Err(FileError::EINVAL()),
+		}
+	}
+
+	fn getdents(&mut self, buf: &mut [u8]) -> Result<usize, FileError> {
+		let mut written = 0;
+
+		for entry in &self.entries[self.position..] {
+			// the name is terminated by a null byte and the record is aligned to 8 bytes
+			let reclen = align_up!(DIRENT64_HEADER_SIZE + entry.name.len() + 1, 8);
+			if written + reclen > buf.len() {
+				if written == 0 {
+					return Err(FileError::EINVAL());
+				}
+				break;
+			}
+
+			let record = &mut buf[written..written + reclen];
+			self.position += 1;
+			record[0..8].copy_from_slice(&entry.ino.to_ne_bytes());
+			record[8..16].copy_from_slice(&(self.position as i64).to_ne_bytes());
+			record[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+			record[18] = entry.file_type;
+			let name = &mut record[DIRENT64_HEADER_SIZE..];
+			name[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+			for byte in &mut name[entry.name.len()..] {
+				*byte = 0;
+			}
+			written += reclen;
+		}
+
+		Ok(written)
+	}
+}
+
+/// Statistics of a filesystem
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsStat {
+	pub block_size: u64,
+	pub fragment_size: u64,
+	pub blocks: u64,
+	pub blocks_free: u64,
+	pub blocks_available: u64,
+	pub files: u64,
+	pub files_free: u64,
+	pub name_max: u64,
+}
+
+/// Statistics of a filesystem, which are returned by `sys_statfs` (`struct statfs` of Linux)
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct statfs {
+	pub f_type: i64,
+	pub f_bsize: i64,
+	pub f_blocks: u64,
+	pub f_bfree: u64,
+	pub f_bavail: u64,
+	pub f_files: u64,
+	pub f_ffree: u64,
+	pub f_fsid: [i32; 2],
+	pub f_namelen: i64,
+	pub f_frsize: i64,
+	pub f_flags: i64,
+	pub f_spare: [i64; 4],
+}
+
+impl From<FsStat> for statfs {
+	fn from(stat: FsStat) -> Self {
+		Self {
+			f_bsize: stat.block_size as i64,
+			f_blocks: stat.blocks,
+			f_bfree: stat.blocks_free,
+			f_bavail: stat.blocks_available,
+			f_files: stat.files,
+			f_ffree: stat.files_free,
+			f_namelen: stat.name_max as i64,
+			f_frsize: stat.fragment_size as i64,
+			..Default::default()
+		}
+	}
 }
 
+/// Operations, which are not supported by a filesystem, fail with ENOSYS.
 pub trait PosixFileSystem {
 	fn open(&self, _path: &str, _perms: FilePerms) -> Result<Box<dyn PosixFile + Send>, FileError>;
 	fn unlink(&self, _path: &str) -> Result<(), FileError>;
+
+	fn stat(&self, _path: &str) -> Result<FileAttr, FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn mkdir(&self, _path: &str, _mode: u32) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn rmdir(&self, _path: &str) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn rename(&self, _from: &str, _to: &str) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn symlink(&self, _target: &str, _path: &str) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn readlink(&self, _path: &str) -> Result<String, FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn link(&self, _from: &str, _to: &str) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn readdir(&self, _path: &str) -> Result<Vec<DirEntry>, FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn statfs(&self) -> Result<FsStat, FileError> {
+		Err(FileError::ENOSYS())
+	}
 }
 
 pub trait PosixFile {
@@ -193,6 +626,46 @@ pub trait PosixFile {
 	fn read(&mut self, len: u32) -> Result<Vec<u8>, FileError>;
 	fn write(&mut self, buf: &[u8]) -> Result<u64, FileError>;
 	fn lseek(&mut self, offset: isize, whence: SeekWhence) -> Result<usize, FileError>;
+
+	fn fstat(&mut self) -> Result<FileAttr, FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	/// Writes the file back to its storage. With `datasync`, metadata is only
+	/// written, if it is required to read the data.
+	fn fsync(&mut self, _datasync: bool) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	fn ftruncate(&mut self, _size: u64) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	/// Stores the next entries of a directory as `linux_dirent64` records in `buf` and returns
+	/// the number of written bytes. Files, which are no directories, fail with ENOTDIR.
+	fn getdents(&mut self, _buf: &mut [u8]) -> Result<usize, FileError> {
+		Err(FileError::ENOTDIR())
+	}
+
+	/// Allocates (or with FALLOC_FL_PUNCH_HOLE in `mode` deallocates) the range of the file.
+	fn fallocate(&mut self, _mode: u32, _offset: u64, _len: u64) -> Result<(), FileError> {
+		Err(FileError::ENOSYS())
+	}
+
+	/// Maps the range of the file into memory. Files, which can't be mapped, fail with ENODEV.
+	fn mmap(
+		&mut self,
+		_offset: u64,
+		_len: usize,
+		_writable: bool,
+	) -> Result<Box<dyn FileMapping + Send>, FileError> {
+		Err(FileError::ENODEV())
+	}
+}
+
+/// Memory mapping of a file, which is removed when it is dropped
+pub trait FileMapping {
+	fn addr(&self) -> usize;
 }
 
 // TODO: raw is partially redundant, create nicer interface
@@ -204,6 +677,8 @@ pub struct FilePerms {
 	pub trunc: bool,
 	pub append: bool,
 	pub directio: bool,
+	/// The entries of a directory are read by `getdents` instead of its content
+	pub directory: bool,
 	pub raw: u32,
 	pub mode: u32,
 }
@@ -212,4 +687,44 @@ pub enum SeekWhence {
 	Set,
 	Cur,
 	End,
+	/// Next offset, at which the file contains data
+	Data,
+	/// Next offset, at which the file contains a hole
+	Hole,
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn errno_conversion() {
+	for errno in [ENOENT, EACCES, ENOTEMPTY, EXDEV, ENXIO, EOPNOTSUPP].iter() {
+		assert_eq!(FileError::from_errno(*errno).errno(), *errno);
+	}
+	assert_eq!(FileError::from_errno(EDOM).errno(), EIO);
+}
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn directory_entries() {
+	let entry = |ino, name: &str| DirEntry {
+		ino,
+		file_type: 8,
+		name: String::from(name),
+	};
+	let mut dir = Directory::new(vec![entry(2, "a"), entry(3, "file.txt")]);
+	let mut buf = [0xFFu8; 64];
+
+	// the second record doesn't fit into the buffer
+	assert_eq!(dir.getdents(&mut buf[..40]).unwrap(), 24);
+	assert_eq!(&buf[0..8], &2u64.to_ne_bytes());
+	assert_eq!(&buf[8..16], &1i64.to_ne_bytes());
+	assert_eq!(&buf[16..18], &24u16.to_ne_bytes());
+	assert_eq!(&buf[18..24], &[8, b'a', 0, 0, 0, 0]);
+
+	assert!(dir.getdents(&mut buf[..16]).is_err());
+	assert_eq!(dir.getdents(&mut buf).unwrap(), 32);
+	assert_eq!(&buf[19..28], b"file.txt\0");
+	assert_eq!(dir.getdents(&mut buf).unwrap(), 0);
+
+	dir.lseek(0, SeekWhence::Set).unwrap();
+	assert_eq!(dir.getdents(&mut buf).unwrap(), 56);
 }
@@ -17,7 +17,8 @@ use crate::arch;
 use crate::console;
 use crate::environment;
 use crate::errno::*;
-use crate::syscalls::fs::{self, FilePerms, PosixFile, SeekWhence};
+use crate::syscalls::fs::{self, statfs, FileAttr, FilePerms, PosixFile, SeekWhence};
+use crate::syscalls::TxOffload;
 use crate::util;
 
 pub use self::generic::*;
@@ -29,6 +30,8 @@ mod uhyve;
 const SEEK_SET: i32 = 0;
 const SEEK_CUR: i32 = 1;
 const SEEK_END: i32 = 2;
+const SEEK_DATA: i32 = 3;
+const SEEK_HOLE: i32 = 4;
 
 impl TryFrom<i32> for SeekWhence {
 	type Error = &'static str;
@@ -38,6 +41,8 @@ impl TryFrom<i32> for SeekWhence {
 			SEEK_CUR => Ok(SeekWhence::Cur),
 			SEEK_SET => Ok(SeekWhence::Set),
 			SEEK_END => Ok(SeekWhence::End),
+			SEEK_DATA => Ok(SeekWhence::Data),
+			SEEK_HOLE => Ok(SeekWhence::Hole),
 			_ => Err("Got invalid seek whence parameter!"),
 		}
 	}
@@ -52,6 +57,12 @@ const O_EXCL: i32 = 0o0200;
 const O_TRUNC: i32 = 0o1000;
 const O_APPEND: i32 = 0o2000;
 const O_DIRECT: i32 = 0o40000;
+const O_DIRECTORY: i32 = 0o200000;
+
+//const PROT_READ: i32 = 0x1;
+const PROT_WRITE: i32 = 0x2;
+/// Mount flag of a read-only filesystem (like MS_RDONLY of Linux)
+const MOUNT_READ_ONLY: i32 = 0x1;
 
 fn open_flags_to_perm(flags: i32, mode: u32) -> FilePerms {
 	// mode is passed in as hex (0x777). Linux/Fuse expects octal (0o777).
@@ -78,7 +89,10 @@ fn open_flags_to_perm(flags: i32, mode: u32) -> FilePerms {
 	perms.trunc = flags & (O_TRUNC) != 0;
 	perms.append = flags & (O_APPEND) != 0;
 	perms.directio = flags & (O_DIRECT) != 0;
-	if flags & !(O_WRONLY | O_RDWR | O_CREAT | O_EXCL | O_TRUNC | O_APPEND | O_DIRECT) != 0 {
+	perms.directory = flags & (O_DIRECTORY) != 0;
+	if flags & !(O_WRONLY | O_RDWR | O_CREAT | O_EXCL | O_TRUNC | O_APPEND | O_DIRECT | O_DIRECTORY)
+		!= 0
+	{
 		warn!("Unknown file flags used! {}", flags);
 	}
 	perms
@@ -117,52 +131,137 @@ pub trait SyscallInterface: Send + Sync {
 
 	fn get_mac_address(&self) -> Result<[u8; 6], ()> {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => Ok(driver.lock().get_mac_address()),
+			Some(driver) => Ok(driver.get_mac_address()),
 			_ => Err(()),
 		}
 	}
 
 	fn get_mtu(&self) -> Result<u16, ()> {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => Ok(driver.lock().get_mtu()),
+			Some(driver) => Ok(driver.get_mtu()),
 			_ => Err(()),
 		}
 	}
 
 	fn has_packet(&self) -> bool {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => driver.lock().has_packet(),
+			Some(driver) => driver.has_packet(),
 			_ => false,
 		}
 	}
 
 	fn get_tx_buffer(&self, len: usize) -> Result<(*mut u8, usize), ()> {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => driver.lock().get_tx_buffer(len),
+			Some(driver) => driver.get_tx_buffer(len),
 			_ => Err(()),
 		}
 	}
 
 	fn send_tx_buffer(&self, handle: usize, len: usize) -> Result<(), ()> {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => driver.lock().send_tx_buffer(handle, len),
+			Some(driver) => driver.send_tx_buffer(handle, len),
+			_ => Err(()),
+		}
+	}
+
+	fn release_tx_buffer(&self, handle: usize) -> Result<(), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.release_tx_buffer(handle),
+			_ => Err(()),
+		}
+	}
+
+	fn send_tx_buffer_with_offload(
+		&self,
+		handle: usize,
+		len: usize,
+		offload: &TxOffload,
+	) -> Result<(), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.send_tx_buffer_with_offload(handle, len, offload),
 			_ => Err(()),
 		}
 	}
 
 	fn receive_rx_buffer(&self) -> Result<&'static [u8], ()> {
+		self.receive_rx_buffer_with_flags()
+			.map(|(buffer, _)| buffer)
+	}
+
+	fn receive_rx_buffer_with_flags(&self) -> Result<(&'static [u8], u8), ()> {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => driver.lock().receive_rx_buffer(),
+			Some(driver) => driver.receive_rx_buffer(),
 			_ => Err(()),
 		}
 	}
 
 	fn rx_buffer_consumed(&self) -> Result<(), ()> {
 		match arch::kernel::pci::get_network_driver() {
-			Some(driver) => {
-				driver.lock().rx_buffer_consumed();
-				Ok(())
-			}
+			Some(driver) => driver.rx_buffer_consumed(),
+			_ => Err(()),
+		}
+	}
+
+	fn get_net_offloads(&self) -> Result<u32, ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => Ok(driver.get_offloads()),
+			_ => Err(()),
+		}
+	}
+
+	fn get_net_queue_count(&self) -> Result<usize, ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => Ok(driver.get_queue_count()),
+			_ => Err(()),
+		}
+	}
+
+	fn get_tx_buffer_on_queue(&self, queue: usize, len: usize) -> Result<(*mut u8, usize), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.get_tx_buffer_on_queue(queue, len),
+			_ => Err(()),
+		}
+	}
+
+	fn receive_rx_buffer_on_queue(&self, queue: usize) -> Result<&'static [u8], ()> {
+		self.receive_rx_buffer_with_flags_on_queue(queue)
+			.map(|(buffer, _)| buffer)
+	}
+
+	fn receive_rx_buffer_with_flags_on_queue(
+		&self,
+		queue: usize,
+	) -> Result<(&'static [u8], u8), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.receive_rx_buffer_on_queue(queue),
+			_ => Err(()),
+		}
+	}
+
+	fn rx_buffer_consumed_on_queue(&self, queue: usize) -> Result<(), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.rx_buffer_consumed_on_queue(queue),
+			_ => Err(()),
+		}
+	}
+
+	fn take_rx_buffer(&self) -> Result<(usize, &'static mut [u8], u8), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.take_rx_buffer(),
+			_ => Err(()),
+		}
+	}
+
+	fn take_rx_buffer_on_queue(&self, queue: usize) -> Result<(usize, &'static mut [u8], u8), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.take_rx_buffer_on_queue(queue),
+			_ => Err(()),
+		}
+	}
+
+	fn release_rx_buffer(&self, token: usize) -> Result<(), ()> {
+		match arch::kernel::pci::get_network_driver() {
+			Some(driver) => driver.release_rx_buffer(token),
 			_ => Err(()),
 		}
 	}
@@ -178,11 +277,10 @@ pub trait SyscallInterface: Send + Sync {
 		let name = unsafe { util::c_str_to_str(name) };
 		debug!("unlink {}", name);
 
-		fs::FILESYSTEM
-			.lock()
-			.unlink(&name)
-			.expect("Unlinking failed!"); // TODO: error handling
-		0
+		match fs::FILESYSTEM.unlink(&name) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
 	}
 
 	#[cfg(not(target_arch = "x86_64"))]
@@ -200,8 +298,7 @@ pub trait SyscallInterface: Send + Sync {
 		let name = unsafe { util::c_str_to_str(name) };
 		debug!("Open {}, {}, {}", name, flags, mode);
 
-		let mut fs = fs::FILESYSTEM.lock();
-		let fd = fs.open(&name, open_flags_to_perm(flags, mode as u32));
+		let fd = fs::FILESYSTEM.open(&name, open_flags_to_perm(flags, mode as u32));
 
 		if let Ok(fd) = fd {
 			fd as i32
@@ -216,9 +313,10 @@ pub trait SyscallInterface: Send + Sync {
 			return 0;
 		}
 
-		let mut fs = fs::FILESYSTEM.lock();
-		fs.close(fd as u64);
-		0
+		match fs::FILESYSTEM.close(fd as u64) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
 	}
 
 	#[cfg(not(target_arch = "x86_64"))]
@@ -231,9 +329,19 @@ pub trait SyscallInterface: Send + Sync {
 	fn read(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
 		debug!("Read! {}, {}", fd, len);
 
-		let mut fs = fs::FILESYSTEM.lock();
+		if fd == 0 {
+			// stdin is only readable via a virtio console, otherwise it is always at the end of file
+			return match arch::kernel::pci::get_console_driver() {
+				Some(driver) => {
+					let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+					driver.read_console(buf) as isize
+				}
+				None => 0,
+			};
+		}
+
 		let mut read_bytes = 0;
-		fs.fd_op(fd as u64, |file: &mut Box<dyn PosixFile + Send>| {
+		fs::fd_op(fd as u64, |file: &mut Box<dyn PosixFile + Send>| {
 			let dat = file.read(len as u32).unwrap(); // TODO: might fail
 
 			read_bytes = dat.len();
@@ -253,8 +361,7 @@ pub trait SyscallInterface: Send + Sync {
 			let buf = unsafe { slice::from_raw_parts(buf, len) };
 
 			let mut written_bytes = 0;
-			let mut fs = fs::FILESYSTEM.lock();
-			fs.fd_op(fd as u64, |file: &mut Box<dyn PosixFile + Send>| {
+			fs::fd_op(fd as u64, |file: &mut Box<dyn PosixFile + Send>| {
 				written_bytes = file.write(buf).unwrap(); // TODO: might fail
 			});
 			debug!("Write done! {}", written_bytes);
@@ -276,17 +383,213 @@ pub trait SyscallInterface: Send + Sync {
 	fn lseek(&self, fd: i32, offset: isize, whence: i32) -> isize {
 		debug!("lseek! {}, {}, {}", fd, offset, whence);
 
-		let mut fs = fs::FILESYSTEM.lock();
-		let mut ret = 0;
-		fs.fd_op(fd as u64, |file: &mut Box<dyn PosixFile + Send>| {
-			ret = file.lseek(offset, whence.try_into().unwrap()).unwrap(); // TODO: might fail
-		});
+		let whence = match whence.try_into() {
+			Ok(whence) => whence,
+			Err(_) => return -EINVAL as isize,
+		};
+
+		match fs::fd_call(fd as u64, |file| file.lseek(offset, whence)) {
+			Ok(offset) => offset as isize,
+			Err(error) => -error.errno() as isize,
+		}
+	}
+
+	fn stat(&self, file: *const u8, st: usize) -> i32 {
+		let file = unsafe { util::c_str_to_str(file) };
+		debug!("stat {}", file);
+
+		match fs::FILESYSTEM.stat(&file) {
+			Ok(attr) => {
+				unsafe {
+					*(st as *mut FileAttr) = attr;
+				}
+				0
+			}
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn fstat(&self, fd: i32, st: usize) -> i32 {
+		debug!("fstat {}", fd);
+
+		match fs::fd_call(fd as u64, |file| file.fstat()) {
+			Ok(attr) => {
+				unsafe {
+					*(st as *mut FileAttr) = attr;
+				}
+				0
+			}
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn fsync(&self, fd: i32, datasync: bool) -> i32 {
+		debug!("fsync {}", fd);
+
+		// the console is never buffered
+		if fd < 3 {
+			return 0;
+		}
 
-		ret as isize
+		match fs::fd_call(fd as u64, |file| file.fsync(datasync)) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
 	}
 
-	fn stat(&self, _file: *const u8, _st: usize) -> i32 {
-		info!("stat is unimplemented");
+	fn ftruncate(&self, fd: i32, size: u64) -> i32 {
+		debug!("ftruncate {}, {}", fd, size);
+
+		match fs::fd_call(fd as u64, |file| file.ftruncate(size)) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn mkdir(&self, name: *const u8, mode: u32) -> i32 {
+		let name = unsafe { util::c_str_to_str(name) };
+		debug!("mkdir {}, {:o}", name, mode);
+
+		match fs::FILESYSTEM.mkdir(&name, mode) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn rmdir(&self, name: *const u8) -> i32 {
+		let name = unsafe { util::c_str_to_str(name) };
+		debug!("rmdir {}", name);
+
+		match fs::FILESYSTEM.rmdir(&name) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn rename(&self, from: *const u8, to: *const u8) -> i32 {
+		let from = unsafe { util::c_str_to_str(from) };
+		let to = unsafe { util::c_str_to_str(to) };
+		debug!("rename {} {}", from, to);
+
+		match fs::FILESYSTEM.rename(&from, &to) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn symlink(&self, target: *const u8, path: *const u8) -> i32 {
+		let target = unsafe { util::c_str_to_str(target) };
+		let path = unsafe { util::c_str_to_str(path) };
+		debug!("symlink {} {}", target, path);
+
+		match fs::FILESYSTEM.symlink(&target, &path) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn readlink(&self, path: *const u8, buf: *mut u8, len: usize) -> isize {
+		let path = unsafe { util::c_str_to_str(path) };
+		debug!("readlink {}", path);
+
+		match fs::FILESYSTEM.readlink(&path) {
+			Ok(target) => {
+				// like on Linux, the target is truncated and not terminated by a null byte
+				let len = target.len().min(len);
+				unsafe {
+					slice::from_raw_parts_mut(buf, len).copy_from_slice(&target.as_bytes()[..len]);
+				}
+				len as isize
+			}
+			Err(error) => -error.errno() as isize,
+		}
+	}
+
+	fn link(&self, from: *const u8, to: *const u8) -> i32 {
+		let from = unsafe { util::c_str_to_str(from) };
+		let to = unsafe { util::c_str_to_str(to) };
+		debug!("link {} {}", from, to);
+
+		match fs::FILESYSTEM.link(&from, &to) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn getdents(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
+		debug!("getdents {}, {}", fd, len);
+
+		let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+		match fs::fd_call(fd as u64, |file| file.getdents(buf)) {
+			Ok(written) => written as isize,
+			Err(error) => -error.errno() as isize,
+		}
+	}
+
+	fn statfs(&self, path: *const u8, buf: *mut statfs) -> i32 {
+		let path = unsafe { util::c_str_to_str(path) };
+		debug!("statfs {}", path);
+
+		match fs::FILESYSTEM.statfs(&path) {
+			Ok(stat) => {
+				unsafe {
+					*buf = stat.into();
+				}
+				0
+			}
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn fallocate(&self, fd: i32, mode: u32, offset: u64, len: u64) -> i32 {
+		debug!("fallocate {}, {:x}, {}, {}", fd, mode, offset, len);
+
+		match fs::fd_call(fd as u64, |file| file.fallocate(mode, offset, len)) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn mmap(&self, fd: i32, offset: u64, len: usize, prot: i32, addr: *mut *mut u8) -> i32 {
+		debug!("mmap {}, {}, {}, {}", fd, offset, len, prot);
+
+		let writable = prot & PROT_WRITE != 0;
+		match fs::FILESYSTEM.mmap(fd as u64, offset, len, writable) {
+			Ok(mapping) => {
+				unsafe {
+					*addr = mapping as *mut u8;
+				}
+				0
+			}
+			Err(error) => -error.errno(),
+		}
+	}
+
+	fn munmap(&self, addr: *mut u8) -> i32 {
+		debug!("munmap {:p}", addr);
+
+		match fs::FILESYSTEM.munmap(addr as usize) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
+
+	#[cfg(not(target_arch = "x86_64"))]
+	fn mount(&self, _tag: *const u8, _mntpath: *const u8, _flags: i32) -> i32 {
+		debug!("mount is unimplemented, returning -ENOSYS");
 		-ENOSYS
 	}
+
+	#[cfg(target_arch = "x86_64")]
+	fn mount(&self, tag: *const u8, mntpath: *const u8, flags: i32) -> i32 {
+		let tag = unsafe { util::c_str_to_str(tag) };
+		let mntpath = unsafe { util::c_str_to_str(mntpath) };
+		debug!("mount {} {} {:x}", tag, mntpath, flags);
+
+		let read_only = flags & MOUNT_READ_ONLY != 0;
+		match arch::kernel::virtio_fs::mount(&tag, &mntpath, read_only) {
+			Ok(()) => 0,
+			Err(error) => -error.errno(),
+		}
+	}
 }
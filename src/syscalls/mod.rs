@@ -9,6 +9,8 @@
 #[cfg(not(feature = "newlib"))]
 use crate::drivers::net::*;
 use crate::environment;
+#[cfg(not(feature = "newlib"))]
+use crate::errno::*;
 #[cfg(feature = "newlib")]
 use crate::synch::spinlock::SpinlockIrqSave;
 use crate::syscalls::interfaces::SyscallInterface;
@@ -16,6 +18,13 @@ use crate::syscalls::interfaces::SyscallInterface;
 use crate::{__sys_free, __sys_malloc, __sys_realloc};
 
 pub use self::condvar::*;
+#[cfg(not(feature = "newlib"))]
+pub use self::event::*;
+#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+pub use self::net::*;
+pub use self::offload::*;
+#[cfg(not(feature = "newlib"))]
+pub use self::poll::*;
 pub use self::processor::*;
 pub use self::random::*;
 pub use self::recmutex::*;
@@ -24,12 +33,23 @@ pub use self::spinlock::*;
 pub use self::system::*;
 pub use self::tasks::*;
 pub use self::timer::*;
+#[cfg(all(target_arch = "x86_64", not(feature = "newlib")))]
+pub use self::vsock::{
+	sockaddr_vm, AF_VSOCK, VMADDR_CID_ANY, VMADDR_CID_HOST, VMADDR_CID_HYPERVISOR, VMADDR_PORT_ANY,
+};
 
 mod condvar;
+#[cfg(not(feature = "newlib"))]
+mod event;
 pub mod fs;
 mod interfaces;
 #[cfg(feature = "newlib")]
 mod lwip;
+#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+mod net;
+mod offload;
+#[cfg(not(feature = "newlib"))]
+mod poll;
 mod processor;
 mod random;
 mod recmutex;
@@ -38,10 +58,16 @@ mod spinlock;
 mod system;
 mod tasks;
 mod timer;
+#[cfg(all(target_arch = "x86_64", not(feature = "newlib")))]
+mod vsock;
 
 #[cfg(feature = "newlib")]
 const LWIP_FD_BIT: i32 = 1 << 30;
 
+/// Switches a file descriptor between blocking and non-blocking mode (compatible to lwIP)
+#[cfg(not(feature = "newlib"))]
+pub const FIONBIO: i32 = 0x8008_667eu32 as i32;
+
 #[cfg(feature = "newlib")]
 pub static LWIP_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(());
 
@@ -88,7 +114,7 @@ pub fn get_application_parameters() -> (i32, *const *const u8, *const *const u8)
 	unsafe { SYS.get_application_parameters() }
 }
 
-fn __sys_get_mac_address() -> Result<[u8; 6], ()> {
+pub(crate) fn __sys_get_mac_address() -> Result<[u8; 6], ()> {
 	unsafe { SYS.get_mac_address() }
 }
 
@@ -97,7 +123,7 @@ pub fn sys_get_mac_address() -> Result<[u8; 6], ()> {
 	kernel_function!(__sys_get_mac_address())
 }
 
-fn __sys_get_mtu() -> Result<u16, ()> {
+pub(crate) fn __sys_get_mtu() -> Result<u16, ()> {
 	unsafe { SYS.get_mtu() }
 }
 
@@ -106,7 +132,7 @@ pub fn sys_get_mtu() -> Result<u16, ()> {
 	kernel_function!(__sys_get_mtu())
 }
 
-fn __sys_get_tx_buffer(len: usize) -> Result<(*mut u8, usize), ()> {
+pub(crate) fn __sys_get_tx_buffer(len: usize) -> Result<(*mut u8, usize), ()> {
 	unsafe { SYS.get_tx_buffer(len) }
 }
 
@@ -115,7 +141,7 @@ pub fn sys_get_tx_buffer(len: usize) -> Result<(*mut u8, usize), ()> {
 	kernel_function!(__sys_get_tx_buffer(len))
 }
 
-fn __sys_send_tx_buffer(handle: usize, len: usize) -> Result<(), ()> {
+pub(crate) fn __sys_send_tx_buffer(handle: usize, len: usize) -> Result<(), ()> {
 	unsafe { SYS.send_tx_buffer(handle, len) }
 }
 
@@ -124,7 +150,17 @@ pub fn sys_send_tx_buffer(handle: usize, len: usize) -> Result<(), ()> {
 	kernel_function!(__sys_send_tx_buffer(handle, len))
 }
 
-fn __sys_receive_rx_buffer() -> Result<&'static [u8], ()> {
+pub(crate) fn __sys_release_tx_buffer(handle: usize) -> Result<(), ()> {
+	unsafe { SYS.release_tx_buffer(handle) }
+}
+
+/// Returns a TX buffer, which has been reserved by `sys_get_tx_buffer`, without sending it.
+#[no_mangle]
+pub fn sys_release_tx_buffer(handle: usize) -> Result<(), ()> {
+	kernel_function!(__sys_release_tx_buffer(handle))
+}
+
+pub(crate) fn __sys_receive_rx_buffer() -> Result<&'static [u8], ()> {
 	unsafe { SYS.receive_rx_buffer() }
 }
 
@@ -133,7 +169,7 @@ pub fn sys_receive_rx_buffer() -> Result<&'static [u8], ()> {
 	kernel_function!(__sys_receive_rx_buffer())
 }
 
-fn __sys_rx_buffer_consumed() -> Result<(), ()> {
+pub(crate) fn __sys_rx_buffer_consumed() -> Result<(), ()> {
 	unsafe { SYS.rx_buffer_consumed() }
 }
 
@@ -142,6 +178,131 @@ pub fn sys_rx_buffer_consumed() -> Result<(), ()> {
 	kernel_function!(__sys_rx_buffer_consumed())
 }
 
+pub(crate) fn __sys_get_net_offloads() -> Result<u32, ()> {
+	unsafe { SYS.get_net_offloads() }
+}
+
+/// Returns the offloading capabilities (`NET_OFFLOAD_*`) of the network device.
+#[no_mangle]
+pub fn sys_get_net_offloads() -> Result<u32, ()> {
+	kernel_function!(__sys_get_net_offloads())
+}
+
+pub(crate) fn __sys_send_tx_buffer_with_offload(
+	handle: usize,
+	len: usize,
+	offload: &TxOffload,
+) -> Result<(), ()> {
+	unsafe { SYS.send_tx_buffer_with_offload(handle, len, offload) }
+}
+
+/// Sends a TX buffer and delegates checksumming and segmentation to the device.
+/// Fails if the device does not support the requested offloads. In this case,
+/// the buffer is returned to the driver and the handle becomes invalid.
+#[no_mangle]
+pub fn sys_send_tx_buffer_with_offload(
+	handle: usize,
+	len: usize,
+	offload: &TxOffload,
+) -> Result<(), ()> {
+	kernel_function!(__sys_send_tx_buffer_with_offload(handle, len, offload))
+}
+
+pub(crate) fn __sys_receive_rx_buffer_with_flags() -> Result<(&'static [u8], u8), ()> {
+	unsafe { SYS.receive_rx_buffer_with_flags() }
+}
+
+/// Receives a frame together with its flags (`NET_RX_*`).
+#[no_mangle]
+pub fn sys_receive_rx_buffer_with_flags() -> Result<(&'static [u8], u8), ()> {
+	kernel_function!(__sys_receive_rx_buffer_with_flags())
+}
+
+pub(crate) fn __sys_get_net_queue_count() -> Result<usize, ()> {
+	unsafe { SYS.get_net_queue_count() }
+}
+
+/// Returns the number of queue pairs of the network device. Every core
+/// has its own queue pair, if the device supports multiple queues.
+#[no_mangle]
+pub fn sys_get_net_queue_count() -> Result<usize, ()> {
+	kernel_function!(__sys_get_net_queue_count())
+}
+
+pub(crate) fn __sys_get_tx_buffer_on_queue(
+	queue: usize,
+	len: usize,
+) -> Result<(*mut u8, usize), ()> {
+	unsafe { SYS.get_tx_buffer_on_queue(queue, len) }
+}
+
+/// Returns a TX buffer of the given queue pair. The buffer is sent by
+/// `sys_send_tx_buffer`, because the handle identifies the queue pair.
+#[no_mangle]
+pub fn sys_get_tx_buffer_on_queue(queue: usize, len: usize) -> Result<(*mut u8, usize), ()> {
+	kernel_function!(__sys_get_tx_buffer_on_queue(queue, len))
+}
+
+pub(crate) fn __sys_receive_rx_buffer_on_queue(queue: usize) -> Result<&'static [u8], ()> {
+	unsafe { SYS.receive_rx_buffer_on_queue(queue) }
+}
+
+#[no_mangle]
+pub fn sys_receive_rx_buffer_on_queue(queue: usize) -> Result<&'static [u8], ()> {
+	kernel_function!(__sys_receive_rx_buffer_on_queue(queue))
+}
+
+pub(crate) fn __sys_receive_rx_buffer_with_flags_on_queue(
+	queue: usize,
+) -> Result<(&'static [u8], u8), ()> {
+	unsafe { SYS.receive_rx_buffer_with_flags_on_queue(queue) }
+}
+
+#[no_mangle]
+pub fn sys_receive_rx_buffer_with_flags_on_queue(queue: usize) -> Result<(&'static [u8], u8), ()> {
+	kernel_function!(__sys_receive_rx_buffer_with_flags_on_queue(queue))
+}
+
+pub(crate) fn __sys_rx_buffer_consumed_on_queue(queue: usize) -> Result<(), ()> {
+	unsafe { SYS.rx_buffer_consumed_on_queue(queue) }
+}
+
+#[no_mangle]
+pub fn sys_rx_buffer_consumed_on_queue(queue: usize) -> Result<(), ()> {
+	kernel_function!(__sys_rx_buffer_consumed_on_queue(queue))
+}
+
+pub(crate) fn __sys_take_rx_buffer() -> Result<(usize, &'static mut [u8], u8), ()> {
+	unsafe { SYS.take_rx_buffer() }
+}
+
+/// Takes a received frame without copying it. The frame has to be returned
+/// by `sys_release_rx_buffer` with the token of the frame.
+#[no_mangle]
+pub fn sys_take_rx_buffer() -> Result<(usize, &'static mut [u8], u8), ()> {
+	kernel_function!(__sys_take_rx_buffer())
+}
+
+pub(crate) fn __sys_take_rx_buffer_on_queue(
+	queue: usize,
+) -> Result<(usize, &'static mut [u8], u8), ()> {
+	unsafe { SYS.take_rx_buffer_on_queue(queue) }
+}
+
+#[no_mangle]
+pub fn sys_take_rx_buffer_on_queue(queue: usize) -> Result<(usize, &'static mut [u8], u8), ()> {
+	kernel_function!(__sys_take_rx_buffer_on_queue(queue))
+}
+
+pub(crate) fn __sys_release_rx_buffer(token: usize) -> Result<(), ()> {
+	unsafe { SYS.release_rx_buffer(token) }
+}
+
+#[no_mangle]
+pub fn sys_release_rx_buffer(token: usize) -> Result<(), ()> {
+	kernel_function!(__sys_release_rx_buffer(token))
+}
+
 #[cfg(not(feature = "newlib"))]
 fn __sys_netwait(handle: usize, millis: Option<u64>) {
 	netwait(handle, millis)
@@ -190,6 +351,20 @@ pub extern "C" fn sys_open(name: *const u8, flags: i32, mode: i32) -> i32 {
 }
 
 fn __sys_close(fd: i32) -> i32 {
+	#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+	{
+		if crate::net::is_socket(fd) {
+			return socket_close(fd);
+		}
+	}
+
+	#[cfg(not(feature = "newlib"))]
+	{
+		if is_event_object(fd) {
+			return event_close(fd);
+		}
+	}
+
 	unsafe { SYS.close(fd) }
 }
 
@@ -199,6 +374,20 @@ pub extern "C" fn sys_close(fd: i32) -> i32 {
 }
 
 fn __sys_read(fd: i32, buf: *mut u8, len: usize) -> isize {
+	#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+	{
+		if crate::net::is_socket(fd) {
+			return socket_read(fd, buf, len);
+		}
+	}
+
+	#[cfg(not(feature = "newlib"))]
+	{
+		if is_event_object(fd) {
+			return event_read(fd, buf, len);
+		}
+	}
+
 	unsafe { SYS.read(fd, buf, len) }
 }
 #[no_mangle]
@@ -207,6 +396,20 @@ pub extern "C" fn sys_read(fd: i32, buf: *mut u8, len: usize) -> isize {
 }
 
 fn __sys_write(fd: i32, buf: *const u8, len: usize) -> isize {
+	#[cfg(all(feature = "tcp", not(feature = "newlib")))]
+	{
+		if crate::net::is_socket(fd) {
+			return socket_write(fd, buf, len);
+		}
+	}
+
+	#[cfg(not(feature = "newlib"))]
+	{
+		if is_event_object(fd) {
+			return event_write(fd, buf, len);
+		}
+	}
+
 	unsafe { SYS.write(fd, buf, len) }
 }
 
@@ -219,6 +422,41 @@ fn __sys_lseek(fd: i32, offset: isize, whence: i32) -> isize {
 	unsafe { SYS.lseek(fd, offset, whence) }
 }
 
+#[cfg(not(feature = "newlib"))]
+fn __sys_ioctl(fd: i32, cmd: i32, argp: *mut u8) -> i32 {
+	if cmd != FIONBIO {
+		debug!("sys_ioctl called with unsupported command {:#x}", cmd);
+		return -EINVAL;
+	}
+	if argp.is_null() {
+		return -EFAULT;
+	}
+
+	let nonblocking = unsafe { *(argp as *const i32) } != 0;
+
+	#[cfg(feature = "tcp")]
+	{
+		if crate::net::is_socket(fd) {
+			return socket_set_nonblocking(fd, nonblocking);
+		}
+	}
+
+	if is_event_object(fd) {
+		set_nonblocking(fd, nonblocking)
+			.map(|_| 0)
+			.unwrap_or_else(|e| e)
+	} else {
+		// Files and the console never block.
+		-ENOTTY
+	}
+}
+
+#[cfg(not(feature = "newlib"))]
+#[no_mangle]
+pub extern "C" fn sys_ioctl(fd: i32, cmd: i32, argp: *mut u8) -> i32 {
+	kernel_function!(__sys_ioctl(fd, cmd, argp))
+}
+
 #[no_mangle]
 pub extern "C" fn sys_lseek(fd: i32, offset: isize, whence: i32) -> isize {
 	kernel_function!(__sys_lseek(fd, offset, whence))
@@ -232,3 +470,157 @@ fn __sys_stat(file: *const u8, st: usize) -> i32 {
 pub extern "C" fn sys_stat(file: *const u8, st: usize) -> i32 {
 	kernel_function!(__sys_stat(file, st))
 }
+
+fn __sys_fstat(fd: i32, st: usize) -> i32 {
+	unsafe { SYS.fstat(fd, st) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_fstat(fd: i32, st: usize) -> i32 {
+	kernel_function!(__sys_fstat(fd, st))
+}
+
+fn __sys_fsync(fd: i32) -> i32 {
+	unsafe { SYS.fsync(fd, false) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_fsync(fd: i32) -> i32 {
+	kernel_function!(__sys_fsync(fd))
+}
+
+fn __sys_fdatasync(fd: i32) -> i32 {
+	unsafe { SYS.fsync(fd, true) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_fdatasync(fd: i32) -> i32 {
+	kernel_function!(__sys_fdatasync(fd))
+}
+
+fn __sys_ftruncate(fd: i32, size: u64) -> i32 {
+	unsafe { SYS.ftruncate(fd, size) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_ftruncate(fd: i32, size: u64) -> i32 {
+	kernel_function!(__sys_ftruncate(fd, size))
+}
+
+fn __sys_mkdir(name: *const u8, mode: u32) -> i32 {
+	unsafe { SYS.mkdir(name, mode) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_mkdir(name: *const u8, mode: u32) -> i32 {
+	kernel_function!(__sys_mkdir(name, mode))
+}
+
+fn __sys_rmdir(name: *const u8) -> i32 {
+	unsafe { SYS.rmdir(name) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_rmdir(name: *const u8) -> i32 {
+	kernel_function!(__sys_rmdir(name))
+}
+
+fn __sys_rename(from: *const u8, to: *const u8) -> i32 {
+	unsafe { SYS.rename(from, to) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_rename(from: *const u8, to: *const u8) -> i32 {
+	kernel_function!(__sys_rename(from, to))
+}
+
+fn __sys_symlink(target: *const u8, path: *const u8) -> i32 {
+	unsafe { SYS.symlink(target, path) }
+}
+
+/// Creates the symbolic link `path`, which points to `target`.
+#[no_mangle]
+pub extern "C" fn sys_symlink(target: *const u8, path: *const u8) -> i32 {
+	kernel_function!(__sys_symlink(target, path))
+}
+
+fn __sys_readlink(path: *const u8, buf: *mut u8, len: usize) -> isize {
+	unsafe { SYS.readlink(path, buf, len) }
+}
+
+/// Stores the target of the symbolic link `path` in `buf` without a terminating null byte.
+/// Returns the number of stored bytes or a negative errno value.
+#[no_mangle]
+pub extern "C" fn sys_readlink(path: *const u8, buf: *mut u8, len: usize) -> isize {
+	kernel_function!(__sys_readlink(path, buf, len))
+}
+
+fn __sys_link(from: *const u8, to: *const u8) -> i32 {
+	unsafe { SYS.link(from, to) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_link(from: *const u8, to: *const u8) -> i32 {
+	kernel_function!(__sys_link(from, to))
+}
+
+fn __sys_getdents64(fd: i32, buf: *mut u8, len: usize) -> isize {
+	unsafe { SYS.getdents(fd, buf, len) }
+}
+
+/// Stores the next entries of the directory `fd`, which has been opened with O_DIRECTORY,
+/// as `linux_dirent64` records in `buf`. Returns the number of stored bytes, 0 at the end
+/// of the directory or a negative errno value.
+#[no_mangle]
+pub extern "C" fn sys_getdents64(fd: i32, buf: *mut u8, len: usize) -> isize {
+	kernel_function!(__sys_getdents64(fd, buf, len))
+}
+
+fn __sys_statfs(path: *const u8, buf: *mut fs::statfs) -> i32 {
+	unsafe { SYS.statfs(path, buf) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_statfs(path: *const u8, buf: *mut fs::statfs) -> i32 {
+	kernel_function!(__sys_statfs(path, buf))
+}
+
+fn __sys_fallocate(fd: i32, mode: u32, offset: u64, len: u64) -> i32 {
+	unsafe { SYS.fallocate(fd, mode, offset, len) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_fallocate(fd: i32, mode: u32, offset: u64, len: u64) -> i32 {
+	kernel_function!(__sys_fallocate(fd, mode, offset, len))
+}
+
+fn __sys_mmap(fd: i32, offset: u64, len: usize, prot: i32, addr: *mut *mut u8) -> i32 {
+	unsafe { SYS.mmap(fd, offset, len, prot, addr) }
+}
+
+/// Maps `len` bytes of the file `fd` at `offset` into memory. The address of the
+/// mapping is stored in `addr`. Returns 0 on success or a negative errno value.
+#[no_mangle]
+pub extern "C" fn sys_mmap(fd: i32, offset: u64, len: usize, prot: i32, addr: *mut *mut u8) -> i32 {
+	kernel_function!(__sys_mmap(fd, offset, len, prot, addr))
+}
+
+fn __sys_munmap(addr: *mut u8) -> i32 {
+	unsafe { SYS.munmap(addr) }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_munmap(addr: *mut u8) -> i32 {
+	kernel_function!(__sys_munmap(addr))
+}
+
+fn __sys_mount(tag: *const u8, mntpath: *const u8, flags: i32) -> i32 {
+	unsafe { SYS.mount(tag, mntpath, flags) }
+}
+
+/// Mounts the virtio-fs share `tag` at `mntpath` (e.g. a share with the option noauto).
+/// With the flag 0x1, the share is mounted read-only. Returns 0 on success or a negative errno value.
+#[no_mangle]
+pub extern "C" fn sys_mount(tag: *const u8, mntpath: *const u8, flags: i32) -> i32 {
+	kernel_function!(__sys_mount(tag, mntpath, flags))
+}
@@ -0,0 +1,609 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! BSD socket interface of the in-kernel TCP/IP stack.
+//! The constants and structures are compatible to the ones of lwIP and newlib.
+
+use crate::errno::*;
+use crate::net::{
+	block_on_network, get_cached_socket_events, get_socket_events, with_network, Socket, WAITERS,
+};
+use crate::scheduler::task::{TaskHandle, TaskId};
+#[cfg(target_arch = "x86_64")]
+use crate::syscalls::event::is_event_object;
+use crate::syscalls::event::ObjectInterface;
+use crate::syscalls::timer::timeval;
+#[cfg(target_arch = "x86_64")]
+use crate::syscalls::vsock::{self, sockaddr_vm, AF_VSOCK};
+use crate::syscalls::POLLNVAL;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::{mem, slice};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address, Ipv6Address};
+
+pub const AF_INET: i32 = 2;
+pub const AF_INET6: i32 = 10;
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+pub const IPPROTO_IP: i32 = 0;
+pub const IPPROTO_TCP: i32 = 6;
+pub const IPPROTO_UDP: i32 = 17;
+pub const SOL_SOCKET: i32 = 0xfff;
+pub const SO_KEEPALIVE: i32 = 0x0008;
+pub const SO_SNDTIMEO: i32 = 0x1005;
+pub const SO_RCVTIMEO: i32 = 0x1006;
+pub const TCP_NODELAY: i32 = 0x01;
+pub const MSG_PEEK: i32 = 0x01;
+pub const MSG_DONTWAIT: i32 = 0x08;
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+pub type socklen_t = u32;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct sockaddr {
+	pub sa_len: u8,
+	pub sa_family: u8,
+	pub sa_data: [u8; 14],
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct in_addr {
+	pub s_addr: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct sockaddr_in {
+	pub sin_len: u8,
+	pub sin_family: u8,
+	pub sin_port: u16,
+	pub sin_addr: in_addr,
+	pub sin_zero: [u8; 8],
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct in6_addr {
+	pub s6_addr: [u8; 16],
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct sockaddr_in6 {
+	pub sin6_len: u8,
+	pub sin6_family: u8,
+	pub sin6_port: u16,
+	pub sin6_flowinfo: u32,
+	pub sin6_addr: in6_addr,
+	pub sin6_scope_id: u32,
+}
+
+/// Converts a socket address of the application into an endpoint of the stack.
+fn sockaddr_to_endpoint(addr: *const sockaddr, addrlen: socklen_t) -> Result<IpEndpoint, i32> {
+	if addr.is_null() || (addrlen as usize) < mem::size_of::<sockaddr_in>() {
+		return Err(-EINVAL);
+	}
+
+	match i32::from(unsafe { (*addr).sa_family }) {
+		AF_INET => {
+			let addr = unsafe { &*(addr as *const sockaddr_in) };
+			Ok(IpEndpoint::new(
+				IpAddress::Ipv4(Ipv4Address::from_bytes(&addr.sin_addr.s_addr.to_ne_bytes())),
+				u16::from_be(addr.sin_port),
+			))
+		}
+		AF_INET6 if addrlen as usize >= mem::size_of::<sockaddr_in6>() => {
+			let addr = unsafe { &*(addr as *const sockaddr_in6) };
+			Ok(IpEndpoint::new(
+				IpAddress::Ipv6(Ipv6Address::from_bytes(&addr.sin6_addr.s6_addr)),
+				u16::from_be(addr.sin6_port),
+			))
+		}
+		AF_INET6 => Err(-EINVAL),
+		_ => Err(-EAFNOSUPPORT),
+	}
+}
+
+/// Stores an endpoint of the stack as socket address of the application.
+fn endpoint_to_sockaddr(endpoint: IpEndpoint, addr: *mut sockaddr, addrlen: *mut socklen_t) {
+	if addr.is_null() || addrlen.is_null() {
+		return;
+	}
+
+	let available = unsafe { *addrlen } as usize;
+	match endpoint.addr {
+		IpAddress::Ipv6(address) => {
+			let sockaddr = sockaddr_in6 {
+				sin6_len: mem::size_of::<sockaddr_in6>() as u8,
+				sin6_family: AF_INET6 as u8,
+				sin6_port: endpoint.port.to_be(),
+				sin6_flowinfo: 0,
+				sin6_addr: in6_addr { s6_addr: address.0 },
+				sin6_scope_id: 0,
+			};
+			copy_sockaddr(&sockaddr, addr, available);
+			unsafe {
+				*addrlen = mem::size_of::<sockaddr_in6>() as socklen_t;
+			}
+		}
+		address => {
+			let octets = match address {
+				IpAddress::Ipv4(address) => address.0,
+				_ => [0; 4],
+			};
+			let sockaddr = sockaddr_in {
+				sin_len: mem::size_of::<sockaddr_in>() as u8,
+				sin_family: AF_INET as u8,
+				sin_port: endpoint.port.to_be(),
+				sin_addr: in_addr {
+					s_addr: u32::from_ne_bytes(octets),
+				},
+				sin_zero: [0; 8],
+			};
+			copy_sockaddr(&sockaddr, addr, available);
+			unsafe {
+				*addrlen = mem::size_of::<sockaddr_in>() as socklen_t;
+			}
+		}
+	}
+}
+
+/// Copies a socket address into the buffer of the application and truncates it if necessary.
+fn copy_sockaddr<T>(sockaddr: &T, addr: *mut sockaddr, available: usize) {
+	let len = mem::size_of::<T>().min(available);
+	unsafe {
+		let source = slice::from_raw_parts(sockaddr as *const T as *const u8, len);
+		slice::from_raw_parts_mut(addr as *mut u8, len).copy_from_slice(source);
+	}
+}
+
+/// Readiness of a socket, which is used by poll and epoll
+struct SocketObject(i32);
+
+impl ObjectInterface for SocketObject {
+	fn poll(&self) -> i16 {
+		get_cached_socket_events(self.0).map_or(POLLNVAL, |(events, _)| events)
+	}
+
+	fn register(&self, task: TaskHandle) {
+		WAITERS.register(task);
+	}
+
+	fn unregister(&self, id: TaskId) {
+		WAITERS.unregister(id);
+	}
+
+	fn generation(&self) -> u64 {
+		get_cached_socket_events(self.0).map_or(0, |(_, generation)| generation)
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+pub(crate) fn get_socket_object(fd: i32) -> Result<Arc<dyn ObjectInterface>, i32> {
+	get_socket_events(fd)?;
+	Ok(Arc::new(SocketObject(fd)))
+}
+
+/// Returns the receive timeout of the socket. Non-blocking sockets use a timeout of zero.
+fn get_recv_timeout(socket: &Socket) -> Option<u64> {
+	if socket.nonblocking {
+		Some(0)
+	} else {
+		socket.recv_timeout
+	}
+}
+
+/// Returns the send timeout of the socket. Non-blocking sockets use a timeout of zero.
+fn get_send_timeout(socket: &Socket) -> Option<u64> {
+	if socket.nonblocking {
+		Some(0)
+	} else {
+		socket.send_timeout
+	}
+}
+
+fn timeval_to_milliseconds(tv: &timeval) -> Option<u64> {
+	let milliseconds = tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+
+	// A zero timeout means that the operation never times out.
+	if milliseconds == 0 {
+		None
+	} else {
+		Some(milliseconds)
+	}
+}
+
+fn __sys_socket(domain: i32, type_: i32, protocol: i32) -> i32 {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if domain == AF_VSOCK {
+			return vsock::vsock_socket(type_, protocol);
+		}
+	}
+
+	if domain != AF_INET && domain != AF_INET6 {
+		debug!("sys_socket called with unsupported domain {}", domain);
+		return -EAFNOSUPPORT;
+	}
+
+	let stream = match (type_, protocol) {
+		(SOCK_STREAM, IPPROTO_IP) | (SOCK_STREAM, IPPROTO_TCP) => true,
+		(SOCK_DGRAM, IPPROTO_IP) | (SOCK_DGRAM, IPPROTO_UDP) => false,
+		_ => {
+			debug!(
+				"sys_socket called with unsupported type {} and protocol {}",
+				type_, protocol
+			);
+			return -EPROTONOSUPPORT;
+		}
+	};
+
+	with_network(|network| Ok(network.socket(stream))).unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_socket(domain: i32, type_: i32, protocol: i32) -> i32 {
+	kernel_function!(__sys_socket(domain, type_, protocol))
+}
+
+fn __sys_bind(fd: i32, addr: *const sockaddr, addrlen: socklen_t) -> i32 {
+	// vsock sockets are event objects
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_bind(fd, addr as *const sockaddr_vm, addrlen);
+		}
+	}
+
+	let endpoint = match sockaddr_to_endpoint(addr, addrlen) {
+		Ok(endpoint) => endpoint,
+		Err(e) => return e,
+	};
+
+	with_network(|network| network.bind(fd, endpoint))
+		.map(|_| 0)
+		.unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_bind(fd: i32, addr: *const sockaddr, addrlen: socklen_t) -> i32 {
+	kernel_function!(__sys_bind(fd, addr, addrlen))
+}
+
+fn __sys_listen(fd: i32, backlog: i32) -> i32 {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_listen(fd, backlog);
+		}
+	}
+
+	with_network(|network| network.listen(fd, backlog.max(1) as usize))
+		.map(|_| 0)
+		.unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_listen(fd: i32, backlog: i32) -> i32 {
+	kernel_function!(__sys_listen(fd, backlog))
+}
+
+fn __sys_accept(fd: i32, addr: *mut sockaddr, addrlen: *mut socklen_t) -> i32 {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_accept(fd, addr as *mut sockaddr_vm, addrlen);
+		}
+	}
+
+	let timeout = match with_network(|network| Ok(get_recv_timeout(network.get_socket(fd)?))) {
+		Ok(timeout) => timeout,
+		Err(e) => return e,
+	};
+
+	match block_on_network(timeout, |network| network.accept(fd)) {
+		Ok((new_fd, endpoint)) => {
+			endpoint_to_sockaddr(endpoint, addr, addrlen);
+			new_fd
+		}
+		Err(e) => e,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_accept(fd: i32, addr: *mut sockaddr, addrlen: *mut socklen_t) -> i32 {
+	kernel_function!(__sys_accept(fd, addr, addrlen))
+}
+
+fn __sys_connect(fd: i32, addr: *const sockaddr, addrlen: socklen_t) -> i32 {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_connect(fd, addr as *const sockaddr_vm, addrlen);
+		}
+	}
+
+	let endpoint = match sockaddr_to_endpoint(addr, addrlen) {
+		Ok(endpoint) => endpoint,
+		Err(e) => return e,
+	};
+
+	let timeout = match with_network(|network| {
+		network.connect(fd, endpoint)?;
+		let socket = network.get_socket(fd)?;
+		if socket.nonblocking {
+			// The application has to wait for POLLOUT.
+			Err(-EINPROGRESS)
+		} else {
+			Ok(socket.send_timeout)
+		}
+	}) {
+		Ok(timeout) => timeout,
+		Err(e) => return e,
+	};
+
+	// Wait for the TCP handshake.
+	block_on_network(timeout, |network| network.is_connected(fd))
+		.map(|_| 0)
+		.unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_connect(fd: i32, addr: *const sockaddr, addrlen: socklen_t) -> i32 {
+	kernel_function!(__sys_connect(fd, addr, addrlen))
+}
+
+fn send_to(fd: i32, buf: *const u8, len: usize, flags: i32, endpoint: Option<IpEndpoint>) -> isize {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_send(fd, buf, len, flags);
+		}
+	}
+
+	let buffer = unsafe { slice::from_raw_parts(buf, len) };
+	let timeout = if flags & MSG_DONTWAIT != 0 {
+		Some(0)
+	} else {
+		match with_network(|network| Ok(get_send_timeout(network.get_socket(fd)?))) {
+			Ok(timeout) => timeout,
+			Err(e) => return e as isize,
+		}
+	};
+
+	match block_on_network(timeout, |network| network.send(fd, buffer, endpoint)) {
+		Ok(len) => len as isize,
+		Err(e) => e as isize,
+	}
+}
+
+fn __sys_send(fd: i32, buf: *const u8, len: usize, flags: i32) -> isize {
+	send_to(fd, buf, len, flags, None)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_send(fd: i32, buf: *const u8, len: usize, flags: i32) -> isize {
+	kernel_function!(__sys_send(fd, buf, len, flags))
+}
+
+fn __sys_sendto(
+	fd: i32,
+	buf: *const u8,
+	len: usize,
+	flags: i32,
+	addr: *const sockaddr,
+	addrlen: socklen_t,
+) -> isize {
+	if addr.is_null() {
+		return send_to(fd, buf, len, flags, None);
+	}
+
+	match sockaddr_to_endpoint(addr, addrlen) {
+		Ok(endpoint) => send_to(fd, buf, len, flags, Some(endpoint)),
+		Err(e) => e as isize,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_sendto(
+	fd: i32,
+	buf: *const u8,
+	len: usize,
+	flags: i32,
+	addr: *const sockaddr,
+	addrlen: socklen_t,
+) -> isize {
+	kernel_function!(__sys_sendto(fd, buf, len, flags, addr, addrlen))
+}
+
+fn __sys_recvfrom(
+	fd: i32,
+	buf: *mut u8,
+	len: usize,
+	flags: i32,
+	addr: *mut sockaddr,
+	addrlen: *mut socklen_t,
+) -> isize {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_recv(fd, buf, len, flags);
+		}
+	}
+
+	let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+	let timeout = if flags & MSG_DONTWAIT != 0 {
+		Some(0)
+	} else {
+		match with_network(|network| Ok(get_recv_timeout(network.get_socket(fd)?))) {
+			Ok(timeout) => timeout,
+			Err(e) => return e as isize,
+		}
+	};
+
+	match block_on_network(timeout, |network| {
+		network.recv(fd, buffer, flags & MSG_PEEK != 0)
+	}) {
+		Ok((len, endpoint)) => {
+			endpoint_to_sockaddr(endpoint, addr, addrlen);
+			len as isize
+		}
+		Err(e) => e as isize,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_recvfrom(
+	fd: i32,
+	buf: *mut u8,
+	len: usize,
+	flags: i32,
+	addr: *mut sockaddr,
+	addrlen: *mut socklen_t,
+) -> isize {
+	kernel_function!(__sys_recvfrom(fd, buf, len, flags, addr, addrlen))
+}
+
+fn __sys_recv(fd: i32, buf: *mut u8, len: usize, flags: i32) -> isize {
+	__sys_recvfrom(
+		fd,
+		buf,
+		len,
+		flags,
+		core::ptr::null_mut(),
+		core::ptr::null_mut(),
+	)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_recv(fd: i32, buf: *mut u8, len: usize, flags: i32) -> isize {
+	kernel_function!(__sys_recv(fd, buf, len, flags))
+}
+
+fn __sys_shutdown_socket(fd: i32, how: i32) -> i32 {
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_event_object(fd) {
+			return vsock::vsock_shutdown(fd, how);
+		}
+	}
+
+	let (read, write) = match how {
+		SHUT_RD => (true, false),
+		SHUT_WR => (false, true),
+		SHUT_RDWR => (true, true),
+		_ => return -EINVAL,
+	};
+
+	with_network(|network| network.shutdown(fd, read, write))
+		.map(|_| 0)
+		.unwrap_or_else(|e| e)
+}
+
+/// Shuts down a part of a full-duplex connection.
+/// Named differently than `shutdown` to avoid a conflict with `sys_shutdown`.
+#[no_mangle]
+pub extern "C" fn sys_shutdown_socket(fd: i32, how: i32) -> i32 {
+	kernel_function!(__sys_shutdown_socket(fd, how))
+}
+
+fn __sys_setsockopt(
+	fd: i32,
+	level: i32,
+	optname: i32,
+	optval: *const u8,
+	optlen: socklen_t,
+) -> i32 {
+	if optval.is_null() {
+		return -EINVAL;
+	}
+
+	let result = match (level, optname) {
+		(SOL_SOCKET, SO_KEEPALIVE) if optlen as usize >= mem::size_of::<i32>() => {
+			let enable = unsafe { *(optval as *const i32) } != 0;
+			with_network(|network| network.set_keep_alive(fd, enable))
+		}
+		(SOL_SOCKET, SO_RCVTIMEO) | (SOL_SOCKET, SO_SNDTIMEO)
+			if optlen as usize >= mem::size_of::<timeval>() =>
+		{
+			let timeout = timeval_to_milliseconds(unsafe { &*(optval as *const timeval) });
+			with_network(|network| {
+				let socket = network.get_socket(fd)?;
+				if optname == SO_RCVTIMEO {
+					socket.recv_timeout = timeout;
+				} else {
+					socket.send_timeout = timeout;
+				}
+				Ok(())
+			})
+		}
+		(IPPROTO_TCP, TCP_NODELAY) if optlen as usize >= mem::size_of::<i32>() => {
+			// smoltcp does not implement Nagle's algorithm, so TCP_NODELAY is always enabled.
+			with_network(|network| {
+				if network.is_stream(fd)? {
+					Ok(())
+				} else {
+					Err(-ENOPROTOOPT)
+				}
+			})
+		}
+		(SOL_SOCKET, _) | (IPPROTO_TCP, _) => Err(-EINVAL),
+		_ => {
+			debug!(
+				"sys_setsockopt called with unsupported option {} at level {}",
+				optname, level
+			);
+			Err(-ENOPROTOOPT)
+		}
+	};
+
+	result.map(|_| 0).unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_setsockopt(
+	fd: i32,
+	level: i32,
+	optname: i32,
+	optval: *const u8,
+	optlen: socklen_t,
+) -> i32 {
+	kernel_function!(__sys_setsockopt(fd, level, optname, optval, optlen))
+}
+
+/// Called by sys_read for file descriptors of sockets
+pub fn socket_read(fd: i32, buf: *mut u8, len: usize) -> isize {
+	__sys_recv(fd, buf, len, 0)
+}
+
+/// Called by sys_write for file descriptors of sockets
+pub fn socket_write(fd: i32, buf: *const u8, len: usize) -> isize {
+	__sys_send(fd, buf, len, 0)
+}
+
+/// Called by sys_close for file descriptors of sockets
+pub fn socket_close(fd: i32) -> i32 {
+	with_network(|network| network.close(fd))
+		.map(|_| 0)
+		.unwrap_or_else(|e| e)
+}
+
+/// Called by sys_ioctl (FIONBIO) for file descriptors of sockets
+pub fn socket_set_nonblocking(fd: i32, nonblocking: bool) -> i32 {
+	with_network(|network| {
+		network.get_socket(fd)?.nonblocking = nonblocking;
+		Ok(0)
+	})
+	.unwrap_or_else(|e| e)
+}
@@ -0,0 +1,51 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Offloading capabilities of the raw frame interface (see `sys_get_net_offloads`).
+
+/// The device computes the checksums of transmitted frames.
+pub const NET_OFFLOAD_TX_CSUM: u32 = 1 << 0;
+/// The device segments large TCP/IPv4 frames.
+pub const NET_OFFLOAD_TX_TSO4: u32 = 1 << 1;
+/// The device segments large TCP/IPv6 frames.
+pub const NET_OFFLOAD_TX_TSO6: u32 = 1 << 2;
+/// The device fragments large UDP frames.
+pub const NET_OFFLOAD_TX_UFO: u32 = 1 << 3;
+/// Received frames may be flagged with `NET_RX_CSUM_VALID`.
+pub const NET_OFFLOAD_RX_CSUM: u32 = 1 << 4;
+/// Received TCP frames may be larger than the MTU, because the device coalesces segments.
+pub const NET_OFFLOAD_RX_TSO: u32 = 1 << 5;
+
+/// The checksums of the received frame are known to be correct.
+pub const NET_RX_CSUM_VALID: u8 = 1 << 0;
+
+/// No segmentation
+pub const NET_GSO_NONE: u8 = 0;
+/// TCP segmentation of an IPv4 frame
+pub const NET_GSO_TCPV4: u8 = 1;
+/// UDP fragmentation
+pub const NET_GSO_UDP: u8 = 3;
+/// TCP segmentation of an IPv6 frame
+pub const NET_GSO_TCPV6: u8 = 4;
+
+/// Offloading request for a transmitted frame
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TxOffload {
+	/// The device computes the checksum from `csum_start` to the end of the frame
+	/// and stores it at `csum_start + csum_offset`. The checksum field has to contain
+	/// the checksum of the pseudo header.
+	pub csum: bool,
+	pub csum_start: u16,
+	pub csum_offset: u16,
+	/// Segmentation type (`NET_GSO_*`), which requires checksum offloading
+	pub gso_type: u8,
+	/// Maximum payload size of a segment
+	pub gso_size: u16,
+	/// Length of the Ethernet, IP and TCP/UDP headers
+	pub hdr_len: u16,
+}
@@ -0,0 +1,489 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Readiness multiplexing (poll and epoll) over files, sockets and event objects.
+//! The constants and structures are compatible to the ones of Linux.
+
+use crate::arch;
+use crate::errno::*;
+use crate::scheduler::task::{TaskHandle, TaskId};
+use crate::synch::spinlock::SpinlockIrqSave;
+use crate::synch::waitqueue::WaitQueue;
+use crate::syscalls::event::{
+	get_object, insert_object, is_event_object, wait_for_event, ObjectInterface, O_CLOEXEC,
+};
+use crate::syscalls::fs::is_open_file;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::slice;
+
+pub const POLLIN: i16 = 0x1;
+pub const POLLPRI: i16 = 0x2;
+pub const POLLOUT: i16 = 0x4;
+pub const POLLERR: i16 = 0x8;
+pub const POLLHUP: i16 = 0x10;
+pub const POLLNVAL: i16 = 0x20;
+pub const POLLRDHUP: i16 = 0x2000;
+
+pub const EPOLLIN: u32 = 0x1;
+pub const EPOLLPRI: u32 = 0x2;
+pub const EPOLLOUT: u32 = 0x4;
+pub const EPOLLERR: u32 = 0x8;
+pub const EPOLLHUP: u32 = 0x10;
+pub const EPOLLRDHUP: u32 = 0x2000;
+pub const EPOLLONESHOT: u32 = 1 << 30;
+pub const EPOLLET: u32 = 1 << 31;
+pub const EPOLL_CLOEXEC: i32 = O_CLOEXEC;
+pub const EPOLL_CTL_ADD: i32 = 1;
+pub const EPOLL_CTL_DEL: i32 = 2;
+pub const EPOLL_CTL_MOD: i32 = 3;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct pollfd {
+	pub fd: i32,
+	pub events: i16,
+	pub revents: i16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct epoll_event {
+	pub events: u32,
+	pub data: u64,
+}
+
+/// Regular files and the console, which never block
+struct AlwaysReady(i16);
+
+impl ObjectInterface for AlwaysReady {
+	fn poll(&self) -> i16 {
+		self.0
+	}
+
+	fn register(&self, _task: TaskHandle) {}
+
+	fn unregister(&self, _id: TaskId) {}
+
+	fn generation(&self) -> u64 {
+		0
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+fn is_regular_file(fd: i32) -> bool {
+	#[cfg(feature = "tcp")]
+	{
+		if crate::net::is_socket(fd) {
+			return false;
+		}
+	}
+
+	fd > 2 && !is_event_object(fd) && is_open_file(fd as u64)
+}
+
+/// Returns the object, which describes the readiness of the file descriptor.
+fn get_pollable(fd: i32) -> Result<Arc<dyn ObjectInterface>, i32> {
+	#[cfg(feature = "tcp")]
+	{
+		if crate::net::is_socket(fd) {
+			return crate::syscalls::net::get_socket_object(fd);
+		}
+	}
+
+	if is_event_object(fd) {
+		return get_object(fd).map(|(object, _)| object);
+	}
+
+	match fd {
+		// Input of the console is not supported.
+		0 => Ok(Arc::new(AlwaysReady(0))),
+		1 | 2 => Ok(Arc::new(AlwaysReady(POLLOUT))),
+		_ if fd > 2 && is_open_file(fd as u64) => Ok(Arc::new(AlwaysReady(POLLIN | POLLOUT))),
+		_ => Err(-EBADF),
+	}
+}
+
+/// Converts a timeout in milliseconds into a deadline in microseconds since boot.
+/// A negative timeout means that the task waits infinitely.
+fn timeout_to_deadline(timeout: i32) -> Option<u64> {
+	if timeout < 0 {
+		None
+	} else {
+		Some(arch::processor::get_timer_ticks() + timeout as u64 * 1000)
+	}
+}
+
+/// Updates the returned events of all entries and returns the number of ready entries.
+fn poll_all(fds: &mut [pollfd], objects: &[Option<Arc<dyn ObjectInterface>>]) -> i32 {
+	let mut count = 0;
+
+	for (pollfd, object) in fds.iter_mut().zip(objects.iter()) {
+		pollfd.revents = match object {
+			Some(object) => object.poll() & (pollfd.events | POLLERR | POLLHUP),
+			// Negative file descriptors are ignored.
+			None if pollfd.fd < 0 => 0,
+			None => POLLNVAL,
+		};
+
+		if pollfd.revents != 0 {
+			count += 1;
+		}
+	}
+
+	count
+}
+
+fn __sys_poll(fds: *mut pollfd, nfds: usize, timeout: i32) -> i32 {
+	let fds: &mut [pollfd] = if nfds == 0 {
+		&mut []
+	} else if fds.is_null() {
+		return -EFAULT;
+	} else {
+		unsafe { slice::from_raw_parts_mut(fds, nfds) }
+	};
+
+	let objects: Vec<Option<Arc<dyn ObjectInterface>>> = fds
+		.iter()
+		.map(|pollfd| get_pollable(pollfd.fd).ok())
+		.collect();
+	let waitable: Vec<Arc<dyn ObjectInterface>> = objects.iter().flatten().cloned().collect();
+	let deadline = timeout_to_deadline(timeout);
+
+	loop {
+		let count = poll_all(fds, &objects);
+		if count > 0 {
+			return count;
+		}
+
+		let is_ready = wait_for_event(&waitable, deadline, || {
+			fds.iter()
+				.zip(objects.iter())
+				.any(|(pollfd, object)| match object {
+					Some(object) => object.poll() & (pollfd.events | POLLERR | POLLHUP) != 0,
+					None => false,
+				})
+		});
+
+		if !is_ready {
+			return 0;
+		}
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_poll(fds: *mut pollfd, nfds: usize, timeout: i32) -> i32 {
+	kernel_function!(__sys_poll(fds, nfds, timeout))
+}
+
+struct Interest {
+	/// Requested events and flags (EPOLLET, EPOLLONESHOT)
+	events: u32,
+	data: u64,
+	/// A one-shot interest is disabled after it has reported an event.
+	enabled: bool,
+	/// Generation of the object, at which an edge-triggered interest has reported an event
+	last_generation: Option<u64>,
+}
+
+impl Interest {
+	/// Returns the events, which have to be reported to the application,
+	/// along with the generation of the object.
+	fn check(&self, object: &dyn ObjectInterface) -> (u32, u64) {
+		if !self.enabled {
+			return (0, 0);
+		}
+
+		// Read the generation first to avoid missing an event in between.
+		let generation = object.generation();
+		let events = object.poll() as u16 as u32 & (self.events | EPOLLERR | EPOLLHUP);
+		if self.events & EPOLLET != 0 && self.last_generation == Some(generation) {
+			// The event has already been reported and nothing has happened since then.
+			(0, generation)
+		} else {
+			(events, generation)
+		}
+	}
+}
+
+pub struct Epoll {
+	/// Registered file descriptors
+	interests: SpinlockIrqSave<BTreeMap<i32, Interest>>,
+	/// Notifies waiting tasks about changes of the interest list
+	queue: WaitQueue,
+	/// Generations of the interest list and of the monitored objects, which have been
+	/// observed last, along with the number of observed changes
+	observed: SpinlockIrqSave<(Vec<(i32, u64)>, u64)>,
+}
+
+impl Epoll {
+	/// Stores the ready events in `events` and returns their number.
+	fn collect(&self, events: &mut [epoll_event]) -> usize {
+		let mut interests = self.interests.lock();
+		let mut closed = Vec::new();
+		let mut count = 0;
+
+		for (fd, interest) in interests.iter_mut() {
+			let object = match get_pollable(*fd) {
+				Ok(object) => object,
+				Err(_) => {
+					closed.push(*fd);
+					continue;
+				}
+			};
+
+			if count == events.len() {
+				break;
+			}
+
+			let (ready, generation) = interest.check(&*object);
+			if ready != 0 {
+				events[count] = epoll_event {
+					events: ready,
+					data: interest.data,
+				};
+				count += 1;
+
+				if interest.events & EPOLLET != 0 {
+					interest.last_generation = Some(generation);
+				}
+				if interest.events & EPOLLONESHOT != 0 {
+					interest.enabled = false;
+				}
+			}
+		}
+
+		// Closed file descriptors are removed automatically.
+		for fd in closed {
+			interests.remove(&fd);
+		}
+
+		count
+	}
+
+	fn has_events(&self) -> bool {
+		self.interests.lock().iter().any(|(fd, interest)| {
+			get_pollable(*fd).map_or(false, |object| interest.check(&*object).0 != 0)
+		})
+	}
+
+	fn control(&self, op: i32, fd: i32, event: Option<epoll_event>) -> Result<(), i32> {
+		get_pollable(fd)?;
+		if is_regular_file(fd) {
+			// Like on Linux, regular files are always ready and cannot be monitored.
+			return Err(-EPERM);
+		}
+
+		{
+			let mut interests = self.interests.lock();
+			match (op, event) {
+				(EPOLL_CTL_ADD, Some(event)) => {
+					if interests.contains_key(&fd) {
+						return Err(-EEXIST);
+					}
+					interests.insert(
+						fd,
+						Interest {
+							events: event.events,
+							data: event.data,
+							enabled: true,
+							last_generation: None,
+						},
+					);
+				}
+				(EPOLL_CTL_MOD, Some(event)) => {
+					let interest = interests.get_mut(&fd).ok_or(-ENOENT)?;
+					interest.events = event.events;
+					interest.data = event.data;
+					interest.enabled = true;
+					interest.last_generation = None;
+				}
+				(EPOLL_CTL_DEL, _) => {
+					interests.remove(&fd).ok_or(-ENOENT)?;
+				}
+				(EPOLL_CTL_ADD, None) | (EPOLL_CTL_MOD, None) => return Err(-EFAULT),
+				_ => return Err(-EINVAL),
+			}
+		}
+
+		// Waiting tasks have to check the new interest list.
+		self.queue.wakeup_all();
+		Ok(())
+	}
+}
+
+impl ObjectInterface for Epoll {
+	fn poll(&self) -> i16 {
+		if self.has_events() {
+			POLLIN
+		} else {
+			0
+		}
+	}
+
+	fn register(&self, task: TaskHandle) {
+		self.queue.register(task);
+		for fd in self.interests.lock().keys() {
+			if let Ok(object) = get_pollable(*fd) {
+				object.register(task);
+			}
+		}
+	}
+
+	fn unregister(&self, id: TaskId) {
+		self.queue.unregister(id);
+		for fd in self.interests.lock().keys() {
+			if let Ok(object) = get_pollable(*fd) {
+				object.unregister(id);
+			}
+		}
+	}
+
+	fn deadline(&self) -> Option<u64> {
+		self.interests
+			.lock()
+			.keys()
+			.filter_map(|fd| get_pollable(*fd).ok())
+			.filter_map(|object| object.deadline())
+			.min()
+	}
+
+	fn generation(&self) -> u64 {
+		// A sum of the generations may repeat after the interest list has changed.
+		// Therefore, the generation of each object is compared with the last observed one.
+		let mut generations = vec![(-1, self.queue.generation())];
+		generations.extend(self.interests.lock().keys().filter_map(|fd| {
+			get_pollable(*fd)
+				.ok()
+				.map(|object| (*fd, object.generation()))
+		}));
+
+		let mut observed = self.observed.lock();
+		if observed.0 != generations {
+			observed.0 = generations;
+			observed.1 += 1;
+		}
+		observed.1
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+fn get_epoll(epfd: i32) -> Result<Arc<dyn ObjectInterface>, i32> {
+	let (object, _) = get_object(epfd)?;
+	if object.as_any().is::<Epoll>() {
+		Ok(object)
+	} else {
+		Err(-EINVAL)
+	}
+}
+
+fn __sys_epoll_create1(flags: i32) -> i32 {
+	if flags & !EPOLL_CLOEXEC != 0 {
+		return -EINVAL;
+	}
+
+	let epoll = Epoll {
+		interests: SpinlockIrqSave::new(BTreeMap::new()),
+		queue: WaitQueue::new(),
+		observed: SpinlockIrqSave::new((Vec::new(), 0)),
+	};
+
+	insert_object(Arc::new(epoll), false)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_epoll_create1(flags: i32) -> i32 {
+	kernel_function!(__sys_epoll_create1(flags))
+}
+
+fn __sys_epoll_create(size: i32) -> i32 {
+	// The size is only a hint, which has been ignored by Linux since 2.6.8.
+	if size <= 0 {
+		return -EINVAL;
+	}
+
+	__sys_epoll_create1(0)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_epoll_create(size: i32) -> i32 {
+	kernel_function!(__sys_epoll_create(size))
+}
+
+fn __sys_epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut epoll_event) -> i32 {
+	if epfd == fd {
+		return -EINVAL;
+	}
+
+	let object = match get_epoll(epfd) {
+		Ok(object) => object,
+		Err(e) => return e,
+	};
+	let epoll = object.as_any().downcast_ref::<Epoll>().unwrap();
+	let event = if event.is_null() {
+		None
+	} else {
+		Some(unsafe { *event })
+	};
+
+	epoll
+		.control(op, fd, event)
+		.map(|_| 0)
+		.unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut epoll_event) -> i32 {
+	kernel_function!(__sys_epoll_ctl(epfd, op, fd, event))
+}
+
+fn __sys_epoll_wait(epfd: i32, events: *mut epoll_event, maxevents: i32, timeout: i32) -> i32 {
+	if maxevents <= 0 {
+		return -EINVAL;
+	}
+	if events.is_null() {
+		return -EFAULT;
+	}
+
+	let object = match get_epoll(epfd) {
+		Ok(object) => object,
+		Err(e) => return e,
+	};
+	let epoll = object.as_any().downcast_ref::<Epoll>().unwrap();
+	let events = unsafe { slice::from_raw_parts_mut(events, maxevents as usize) };
+	let deadline = timeout_to_deadline(timeout);
+
+	loop {
+		let count = epoll.collect(events);
+		if count > 0 {
+			return count as i32;
+		}
+
+		// The epoll object registers the task on all objects of its interest list.
+		if !wait_for_event(slice::from_ref(&object), deadline, || epoll.has_events()) {
+			return 0;
+		}
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_epoll_wait(
+	epfd: i32,
+	events: *mut epoll_event,
+	maxevents: i32,
+	timeout: i32,
+) -> i32 {
+	kernel_function!(__sys_epoll_wait(epfd, events, maxevents, timeout))
+}
@@ -6,6 +6,8 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::arch::get_processor_count;
+use crate::errno::*;
+use crate::scheduler;
 use core::convert::TryInto;
 
 fn __sys_get_processor_count() -> usize {
@@ -27,3 +29,26 @@ fn __sys_get_processor_frequency() -> u16 {
 pub extern "C" fn sys_get_processor_frequency() -> u16 {
 	kernel_function!(__sys_get_processor_frequency())
 }
+
+fn __sys_get_idle_time(core_id: u32, idle_time: *mut u64) -> i32 {
+	assert!(
+		!idle_time.is_null(),
+		"sys_get_idle_time called with a zero idle_time parameter"
+	);
+
+	match scheduler::get_idle_time(core_id) {
+		Some(time) => {
+			unsafe {
+				*idle_time = time;
+			}
+			0
+		}
+		None => -EINVAL,
+	}
+}
+
+/** Returns the time (in nanoseconds), which the given core has spent in an idle state. */
+#[no_mangle]
+pub extern "C" fn sys_get_idle_time(core_id: u32, idle_time: *mut u64) -> i32 {
+	kernel_function!(__sys_get_idle_time(core_id, idle_time))
+}
@@ -6,11 +6,19 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::arch;
+use crate::entropy;
+use crate::errno::*;
 use crate::synch::spinlock::Spinlock;
+use core::slice;
 
 static PARK_MILLER_LEHMER_SEED: Spinlock<u32> = Spinlock::new(0);
 const RAND_MAX: u64 = 2_147_483_647;
 
+/// sys_getrandom fails instead of blocking, if the generator hasn't been seeded yet
+pub const GRND_NONBLOCK: u32 = 0x0001;
+/// The kernel has only one generator, so the flag is accepted but ignored.
+pub const GRND_RANDOM: u32 = 0x0002;
+
 fn generate_park_miller_lehmer_random_number() -> u32 {
 	let mut seed = PARK_MILLER_LEHMER_SEED.lock();
 	let random = ((u64::from(*seed) * 48271) % RAND_MAX) as u32;
@@ -19,35 +27,68 @@ fn generate_park_miller_lehmer_random_number() -> u32 {
 }
 
 fn __sys_rand32() -> Option<u32> {
-	arch::processor::generate_random_number32()
+	let mut buf = [0u8; 4];
+	entropy::fill(&mut buf);
+	Some(u32::from_ne_bytes(buf))
 }
 
 fn __sys_rand64() -> Option<u64> {
-	arch::processor::generate_random_number64()
+	let mut buf = [0u8; 8];
+	entropy::fill(&mut buf);
+	Some(u64::from_ne_bytes(buf))
 }
 
 fn __sys_rand() -> u32 {
 	generate_park_miller_lehmer_random_number()
 }
 
-/// Create a cryptographicly secure 32bit random number with the support of
-/// the underlying hardware. If the required hardware isn't available,
-/// the function returns `None`.
+/// Create a cryptographicly secure 32bit random number with the generator of the kernel.
+/// The function waits for the initial seeding of the generator and never returns `None`.
 #[cfg(not(feature = "newlib"))]
 #[no_mangle]
 pub fn sys_secure_rand32() -> Option<u32> {
 	kernel_function!(__sys_rand32())
 }
 
-/// Create a cryptographicly secure 64bit random number with the support of
-/// the underlying hardware. If the required hardware isn't available,
-/// the function returns `None`.
+/// Create a cryptographicly secure 64bit random number with the generator of the kernel.
+/// The function waits for the initial seeding of the generator and never returns `None`.
 #[cfg(not(feature = "newlib"))]
 #[no_mangle]
 pub fn sys_secure_rand64() -> Option<u64> {
 	kernel_function!(__sys_rand64())
 }
 
+fn __sys_getrandom(buf: *mut u8, len: usize, flags: u32) -> isize {
+	if flags & !(GRND_NONBLOCK | GRND_RANDOM) != 0 {
+		return -EINVAL as isize;
+	}
+	if len == 0 {
+		return 0;
+	}
+	if buf.is_null() {
+		return -EFAULT as isize;
+	}
+
+	let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+	if flags & GRND_NONBLOCK != 0 {
+		if !entropy::try_fill(buffer) {
+			return -EAGAIN as isize;
+		}
+	} else {
+		entropy::fill(buffer);
+	}
+
+	len as isize
+}
+
+/// Fills the buffer with cryptographically secure random bytes and returns their number.
+/// Before the generator of the kernel has been seeded, the function blocks or fails with
+/// `-EAGAIN` (GRND_NONBLOCK). Afterwards, it never fails.
+#[no_mangle]
+pub extern "C" fn sys_getrandom(buf: *mut u8, len: usize, flags: u32) -> isize {
+	kernel_function!(__sys_getrandom(buf, len, flags))
+}
+
 /// The function computes a sequence of pseudo-random integers
 /// in the range of 0 to RAND_MAX
 #[no_mangle]
@@ -70,4 +111,6 @@ pub fn random_init() {
 	let seed: u32 = arch::processor::get_timestamp() as u32;
 
 	*PARK_MILLER_LEHMER_SEED.lock() = seed;
+
+	entropy::init();
 }
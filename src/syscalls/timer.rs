@@ -7,6 +7,7 @@
 
 use crate::arch;
 use crate::errno::*;
+use crate::synch::spinlock::Spinlock;
 use crate::syscalls::__sys_usleep;
 
 #[derive(Copy, Clone, Debug)]
@@ -16,6 +17,13 @@ pub struct itimerval {
 	pub it_value: timeval,
 }
 
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct itimerspec {
+	pub it_interval: timespec,
+	pub it_value: timespec,
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct timespec {
@@ -36,9 +44,91 @@ pub const CLOCK_THREAD_CPUTIME_ID: u64 = 3;
 pub const CLOCK_MONOTONIC: u64 = 4;
 pub const TIMER_ABSTIME: i32 = 4;
 
-fn microseconds_to_timespec(microseconds: u64, result: &mut timespec) {
-	result.tv_sec = (microseconds / 1_000_000) as i64;
-	result.tv_nsec = ((microseconds % 1_000_000) * 1000) as i64;
+/// Maximum rate (in parts per million) at which adjtime slews the wall clock.
+/// Like on BSD, the clock is slowed down or sped up by 0.05%.
+const ADJTIME_SLEW_RATE_PPM: u64 = 500;
+
+static WALL_CLOCK: Spinlock<WallClock> = Spinlock::new(WallClock::new());
+
+/// The wall clock (CLOCK_REALTIME) is maintained as an offset to the monotonic clock.
+/// Therefore, setting or adjusting it never influences the monotonic clock.
+struct WallClock {
+	/// Difference between the wall clock and the monotonic clock in nanoseconds
+	offset: i64,
+	/// Part of the adjtime correction (in nanoseconds), which has not been applied yet
+	pending_adjustment: i64,
+	/// Monotonic time (in nanoseconds) when the offset has been updated the last time
+	last_update: u64,
+	/// The offset is lazily initialized with the boot time
+	initialized: bool,
+}
+
+impl WallClock {
+	const fn new() -> Self {
+		Self {
+			offset: 0,
+			pending_adjustment: 0,
+			last_update: 0,
+			initialized: false,
+		}
+	}
+
+	/// Apply the part of a pending adjustment, which can be slewed in since the last update.
+	fn update(&mut self, now: u64) {
+		if !self.initialized {
+			self.offset = (arch::get_boot_time() * 1000) as i64;
+			self.last_update = now;
+			self.initialized = true;
+		}
+
+		let elapsed = now.saturating_sub(self.last_update);
+		let max_step = (elapsed * ADJTIME_SLEW_RATE_PPM / 1_000_000) as i64;
+		let step = if self.pending_adjustment > 0 {
+			self.pending_adjustment.min(max_step)
+		} else {
+			self.pending_adjustment.max(-max_step)
+		};
+
+		self.offset += step;
+		self.pending_adjustment -= step;
+		self.last_update = now;
+	}
+
+	/// Returns the wall clock time in nanoseconds for the given monotonic time.
+	fn get(&mut self, now: u64) -> u64 {
+		self.update(now);
+		(now as i64 + self.offset) as u64
+	}
+
+	/// Step the wall clock to the given time, which cancels a running adjustment.
+	fn set(&mut self, now: u64, nanoseconds: u64) {
+		self.update(now);
+		self.offset = nanoseconds as i64 - now as i64;
+		self.pending_adjustment = 0;
+	}
+
+	/// Start to slew the wall clock by the given number of nanoseconds and
+	/// return the remaining part of the previous adjustment.
+	fn adjust(&mut self, now: u64, delta: Option<i64>) -> i64 {
+		self.update(now);
+		let remaining = self.pending_adjustment;
+		if let Some(delta) = delta {
+			self.pending_adjustment = delta;
+		}
+
+		remaining
+	}
+}
+
+/// Returns the current wall clock time in nanoseconds since the epoch.
+pub(crate) fn get_realtime_nanos() -> u64 {
+	let now = arch::processor::get_timer_nanos();
+	WALL_CLOCK.lock().get(now)
+}
+
+fn nanoseconds_to_timespec(nanoseconds: u64, result: &mut timespec) {
+	result.tv_sec = (nanoseconds / 1_000_000_000) as i64;
+	result.tv_nsec = (nanoseconds % 1_000_000_000) as i64;
 }
 
 fn microseconds_to_timeval(microseconds: u64, result: &mut timeval) {
@@ -46,6 +136,12 @@ fn microseconds_to_timeval(microseconds: u64, result: &mut timeval) {
 	result.tv_usec = (microseconds % 1_000_000) as i64;
 }
 
+fn nanoseconds_to_signed_timeval(nanoseconds: i64, result: &mut timeval) {
+	let microseconds = nanoseconds / 1000;
+	result.tv_sec = microseconds / 1_000_000;
+	result.tv_usec = microseconds % 1_000_000;
+}
+
 fn __sys_clock_getres(clock_id: u64, res: *mut timespec) -> i32 {
 	assert!(
 		!res.is_null(),
@@ -55,8 +151,8 @@ fn __sys_clock_getres(clock_id: u64, res: *mut timespec) -> i32 {
 
 	match clock_id {
 		CLOCK_REALTIME | CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID | CLOCK_MONOTONIC => {
-			// All clocks in HermitCore have 1 microsecond resolution.
-			microseconds_to_timespec(1, result);
+			// All clocks in HermitCore are derived from the same nanosecond timer.
+			nanoseconds_to_timespec(arch::processor::get_timer_resolution(), result);
 			0
 		}
 		_ => {
@@ -79,14 +175,12 @@ fn __sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
 	let result = unsafe { &mut *tp };
 
 	match clock_id {
-		CLOCK_REALTIME | CLOCK_MONOTONIC => {
-			let mut microseconds = arch::processor::get_timer_ticks();
-
-			if clock_id == CLOCK_REALTIME {
-				microseconds += arch::get_boot_time();
-			}
-
-			microseconds_to_timespec(microseconds, result);
+		CLOCK_REALTIME => {
+			nanoseconds_to_timespec(get_realtime_nanos(), result);
+			0
+		}
+		CLOCK_MONOTONIC => {
+			nanoseconds_to_timespec(arch::processor::get_timer_nanos(), result);
 			0
 		}
 		_ => {
@@ -129,11 +223,13 @@ fn __sys_clock_nanosleep(
 				+ (requested_time.tv_nsec as u64) / 1_000;
 
 			if flags & TIMER_ABSTIME > 0 {
-				microseconds -= arch::processor::get_timer_ticks();
+				let now = if clock_id == CLOCK_REALTIME {
+					get_realtime_nanos() / 1000
+				} else {
+					arch::processor::get_timer_ticks()
+				};
 
-				if clock_id == CLOCK_REALTIME {
-					microseconds -= arch::get_boot_time();
-				}
+				microseconds = microseconds.saturating_sub(now);
 			}
 
 			__sys_usleep(microseconds);
@@ -153,10 +249,39 @@ pub extern "C" fn sys_clock_nanosleep(
 	kernel_function!(__sys_clock_nanosleep(clock_id, flags, rqtp, rmtp))
 }
 
-fn __sys_clock_settime(_clock_id: u64, _tp: *const timespec) -> i32 {
-	// We don't support setting any clocks yet.
-	debug!("sys_clock_settime is unimplemented, returning -EINVAL");
-	-EINVAL
+fn __sys_clock_settime(clock_id: u64, tp: *const timespec) -> i32 {
+	assert!(
+		!tp.is_null(),
+		"sys_clock_settime called with a zero tp parameter"
+	);
+	let requested_time = unsafe { &*tp };
+	if requested_time.tv_sec < 0
+		|| requested_time.tv_nsec < 0
+		|| requested_time.tv_nsec > 999_999_999
+	{
+		debug!("sys_clock_settime called with an invalid time, returning -EINVAL");
+		return -EINVAL;
+	}
+
+	match clock_id {
+		CLOCK_REALTIME => {
+			let nanoseconds =
+				(requested_time.tv_sec as u64) * 1_000_000_000 + requested_time.tv_nsec as u64;
+			let now = arch::processor::get_timer_nanos();
+
+			WALL_CLOCK.lock().set(now, nanoseconds);
+			arch::set_system_time(nanoseconds / 1000);
+			0
+		}
+		_ => {
+			// The monotonic clock and the CPU time clocks can't be set.
+			debug!(
+				"Called sys_clock_settime for unsupported clock {}",
+				clock_id
+			);
+			-EINVAL
+		}
+	}
 }
 
 #[no_mangle]
@@ -166,10 +291,7 @@ pub extern "C" fn sys_clock_settime(clock_id: u64, tp: *const timespec) -> i32 {
 
 fn __sys_gettimeofday(tp: *mut timeval, tz: usize) -> i32 {
 	if let Some(result) = unsafe { tp.as_mut() } {
-		// Return the current time based on the wallclock time when we were booted up
-		// plus the current timer ticks.
-		let microseconds = arch::get_boot_time() + arch::processor::get_timer_ticks();
-		microseconds_to_timeval(microseconds, result);
+		microseconds_to_timeval(get_realtime_nanos() / 1000, result);
 	}
 
 	if tz > 0 {
@@ -185,6 +307,43 @@ pub extern "C" fn sys_gettimeofday(tp: *mut timeval, tz: usize) -> i32 {
 	kernel_function!(__sys_gettimeofday(tp, tz))
 }
 
+fn __sys_adjtime(delta: *const timeval, olddelta: *mut timeval) -> i32 {
+	let delta = match unsafe { delta.as_ref() } {
+		Some(delta) => {
+			if delta.tv_usec <= -1_000_000 || delta.tv_usec >= 1_000_000 {
+				debug!("sys_adjtime called with an invalid delta, returning -EINVAL");
+				return -EINVAL;
+			}
+
+			match delta
+				.tv_sec
+				.checked_mul(1_000_000_000)
+				.and_then(|nanoseconds| nanoseconds.checked_add(delta.tv_usec * 1000))
+			{
+				Some(nanoseconds) => Some(nanoseconds),
+				None => return -EINVAL,
+			}
+		}
+		None => None,
+	};
+
+	let now = arch::processor::get_timer_nanos();
+	let remaining = WALL_CLOCK.lock().adjust(now, delta);
+
+	if let Some(result) = unsafe { olddelta.as_mut() } {
+		nanoseconds_to_signed_timeval(remaining, result);
+	}
+
+	0
+}
+
+/// Gradually adjusts the wall clock by `delta` by slowing it down or speeding it up.
+/// The remaining part of a previous adjustment is returned in `olddelta`.
+#[no_mangle]
+pub extern "C" fn sys_adjtime(delta: *const timeval, olddelta: *mut timeval) -> i32 {
+	kernel_function!(__sys_adjtime(delta, olddelta))
+}
+
 #[no_mangle]
 fn __sys_setitimer(_which: i32, _value: *const itimerval, _ovalue: *mut itimerval) -> i32 {
 	debug!("Called sys_setitimer, which is unimplemented and always returns 0");
@@ -199,3 +358,21 @@ pub extern "C" fn sys_setitimer(
 ) -> i32 {
 	kernel_function!(__sys_setitimer(which, value, ovalue))
 }
+
+#[cfg(not(target_os = "hermit"))]
+#[test]
+fn slew_wall_clock() {
+	let mut wall_clock = WallClock::new();
+	wall_clock.initialized = true;
+
+	wall_clock.set(0, 1_000_000_000);
+	assert_eq!(wall_clock.adjust(0, Some(-1_000_000)), 0);
+
+	// After one second, 500 microseconds of the adjustment have been applied.
+	assert_eq!(wall_clock.get(1_000_000_000), 1_999_500_000);
+	assert_eq!(wall_clock.adjust(1_000_000_000, None), -500_000);
+
+	// The adjustment is never overshot.
+	assert_eq!(wall_clock.get(10_000_000_000), 10_999_000_000);
+	assert_eq!(wall_clock.adjust(10_000_000_000, None), 0);
+}
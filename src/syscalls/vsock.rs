@@ -0,0 +1,406 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Stream sockets of the address family AF_VSOCK, which are connected to the host
+//! by a virtio-vsock device. The sockets are event objects, so they share the
+//! descriptor table with pipes and timers and can be used with poll and epoll.
+//!
+//! With the feature `tcp`, the socket system calls dispatch to this module.
+//! Otherwise, the module provides the socket system calls itself.
+
+use crate::arch;
+use crate::arch::x86_64::kernel::pci;
+use crate::arch::x86_64::kernel::virtio_vsock::{
+	ConnectionKey, VirtioVsockDriver, VIRTIO_VSOCK_SHUTDOWN_RCV, VIRTIO_VSOCK_SHUTDOWN_SEND,
+};
+use crate::errno::*;
+use crate::scheduler::task::{TaskHandle, TaskId};
+use crate::synch::spinlock::Spinlock;
+use crate::syscalls::event::{
+	block_on_object, get_object, insert_object, wait_for_event, ObjectInterface,
+};
+use crate::syscalls::poll::{POLLHUP, POLLIN, POLLOUT};
+use alloc::sync::Arc;
+use core::any::Any;
+use core::{mem, slice};
+
+pub const AF_VSOCK: i32 = 40;
+/// Binds a socket to the context id of the guest
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+pub const VMADDR_CID_HYPERVISOR: u32 = 0;
+pub const VMADDR_CID_HOST: u32 = 2;
+/// Binds a socket to an unused port
+pub const VMADDR_PORT_ANY: u32 = u32::MAX;
+
+const SOCK_STREAM: i32 = 1;
+const MSG_DONTWAIT: i32 = 0x08;
+const SHUT_RD: i32 = 0;
+const SHUT_WR: i32 = 1;
+const SHUT_RDWR: i32 = 2;
+
+/// Time (in microseconds), after which a blocking connect fails
+const CONNECT_TIMEOUT: u64 = 2_000_000;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct sockaddr_vm {
+	pub svm_len: u8,
+	pub svm_family: u8,
+	pub svm_reserved1: u16,
+	pub svm_port: u32,
+	pub svm_cid: u32,
+	pub svm_zero: [u8; 4],
+}
+
+#[derive(Copy, Clone, Debug)]
+enum SocketState {
+	Unbound,
+	/// The port is VMADDR_PORT_ANY, if the socket is bound to an unused port.
+	Bound(u32),
+	Listening(u32),
+	/// The connection is established or pending
+	Stream(ConnectionKey),
+}
+
+struct VsockSocket {
+	driver: &'static VirtioVsockDriver<'static>,
+	state: Spinlock<SocketState>,
+}
+
+impl VsockSocket {
+	fn state(&self) -> SocketState {
+		*self.state.lock()
+	}
+}
+
+impl Drop for VsockSocket {
+	fn drop(&mut self) {
+		match self.state() {
+			SocketState::Listening(port) => self.driver.unlisten(port),
+			SocketState::Stream(key) => self.driver.close(&key),
+			_ => {}
+		}
+	}
+}
+
+impl ObjectInterface for VsockSocket {
+	fn poll(&self) -> i16 {
+		match self.state() {
+			SocketState::Listening(port) => self.driver.listener_events(port),
+			SocketState::Stream(key) => self.driver.connection_events(&key),
+			// an unconnected socket is closed
+			_ => POLLOUT | POLLHUP,
+		}
+	}
+
+	fn register(&self, task: TaskHandle) {
+		self.driver.waiters().register(task);
+	}
+
+	fn unregister(&self, id: TaskId) {
+		self.driver.waiters().unregister(id);
+	}
+
+	fn generation(&self) -> u64 {
+		self.driver.waiters().generation()
+	}
+
+	fn read(&self, buf: &mut [u8]) -> Result<usize, i32> {
+		match self.state() {
+			SocketState::Stream(key) => self.driver.recv(&key, buf),
+			_ => Err(-ENOTCONN),
+		}
+	}
+
+	fn write(&self, buf: &[u8]) -> Result<usize, i32> {
+		match self.state() {
+			SocketState::Stream(key) => self.driver.send(&key, buf),
+			_ => Err(-ENOTCONN),
+		}
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+/// Calls `f` with the socket `fd` and its blocking mode.
+fn with_socket<T, F>(fd: i32, f: F) -> Result<T, i32>
+where
+	F: FnOnce(&Arc<dyn ObjectInterface>, &VsockSocket, bool) -> Result<T, i32>,
+{
+	let (object, nonblocking) = get_object(fd)?;
+	let socket = object
+		.as_any()
+		.downcast_ref::<VsockSocket>()
+		.ok_or(-ENOTSOCK)?;
+
+	f(&object, socket, nonblocking)
+}
+
+fn sockaddr_to_address(addr: *const sockaddr_vm, addrlen: u32) -> Result<(u32, u32), i32> {
+	if addr.is_null() || (addrlen as usize) < mem::size_of::<sockaddr_vm>() {
+		return Err(-EINVAL);
+	}
+
+	let addr = unsafe { &*addr };
+	if i32::from(addr.svm_family) != AF_VSOCK {
+		return Err(-EAFNOSUPPORT);
+	}
+
+	Ok((addr.svm_cid, addr.svm_port))
+}
+
+/// Stores the address in the buffer of the application and truncates it if necessary.
+fn address_to_sockaddr(cid: u64, port: u32, addr: *mut sockaddr_vm, addrlen: *mut u32) {
+	if addr.is_null() || addrlen.is_null() {
+		return;
+	}
+
+	let sockaddr = sockaddr_vm {
+		svm_len: mem::size_of::<sockaddr_vm>() as u8,
+		svm_family: AF_VSOCK as u8,
+		svm_reserved1: 0,
+		svm_port: port,
+		svm_cid: cid as u32,
+		svm_zero: [0; 4],
+	};
+	let len = mem::size_of::<sockaddr_vm>().min(unsafe { *addrlen } as usize);
+	unsafe {
+		let source = slice::from_raw_parts(&sockaddr as *const _ as *const u8, len);
+		slice::from_raw_parts_mut(addr as *mut u8, len).copy_from_slice(source);
+		*addrlen = mem::size_of::<sockaddr_vm>() as u32;
+	}
+}
+
+pub(crate) fn vsock_socket(type_: i32, protocol: i32) -> i32 {
+	if type_ != SOCK_STREAM || protocol != 0 {
+		debug!(
+			"vsock socket with unsupported type {} and protocol {}",
+			type_, protocol
+		);
+		return -EPROTONOSUPPORT;
+	}
+
+	match pci::get_vsock_driver() {
+		Some(driver) => insert_object(
+			Arc::new(VsockSocket {
+				driver,
+				state: Spinlock::new(SocketState::Unbound),
+			}),
+			false,
+		),
+		None => -EAFNOSUPPORT,
+	}
+}
+
+pub(crate) fn vsock_bind(fd: i32, addr: *const sockaddr_vm, addrlen: u32) -> i32 {
+	with_socket(fd, |_, socket, _| {
+		let (cid, port) = sockaddr_to_address(addr, addrlen)?;
+		if cid != VMADDR_CID_ANY && u64::from(cid) != socket.driver.guest_cid() {
+			return Err(-EADDRNOTAVAIL);
+		}
+
+		let mut state = socket.state.lock();
+		match *state {
+			SocketState::Unbound => {
+				*state = SocketState::Bound(port);
+				Ok(0)
+			}
+			_ => Err(-EINVAL),
+		}
+	})
+	.unwrap_or_else(|e| e)
+}
+
+pub(crate) fn vsock_listen(fd: i32, backlog: i32) -> i32 {
+	with_socket(fd, |_, socket, _| {
+		let mut state = socket.state.lock();
+		match *state {
+			SocketState::Bound(port) => {
+				let port = Some(port).filter(|port| *port != VMADDR_PORT_ANY);
+				let port = socket.driver.listen(port, backlog.max(1) as usize)?;
+				*state = SocketState::Listening(port);
+				Ok(0)
+			}
+			SocketState::Listening(_) => Ok(0),
+			_ => Err(-EINVAL),
+		}
+	})
+	.unwrap_or_else(|e| e)
+}
+
+pub(crate) fn vsock_accept(fd: i32, addr: *mut sockaddr_vm, addrlen: *mut u32) -> i32 {
+	with_socket(fd, |object, socket, nonblocking| {
+		let port = match socket.state() {
+			SocketState::Listening(port) => port,
+			_ => return Err(-EINVAL),
+		};
+
+		let key = block_on_object(object, nonblocking, POLLIN, || socket.driver.accept(port))?;
+		address_to_sockaddr(key.peer_cid, key.peer_port, addr, addrlen);
+
+		Ok(insert_object(
+			Arc::new(VsockSocket {
+				driver: socket.driver,
+				state: Spinlock::new(SocketState::Stream(key)),
+			}),
+			false,
+		))
+	})
+	.unwrap_or_else(|e| e)
+}
+
+pub(crate) fn vsock_connect(fd: i32, addr: *const sockaddr_vm, addrlen: u32) -> i32 {
+	with_socket(fd, |object, socket, nonblocking| {
+		let (cid, port) = sockaddr_to_address(addr, addrlen)?;
+
+		let key = {
+			let mut state = socket.state.lock();
+			let local_port = match *state {
+				SocketState::Unbound | SocketState::Bound(VMADDR_PORT_ANY) => None,
+				SocketState::Bound(port) => Some(port),
+				SocketState::Listening(_) => return Err(-EINVAL),
+				SocketState::Stream(key) => {
+					return match socket.driver.is_connected(&key) {
+						Ok(true) => Err(-EISCONN),
+						Ok(false) => Err(-EALREADY),
+						Err(e) => Err(e),
+					};
+				}
+			};
+
+			let key = socket.driver.connect(local_port, cid.into(), port)?;
+			*state = SocketState::Stream(key);
+			key
+		};
+
+		if nonblocking {
+			// The application has to wait for POLLOUT.
+			return Err(-EINPROGRESS);
+		}
+
+		let deadline = arch::processor::get_timer_ticks() + CONNECT_TIMEOUT;
+		let ready = wait_for_event(slice::from_ref(object), Some(deadline), || {
+			socket.driver.is_connected(&key) != Ok(false)
+		});
+		if !ready {
+			// abort the pending connection
+			socket.driver.close(&key);
+			*socket.state.lock() = SocketState::Unbound;
+			return Err(-ETIMEDOUT);
+		}
+
+		socket.driver.is_connected(&key).map(|_| 0)
+	})
+	.unwrap_or_else(|e| e)
+}
+
+pub(crate) fn vsock_send(fd: i32, buf: *const u8, len: usize, flags: i32) -> isize {
+	let buffer = unsafe { slice::from_raw_parts(buf, len) };
+
+	with_socket(fd, |object, _, nonblocking| {
+		block_on_object(
+			object,
+			nonblocking || flags & MSG_DONTWAIT != 0,
+			POLLOUT,
+			|| object.write(buffer),
+		)
+	})
+	.map(|len| len as isize)
+	.unwrap_or_else(|e| e as isize)
+}
+
+pub(crate) fn vsock_recv(fd: i32, buf: *mut u8, len: usize, flags: i32) -> isize {
+	let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+
+	with_socket(fd, |object, _, nonblocking| {
+		block_on_object(
+			object,
+			nonblocking || flags & MSG_DONTWAIT != 0,
+			POLLIN,
+			|| object.read(buffer),
+		)
+	})
+	.map(|len| len as isize)
+	.unwrap_or_else(|e| e as isize)
+}
+
+pub(crate) fn vsock_shutdown(fd: i32, how: i32) -> i32 {
+	let flags = match how {
+		SHUT_RD => VIRTIO_VSOCK_SHUTDOWN_RCV,
+		SHUT_WR => VIRTIO_VSOCK_SHUTDOWN_SEND,
+		SHUT_RDWR => VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND,
+		_ => return -EINVAL,
+	};
+
+	with_socket(fd, |_, socket, _| match socket.state() {
+		SocketState::Stream(key) => socket.driver.shutdown(&key, flags).map(|_| 0),
+		_ => Err(-ENOTCONN),
+	})
+	.unwrap_or_else(|e| e)
+}
+
+#[cfg(not(feature = "tcp"))]
+fn __sys_socket(domain: i32, type_: i32, protocol: i32) -> i32 {
+	if domain != AF_VSOCK {
+		debug!("sys_socket called with unsupported domain {}", domain);
+		return -EAFNOSUPPORT;
+	}
+
+	vsock_socket(type_, protocol)
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_socket(domain: i32, type_: i32, protocol: i32) -> i32 {
+	kernel_function!(__sys_socket(domain, type_, protocol))
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_bind(fd: i32, addr: *const sockaddr_vm, addrlen: u32) -> i32 {
+	kernel_function!(vsock_bind(fd, addr, addrlen))
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_listen(fd: i32, backlog: i32) -> i32 {
+	kernel_function!(vsock_listen(fd, backlog))
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_accept(fd: i32, addr: *mut sockaddr_vm, addrlen: *mut u32) -> i32 {
+	kernel_function!(vsock_accept(fd, addr, addrlen))
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_connect(fd: i32, addr: *const sockaddr_vm, addrlen: u32) -> i32 {
+	kernel_function!(vsock_connect(fd, addr, addrlen))
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_send(fd: i32, buf: *const u8, len: usize, flags: i32) -> isize {
+	kernel_function!(vsock_send(fd, buf, len, flags))
+}
+
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_recv(fd: i32, buf: *mut u8, len: usize, flags: i32) -> isize {
+	kernel_function!(vsock_recv(fd, buf, len, flags))
+}
+
+/// Shuts down a part of a full-duplex connection.
+/// Named differently than `shutdown` to avoid a conflict with `sys_shutdown`.
+#[cfg(not(feature = "tcp"))]
+#[no_mangle]
+pub extern "C" fn sys_shutdown_socket(fd: i32, how: i32) -> i32 {
+	kernel_function!(vsock_shutdown(fd, how))
+}